@@ -2,8 +2,8 @@ use std::{
     collections::{HashMap, HashSet},
     iter::zip,
     pin::Pin,
-    sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
@@ -21,6 +21,7 @@ use crate::{
 };
 
 const GATEWAYS_PAGE_SIZE: U256 = U256([10000, 0, 0, 0]);
+const GATEWAY_INFO_TTL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Clone)]
 pub struct Allocation {
@@ -29,6 +30,45 @@ pub struct Allocation {
     pub computation_units: U256,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Epoch {
+    pub number: u32,
+    /// First L1 block of the epoch
+    pub start_block: u64,
+    /// Last L1 block of the epoch
+    pub end_block: u64,
+    pub start_time: SystemTime,
+    /// Estimated based on the average block time
+    pub end_time: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayAllocation {
+    pub gateway_id: PeerId,
+    pub epoch: u32,
+    pub computation_units: U256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayStatus {
+    Staked,
+    /// Gateway is registered, but its operator has nothing staked
+    NoStake,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayInfo {
+    pub peer_id: PeerId,
+    pub operator_addr: Address,
+    pub status: GatewayStatus,
+    /// Amount staked by the operator, shared by all gateways in the cluster
+    pub stake: U256,
+    /// L1 block at which the stake was locked. The registration block itself is not stored
+    /// on chain.
+    pub stake_lock_start: u128,
+    pub stake_lock_end: u128,
+}
+
 #[derive(Debug, Clone)]
 pub struct GatewayCluster {
     pub operator_addr: Address,
@@ -63,6 +103,8 @@ impl Worker {
 
 pub type NodeStream =
     Pin<Box<dyn Stream<Item = Result<HashSet<PeerId>, ClientError>> + Send + 'static>>;
+pub type WorkerStakeStream =
+    Pin<Box<dyn Stream<Item = Result<HashMap<PeerId, U256>, ClientError>> + Send + 'static>>;
 
 #[async_trait]
 pub trait Client: Send + Sync + 'static {
@@ -75,6 +117,17 @@ pub trait Client: Send + Sync + 'static {
     /// Get the time when the current epoch started
     async fn current_epoch_start(&self) -> Result<SystemTime, ClientError>;
 
+    /// Get the current epoch number along with its block range and timing
+    async fn current_epoch_info(&self) -> Result<Epoch, ClientError>;
+
+    /// Get the computation units allocated to the gateway in the given epoch.
+    /// Only the current epoch is available.
+    async fn gateway_allocation(
+        &self,
+        gateway_id: PeerId,
+        epoch: u32,
+    ) -> Result<GatewayAllocation, ClientError>;
+
     /// Get the on-chain ID for the worker
     async fn worker_id(&self, peer_id: PeerId) -> Result<U256, ClientError>;
 
@@ -90,6 +143,9 @@ pub trait Client: Send + Sync + 'static {
         peer_id: PeerId,
     ) -> Result<Option<SystemTime>, ClientError>;
 
+    /// Get gateway's operator and stake. Fails if the gateway is not registered.
+    async fn gateway_info(&self, peer_id: PeerId) -> Result<GatewayInfo, ClientError>;
+
     /// Get current active gateways
     async fn active_gateways(&self) -> Result<Vec<PeerId>, ClientError>;
 
@@ -117,6 +173,18 @@ pub trait Client: Send + Sync + 'static {
             }
         }))
     }
+
+    /// Get a stream of bonds of all active workers, keyed by peer ID
+    /// Updated on the given interval
+    fn worker_stakes_stream(self: Box<Self>, interval: Duration) -> WorkerStakeStream {
+        Box::pin(IntervalStream::new(tokio::time::interval(interval)).then(move |_| {
+            let client = self.clone_client();
+            async move {
+                let workers = client.active_workers().await?;
+                Ok(workers.into_iter().map(|w| (w.peer_id, w.bond)).collect())
+            }
+        }))
+    }
 }
 
 pub async fn get_client(rpc_args: &RpcArgs) -> Result<Box<dyn Client>, ClientError> {
@@ -142,6 +210,10 @@ struct EthersClient {
     allocations_viewer: AllocationsViewer<Provider<Transport>>,
     default_strategy_addr: Address,
     multicall_contract_addr: Option<Address>,
+    // Epoch info and gateway allocations rarely change within an epoch
+    epoch_cache: Arc<Mutex<Option<Epoch>>>,
+    allocations_cache: Arc<Mutex<HashMap<(PeerId, u32), U256>>>,
+    gateway_info_cache: Arc<Mutex<HashMap<PeerId, (Instant, GatewayInfo)>>>,
 }
 
 impl EthersClient {
@@ -168,6 +240,9 @@ impl EthersClient {
             allocations_viewer,
             default_strategy_addr,
             multicall_contract_addr: Some(rpc_args.multicall_addr()),
+            epoch_cache: Default::default(),
+            allocations_cache: Default::default(),
+            gateway_info_cache: Default::default(),
         }))
     }
 
@@ -209,6 +284,85 @@ impl Client for EthersClient {
         Ok(UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64()))
     }
 
+    async fn current_epoch_info(&self) -> Result<Epoch, ClientError> {
+        let cached = *self.epoch_cache.lock().expect("lock should not be poisoned");
+        if let Some(epoch) = cached.filter(|e| e.end_time > SystemTime::now()) {
+            return Ok(epoch);
+        }
+
+        let number = self.current_epoch().await?;
+        let next_epoch_start_block: u64 = self
+            .network_controller
+            .next_epoch()
+            .call()
+            .await?
+            .try_into()
+            .expect("Block number should not exceed u64 range");
+        let epoch_length: u64 = self
+            .network_controller
+            .epoch_length()
+            .call()
+            .await?
+            .try_into()
+            .expect("Epoch length should not exceed u64 range");
+        let avg_block_time = self.gateway_registry.average_block_time().call().await?.as_u64();
+        let start_block = next_epoch_start_block - epoch_length;
+        // Blocks returned by `next_epoch()` and `epoch_length()` are **L1 blocks**
+        let block = self
+            .l1_client
+            .get_block(BlockId::Number(start_block.into()))
+            .await?
+            .ok_or(ClientError::BlockNotFound)?;
+        let start_time = UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64());
+        let epoch = Epoch {
+            number,
+            start_block,
+            end_block: next_epoch_start_block - 1,
+            start_time,
+            end_time: start_time + Duration::from_secs(epoch_length * avg_block_time),
+        };
+        log::debug!("Current epoch: {epoch:?}");
+        *self.epoch_cache.lock().expect("lock should not be poisoned") = Some(epoch);
+        Ok(epoch)
+    }
+
+    async fn gateway_allocation(
+        &self,
+        gateway_id: PeerId,
+        epoch: u32,
+    ) -> Result<GatewayAllocation, ClientError> {
+        let cached = self
+            .allocations_cache
+            .lock()
+            .expect("lock should not be poisoned")
+            .get(&(gateway_id, epoch))
+            .copied();
+        let computation_units = match cached {
+            Some(cus) => cus,
+            None => {
+                // The contract only exposes the allocation for the current epoch
+                let current_epoch = self.current_epoch_info().await?.number;
+                if epoch != current_epoch {
+                    return Err(ClientError::EpochNotAvailable(epoch));
+                }
+                let cus = self
+                    .gateway_registry
+                    .computation_units_available(gateway_id.to_bytes().into())
+                    .call()
+                    .await?;
+                let mut cache = self.allocations_cache.lock().expect("lock should not be poisoned");
+                cache.retain(|(_, e), _| *e == epoch);
+                cache.insert((gateway_id, epoch), cus);
+                cus
+            }
+        };
+        Ok(GatewayAllocation {
+            gateway_id,
+            epoch,
+            computation_units,
+        })
+    }
+
     async fn worker_id(&self, peer_id: PeerId) -> Result<U256, ClientError> {
         let peer_id = peer_id.to_bytes().into();
         let id: U256 = self.worker_registration.worker_ids(peer_id).call().await?;
@@ -267,6 +421,44 @@ impl Client for EthersClient {
         Ok(Some(UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64())))
     }
 
+    async fn gateway_info(&self, peer_id: PeerId) -> Result<GatewayInfo, ClientError> {
+        let cached = self
+            .gateway_info_cache
+            .lock()
+            .expect("lock should not be poisoned")
+            .get(&peer_id)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < GATEWAY_INFO_TTL)
+            .map(|(_, info)| info.clone());
+        if let Some(info) = cached {
+            return Ok(info);
+        }
+
+        let gateway: contracts::Gateway =
+            self.gateway_registry.get_gateway(peer_id.to_bytes().into()).call().await?;
+        if gateway.operator == Address::zero() {
+            return Err(ClientError::GatewayNotRegistered(peer_id));
+        }
+        let stake = self.gateway_registry.get_stake(gateway.operator).call().await?;
+        let status = if stake.amount.is_zero() {
+            GatewayStatus::NoStake
+        } else {
+            GatewayStatus::Staked
+        };
+        let info = GatewayInfo {
+            peer_id,
+            operator_addr: gateway.operator,
+            status,
+            stake: stake.amount,
+            stake_lock_start: stake.lock_start,
+            stake_lock_end: stake.lock_end,
+        };
+        self.gateway_info_cache
+            .lock()
+            .expect("lock should not be poisoned")
+            .insert(peer_id, (Instant::now(), info.clone()));
+        Ok(info)
+    }
+
     async fn active_gateways(&self) -> Result<Vec<PeerId>, ClientError> {
         let latest_block = self.l2_client.get_block_number().await?;
         let mut active_gateways = Vec::new();
@@ -2,7 +2,7 @@ use ethers::{
     contract::{ContractError, MulticallError},
     prelude::{AbiError, Middleware},
 };
-use libp2p::identity::ParseError;
+use libp2p::{identity::ParseError, PeerId};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -22,6 +22,10 @@ pub enum ClientError {
     TxReceiptMissing,
     #[error("Block not found")]
     BlockNotFound,
+    #[error("Data for epoch {0} not available")]
+    EpochNotAvailable(u32),
+    #[error("Gateway {0} not registered")]
+    GatewayNotRegistered(PeerId),
 }
 
 impl<M: Middleware> From<ContractError<M>> for ClientError {
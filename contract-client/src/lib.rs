@@ -24,5 +24,8 @@ pub use ethers::types::{Address, U256};
 pub use libp2p::PeerId;
 
 pub use cli::{Network, RpcArgs};
-pub use client::{get_client, Allocation, Client, GatewayCluster, NodeStream, Worker};
+pub use client::{
+    get_client, Allocation, Client, Epoch, GatewayAllocation, GatewayCluster, GatewayInfo,
+    GatewayStatus, NodeStream, Worker, WorkerStakeStream,
+};
 pub use error::ClientError;
@@ -11,7 +11,11 @@ fn main() -> std::io::Result<()> {
         .field_attribute("messages.Pong.ping_hash", "#[serde(with = \"hex\")]")
         .field_attribute("messages.Ping.signature","#[serde(with = \"hex\")]")
         .field_attribute("messages.Query.signature", "#[serde(with = \"hex\")]")
+        .field_attribute("messages.DatasetRecord.signature", "#[serde(with = \"hex\")]")
         .field_attribute("messages.QueryExecuted.signature", "#[serde(with = \"hex\")]")
+        .field_attribute("messages.HandshakeChallenge.nonce", "#[serde(with = \"hex\")]")
+        .field_attribute("messages.HandshakeResponse.nonce", "#[serde(with = \"hex\")]")
+        .field_attribute("messages.HandshakeResponse.signature", "#[serde(with = \"hex\")]")
         .protoc_arg("--experimental_allow_proto3_optional")
         .compile_protos(&["proto/messages.proto"], &["proto/"])?;
     Ok(())
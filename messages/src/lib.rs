@@ -58,6 +58,43 @@ impl From<&query_result::Result> for query_finished::Result {
             query_result::Result::ServerError(err) => Self::ServerError(err.clone()),
             query_result::Result::NoAllocation(()) => Self::NoAllocation(()),
             query_result::Result::Timeout(()) => Self::Timeout(()),
+            query_result::Result::NotFound(err) => Self::NotFound(err.clone()),
+            query_result::Result::Overloaded(()) => Self::Overloaded(()),
+        }
+    }
+}
+
+/// Category of a failed query, telling the gateway whether to retry it with another worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryErrorKind {
+    /// The query itself is invalid, it would fail on any worker
+    BadRequest,
+    /// The worker doesn't have the requested data
+    NotFound,
+    Timeout,
+    /// The worker can't take the query right now
+    Overloaded,
+    Internal,
+}
+
+impl QueryErrorKind {
+    /// Whether sending the same query to a different worker may succeed
+    pub fn is_retriable(self) -> bool {
+        !matches!(self, Self::BadRequest)
+    }
+}
+
+impl query_result::Result {
+    /// Error category, or `None` if the query succeeded
+    pub fn error_kind(&self) -> Option<QueryErrorKind> {
+        match self {
+            Self::Ok(_) => None,
+            Self::BadRequest(_) => Some(QueryErrorKind::BadRequest),
+            Self::NotFound(_) => Some(QueryErrorKind::NotFound),
+            Self::Timeout(()) => Some(QueryErrorKind::Timeout),
+            // No compute units allocated for the client, another worker may still serve it
+            Self::NoAllocation(()) | Self::Overloaded(()) => Some(QueryErrorKind::Overloaded),
+            Self::ServerError(_) => Some(QueryErrorKind::Internal),
         }
     }
 }
@@ -75,6 +112,23 @@ impl SizeAndHash {
     }
 }
 
+impl Ping {
+    /// IDs of the datasets the worker has data for
+    pub fn datasets(&self) -> impl Iterator<Item = &str> {
+        self.stored_ranges.iter().map(|ranges| ranges.url.as_str())
+    }
+
+    /// Free storage space in bytes. `None` if the worker doesn't report it (older versions).
+    pub fn free_capacity(&self) -> Option<u64> {
+        self.free_bytes
+    }
+
+    /// Whether the worker reported being overloaded. Older workers never do.
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.unwrap_or(false)
+    }
+}
+
 #[cfg(feature = "semver")]
 impl Ping {
     pub fn sem_version(&self) -> semver::Version {
@@ -131,6 +185,96 @@ impl QueryResult {
         Self {
             query_id,
             result: Some(result),
+            trace_id: None,
+            stats: None,
         }
     }
+
+    /// Result with the trace ID of the query, if it has one
+    pub fn traced(mut self, query: &Query) -> Self {
+        self.trace_id.clone_from(&query.trace_id);
+        self
+    }
+
+    /// Result with execution stats attached. Errors may carry stats too, e.g. of the part
+    /// of the data scanned before the failure.
+    pub fn with_stats(mut self, stats: QueryStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Error category, or `None` if the query succeeded. A result without any payload
+    /// is treated as an internal error.
+    pub fn error_kind(&self) -> Option<QueryErrorKind> {
+        self.result
+            .as_ref()
+            .map_or(Some(QueryErrorKind::Internal), query_result::Result::error_kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_decisions() {
+        let retry_elsewhere = |result: query_result::Result| {
+            QueryResult::new("query".to_string(), result)
+                .error_kind()
+                .map(QueryErrorKind::is_retriable)
+        };
+        assert_eq!(retry_elsewhere(query_result::Result::Ok(Default::default())), None);
+        assert_eq!(
+            retry_elsewhere(query_result::Result::BadRequest("invalid".into())),
+            Some(false)
+        );
+        assert_eq!(retry_elsewhere(query_result::Result::NotFound("no chunk".into())), Some(true));
+        assert_eq!(retry_elsewhere(query_result::Result::Timeout(())), Some(true));
+        assert_eq!(retry_elsewhere(query_result::Result::Overloaded(())), Some(true));
+        assert_eq!(retry_elsewhere(query_result::Result::NoAllocation(())), Some(true));
+        assert_eq!(retry_elsewhere(query_result::Result::ServerError("error".into())), Some(true));
+        let empty = QueryResult {
+            query_id: "query".to_string(),
+            result: None,
+            trace_id: None,
+            stats: None,
+        };
+        assert_eq!(empty.error_kind(), Some(QueryErrorKind::Internal));
+    }
+
+    #[test]
+    fn test_traced_result() {
+        let query = Query {
+            trace_id: Some("trace".to_string()),
+            ..Default::default()
+        };
+        let result = QueryResult::new("query".to_string(), query_result::Result::Timeout(()));
+        assert_eq!(result.clone().traced(&query).trace_id.as_deref(), Some("trace"));
+        assert_eq!(result.traced(&Query::default()).trace_id, None);
+    }
+
+    #[test]
+    fn test_ping_state() {
+        // Pings from older workers don't have the new fields
+        let old = Ping {
+            worker_id: Some("worker".to_string()),
+            stored_ranges: vec![DatasetRanges {
+                url: "s3://moonbeam-evm-1".to_string(),
+                ranges: vec![],
+            }],
+            ..Default::default()
+        };
+        let decoded = Ping::decode(old.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded.datasets().collect::<Vec<_>>(), vec!["s3://moonbeam-evm-1"]);
+        assert_eq!(decoded.free_capacity(), None);
+        assert!(!decoded.is_overloaded());
+
+        let ping = Ping {
+            free_bytes: Some(1024),
+            overloaded: Some(true),
+            ..old
+        };
+        assert_eq!(ping.free_capacity(), Some(1024));
+        assert!(ping.is_overloaded());
+    }
 }
@@ -5,7 +5,9 @@ use libp2p::{
     PeerId,
 };
 
-use crate::{Ping, ProstMsg, Query, QueryExecuted};
+use crate::{DatasetRecord, HandshakeResponse, Ping, ProstMsg, Query, QueryExecuted};
+
+const HANDSHAKE_DOMAIN: &[u8] = b"subsquid-handshake/1";
 
 pub fn msg_hash<M: ProstMsg>(msg: &M) -> Vec<u8> {
     let mut result = [0u8; 32];
@@ -17,7 +19,7 @@ pub fn msg_hash<M: ProstMsg>(msg: &M) -> Vec<u8> {
 
 fn verify_signature<T: SignedMessage>(peer_id: &PeerId, msg: &mut T) -> bool {
     let sig = msg.detach_signature();
-    let encoded = msg.encode_to_vec();
+    let encoded = msg.signed_bytes();
     let result = match PublicKey::try_decode_protobuf(&peer_id.to_bytes()[2..]) {
         Ok(pubkey) => pubkey.verify(&encoded, &sig),
         Err(_) => false,
@@ -30,9 +32,14 @@ pub trait SignedMessage: ProstMsg + Sized {
     fn detach_signature(&mut self) -> Vec<u8>;
     fn attach_signature(&mut self, signature: Vec<u8>);
 
+    /// Bytes covered by the signature, the message encoded without the signature by default
+    fn signed_bytes(&self) -> Vec<u8> {
+        self.encode_to_vec()
+    }
+
     fn sign(&mut self, keypair: &Keypair) {
         _ = self.detach_signature(); // To make signing idempotent
-        let bytes = self.encode_to_vec();
+        let bytes = self.signed_bytes();
         let signature = keypair.sign(&bytes).expect("infallible for Ed25519");
         self.attach_signature(signature);
     }
@@ -52,6 +59,16 @@ impl SignedMessage for Ping {
     }
 }
 
+impl SignedMessage for DatasetRecord {
+    fn detach_signature(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.signature)
+    }
+
+    fn attach_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+}
+
 impl SignedMessage for Query {
     fn detach_signature(&mut self) -> Vec<u8> {
         std::mem::take(&mut self.signature)
@@ -62,6 +79,22 @@ impl SignedMessage for Query {
     }
 }
 
+impl SignedMessage for HandshakeResponse {
+    fn detach_signature(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.signature)
+    }
+
+    fn attach_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+
+    // The protobuf encoding of the response could also be a valid encoding of another
+    // signed message, so the signed payload is prefixed with a domain tag instead
+    fn signed_bytes(&self) -> Vec<u8> {
+        [HANDSHAKE_DOMAIN, &self.nonce, self.role.as_bytes()].concat()
+    }
+}
+
 impl SignedMessage for QueryExecuted {
     fn detach_signature(&mut self) -> Vec<u8> {
         std::mem::take(&mut self.signature)
@@ -1,3 +1,4 @@
+pub mod base;
 #[cfg(feature = "gateway")]
 pub mod gateway;
 #[cfg(feature = "logs-collector")]
@@ -6,5 +7,7 @@ pub mod logs_collector;
 pub mod observer;
 #[cfg(feature = "scheduler")]
 pub mod scheduler;
+#[cfg(test)]
+pub(crate) mod testing;
 #[cfg(feature = "worker")]
 pub mod worker;
@@ -0,0 +1,298 @@
+use std::{future::Future, time::Duration};
+
+use futures_core::Stream;
+use libp2p::{
+    swarm::{dial_opts::DialOpts, NetworkBehaviour},
+    Multiaddr, PeerId, Swarm,
+};
+use tokio::sync::watch;
+
+use crate::{
+    bandwidth::{BandwidthStats, BandwidthTracker},
+    behaviour::{
+        base::{
+            BaseBehaviour, BootNodeUpdate, BootstrapError, ConnectionEvent, ConnectionEvents,
+            ConnectionType, ConnectionTypes, NatState, PauseMode, PeerInfo, PeerInfoCache,
+            PeerWaiters, TopicStreams, TransportPause,
+        },
+        handshake::{NodeRole, PeerRoles},
+        peer_blocks::{PeerBlockState, PeerBlocks},
+        pubsub::PubsubMsg,
+        reconnect_backoff::{ReconnectBackoffState, ReconnectBackoffs},
+        substream_rates::SubstreamRates,
+    },
+    clock_skew::ClockSkewTracker,
+    protocol::Topic,
+    util::Sender,
+    BootNode, QueueFull, Timeout,
+};
+
+/// Part of the transport handles shared by all the actors: peer management, boot nodes, pausing,
+/// topic streams and the state of the [`BaseBehaviour`]. Each actor's handle dereferences to it.
+#[derive(Clone)]
+pub struct BaseTransportHandle {
+    disconnects_tx: Sender<PeerId>,
+    boot_node_updates_tx: Sender<BootNodeUpdate>,
+    boot_nodes: watch::Receiver<Vec<BootNode>>,
+    peer_info: PeerInfoCache,
+    bandwidth: BandwidthTracker,
+    reconnect_backoffs: ReconnectBackoffs,
+    peer_blocks: PeerBlocks,
+    substream_rates: SubstreamRates,
+    clock_skew: ClockSkewTracker,
+    pub(super) peer_waiters: PeerWaiters,
+    pause: TransportPause,
+    topic_streams: TopicStreams,
+    connection_events: ConnectionEvents,
+    peer_roles: PeerRoles,
+    connection_types: ConnectionTypes,
+    routing_table_size: watch::Receiver<usize>,
+    nat_state: watch::Receiver<NatState>,
+}
+
+impl BaseTransportHandle {
+    pub(super) fn new(
+        base: &BaseBehaviour,
+        disconnects_tx: Sender<PeerId>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
+    ) -> Self {
+        Self {
+            disconnects_tx,
+            boot_node_updates_tx,
+            boot_nodes: base.boot_nodes(),
+            peer_info: base.peer_info_cache(),
+            bandwidth: base.bandwidth_tracker(),
+            reconnect_backoffs: base.reconnect_backoffs(),
+            peer_blocks: base.peer_blocks(),
+            substream_rates: base.substream_rates(),
+            clock_skew: base.clock_skew_tracker(),
+            peer_waiters: base.peer_waiters(),
+            pause: base.pause_state(),
+            topic_streams: base.topic_streams(),
+            connection_events: base.connection_events(),
+            peer_roles: base.peer_roles(),
+            connection_types: base.connection_types(),
+            routing_table_size: base.routing_table_size(),
+            nat_state: base.nat_state(),
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    pub(super) fn mock(
+        disconnects_tx: Sender<PeerId>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
+    ) -> Self {
+        Self {
+            disconnects_tx,
+            boot_node_updates_tx,
+            boot_nodes: watch::channel(vec![]).1,
+            peer_info: Default::default(),
+            bandwidth: Default::default(),
+            reconnect_backoffs: Default::default(),
+            peer_blocks: Default::default(),
+            substream_rates: Default::default(),
+            clock_skew: Default::default(),
+            peer_waiters: Default::default(),
+            pause: Default::default(),
+            topic_streams: Default::default(),
+            connection_events: Default::default(),
+            peer_roles: Default::default(),
+            connection_types: Default::default(),
+            // The mock transport is always ready
+            routing_table_size: watch::channel(usize::MAX).1,
+            nat_state: watch::channel(Default::default()).1,
+        }
+    }
+
+    /// Close all connections to the peer. Unlike blocking, it doesn't prevent reconnecting.
+    pub fn disconnect_peer(&self, peer_id: PeerId) -> Result<(), QueueFull> {
+        log::debug!("Queueing disconnect of {peer_id}");
+        self.disconnects_tx.try_send(peer_id)
+    }
+
+    /// Disconnect the peer and refuse connections with it until the block expires. Each repeated
+    /// block lasts twice as long, up to the configured limit. Returns the duration of the block,
+    /// which applies even if the disconnect couldn't be queued.
+    pub fn block_peer(&self, peer_id: PeerId) -> Result<Duration, QueueFull> {
+        let ttl = self.peer_blocks.block(peer_id);
+        self.disconnects_tx.try_send(peer_id)?;
+        Ok(ttl)
+    }
+
+    /// Lift the block early. Returns `false` if the peer wasn't blocked.
+    pub fn unblock_peer(&self, peer_id: &PeerId) -> bool {
+        self.peer_blocks.unblock(peer_id)
+    }
+
+    /// Block of the peer, including the time until it expires
+    pub fn peer_block(&self, peer_id: &PeerId) -> Option<PeerBlockState> {
+        self.peer_blocks.get(peer_id)
+    }
+
+    /// Inbound substreams per second recently opened by the peer, averaged over 10 seconds
+    pub fn peer_substream_rate(&self, peer_id: &PeerId) -> f64 {
+        self.substream_rates.rate(peer_id)
+    }
+
+    /// Add a boot node at runtime. It's dialed unless already connected.
+    pub fn add_boot_node(&self, peer_id: PeerId, address: Multiaddr) -> Result<(), QueueFull> {
+        log::debug!("Queueing addition of boot node {peer_id}");
+        let node = BootNode { peer_id, address };
+        self.boot_node_updates_tx.try_send(BootNodeUpdate::Add(node))
+    }
+
+    pub fn remove_boot_node(&self, peer_id: PeerId) -> Result<(), QueueFull> {
+        log::debug!("Queueing removal of boot node {peer_id}");
+        self.boot_node_updates_tx.try_send(BootNodeUpdate::Remove(peer_id))
+    }
+
+    /// Bootstrap the routing table now instead of waiting for the periodic bootstrap,
+    /// e.g. after adding a boot node. The returned future resolves once the bootstrap
+    /// has finished, it doesn't need to be awaited.
+    pub fn bootstrap_now(
+        &self,
+    ) -> Result<impl Future<Output = Result<(), BootstrapError>>, QueueFull> {
+        log::debug!("Queueing bootstrap");
+        let (update, result) = BootNodeUpdate::bootstrap();
+        self.boot_node_updates_tx.try_send(update)?;
+        Ok(result)
+    }
+
+    /// Current boot node set
+    pub fn boot_nodes(&self) -> Vec<BootNode> {
+        self.boot_nodes.borrow().clone()
+    }
+
+    /// Agent and protocol version advertised by the peer, if it has been identified
+    pub fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peer_info.get(peer_id)
+    }
+
+    /// Bytes exchanged with the peer over direct connections (zero if not known)
+    pub fn peer_bandwidth(&self, peer_id: &PeerId) -> BandwidthStats {
+        self.bandwidth.get(peer_id).unwrap_or_default()
+    }
+
+    /// Reconnection backoff of the peer, if it has disconnected recently
+    pub fn peer_reconnect_backoff(&self, peer_id: &PeerId) -> Option<ReconnectBackoffState> {
+        self.reconnect_backoffs.get(peer_id)
+    }
+
+    /// Estimated difference between the peer's clock and the local one in milliseconds
+    /// (positive if the peer's clock is ahead), based on the timestamps of its messages
+    pub fn estimated_clock_skew(&self, peer_id: &PeerId) -> Option<i64> {
+        self.clock_skew.get(peer_id)
+    }
+
+    /// How the open connections to the peer have been established (empty if not connected)
+    pub fn peer_connections(&self, peer_id: &PeerId) -> Vec<ConnectionType> {
+        self.connection_types.get(peer_id)
+    }
+
+    /// Number of established connections to all peers
+    pub fn connection_count(&self) -> usize {
+        self.connection_types.count()
+    }
+
+    /// Role the peer has proven in the handshake (see [`crate::P2PTransportBuilder::with_handshake`]),
+    /// `None` if it hasn't (yet) or if it's not connected
+    pub fn peer_role(&self, peer_id: &PeerId) -> Option<NodeRole> {
+        self.peer_roles.get(peer_id)
+    }
+
+    /// Wait until the peer is connected, looking it up in the DHT and dialing it if needed
+    pub async fn wait_for_peer(&self, peer_id: PeerId, timeout: Duration) -> Result<(), Timeout> {
+        self.peer_waiters.wait_for(peer_id, timeout).await
+    }
+
+    /// Stop dialing peers and sending new outbound messages, keeping the existing connections
+    /// alive, e.g. during maintenance. Messages submitted meanwhile are kept in the queues
+    /// or dropped, depending on `mode`.
+    pub fn pause(&self, mode: PauseMode) {
+        self.pause.pause(mode);
+    }
+
+    pub fn resume(&self) {
+        self.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// Stream of pub-sub messages on the topic (see [`Topic`] for well-known topics) which
+    /// have passed the same validation as the messages emitted as events.
+    /// While the stream is alive, the topic's messages are delivered to it instead of the event
+    /// stream. It may be created before the transport subscribes to the topic.
+    pub fn topic_stream(&self, topic: impl Into<Topic>) -> impl Stream<Item = PubsubMsg> {
+        self.topic_streams.register(topic.into().name())
+    }
+
+    /// Same as [`Self::topic_stream`], but buffering up to `capacity` messages instead of
+    /// the default 100. If the stream isn't consumed fast enough, the topic's messages are dropped.
+    pub fn topic_stream_with_capacity(
+        &self,
+        topic: impl Into<Topic>,
+        capacity: usize,
+    ) -> impl Stream<Item = PubsubMsg> {
+        self.topic_streams.register_with_capacity(topic.into().name(), capacity)
+    }
+
+    /// The number of the topic's messages dropped because a topic stream was full
+    pub fn topic_dropped_messages(&self, topic: impl Into<Topic>) -> u64 {
+        self.topic_streams.dropped(topic.into().name())
+    }
+
+    /// Stream of connection lifecycle events, independent of the event stream. Events are
+    /// dropped if the stream isn't consumed fast enough (see [`Self::dropped_connection_events`]).
+    pub fn connection_events(&self) -> impl Stream<Item = ConnectionEvent> {
+        self.connection_events.subscribe()
+    }
+
+    /// The number of connection events dropped because a stream was full
+    pub fn dropped_connection_events(&self) -> u64 {
+        self.connection_events.dropped()
+    }
+
+    /// Reachability of the node determined by AutoNAT, along with the confidence in it
+    pub fn nat_state(&self) -> NatState {
+        self.nat_state.borrow().clone()
+    }
+
+    /// Wait until the DHT routing table contains at least `min_routing_table_size` peers,
+    /// so that other peers can be found. Returns `false` if it doesn't happen within `timeout`.
+    pub async fn wait_until_ready(&self, min_routing_table_size: usize, timeout: Duration) -> bool {
+        let mut routing_table_size = self.routing_table_size.clone();
+        let ready = routing_table_size.wait_for(|size| *size >= min_routing_table_size);
+        matches!(tokio::time::timeout(timeout, ready).await, Ok(Ok(_)))
+    }
+}
+
+/// Handle a disconnect requested through [`BaseTransportHandle::disconnect_peer`]
+pub(super) fn disconnect_peer<B: NetworkBehaviour>(swarm: &mut Swarm<B>, peer_id: PeerId) {
+    log::info!("Disconnecting peer {peer_id}");
+    if swarm.disconnect_peer_id(peer_id).is_err() {
+        log::debug!("Peer {peer_id} not connected");
+    }
+}
+
+/// Apply a boot node update requested through the handle and dial the added boot node.
+/// `base` picks the [`BaseBehaviour`] out of the actor's behaviour.
+pub(super) fn update_boot_nodes<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    update: BootNodeUpdate,
+    base: impl FnOnce(&mut B) -> &mut BaseBehaviour,
+) {
+    let Some(BootNode { peer_id, address }) = base(swarm.behaviour_mut()).update_boot_nodes(update)
+    else {
+        return;
+    };
+    if swarm.is_connected(&peer_id) {
+        return log::debug!("Boot node {peer_id} already connected");
+    }
+    log::info!("Connecting to boot node {peer_id} at {address}");
+    let opts = DialOpts::peer_id(peer_id).addresses(vec![address]).build();
+    if let Err(e) = swarm.dial(opts) {
+        log::warn!("Cannot dial boot node {peer_id}: {e:?}");
+    }
+}
@@ -1,14 +1,26 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Deref,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
 
+use contract_client::{WorkerStakeStream, U256};
 use futures::StreamExt;
 use futures_core::Stream;
 use libp2p::{
     request_response::OutboundRequestId,
-    swarm::{NetworkBehaviour, SwarmEvent, ToSwarm},
+    swarm::{dial_opts::PeerCondition, NetworkBehaviour, SwarmEvent, ToSwarm},
     PeerId, Swarm,
 };
 use libp2p_swarm_derive::NetworkBehaviour;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use tokio_util::sync::CancellationToken;
 
 use subsquid_messages::{
@@ -17,18 +29,30 @@ use subsquid_messages::{
 };
 
 use crate::{
+    actors::base::{disconnect_peer, update_boot_nodes, BaseTransportHandle},
     behaviour::{
-        base::{BaseBehaviour, BaseBehaviourEvent},
-        request_client::{ClientBehaviour, ClientConfig, ClientEvent},
+        base::{
+            BaseBehaviour, BaseBehaviourEvent, BootNodeUpdate, DatasetProvider, PauseMode,
+            PauseReceiver,
+        },
+        handshake::NodeRole,
+        request_client::{
+            ClientBehaviour, ClientConfig, ClientEvent, DeliveryStats, DeliveryStatsTracker,
+        },
+        request_server::{Request, ServerBehaviour},
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
     codec::{ProtoCodec, ACK_SIZE},
     protocol::{
         GATEWAY_LOGS_PROTOCOL, MAX_GATEWAY_LOG_SIZE, MAX_QUERY_RESULT_SIZE, MAX_QUERY_SIZE,
-        QUERY_PROTOCOL,
+        QUERY_PROTOCOL, QUERY_PROTOCOL_COMPRESSED, QUERY_RESULT_PROTOCOL,
     },
     record_event,
-    util::{new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    swarm_events::{tap_event, SwarmEventKind},
+    util::{
+        new_actor_queue, new_queue, select_peer, select_weighted, Receiver, Sender, TaskManager,
+        DEFAULT_SHUTDOWN_TIMEOUT,
+    },
     QueueFull,
 };
 
@@ -38,16 +62,174 @@ pub enum GatewayEvent {
         peer_id: PeerId,
         ping: Ping,
     },
+    /// Result of a query sent to the worker. [`QueryResult::error_kind`] tells
+    /// whether a failed query is worth retrying with another worker.
     QueryResult {
         peer_id: PeerId,
         result: QueryResult,
     },
+    /// Queries buffered while the worker was looked up have been sent, once it got connected
+    PendingQueriesSent {
+        peer_id: PeerId,
+        count: usize,
+        /// Time since the lookup has started
+        waited: Duration,
+    },
+    /// Peer doesn't support one of the protocols required by the gateway
+    UnsupportedProtocol {
+        peer_id: PeerId,
+        protocol: String,
+        /// Protocols the peer has advertised via identify
+        peer_protocols: Vec<String>,
+    },
+    /// The transport has been paused, see [`BaseTransportHandle::pause`]
+    Paused {
+        mode: PauseMode,
+    },
+    /// The transport has been resumed after a pause
+    Resumed,
+}
+
+/// Immediate outcome of queueing a query, without waiting for the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SendDisposition {
+    /// The peer is connected, so the query is going to be sent right away
+    Sent,
+    /// The peer is not connected. The query waits until the peer is found and dialed.
+    Buffered,
+    /// The outbound queue is full and the query has been dropped
+    Failed,
+}
+
+/// Reason why no result has been received for a query
+#[derive(thiserror::Error, Debug)]
+pub enum QueryError {
+    #[error("Query without ID")]
+    MissingId,
+    #[error(transparent)]
+    QueueFull(#[from] QueueFull),
+    #[error("No result before the deadline")]
+    Timeout,
+}
+
+/// Reason why a dataset lookup has failed
+#[derive(thiserror::Error, Debug)]
+pub enum LookupError {
+    #[error(transparent)]
+    QueueFull(#[from] QueueFull),
+    #[error("Transport stopped before the lookup finished")]
+    TransportStopped,
+}
+
+struct ResultCollector {
+    tx: mpsc::Sender<(PeerId, QueryResult)>,
+    // Results still expected from the peers
+    outstanding: usize,
+}
+
+/// Consumers of the results of scattered queries, by query ID. Shared between
+/// the behaviour and the transport handles.
+#[derive(Clone, Default)]
+struct ResultCollectors(Arc<Mutex<HashMap<String, ResultCollector>>>);
+
+impl ResultCollectors {
+    fn register(
+        &self,
+        query_id: String,
+        num_peers: usize,
+    ) -> mpsc::Receiver<(PeerId, QueryResult)> {
+        let (tx, rx) = mpsc::channel(num_peers.max(1));
+        let collector = ResultCollector {
+            tx,
+            outstanding: num_peers,
+        };
+        self.0.lock().unwrap().insert(query_id, collector);
+        rx
+    }
+
+    /// Stop expecting `count` results for the query, e.g. because they couldn't be sent
+    fn abandon(&self, query_id: &str, count: usize) {
+        let mut collectors = self.0.lock().unwrap();
+        if let Some(collector) = collectors.get_mut(query_id) {
+            collector.outstanding = collector.outstanding.saturating_sub(count);
+            if collector.outstanding == 0 {
+                collectors.remove(query_id);
+            }
+        }
+    }
+
+    /// Stop expecting any results for the query
+    fn remove(&self, query_id: &str) {
+        self.0.lock().unwrap().remove(query_id);
+    }
+
+    /// Pass the result to its collector. Returns it back if the query hasn't been scattered.
+    fn collect(&self, peer_id: PeerId, result: QueryResult) -> Option<QueryResult> {
+        let mut collectors = self.0.lock().unwrap();
+        let Some(collector) = collectors.get_mut(&result.query_id) else {
+            return Some(result);
+        };
+        collector.outstanding = collector.outstanding.saturating_sub(1);
+        let query_id = result.query_id.clone();
+        // The collector may have stopped waiting already, then the result is dropped
+        _ = collector.tx.try_send((peer_id, result));
+        if collector.outstanding == 0 {
+            collectors.remove(&query_id);
+        }
+        None
+    }
+}
+
+/// Queries sent to workers and waiting for results, by request ID
+struct PendingQueries<K>(BTreeMap<K, (PeerId, String)>);
+
+impl<K> Default for PendingQueries<K> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<K: Ord + Copy> PendingQueries<K> {
+    fn insert(&mut self, req_id: K, peer_id: PeerId, query_id: String) {
+        self.0.insert(req_id, (peer_id, query_id));
+    }
+
+    fn remove(&mut self, req_id: &K) -> Option<String> {
+        self.0.remove(req_id).map(|(_, query_id)| query_id)
+    }
+
+    /// Match a result re-sent by the worker in a separate request, because the connection
+    /// the query had been sent over was closed. Returns the ID of the original request.
+    fn remove_redelivered(&mut self, peer_id: PeerId, query_id: &str) -> Option<K> {
+        let req_id = self
+            .0
+            .iter()
+            .find(|(_, (peer, id))| *peer == peer_id && id == query_id)
+            .map(|(req_id, _)| *req_id)?;
+        self.0.remove(&req_id);
+        Some(req_id)
+    }
+
+    /// Remove all the requests sending the query, returns their IDs
+    fn remove_query(&mut self, query_id: &str) -> Vec<K> {
+        let req_ids: Vec<K> = self
+            .0
+            .iter()
+            .filter(|(_, (_, id))| id == query_id)
+            .map(|(req_id, _)| *req_id)
+            .collect();
+        for req_id in &req_ids {
+            self.0.remove(req_id);
+        }
+        req_ids
+    }
 }
 
 #[derive(NetworkBehaviour)]
 pub struct InnerBehaviour {
     base: Wrapped<BaseBehaviour>,
     query: Wrapped<ClientBehaviour<ProtoCodec<Query, QueryResult>>>,
+    query_result: Wrapped<ServerBehaviour<ProtoCodec<QueryResult, u32>>>,
     logs: Wrapped<ClientBehaviour<ProtoCodec<GatewayLogMsg, u32>>>,
 }
 
@@ -61,8 +243,22 @@ pub struct GatewayConfig {
     pub max_query_log_size: u64,
     pub queries_queue_size: usize,
     pub logs_queue_size: usize,
+    pub disconnects_queue_size: usize,
+    pub warm_connections_queue_size: usize,
+    pub dataset_lookups_queue_size: usize,
+    pub boot_node_updates_queue_size: usize,
     pub events_queue_size: usize,
+    /// Queue of the scattered queries whose remaining results are no longer awaited
+    #[serde(default = "default_cancellations_queue_size")]
+    pub cancellations_queue_size: usize,
+    /// How often worker stakes used by [`GatewayTransportHandle::select_worker_weighted`]
+    /// are fetched from the chain (disabled if `None`)
+    pub worker_stakes_update_interval: Option<Duration>,
     pub shutdown_timeout: Duration,
+    /// Label added to the metrics of this actor's queues, to tell apart actors running in one
+    /// process (no label if `None`). Not part of the serialized config.
+    #[serde(skip)]
+    pub metrics_namespace: Option<&'static str>,
 }
 
 impl GatewayConfig {
@@ -76,30 +272,80 @@ impl GatewayConfig {
             max_query_log_size: MAX_GATEWAY_LOG_SIZE,
             queries_queue_size: 100,
             logs_queue_size: 100,
+            disconnects_queue_size: 100,
+            warm_connections_queue_size: 100,
+            dataset_lookups_queue_size: 100,
+            boot_node_updates_queue_size: 100,
             events_queue_size: 100,
+            cancellations_queue_size: default_cancellations_queue_size(),
+            worker_stakes_update_interval: None,
             shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics_namespace: None,
         }
     }
 }
 
+fn default_cancellations_queue_size() -> usize {
+    100
+}
+
+/// Weights of the active workers for the stake-proportional selection (bond in whole SQD),
+/// shared between the transport and the transport handles
+#[derive(Clone, Default)]
+pub struct WorkerWeights(Arc<RwLock<HashMap<PeerId, u64>>>);
+
+impl WorkerWeights {
+    pub fn get(&self) -> HashMap<PeerId, u64> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, weights: HashMap<PeerId, u64>) {
+        *self.0.write().unwrap() = weights;
+    }
+
+    fn update(&self, stakes: HashMap<PeerId, U256>) {
+        let sqd = U256::exp10(18);
+        let weights = stakes
+            .into_iter()
+            .map(|(peer_id, stake)| (peer_id, (stake / sqd).min(u64::MAX.into()).as_u64()))
+            .collect();
+        self.set(weights);
+    }
+}
+
 pub struct GatewayBehaviour {
     inner: InnerBehaviour,
     logs_collector_id: PeerId,
-    query_ids: BTreeMap<OutboundRequestId, String>,
+    query_ids: PendingQueries<OutboundRequestId>,
+    result_collectors: ResultCollectors,
+    worker_weights: WorkerWeights,
+    worker_stakes: Option<WorkerStakeStream>,
 }
 
 impl GatewayBehaviour {
-    pub fn new(mut base: BaseBehaviour, config: GatewayConfig) -> Wrapped<Self> {
+    pub fn new(
+        mut base: BaseBehaviour,
+        contract_client: Box<dyn contract_client::Client>,
+        config: GatewayConfig,
+    ) -> Wrapped<Self> {
         base.subscribe_pings();
+        base.set_role(NodeRole::Gateway);
         base.allow_peer(config.logs_collector_id);
+        let query_result = ServerBehaviour::new(
+            ProtoCodec::new(config.max_query_result_size, ACK_SIZE),
+            QUERY_RESULT_PROTOCOL,
+        )
+        .with_substream_rates(base.substream_rates());
         let inner = InnerBehaviour {
             base: base.into(),
-            query: ClientBehaviour::new(
+            // Workers without compression support negotiate the plain protocol
+            query: ClientBehaviour::with_protocols(
                 ProtoCodec::new(config.max_query_size, config.max_query_result_size),
-                QUERY_PROTOCOL,
+                vec![QUERY_PROTOCOL_COMPRESSED, QUERY_PROTOCOL],
                 config.query_config,
             )
             .into(),
+            query_result: query_result.into(),
             logs: ClientBehaviour::new(
                 ProtoCodec::new(config.max_query_log_size, ACK_SIZE),
                 GATEWAY_LOGS_PROTOCOL,
@@ -111,12 +357,20 @@ impl GatewayBehaviour {
             inner,
             logs_collector_id: config.logs_collector_id,
             query_ids: Default::default(),
+            result_collectors: Default::default(),
+            worker_weights: Default::default(),
+            worker_stakes: config
+                .worker_stakes_update_interval
+                .map(|interval| contract_client.worker_stakes_stream(interval)),
         }
         .into()
     }
     fn on_base_event(&mut self, ev: BaseBehaviourEvent) -> Option<GatewayEvent> {
         match ev {
-            BaseBehaviourEvent::Ping { peer_id, ping } => self.on_ping(peer_id, ping),
+            BaseBehaviourEvent::Ping { peer_id, ping, msg } => {
+                let ev = self.on_ping(peer_id, ping)?;
+                self.inner.base.route_to_topic_stream(&msg, ev)
+            }
             _ => None,
         }
     }
@@ -136,7 +390,7 @@ impl GatewayBehaviour {
     fn on_query_result(
         &mut self,
         peer_id: PeerId,
-        result: QueryResult,
+        mut result: QueryResult,
         req_id: Option<OutboundRequestId>,
     ) -> Option<GatewayEvent> {
         log::debug!("Got query result from {peer_id}: {result:?}");
@@ -144,6 +398,8 @@ impl GatewayBehaviour {
         if let Some(req_id) = req_id {
             match self.query_ids.remove(&req_id) {
                 Some(query_id) if query_id == result.query_id => {}
+                // Queries which couldn't be read by the worker (e.g. too large) have no ID
+                Some(query_id) if result.query_id.is_empty() => result.query_id = query_id,
                 _ => {
                     log::error!("Unknown request ID: {req_id}");
                     return None;
@@ -153,18 +409,84 @@ impl GatewayBehaviour {
         Some(GatewayEvent::QueryResult { peer_id, result })
     }
 
-    fn on_query_event(&mut self, ev: ClientEvent<QueryResult>) -> Option<GatewayEvent> {
+    fn on_query_event(&mut self, ev: ClientEvent<QueryResult>) -> Vec<GatewayEvent> {
         match ev {
             ClientEvent::Response {
                 peer_id,
                 req_id,
                 response,
-            } => self.on_query_result(peer_id, response, Some(req_id)),
-            ClientEvent::Timeout { req_id, peer_id } => self.on_query_timeout(req_id, peer_id),
+            } => self.on_query_result(peer_id, response, Some(req_id)).into_iter().collect(),
+            ClientEvent::Timeout { req_id, peer_id } => {
+                self.on_query_timeout(req_id, peer_id).into_iter().collect()
+            }
             ClientEvent::PeerUnknown { peer_id } => {
                 self.inner.base.find_and_dial(peer_id);
-                None
+                vec![]
             }
+            ClientEvent::PendingRequestsSent {
+                peer_id,
+                count,
+                waited,
+            } => {
+                log::debug!("Sent {count} queries to {peer_id} after {}ms", waited.as_millis());
+                vec![GatewayEvent::PendingQueriesSent {
+                    peer_id,
+                    count,
+                    waited,
+                }]
+            }
+            ClientEvent::UnsupportedProtocol { peer_id, req_id } => {
+                self.on_query_unsupported(req_id, peer_id)
+            }
+        }
+    }
+
+    /// Result re-sent by the worker because the connection the query was sent over has been
+    /// closed before the result was ready
+    fn on_redelivered_result(
+        &mut self,
+        peer_id: PeerId,
+        result: QueryResult,
+    ) -> Option<GatewayEvent> {
+        if self.query_ids.remove_redelivered(peer_id, &result.query_id).is_none() {
+            log::warn!("Unexpected result of query {} from {peer_id}", result.query_id);
+            return None;
+        }
+        log::debug!("Got re-sent result of query {} from {peer_id}", result.query_id);
+        Some(GatewayEvent::QueryResult { peer_id, result })
+    }
+
+    fn on_query_unsupported(
+        &mut self,
+        req_id: OutboundRequestId,
+        peer_id: PeerId,
+    ) -> Vec<GatewayEvent> {
+        let mut events = vec![self.unsupported_protocol_event(peer_id, QUERY_PROTOCOL)];
+        let Some(query_id) = self.query_ids.remove(&req_id) else {
+            log::error!("Unknown request ID: {req_id}");
+            return events;
+        };
+        log::debug!("Query {query_id} failed: {peer_id} doesn't support {QUERY_PROTOCOL}");
+        let error = format!("Worker doesn't support protocol {QUERY_PROTOCOL}");
+        events.push(GatewayEvent::QueryResult {
+            peer_id,
+            result: QueryResult::new(query_id, query_result::Result::ServerError(error)),
+        });
+        events
+    }
+
+    fn unsupported_protocol_event(&self, peer_id: PeerId, protocol: &str) -> GatewayEvent {
+        let peer_protocols = self
+            .inner
+            .base
+            .peer_protocols(&peer_id)
+            .into_iter()
+            .map(|p| p.to_string())
+            .collect();
+        GatewayEvent::UnsupportedProtocol {
+            peer_id,
+            protocol: protocol.to_string(),
+            peer_protocols,
         }
     }
 
@@ -189,6 +511,9 @@ impl GatewayBehaviour {
         match ev {
             ClientEvent::PeerUnknown { peer_id } => self.inner.base.find_and_dial(peer_id),
             ClientEvent::Timeout { .. } => log::warn!("Sending logs to collector timed out"),
+            ClientEvent::UnsupportedProtocol { peer_id, .. } => {
+                return Some(self.unsupported_protocol_event(peer_id, GATEWAY_LOGS_PROTOCOL))
+            }
             _ => {}
         }
         None
@@ -203,12 +528,48 @@ impl GatewayBehaviour {
         };
         self.inner.base.sign(&mut query);
         if let Ok(req_id) = self.inner.query.try_send_request(peer_id, query) {
-            self.query_ids.insert(req_id, query_id);
+            self.query_ids.insert(req_id, peer_id, query_id);
         } else {
-            log::error!("Outbound message queue full. Query {query_id} dropped.")
+            log::error!("Outbound message queue full. Query {query_id} dropped.");
+            self.result_collectors.abandon(&query_id, 1);
         }
     }
 
+    /// Stop waiting for the results of the scattered query. Requests which haven't been sent
+    /// out yet are dropped, and results of the ones already sent are discarded.
+    fn cancel_query(&mut self, query_id: &str) {
+        let req_ids = self.query_ids.remove_query(query_id);
+        log::debug!("Cancelling {} requests of query {query_id}", req_ids.len());
+        for req_id in req_ids {
+            self.inner.query.cancel_request(req_id);
+        }
+        self.result_collectors.remove(query_id);
+    }
+
+    /// Deterministically pick the worker to send the query to, so that the same query
+    /// consistently hits the same worker. Uses rendezvous hashing over the candidates.
+    pub fn select_worker(
+        query_id: &str,
+        candidates: impl IntoIterator<Item = PeerId>,
+    ) -> Option<PeerId> {
+        select_peer(query_id, candidates)
+    }
+
+    /// Randomly pick the worker to send the query to, with probability proportional to
+    /// its weight (see [`WorkerWeights`]). Workers without stake are picked only if
+    /// none of the candidates has any.
+    pub fn select_worker_weighted(
+        candidates: impl IntoIterator<Item = PeerId>,
+        weights: &HashMap<PeerId, u64>,
+        rng: &mut impl Rng,
+    ) -> Option<PeerId> {
+        select_weighted(candidates, weights, rng)
+    }
+
+    pub fn worker_weights(&self) -> WorkerWeights {
+        self.worker_weights.clone()
+    }
+
     pub fn send_log_msg(&mut self, msg: GatewayLogMsg) {
         log::debug!("Sending log message: {msg:?}");
         if self.inner.logs.try_send_request(self.logs_collector_id, msg).is_err() {
@@ -229,12 +590,41 @@ impl BehaviourWrapper for GatewayBehaviour {
         &mut self,
         ev: <Self::Inner as NetworkBehaviour>::ToSwarm,
     ) -> impl IntoIterator<Item = TToSwarm<Self>> {
-        let ev = match ev {
-            InnerBehaviourEvent::Base(ev) => self.on_base_event(ev),
+        let events = match ev {
+            InnerBehaviourEvent::Base(ev) => self.on_base_event(ev).into_iter().collect(),
             InnerBehaviourEvent::Query(query_res) => self.on_query_event(query_res),
-            InnerBehaviourEvent::Logs(ev) => self.on_logs_event(ev),
+            InnerBehaviourEvent::QueryResult(Request {
+                peer_id,
+                request,
+                response_channel,
+            }) => {
+                _ = self.inner.query_result.try_send_response(response_channel, 1);
+                self.on_redelivered_result(peer_id, request).into_iter().collect()
+            }
+            InnerBehaviourEvent::Logs(ev) => self.on_logs_event(ev).into_iter().collect(),
         };
-        ev.map(ToSwarm::GenerateEvent)
+        let collectors = self.result_collectors.clone();
+        events
+            .into_iter()
+            .filter_map(move |ev| match ev {
+                GatewayEvent::QueryResult { peer_id, result } => collectors
+                    .collect(peer_id, result)
+                    .map(|result| GatewayEvent::QueryResult { peer_id, result }),
+                ev => Some(ev),
+            })
+            .map(ToSwarm::GenerateEvent)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
+        if let Some(stakes) = &mut self.worker_stakes {
+            while let Poll::Ready(Some(result)) = stakes.poll_next_unpin(cx) {
+                match result {
+                    Ok(stakes) => self.worker_weights.update(stakes),
+                    Err(e) => log::warn!("Error retrieving worker stakes: {e:?}"),
+                }
+            }
+        }
+        Poll::<Vec<TToSwarm<Self>>>::Pending
     }
 }
 
@@ -242,7 +632,15 @@ struct GatewayTransport {
     swarm: Swarm<Wrapped<GatewayBehaviour>>,
     queries_rx: Receiver<(PeerId, Query)>,
     logs_rx: Receiver<GatewayLogMsg>,
+    disconnects_rx: Receiver<PeerId>,
+    warm_connections_rx: Receiver<(Vec<PeerId>, PeerCondition)>,
+    dataset_lookups_rx: Receiver<(String, oneshot::Sender<Vec<DatasetProvider>>)>,
+    boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    cancellations_rx: Receiver<String>,
     events_tx: Sender<GatewayEvent>,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    pause_rx: PauseReceiver,
+    connected_peers: Arc<RwLock<HashSet<PeerId>>>,
 }
 
 impl GatewayTransport {
@@ -251,19 +649,71 @@ impl GatewayTransport {
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => break,
+                _ = self.events_tx.closed() => {
+                    log::info!("Event stream dropped by the consumer");
+                    break;
+                }
                 ev = self.swarm.select_next_some() => self.on_swarm_event(ev),
-                Some((peer_id, query)) = self.queries_rx.recv() => self.swarm.behaviour_mut().send_query(peer_id, query),
-                Some(log_msg) = self.logs_rx.recv() => self.swarm.behaviour_mut().send_log_msg(log_msg),
+                Some((peer_id, query)) = self.queries_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("query") {
+                        self.swarm.behaviour_mut().send_query(peer_id, query)
+                    } else if let Some(query_id) = &query.query_id {
+                        // Don't keep the scatter_gather caller waiting for the result
+                        self.swarm.behaviour().result_collectors.abandon(query_id, 1)
+                    }
+                }
+                Some(log_msg) = self.logs_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("log message") {
+                        self.swarm.behaviour_mut().send_log_msg(log_msg)
+                    }
+                }
+                Some(peer_id) = self.disconnects_rx.recv() => {
+                    disconnect_peer(&mut self.swarm, peer_id)
+                }
+                Some((peers, condition)) = self.warm_connections_rx.recv() => {
+                    self.dial_peers(peers, condition)
+                }
+                Some((dataset, result_tx)) = self.dataset_lookups_rx.recv() => {
+                    self.swarm.behaviour_mut().inner.base.find_dataset_providers(dataset, result_tx)
+                }
+                Some(update) = self.boot_node_updates_rx.recv() => {
+                    update_boot_nodes(&mut self.swarm, update, |b| &mut b.inner.base)
+                }
+                Some(query_id) = self.cancellations_rx.recv() => {
+                    self.swarm.behaviour_mut().cancel_query(&query_id)
+                }
+                ev = self.pause_rx.next_event(
+                    |mode| GatewayEvent::Paused { mode },
+                    GatewayEvent::Resumed,
+                ) => self.events_tx.send_lossy(ev),
             }
         }
         log::info!("Shutting down gateway P2P transport");
     }
 
+    fn dial_peers(&mut self, peers: Vec<PeerId>, condition: PeerCondition) {
+        for peer_id in peers {
+            self.swarm.behaviour_mut().inner.base.dial_peer_with(peer_id, condition);
+        }
+    }
+
     fn on_swarm_event(&mut self, ev: SwarmEvent<GatewayEvent>) {
         log::trace!("Swarm event: {ev:?}");
         record_event(&ev);
-        if let SwarmEvent::Behaviour(ev) = ev {
-            self.events_tx.send_lossy(ev)
+        tap_event(&self.raw_events_tx, &ev);
+        match ev {
+            SwarmEvent::Behaviour(ev) => self.events_tx.send_lossy(ev),
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                self.connected_peers.write().unwrap().insert(peer_id);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.connected_peers.write().unwrap().remove(&peer_id);
+            }
+            _ => {}
         }
     }
 }
@@ -272,21 +722,52 @@ impl GatewayTransport {
 pub struct GatewayTransportHandle {
     queries_tx: Sender<(PeerId, Query)>,
     logs_tx: Sender<GatewayLogMsg>,
+    warm_connections_tx: Sender<(Vec<PeerId>, PeerCondition)>,
+    dataset_lookups_tx: Sender<(String, oneshot::Sender<Vec<DatasetProvider>>)>,
+    cancellations_tx: Sender<String>,
+    connected_peers: Arc<RwLock<HashSet<PeerId>>>,
+    delivery_stats: DeliveryStatsTracker,
+    result_collectors: ResultCollectors,
+    worker_weights: WorkerWeights,
+    base: BaseTransportHandle,
     _task_manager: Arc<TaskManager>,
 }
 
 impl GatewayTransportHandle {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         queries_tx: Sender<(PeerId, Query)>,
         logs_tx: Sender<GatewayLogMsg>,
+        disconnects_tx: Sender<PeerId>,
+        warm_connections_tx: Sender<(Vec<PeerId>, PeerCondition)>,
+        dataset_lookups_tx: Sender<(String, oneshot::Sender<Vec<DatasetProvider>>)>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
+        cancellations_tx: Sender<String>,
+        connected_peers: Arc<RwLock<HashSet<PeerId>>>,
         transport: GatewayTransport,
         shutdown_timeout: Duration,
     ) -> Self {
+        let delivery_stats = transport.swarm.behaviour().inner.query.delivery_stats();
+        let result_collectors = transport.swarm.behaviour().result_collectors.clone();
+        let worker_weights = transport.swarm.behaviour().worker_weights();
+        let base = BaseTransportHandle::new(
+            &transport.swarm.behaviour().inner.base,
+            disconnects_tx,
+            boot_node_updates_tx,
+        );
         let mut task_manager = TaskManager::new(shutdown_timeout);
         task_manager.spawn(|c| transport.run(c));
         Self {
             queries_tx,
             logs_tx,
+            warm_connections_tx,
+            dataset_lookups_tx,
+            cancellations_tx,
+            connected_peers,
+            delivery_stats,
+            result_collectors,
+            worker_weights,
+            base,
             _task_manager: Arc::new(task_manager),
         }
     }
@@ -295,6 +776,87 @@ impl GatewayTransportHandle {
         self.queries_tx.try_send((peer_id, query))
     }
 
+    /// Send the query to all the `peers` and wait for the first `k` results, but not longer
+    /// than `deadline`. If fewer than `k` results arrive in time, the ones received so far
+    /// are returned (possibly none). Any result counts, including errors and timeouts.
+    ///
+    /// Once it returns, the query is cancelled: requests still waiting for a connection aren't
+    /// sent, and results of the ones already out are dropped. Results of the query never
+    /// appear in the event stream. The query must have an ID which is not used by other
+    /// queries in flight.
+    pub async fn scatter_gather(
+        &self,
+        query: Query,
+        peers: &[PeerId],
+        k: usize,
+        deadline: Duration,
+    ) -> Result<Vec<(PeerId, QueryResult)>, QueueFull> {
+        let Some(query_id) = query.query_id.clone() else {
+            log::error!("Query without ID dropped");
+            return Ok(vec![]);
+        };
+        let deadline = Instant::now() + deadline;
+        let mut results_rx = self.result_collectors.register(query_id.clone(), peers.len());
+        for (i, peer_id) in peers.iter().enumerate() {
+            if let Err(e) = self.send_query(*peer_id, query.clone()) {
+                self.result_collectors.abandon(&query_id, peers.len() - i);
+                return Err(e);
+            }
+        }
+
+        let mut results = Vec::with_capacity(k.min(peers.len()));
+        let mut all_received = false;
+        while results.len() < k {
+            match tokio::time::timeout_at(deadline, results_rx.recv()).await {
+                Ok(Some(result)) => results.push(result),
+                // All peers responded
+                Ok(None) => {
+                    all_received = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        if !all_received {
+            self.cancel_query(query_id);
+        }
+        Ok(results)
+    }
+
+    fn cancel_query(&self, query_id: String) {
+        if self.cancellations_tx.try_send(query_id.clone()).is_err() {
+            // The results will still be dropped by the collector once they arrive
+            log::warn!("Cancellations queue full. Query {query_id} not cancelled.");
+        }
+    }
+
+    /// Send the query to the peer and wait for its result, but not longer than `deadline`.
+    /// The result is matched to the request by the transport and doesn't appear in the event
+    /// stream. The query must have an ID which is not used by other queries in flight.
+    pub async fn query(
+        &self,
+        peer_id: PeerId,
+        query: Query,
+        deadline: Duration,
+    ) -> Result<QueryResult, QueryError> {
+        if query.query_id.is_none() {
+            return Err(QueryError::MissingId);
+        }
+        let mut results = self.scatter_gather(query, &[peer_id], 1, deadline).await?;
+        results.pop().map(|(_, result)| result).ok_or(QueryError::Timeout)
+    }
+
+    /// Queue the query and report right away whether it's going to be sent immediately.
+    /// Lets the caller pick a different worker without waiting for the response.
+    pub fn try_send_query(&self, peer_id: PeerId, query: Query) -> SendDisposition {
+        let connected = self.connected_peers.read().unwrap().contains(&peer_id);
+        match self.send_query(peer_id, query) {
+            Err(_) => SendDisposition::Failed,
+            Ok(()) if connected => SendDisposition::Sent,
+            Ok(()) => SendDisposition::Buffered,
+        }
+    }
+
     pub fn query_submitted(&self, msg: QuerySubmitted) -> Result<(), QueueFull> {
         log::debug!("Queueing QuerySubmitted message: {msg:?}");
         let msg = gateway_log_msg::Msg::QuerySubmitted(msg).into();
@@ -306,22 +868,410 @@ impl GatewayTransportHandle {
         let msg = gateway_log_msg::Msg::QueryFinished(msg).into();
         self.logs_tx.try_send(msg)
     }
+
+    /// Start connecting to the peers in the background, so that queries sent to them later
+    /// don't wait for the connection. Peers which are already connected are skipped. Dials
+    /// go through the dial queue, so they are subject to the concurrent dials limit.
+    pub fn warm_connections(&self, peers: Vec<PeerId>) -> Result<(), QueueFull> {
+        let peers: Vec<PeerId> = {
+            let connected = self.connected_peers.read().unwrap();
+            peers.into_iter().filter(|peer_id| !connected.contains(peer_id)).collect()
+        };
+        if peers.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Queueing warm-up of connections to {} peers", peers.len());
+        self.warm_connections_tx.try_send((peers, PeerCondition::Disconnected))
+    }
+
+    /// Dial the peer in the background if `condition` allows it. `PeerCondition::Always`
+    /// opens another connection even if the peer is connected, as long as the per-peer
+    /// connection limit isn't reached. [`Self::warm_connections`] uses
+    /// `PeerCondition::Disconnected`.
+    pub fn dial_peer_with(
+        &self,
+        peer_id: PeerId,
+        condition: PeerCondition,
+    ) -> Result<(), QueueFull> {
+        log::debug!("Queueing dial of {peer_id} ({condition:?})");
+        self.warm_connections_tx.try_send((vec![peer_id], condition))
+    }
+
+    /// Find the workers which have advertised the dataset in the DHT. It's an alternative
+    /// to learning about the datasets from worker pings. Resolves once the lookup has finished.
+    pub async fn find_dataset_providers(
+        &self,
+        dataset: String,
+    ) -> Result<Vec<DatasetProvider>, LookupError> {
+        log::debug!("Queueing lookup of dataset {dataset}");
+        let (result_tx, result_rx) = oneshot::channel();
+        self.dataset_lookups_tx.try_send((dataset, result_tx))?;
+        result_rx.await.map_err(|_| LookupError::TransportStopped)
+    }
+
+    /// Randomly pick one of the candidates, proportionally to the worker stakes
+    /// fetched from the chain (see [`GatewayConfig::worker_stakes_update_interval`])
+    pub fn select_worker_weighted(
+        &self,
+        candidates: impl IntoIterator<Item = PeerId>,
+    ) -> Option<PeerId> {
+        let weights = self.worker_weights.0.read().unwrap();
+        select_weighted(candidates, &weights, &mut rand::thread_rng())
+    }
+
+    /// Outcomes of the queries sent to the worker since it was connected
+    pub fn peer_delivery_stats(&self, peer_id: &PeerId) -> Option<DeliveryStats> {
+        self.delivery_stats.get(peer_id)
+    }
+}
+
+impl Deref for GatewayTransportHandle {
+    type Target = BaseTransportHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
 }
 
 pub fn start_transport(
     swarm: Swarm<Wrapped<GatewayBehaviour>>,
     config: GatewayConfig,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
 ) -> (impl Stream<Item = GatewayEvent>, GatewayTransportHandle) {
-    let (queries_tx, queries_rx) = new_queue(config.queries_queue_size, "queries");
-    let (logs_tx, logs_rx) = new_queue(config.logs_queue_size, "logs");
-    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let namespace = config.metrics_namespace;
+    let (queries_tx, queries_rx) = new_actor_queue(config.queries_queue_size, "queries", namespace);
+    let (logs_tx, logs_rx) = new_actor_queue(config.logs_queue_size, "logs", namespace);
+    let (disconnects_tx, disconnects_rx) =
+        new_actor_queue(config.disconnects_queue_size, "disconnects", namespace);
+    let (warm_connections_tx, warm_connections_rx) =
+        new_actor_queue(config.warm_connections_queue_size, "warm_connections", namespace);
+    let (dataset_lookups_tx, dataset_lookups_rx) =
+        new_actor_queue(config.dataset_lookups_queue_size, "dataset_lookups", namespace);
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_actor_queue(config.boot_node_updates_queue_size, "boot_node_updates", namespace);
+    let (cancellations_tx, cancellations_rx) =
+        new_actor_queue(config.cancellations_queue_size, "cancellations", namespace);
+    let (events_tx, events_rx) = new_actor_queue(config.events_queue_size, "events", namespace);
+    let connected_peers = Arc::new(RwLock::new(HashSet::new()));
+    let pause_rx = swarm.behaviour().inner.base.pause_state().subscribe();
     let transport = GatewayTransport {
         swarm,
         queries_rx,
         logs_rx,
+        disconnects_rx,
+        warm_connections_rx,
+        dataset_lookups_rx,
+        boot_node_updates_rx,
+        cancellations_rx,
         events_tx,
+        raw_events_tx,
+        pause_rx,
+        connected_peers: connected_peers.clone(),
     };
-    let handle =
-        GatewayTransportHandle::new(queries_tx, logs_tx, transport, config.shutdown_timeout);
+    let handle = GatewayTransportHandle::new(
+        queries_tx,
+        logs_tx,
+        disconnects_tx,
+        warm_connections_tx,
+        dataset_lookups_tx,
+        boot_node_updates_tx,
+        cancellations_tx,
+        connected_peers,
+        transport,
+        config.shutdown_timeout,
+    );
     (events_rx, handle)
 }
+
+/// Mock end of the gateway transport, see [`crate::mock`]
+#[cfg(feature = "test-util")]
+pub struct MockGatewayTransport {
+    pub queries_rx: Receiver<(PeerId, Query)>,
+    pub logs_rx: Receiver<GatewayLogMsg>,
+    pub disconnects_rx: Receiver<PeerId>,
+    pub warm_connections_rx: Receiver<(Vec<PeerId>, PeerCondition)>,
+    pub dataset_lookups_rx: Receiver<(String, oneshot::Sender<Vec<DatasetProvider>>)>,
+    pub boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    /// IDs of the queries [`GatewayTransportHandle::scatter_gather`] stopped waiting for
+    pub cancellations_rx: Receiver<String>,
+    pub events_tx: Sender<GatewayEvent>,
+    /// Weights used by [`GatewayTransportHandle::select_worker_weighted`]
+    pub worker_weights: WorkerWeights,
+    result_collectors: ResultCollectors,
+}
+
+#[cfg(feature = "test-util")]
+impl MockGatewayTransport {
+    /// Deliver the query result like the real transport does: to the pending
+    /// [`GatewayTransportHandle::scatter_gather`] call, or else to the event stream
+    pub fn send_query_result(&self, peer_id: PeerId, result: QueryResult) -> Result<(), QueueFull> {
+        match self.result_collectors.collect(peer_id, result) {
+            Some(result) => self.events_tx.try_send(GatewayEvent::QueryResult { peer_id, result }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+pub fn start_mock_transport(
+    config: GatewayConfig,
+) -> (impl Stream<Item = GatewayEvent>, GatewayTransportHandle, MockGatewayTransport) {
+    let (queries_tx, queries_rx) = new_queue(config.queries_queue_size, "queries");
+    let (logs_tx, logs_rx) = new_queue(config.logs_queue_size, "logs");
+    let (disconnects_tx, disconnects_rx) = new_queue(config.disconnects_queue_size, "disconnects");
+    let (warm_connections_tx, warm_connections_rx) =
+        new_queue(config.warm_connections_queue_size, "warm_connections");
+    let (dataset_lookups_tx, dataset_lookups_rx) =
+        new_queue(config.dataset_lookups_queue_size, "dataset_lookups");
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_queue(config.boot_node_updates_queue_size, "boot_node_updates");
+    let (cancellations_tx, cancellations_rx) =
+        new_queue(config.cancellations_queue_size, "cancellations");
+    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let result_collectors = ResultCollectors::default();
+    let worker_weights = WorkerWeights::default();
+    let handle = GatewayTransportHandle {
+        queries_tx,
+        logs_tx,
+        warm_connections_tx,
+        dataset_lookups_tx,
+        cancellations_tx,
+        connected_peers: Default::default(),
+        delivery_stats: Default::default(),
+        result_collectors: result_collectors.clone(),
+        worker_weights: worker_weights.clone(),
+        base: BaseTransportHandle::mock(disconnects_tx, boot_node_updates_tx),
+        _task_manager: Arc::new(TaskManager::new(config.shutdown_timeout)),
+    };
+    let mock = MockGatewayTransport {
+        queries_rx,
+        logs_rx,
+        disconnects_rx,
+        warm_connections_rx,
+        dataset_lookups_rx,
+        boot_node_updates_rx,
+        cancellations_rx,
+        events_tx,
+        worker_weights,
+        result_collectors,
+    };
+    (events_rx, handle, mock)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::Either;
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade::Version},
+        noise,
+        swarm::dial_opts::DialOpts,
+        yamux, Multiaddr, Transport,
+    };
+
+    use crate::actors::testing::{new_test_swarm, NoChainClient};
+
+    use super::*;
+
+    #[derive(NetworkBehaviour)]
+    struct TestGateway {
+        query: Wrapped<ClientBehaviour<ProtoCodec<Query, QueryResult>>>,
+        query_result: Wrapped<ServerBehaviour<ProtoCodec<QueryResult, u32>>>,
+    }
+
+    #[derive(NetworkBehaviour)]
+    struct TestWorker {
+        query: Wrapped<ServerBehaviour<ProtoCodec<Query, QueryResult>>>,
+        query_result: Wrapped<ClientBehaviour<ProtoCodec<QueryResult, u32>>>,
+    }
+
+    fn new_swarm<B: NetworkBehaviour>(behaviour: B) -> Swarm<B> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|keypair| {
+                Ok::<_, noise::Error>(
+                    MemoryTransport::default()
+                        .upgrade(Version::V1)
+                        .authenticate(noise::Config::new(keypair)?)
+                        .multiplex(yamux::Config::default()),
+                )
+            })
+            .unwrap()
+            .with_behaviour(|_| behaviour)
+            .unwrap()
+            .build()
+    }
+
+    #[test]
+    fn test_result_collectors() {
+        let collectors = ResultCollectors::default();
+        let result = |query_id: &str| {
+            QueryResult::new(query_id.to_string(), query_result::Result::Timeout(()))
+        };
+        let [peer1, peer2, peer3] = [PeerId::random(), PeerId::random(), PeerId::random()];
+
+        let mut rx = collectors.register("q1".to_string(), 3);
+        assert!(collectors.collect(peer1, result("q1")).is_none());
+        assert!(collectors.collect(peer2, result("other")).is_some());
+        assert_eq!(rx.try_recv().unwrap().0, peer1);
+
+        // Late results are dropped once the collector stops waiting
+        drop(rx);
+        assert!(collectors.collect(peer2, result("q1")).is_none());
+        assert!(collectors.collect(peer3, result("q1")).is_none());
+        assert!(collectors.0.lock().unwrap().is_empty());
+        assert!(collectors.collect(peer3, result("q1")).is_some());
+
+        let _rx = collectors.register("q2".to_string(), 2);
+        collectors.abandon("q2", 2);
+        assert!(collectors.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redelivered_results() {
+        let mut pending = PendingQueries::default();
+        let [worker1, worker2] = [PeerId::random(), PeerId::random()];
+        pending.insert(1, worker1, "q1".to_string());
+        pending.insert(2, worker2, "q2".to_string());
+
+        // The connection to worker1 drops mid-query, it re-sends the result in a new request
+        assert_eq!(pending.remove_redelivered(worker2, "q1"), None);
+        assert_eq!(pending.remove_redelivered(worker1, "q1"), Some(1));
+        assert_eq!(pending.remove_redelivered(worker1, "q1"), None);
+        // A late response to the original request isn't reported again
+        assert_eq!(pending.remove(&1), None);
+
+        assert_eq!(pending.remove(&2).as_deref(), Some("q2"));
+        assert!(pending.0.is_empty());
+    }
+
+    #[test]
+    fn test_cancelled_query() {
+        let mut pending = PendingQueries::default();
+        let [worker1, worker2] = [PeerId::random(), PeerId::random()];
+        pending.insert(1, worker1, "q1".to_string());
+        pending.insert(2, worker2, "q1".to_string());
+        pending.insert(3, worker2, "q2".to_string());
+
+        assert_eq!(pending.remove_query("q1"), vec![1, 2]);
+        // Results of the cancelled requests aren't matched anymore
+        assert_eq!(pending.remove(&1), None);
+        assert_eq!(pending.remove_redelivered(worker2, "q1"), None);
+        assert_eq!(pending.remove(&3).as_deref(), Some("q2"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_query_rejected_while_paused() {
+        let config = GatewayConfig::new(PeerId::random());
+        let swarm =
+            new_test_swarm(|_, base| GatewayBehaviour::new(base, Box::new(NoChainClient), config));
+        let (_events, handle) = start_transport(swarm, config, None);
+        handle.pause(PauseMode::Reject);
+
+        let query = Query {
+            query_id: Some("q1".to_string()),
+            ..Default::default()
+        };
+        // The dropped query isn't awaited until the deadline
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handle.query(PeerId::random(), query, Duration::from_secs(60)),
+        )
+        .await
+        .expect("the query should fail right after being dropped");
+        assert!(matches!(result, Err(QueryError::Timeout)));
+        assert!(handle.result_collectors.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_result_redelivered_after_disconnect() {
+        let query_codec = || ProtoCodec::new(MAX_QUERY_SIZE, MAX_QUERY_RESULT_SIZE);
+        let result_codec = || ProtoCodec::new(MAX_QUERY_RESULT_SIZE, ACK_SIZE);
+        let mut gateway = new_swarm(TestGateway {
+            query: ClientBehaviour::new(query_codec(), QUERY_PROTOCOL, Default::default()).into(),
+            query_result: ServerBehaviour::new(result_codec(), QUERY_RESULT_PROTOCOL).into(),
+        });
+        let mut worker = new_swarm(TestWorker {
+            query: ServerBehaviour::new(query_codec(), QUERY_PROTOCOL).into(),
+            query_result: ClientBehaviour::new(
+                result_codec(),
+                QUERY_RESULT_PROTOCOL,
+                Default::default(),
+            )
+            .into(),
+        });
+        let memory_addr =
+            || -> Multiaddr { format!("/memory/{}", rand::random::<u64>()).parse().unwrap() };
+        let (gateway_addr, worker_addr) = (memory_addr(), memory_addr());
+        gateway.listen_on(gateway_addr.clone()).unwrap();
+        worker.listen_on(worker_addr.clone()).unwrap();
+        gateway.dial(worker_addr).unwrap();
+
+        let mut pending = PendingQueries::default();
+        let mut unanswered = None;
+        let mut disconnected = false;
+        let run = async {
+            loop {
+                let ev = tokio::select! {
+                    ev = gateway.select_next_some() => Either::Left(ev),
+                    ev = worker.select_next_some() => Either::Right(ev),
+                };
+                match ev {
+                    Either::Left(SwarmEvent::ConnectionEstablished { peer_id, .. })
+                        if pending.0.is_empty() =>
+                    {
+                        let query = Query {
+                            query_id: Some("q1".to_string()),
+                            ..Default::default()
+                        };
+                        let req_id =
+                            gateway.behaviour_mut().query.try_send_request(peer_id, query).unwrap();
+                        pending.insert(req_id, peer_id, "q1".to_string());
+                    }
+                    Either::Left(SwarmEvent::Behaviour(TestGatewayEvent::QueryResult(req))) => {
+                        let query_result = &mut gateway.behaviour_mut().query_result;
+                        _ = query_result.try_send_response(req.response_channel, 1);
+                        break (req.peer_id, req.request);
+                    }
+                    Either::Left(SwarmEvent::Behaviour(TestGatewayEvent::Query(
+                        ClientEvent::Response { .. },
+                    ))) => panic!("The result was sent over the closed connection"),
+                    // The connection closes while the query is being executed
+                    Either::Right(SwarmEvent::Behaviour(TestWorkerEvent::Query(req)))
+                        if !disconnected =>
+                    {
+                        unanswered = Some(req.response_channel);
+                        disconnected = true;
+                        _ = worker.disconnect_peer_id(req.peer_id);
+                    }
+                    Either::Right(SwarmEvent::ConnectionClosed { peer_id, .. })
+                        if unanswered.is_some() =>
+                    {
+                        let resp_chan = unanswered.take().unwrap();
+                        assert!(!resp_chan.is_open());
+                        let result = QueryResult::new(
+                            "q1".to_string(),
+                            query_result::Result::Ok(Default::default()),
+                        );
+                        let query_result = &mut worker.behaviour_mut().query_result;
+                        query_result.try_send_request(peer_id, result).unwrap();
+                    }
+                    Either::Right(SwarmEvent::Behaviour(TestWorkerEvent::QueryResult(
+                        ClientEvent::PeerUnknown { peer_id },
+                    ))) => {
+                        let opts = DialOpts::peer_id(peer_id)
+                            .addresses(vec![gateway_addr.clone()])
+                            .build();
+                        worker.dial(opts).unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let (peer_id, result) = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the result should be re-sent over a new connection");
+        assert_eq!(peer_id, *worker.local_peer_id());
+        assert_eq!(result.query_id, "q1");
+        assert!(pending.remove_redelivered(peer_id, &result.query_id).is_some());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{ops::Deref, sync::Arc, time::Duration};
 
 use futures::StreamExt;
 use futures_core::Stream;
@@ -11,20 +11,25 @@ use serde::{Deserialize, Serialize};
 use tokio_util::sync::CancellationToken;
 
 use subsquid_messages::{
-    gateway_log_msg, signatures::SignedMessage, GatewayLogMsg, LogsCollected, QueryExecuted,
-    QueryFinished, QueryLogs, QuerySubmitted,
+    gateway_log_msg, signatures::SignedMessage, GatewayLogMsg, LogsBackfillRequest,
+    LogsBackfillResponse, LogsCollected, QueryExecuted, QueryFinished, QueryLogs, QuerySubmitted,
 };
 
 use crate::{
+    actors::base::{disconnect_peer, update_boot_nodes, BaseTransportHandle},
     behaviour::{
-        base::{BaseBehaviour, BaseBehaviourEvent},
+        base::{BaseBehaviour, BaseBehaviourEvent, BootNodeUpdate, PauseMode, PauseReceiver},
+        request_client::{ClientBehaviour, ClientConfig, ClientEvent},
         request_server::{Request, ServerBehaviour},
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
     codec::{ProtoCodec, ACK_SIZE},
-    protocol::{GATEWAY_LOGS_PROTOCOL, MAX_GATEWAY_LOG_SIZE},
+    protocol::{
+        GATEWAY_LOGS_PROTOCOL, LOGS_BACKFILL_PROTOCOL, MAX_GATEWAY_LOG_SIZE, MAX_LOGS_BACKFILL_SIZE,
+    },
     record_event,
-    util::{new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    swarm_events::{tap_event, SwarmEventKind},
+    util::{new_actor_queue, new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
     QueueFull,
 };
 
@@ -35,24 +40,44 @@ pub enum LogsCollectorEvent {
         peer_id: PeerId,
         logs: Vec<QueryExecuted>,
     },
+    /// Worker re-sent its logs on request, see [`LogsCollectorTransportHandle::request_logs_backfill`]
+    WorkerLogsBackfill {
+        peer_id: PeerId,
+        logs: Vec<QueryExecuted>,
+        /// Some of the requested logs are no longer retained by the worker
+        gap: bool,
+    },
     /// Gateway reports a submitted query
     QuerySubmitted(QuerySubmitted),
     /// Gateway reports a finished query (result received or timeout)
     QueryFinished(QueryFinished),
+    /// The transport has been paused, see [`BaseTransportHandle::pause`]
+    Paused { mode: PauseMode },
+    /// The transport has been resumed after a pause
+    Resumed,
 }
 
 #[derive(NetworkBehaviour)]
 pub struct InnerBehaviour {
     base: Wrapped<BaseBehaviour>,
     gateway_logs: Wrapped<ServerBehaviour<ProtoCodec<GatewayLogMsg, u32>>>,
+    logs_backfill: Wrapped<ClientBehaviour<ProtoCodec<LogsBackfillRequest, LogsBackfillResponse>>>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct LogsCollectorConfig {
     pub max_gateway_log_size: u64,
     pub logs_collected_queue_size: usize,
+    pub logs_backfill: ClientConfig,
+    pub logs_backfill_queue_size: usize,
+    pub disconnects_queue_size: usize,
+    pub boot_node_updates_queue_size: usize,
     pub events_queue_size: usize,
     pub shutdown_timeout: Duration,
+    /// Label added to the metrics of this actor's queues, to tell apart actors running in one
+    /// process (no label if `None`). Not part of the serialized config.
+    #[serde(skip)]
+    pub metrics_namespace: Option<&'static str>,
 }
 
 impl Default for LogsCollectorConfig {
@@ -60,8 +85,13 @@ impl Default for LogsCollectorConfig {
         Self {
             max_gateway_log_size: MAX_GATEWAY_LOG_SIZE,
             logs_collected_queue_size: 100,
+            logs_backfill: Default::default(),
+            logs_backfill_queue_size: 100,
+            disconnects_queue_size: 100,
+            boot_node_updates_queue_size: 100,
             events_queue_size: 100,
             shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics_namespace: None,
         }
     }
 }
@@ -74,12 +104,19 @@ impl LogsCollectorBehaviour {
     pub fn new(mut base: BaseBehaviour, config: LogsCollectorConfig) -> Wrapped<Self> {
         base.subscribe_worker_logs();
         base.subscribe_logs_collected();
+        let gateway_logs = ServerBehaviour::new(
+            ProtoCodec::new(config.max_gateway_log_size, ACK_SIZE),
+            GATEWAY_LOGS_PROTOCOL,
+        )
+        .with_substream_rates(base.substream_rates());
         Self {
             inner: InnerBehaviour {
                 base: base.into(),
-                gateway_logs: ServerBehaviour::new(
-                    ProtoCodec::new(config.max_gateway_log_size, ACK_SIZE),
-                    GATEWAY_LOGS_PROTOCOL,
+                gateway_logs: gateway_logs.into(),
+                logs_backfill: ClientBehaviour::new(
+                    ProtoCodec::new(MAX_LOGS_BACKFILL_SIZE, MAX_LOGS_BACKFILL_SIZE),
+                    LOGS_BACKFILL_PROTOCOL,
+                    config.logs_backfill,
                 )
                 .into(),
             },
@@ -88,18 +125,49 @@ impl LogsCollectorBehaviour {
     }
 
     fn on_worker_logs(&mut self, peer_id: PeerId, logs: QueryLogs) -> Option<LogsCollectorEvent> {
-        let mut logs = logs.queries_executed;
-        log::debug!("Got {} query logs from {peer_id}", logs.len());
-        let worker_id = peer_id.to_base58();
-        logs = logs
-            .into_iter()
-            .filter_map(|mut log| {
-                (log.worker_id == worker_id && log.verify_signature(&peer_id)).then_some(log)
-            })
-            .collect();
+        log::debug!("Got {} query logs from {peer_id}", logs.queries_executed.len());
+        let logs = verify_worker_logs(peer_id, logs.queries_executed);
         (!logs.is_empty()).then_some(LogsCollectorEvent::WorkerLogs { peer_id, logs })
     }
 
+    /// Ask the worker to re-send its logs starting from `from_seq_no`
+    pub fn request_logs_backfill(&mut self, worker_id: PeerId, from_seq_no: u64) {
+        log::debug!("Requesting logs of {worker_id} from seq no {from_seq_no}");
+        let request = LogsBackfillRequest { from_seq_no };
+        _ = self.inner.logs_backfill.try_send_request(worker_id, request);
+    }
+
+    fn on_logs_backfill_event(
+        &mut self,
+        ev: ClientEvent<LogsBackfillResponse>,
+    ) -> Option<LogsCollectorEvent> {
+        match ev {
+            ClientEvent::Response {
+                peer_id, response, ..
+            } => {
+                log::debug!(
+                    "Got {} backfilled query logs from {peer_id}",
+                    response.queries_executed.len()
+                );
+                let logs = verify_worker_logs(peer_id, response.queries_executed);
+                return Some(LogsCollectorEvent::WorkerLogsBackfill {
+                    peer_id,
+                    logs,
+                    gap: response.gap,
+                });
+            }
+            ClientEvent::PeerUnknown { peer_id } => self.inner.base.find_and_dial(peer_id),
+            ClientEvent::Timeout { peer_id, .. } => {
+                log::warn!("Logs backfill request to {peer_id} timed out")
+            }
+            ClientEvent::UnsupportedProtocol { peer_id, .. } => {
+                log::warn!("Worker {peer_id} doesn't support logs backfill")
+            }
+            _ => {}
+        }
+        None
+    }
+
     fn on_gateway_log(
         &mut self,
         peer_id: PeerId,
@@ -142,7 +210,10 @@ impl BehaviourWrapper for LogsCollectorBehaviour {
             InnerBehaviourEvent::Base(BaseBehaviourEvent::WorkerQueryLogs {
                 peer_id,
                 query_logs,
-            }) => self.on_worker_logs(peer_id, query_logs),
+                msg,
+            }) => self
+                .on_worker_logs(peer_id, query_logs)
+                .and_then(|ev| self.inner.base.route_to_topic_stream(&msg, ev)),
             InnerBehaviourEvent::GatewayLogs(Request {
                 peer_id,
                 request,
@@ -151,16 +222,32 @@ impl BehaviourWrapper for LogsCollectorBehaviour {
                 _ = self.inner.gateway_logs.try_send_response(response_channel, 1);
                 self.on_gateway_log(peer_id, request)
             }
+            InnerBehaviourEvent::LogsBackfill(ev) => self.on_logs_backfill_event(ev),
             _ => None,
         };
         ev.map(ToSwarm::GenerateEvent)
     }
 }
 
+/// Keep only the logs signed by the worker they come from
+fn verify_worker_logs(peer_id: PeerId, logs: Vec<QueryExecuted>) -> Vec<QueryExecuted> {
+    let worker_id = peer_id.to_base58();
+    logs.into_iter()
+        .filter_map(|mut log| {
+            (log.worker_id == worker_id && log.verify_signature(&peer_id)).then_some(log)
+        })
+        .collect()
+}
+
 struct LogsCollectorTransport {
     swarm: Swarm<Wrapped<LogsCollectorBehaviour>>,
     logs_collected_rx: Receiver<LogsCollected>,
+    logs_backfill_rx: Receiver<(PeerId, u64)>,
+    disconnects_rx: Receiver<PeerId>,
+    boot_node_updates_rx: Receiver<BootNodeUpdate>,
     events_tx: Sender<LogsCollectorEvent>,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    pause_rx: PauseReceiver,
 }
 
 impl LogsCollectorTransport {
@@ -169,8 +256,31 @@ impl LogsCollectorTransport {
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => break,
+                _ = self.events_tx.closed() => {
+                    log::info!("Event stream dropped by the consumer");
+                    break;
+                }
                 ev = self.swarm.select_next_some() => self.on_swarm_event(ev),
-                Some(logs_collected) = self.logs_collected_rx.recv() => self.swarm.behaviour_mut().logs_collected(logs_collected),
+                Some(logs_collected) = self.logs_collected_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("logs collected message") {
+                        self.swarm.behaviour_mut().logs_collected(logs_collected)
+                    }
+                }
+                Some((worker_id, from_seq_no)) = self.logs_backfill_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("logs backfill request") {
+                        self.swarm.behaviour_mut().request_logs_backfill(worker_id, from_seq_no)
+                    }
+                }
+                Some(peer_id) = self.disconnects_rx.recv() => {
+                    disconnect_peer(&mut self.swarm, peer_id)
+                }
+                Some(update) = self.boot_node_updates_rx.recv() => {
+                    update_boot_nodes(&mut self.swarm, update, |b| &mut b.inner.base)
+                }
+                ev = self.pause_rx.next_event(
+                    |mode| LogsCollectorEvent::Paused { mode },
+                    LogsCollectorEvent::Resumed,
+                ) => self.events_tx.send_lossy(ev),
             }
         }
         log::info!("Shutting down logs collector P2P transport");
@@ -179,6 +289,7 @@ impl LogsCollectorTransport {
     fn on_swarm_event(&mut self, ev: SwarmEvent<LogsCollectorEvent>) {
         log::trace!("Swarm event: {ev:?}");
         record_event(&ev);
+        tap_event(&self.raw_events_tx, &ev);
         if let SwarmEvent::Behaviour(ev) = ev {
             self.events_tx.send_lossy(ev)
         }
@@ -188,19 +299,31 @@ impl LogsCollectorTransport {
 #[derive(Clone)]
 pub struct LogsCollectorTransportHandle {
     logs_collected_tx: Sender<LogsCollected>,
+    logs_backfill_tx: Sender<(PeerId, u64)>,
+    base: BaseTransportHandle,
     _task_manager: Arc<TaskManager>,
 }
 
 impl LogsCollectorTransportHandle {
     fn new(
         logs_collected_tx: Sender<LogsCollected>,
+        logs_backfill_tx: Sender<(PeerId, u64)>,
+        disconnects_tx: Sender<PeerId>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
         transport: LogsCollectorTransport,
         shutdown_timeout: Duration,
     ) -> Self {
+        let base = BaseTransportHandle::new(
+            &transport.swarm.behaviour().inner.base,
+            disconnects_tx,
+            boot_node_updates_tx,
+        );
         let mut task_manager = TaskManager::new(shutdown_timeout);
         task_manager.spawn(|c| transport.run(c));
         Self {
             logs_collected_tx,
+            logs_backfill_tx,
+            base,
             _task_manager: Arc::new(task_manager),
         }
     }
@@ -208,21 +331,104 @@ impl LogsCollectorTransportHandle {
         log::debug!("Queueing LogsCollected message: {logs_collected:?}");
         self.logs_collected_tx.try_send(logs_collected)
     }
+
+    /// Ask the worker to re-send its logs starting from `from_seq_no`, e.g. after the collector
+    /// has been down. Logs arrive in [`LogsCollectorEvent::WorkerLogsBackfill`] events. Workers
+    /// limit the size of a response, so the logs may end before the last one sent. Request
+    /// again after the last received one until there are no more logs.
+    pub fn request_logs_backfill(
+        &self,
+        worker_id: PeerId,
+        from_seq_no: u64,
+    ) -> Result<(), QueueFull> {
+        log::debug!("Queueing logs backfill request to {worker_id}");
+        self.logs_backfill_tx.try_send((worker_id, from_seq_no))
+    }
+}
+
+impl Deref for LogsCollectorTransportHandle {
+    type Target = BaseTransportHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
 }
 
 pub fn start_transport(
     swarm: Swarm<Wrapped<LogsCollectorBehaviour>>,
     config: LogsCollectorConfig,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
 ) -> (impl Stream<Item = LogsCollectorEvent>, LogsCollectorTransportHandle) {
+    let namespace = config.metrics_namespace;
     let (logs_collected_tx, logs_collected_rx) =
-        new_queue(config.logs_collected_queue_size, "logs_collected");
-    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+        new_actor_queue(config.logs_collected_queue_size, "logs_collected", namespace);
+    let (logs_backfill_tx, logs_backfill_rx) =
+        new_actor_queue(config.logs_backfill_queue_size, "logs_backfill", namespace);
+    let (disconnects_tx, disconnects_rx) =
+        new_actor_queue(config.disconnects_queue_size, "disconnects", namespace);
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_actor_queue(config.boot_node_updates_queue_size, "boot_node_updates", namespace);
+    let (events_tx, events_rx) = new_actor_queue(config.events_queue_size, "events", namespace);
+    let pause_rx = swarm.behaviour().inner.base.pause_state().subscribe();
     let transport = LogsCollectorTransport {
         swarm,
         logs_collected_rx,
+        logs_backfill_rx,
+        disconnects_rx,
+        boot_node_updates_rx,
         events_tx,
+        raw_events_tx,
+        pause_rx,
     };
-    let handle =
-        LogsCollectorTransportHandle::new(logs_collected_tx, transport, config.shutdown_timeout);
+    let handle = LogsCollectorTransportHandle::new(
+        logs_collected_tx,
+        logs_backfill_tx,
+        disconnects_tx,
+        boot_node_updates_tx,
+        transport,
+        config.shutdown_timeout,
+    );
     (events_rx, handle)
 }
+
+/// Mock end of the logs collector transport, see [`crate::mock`]
+#[cfg(feature = "test-util")]
+pub struct MockLogsCollectorTransport {
+    pub logs_collected_rx: Receiver<LogsCollected>,
+    pub logs_backfill_rx: Receiver<(PeerId, u64)>,
+    pub disconnects_rx: Receiver<PeerId>,
+    pub boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    pub events_tx: Sender<LogsCollectorEvent>,
+}
+
+#[cfg(feature = "test-util")]
+pub fn start_mock_transport(
+    config: LogsCollectorConfig,
+) -> (
+    impl Stream<Item = LogsCollectorEvent>,
+    LogsCollectorTransportHandle,
+    MockLogsCollectorTransport,
+) {
+    let (logs_collected_tx, logs_collected_rx) =
+        new_queue(config.logs_collected_queue_size, "logs_collected");
+    let (logs_backfill_tx, logs_backfill_rx) =
+        new_queue(config.logs_backfill_queue_size, "logs_backfill");
+    let (disconnects_tx, disconnects_rx) = new_queue(config.disconnects_queue_size, "disconnects");
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_queue(config.boot_node_updates_queue_size, "boot_node_updates");
+    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let handle = LogsCollectorTransportHandle {
+        logs_collected_tx,
+        logs_backfill_tx,
+        base: BaseTransportHandle::mock(disconnects_tx, boot_node_updates_tx),
+        _task_manager: Arc::new(TaskManager::new(config.shutdown_timeout)),
+    };
+    let mock = MockLogsCollectorTransport {
+        logs_collected_rx,
+        logs_backfill_rx,
+        disconnects_rx,
+        boot_node_updates_rx,
+        events_tx,
+    };
+    (events_rx, handle, mock)
+}
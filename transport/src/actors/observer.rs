@@ -1,25 +1,49 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use futures::StreamExt;
 use futures_core::Stream;
 use libp2p::{
-    swarm::{NetworkBehaviour, SwarmEvent, ToSwarm},
-    PeerId, Swarm,
+    swarm::{
+        behaviour::ConnectionEstablished, ConnectionClosed, FromSwarm, NetworkBehaviour,
+        SwarmEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, Swarm,
 };
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 use tokio_util::sync::CancellationToken;
 
 use subsquid_messages::{LogsCollected, Ping, QueryLogs};
 
 use crate::{
+    actors::base::{disconnect_peer, update_boot_nodes, BaseTransportHandle},
     behaviour::{
-        base::{BaseBehaviour, BaseBehaviourEvent},
+        base::{
+            BaseBehaviour, BaseBehaviourEvent, BootNodeUpdate, PauseMode, PauseReceiver,
+            RoutingTableSnapshot,
+        },
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
+    protocol::{LOGS_COLLECTED_TOPIC, PING_TOPIC, WORKER_LOGS_TOPIC},
     record_event,
-    util::{new_queue, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    swarm_events::{tap_event, SwarmEventKind},
+    util::{new_actor_queue, new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    QueueFull,
 };
 
+/// How long a worker is remembered after its last ping, for computing ping coverage
+const KNOWN_WORKER_TTL: Duration = Duration::from_secs(3600);
+/// Maximum number of workers remembered at once
+const MAX_KNOWN_WORKERS: usize = 10_000;
+
+/// Maximum number of peers returned in a routing table snapshot (256 full buckets of 20 peers)
+const ROUTING_TABLE_SNAPSHOT_LIMIT: usize = 5120;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ObserverEvent {
     Ping {
@@ -31,21 +55,161 @@ pub enum ObserverEvent {
         peer_id: PeerId,
         query_logs: QueryLogs,
     },
+    /// The transport has been paused, see [`BaseTransportHandle::pause`]
+    Paused {
+        mode: PauseMode,
+    },
+    /// The transport has been resumed after a pause
+    Resumed,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ObserverConfig {
     pub logs_collector_id: PeerId,
+    pub disconnects_queue_size: usize,
+    pub boot_node_updates_queue_size: usize,
+    pub routing_table_requests_queue_size: usize,
     pub events_queue_size: usize,
     pub shutdown_timeout: Duration,
+    /// Label added to the metrics of this actor's queues, to tell apart actors running in one
+    /// process (no label if `None`). Not part of the serialized config.
+    #[serde(skip)]
+    pub metrics_namespace: Option<&'static str>,
+    /// Time window over which network stats are aggregated
+    pub stats_window: Duration,
 }
 
 impl ObserverConfig {
     pub fn new(logs_collector_id: PeerId) -> Self {
         Self {
             logs_collector_id,
+            disconnects_queue_size: 100,
+            boot_node_updates_queue_size: 100,
+            routing_table_requests_queue_size: 10,
             events_queue_size: 100,
             shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics_namespace: None,
+            stats_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Aggregate statistics derived from the messages and connections seen by the observer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkStats {
+    /// Time window over which activity is aggregated
+    pub window: Duration,
+    pub connected_peers: usize,
+    /// Connected peers which sent a ping within the window
+    pub connected_workers: usize,
+    /// Connected peers which didn't send a ping within the window (gateways, logs collector etc.)
+    pub other_connected_peers: usize,
+    /// Distinct peers which sent a ping within the window, directly or through gossip
+    pub active_workers: usize,
+    /// Share of workers seen within the last hour which sent a ping within the window
+    pub ping_coverage: f64,
+    /// Messages per second received on each topic, averaged over the window
+    pub messages_per_sec: HashMap<String, f64>,
+}
+
+#[derive(Default)]
+struct StatsState {
+    connected: HashSet<PeerId>,
+    last_pings: HashMap<PeerId, Instant>,
+    // Message counts per topic in one-second buckets, covering the window
+    buckets: VecDeque<(Instant, HashMap<&'static str, u64>)>,
+}
+
+/// Network stats shared between the behaviour and the transport handle
+#[derive(Clone)]
+struct NetworkStatsCollector {
+    state: Arc<Mutex<StatsState>>,
+    window: Duration,
+}
+
+impl NetworkStatsCollector {
+    fn new(window: Duration) -> Self {
+        Self {
+            state: Default::default(),
+            window,
+        }
+    }
+
+    fn on_connection(&self, peer_id: PeerId, connected: bool) {
+        let mut state = self.state.lock().unwrap();
+        if connected {
+            state.connected.insert(peer_id);
+        } else {
+            state.connected.remove(&peer_id);
+        }
+    }
+
+    fn on_message(&self, topic: &'static str, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        while state
+            .buckets
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) > self.window)
+        {
+            state.buckets.pop_front();
+        }
+        match state.buckets.back_mut() {
+            Some((start, counts)) if now < *start + Duration::from_secs(1) => {
+                *counts.entry(topic).or_default() += 1;
+            }
+            _ => state.buckets.push_back((now, HashMap::from([(topic, 1)]))),
+        }
+    }
+
+    fn on_ping(&self, peer_id: PeerId, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.last_pings.len() >= MAX_KNOWN_WORKERS && !state.last_pings.contains_key(&peer_id) {
+            state
+                .last_pings
+                .retain(|_, last_ping| now.duration_since(*last_ping) < KNOWN_WORKER_TTL);
+            if state.last_pings.len() >= MAX_KNOWN_WORKERS {
+                return log::warn!("Too many known workers. Ping from {peer_id} not counted");
+            }
+        }
+        state.last_pings.insert(peer_id, now);
+    }
+
+    fn snapshot(&self, now: Instant) -> NetworkStats {
+        let mut state = self.state.lock().unwrap();
+        state
+            .last_pings
+            .retain(|_, last_ping| now.duration_since(*last_ping) < KNOWN_WORKER_TTL);
+        let is_active = |last_ping: &Instant| now.duration_since(*last_ping) < self.window;
+        let active_workers = state.last_pings.values().filter(|t| is_active(t)).count();
+        let connected_workers = state
+            .connected
+            .iter()
+            .filter(|peer_id| state.last_pings.get(peer_id).is_some_and(is_active))
+            .count();
+        let ping_coverage = if state.last_pings.is_empty() {
+            0.0
+        } else {
+            active_workers as f64 / state.last_pings.len() as f64
+        };
+
+        let mut messages_per_sec = HashMap::new();
+        let in_window = |(start, _): &&(Instant, _)| now.duration_since(*start) <= self.window;
+        for (_, counts) in state.buckets.iter().filter(in_window) {
+            for (topic, count) in counts {
+                *messages_per_sec.entry(topic.to_string()).or_default() += *count as f64;
+            }
+        }
+        let window_secs = self.window.as_secs_f64();
+        messages_per_sec.values_mut().for_each(|count: &mut f64| *count /= window_secs);
+
+        NetworkStats {
+            window: self.window,
+            connected_peers: state.connected.len(),
+            connected_workers,
+            other_connected_peers: state.connected.len() - connected_workers,
+            active_workers,
+            ping_coverage,
+            messages_per_sec,
         }
     }
 }
@@ -53,10 +217,12 @@ impl ObserverConfig {
 pub struct ObserverBehaviour {
     base: Wrapped<BaseBehaviour>,
     logs_collector_id: PeerId,
+    stats: NetworkStatsCollector,
 }
 
 impl ObserverBehaviour {
-    pub fn new(mut base: BaseBehaviour, logs_collector_id: PeerId) -> Wrapped<Self> {
+    pub fn new(mut base: BaseBehaviour, config: ObserverConfig) -> Wrapped<Self> {
+        let logs_collector_id = config.logs_collector_id;
         base.subscribe_pings();
         base.subscribe_worker_logs();
         base.subscribe_logs_collected();
@@ -64,30 +230,42 @@ impl ObserverBehaviour {
         Self {
             base: base.into(),
             logs_collector_id,
+            stats: NetworkStatsCollector::new(config.stats_window),
         }
         .into()
     }
 
     fn on_base_event(&mut self, ev: BaseBehaviourEvent) -> Option<ObserverEvent> {
-        match ev {
+        let now = Instant::now();
+        let (msg, ev) = match ev {
             BaseBehaviourEvent::LogsCollected {
                 peer_id,
                 logs_collected,
+                msg,
             } if peer_id == self.logs_collector_id => {
-                Some(ObserverEvent::LogsCollected(logs_collected))
+                self.stats.on_message(LOGS_COLLECTED_TOPIC, now);
+                (msg, ObserverEvent::LogsCollected(logs_collected))
             }
-            BaseBehaviourEvent::Ping { peer_id, ping } => {
-                Some(ObserverEvent::Ping { peer_id, ping })
+            BaseBehaviourEvent::Ping { peer_id, ping, msg } => {
+                self.stats.on_message(PING_TOPIC, now);
+                self.stats.on_ping(peer_id, now);
+                (msg, ObserverEvent::Ping { peer_id, ping })
             }
             BaseBehaviourEvent::WorkerQueryLogs {
                 peer_id,
                 query_logs,
-            } => Some(ObserverEvent::WorkerQueryLogs {
-                peer_id,
-                query_logs,
-            }),
-            _ => None,
-        }
+                msg,
+            } => {
+                self.stats.on_message(WORKER_LOGS_TOPIC, now);
+                let ev = ObserverEvent::WorkerQueryLogs {
+                    peer_id,
+                    query_logs,
+                };
+                (msg, ev)
+            }
+            _ => return None,
+        };
+        self.base.route_to_topic_stream(&msg, ev)
     }
 }
 
@@ -99,6 +277,23 @@ impl BehaviourWrapper for ObserverBehaviour {
         &mut self.base
     }
 
+    fn on_swarm_event(&mut self, ev: FromSwarm) -> impl IntoIterator<Item = TToSwarm<Self>> {
+        match ev {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                other_established: 0,
+                ..
+            }) => self.stats.on_connection(peer_id, true),
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => self.stats.on_connection(peer_id, false),
+            _ => {}
+        }
+        None
+    }
+
     fn on_inner_event(
         &mut self,
         ev: <Self::Inner as NetworkBehaviour>::ToSwarm,
@@ -109,7 +304,12 @@ impl BehaviourWrapper for ObserverBehaviour {
 
 struct ObserverTransport {
     swarm: Swarm<Wrapped<ObserverBehaviour>>,
+    disconnects_rx: Receiver<PeerId>,
+    boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    routing_table_requests_rx: Receiver<oneshot::Sender<RoutingTableSnapshot>>,
     events_tx: Sender<ObserverEvent>,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    pause_rx: PauseReceiver,
 }
 
 impl ObserverTransport {
@@ -118,15 +318,42 @@ impl ObserverTransport {
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => break,
+                _ = self.events_tx.closed() => {
+                    log::info!("Event stream dropped by the consumer");
+                    break;
+                }
                 ev = self.swarm.select_next_some() => self.on_swarm_event(ev),
+                Some(peer_id) = self.disconnects_rx.recv() => {
+                    disconnect_peer(&mut self.swarm, peer_id)
+                }
+                Some(update) = self.boot_node_updates_rx.recv() => {
+                    update_boot_nodes(&mut self.swarm, update, |b| &mut b.base)
+                }
+                ev = self.pause_rx.next_event(
+                    |mode| ObserverEvent::Paused { mode },
+                    ObserverEvent::Resumed,
+                ) => self.events_tx.send_lossy(ev),
+                Some(resp_tx) = self.routing_table_requests_rx.recv() => self.send_routing_table(resp_tx),
             }
         }
         log::info!("Shutting down observer P2P transport");
     }
 
+    fn send_routing_table(&mut self, resp_tx: oneshot::Sender<RoutingTableSnapshot>) {
+        let routing_table = self
+            .swarm
+            .behaviour_mut()
+            .base
+            .routing_table_snapshot(ROUTING_TABLE_SNAPSHOT_LIMIT);
+        if resp_tx.send(routing_table).is_err() {
+            log::debug!("Routing table request cancelled");
+        }
+    }
+
     fn on_swarm_event(&mut self, ev: SwarmEvent<ObserverEvent>) {
         log::trace!("Swarm event: {ev:?}");
         record_event(&ev);
+        tap_event(&self.raw_events_tx, &ev);
         if let SwarmEvent::Behaviour(ev) = ev {
             self.events_tx.send_lossy(ev)
         }
@@ -135,25 +362,192 @@ impl ObserverTransport {
 
 #[derive(Clone)]
 pub struct ObserverTransportHandle {
+    routing_table_requests_tx: Sender<oneshot::Sender<RoutingTableSnapshot>>,
+    stats: NetworkStatsCollector,
+    base: BaseTransportHandle,
     _task_manager: Arc<TaskManager>,
 }
 
 impl ObserverTransportHandle {
-    fn new(transport: ObserverTransport, shutdown_timeout: Duration) -> Self {
+    fn new(
+        disconnects_tx: Sender<PeerId>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
+        routing_table_requests_tx: Sender<oneshot::Sender<RoutingTableSnapshot>>,
+        transport: ObserverTransport,
+        shutdown_timeout: Duration,
+    ) -> Self {
+        let stats = transport.swarm.behaviour().stats.clone();
+        let base = BaseTransportHandle::new(
+            &transport.swarm.behaviour().base,
+            disconnects_tx,
+            boot_node_updates_tx,
+        );
         let mut task_manager = TaskManager::new(shutdown_timeout);
         task_manager.spawn(|c| transport.run(c));
         Self {
+            routing_table_requests_tx,
+            stats,
+            base,
             _task_manager: Arc::new(task_manager),
         }
     }
+
+    /// Aggregate stats of the network activity seen within the configured window
+    pub fn network_stats(&self) -> NetworkStats {
+        self.stats.snapshot(Instant::now())
+    }
+
+    /// Snapshot of all peers in the Kademlia routing table along with their known addresses.
+    /// On well-connected nodes it can be large, so it's capped at a few thousand peers.
+    pub async fn routing_table(&self) -> Result<Vec<(PeerId, Vec<Multiaddr>)>, QueueFull> {
+        let snapshot = self.routing_table_snapshot().await?;
+        Ok(snapshot.peers.into_iter().map(|entry| (entry.peer_id, entry.addrs)).collect())
+    }
+
+    /// Serializable snapshot of the routing table including the buckets of the peers, capped
+    /// like [`Self::routing_table`]. Snapshots taken on different nodes can be compared with
+    /// [`RoutingTableSnapshot::diff`].
+    pub async fn routing_table_snapshot(&self) -> Result<RoutingTableSnapshot, QueueFull> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.routing_table_requests_tx.try_send(resp_tx)?;
+        resp_rx.await.map_err(|_| QueueFull)
+    }
+}
+
+impl Deref for ObserverTransportHandle {
+    type Target = BaseTransportHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
 }
 
 pub fn start_transport(
     swarm: Swarm<Wrapped<ObserverBehaviour>>,
     config: ObserverConfig,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
 ) -> (impl Stream<Item = ObserverEvent>, ObserverTransportHandle) {
-    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
-    let transport = ObserverTransport { swarm, events_tx };
-    let handle = ObserverTransportHandle::new(transport, config.shutdown_timeout);
+    let namespace = config.metrics_namespace;
+    let (disconnects_tx, disconnects_rx) =
+        new_actor_queue(config.disconnects_queue_size, "disconnects", namespace);
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_actor_queue(config.boot_node_updates_queue_size, "boot_node_updates", namespace);
+    let (routing_table_requests_tx, routing_table_requests_rx) = new_actor_queue(
+        config.routing_table_requests_queue_size,
+        "routing_table_requests",
+        namespace,
+    );
+    let (events_tx, events_rx) = new_actor_queue(config.events_queue_size, "events", namespace);
+    let pause_rx = swarm.behaviour().base.pause_state().subscribe();
+    let transport = ObserverTransport {
+        swarm,
+        disconnects_rx,
+        boot_node_updates_rx,
+        routing_table_requests_rx,
+        events_tx,
+        raw_events_tx,
+        pause_rx,
+    };
+    let handle = ObserverTransportHandle::new(
+        disconnects_tx,
+        boot_node_updates_tx,
+        routing_table_requests_tx,
+        transport,
+        config.shutdown_timeout,
+    );
     (events_rx, handle)
 }
+
+/// Mock end of the observer transport, see [`crate::mock`]
+#[cfg(feature = "test-util")]
+pub struct MockObserverTransport {
+    pub disconnects_rx: Receiver<PeerId>,
+    pub boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    pub routing_table_requests_rx: Receiver<oneshot::Sender<RoutingTableSnapshot>>,
+    pub events_tx: Sender<ObserverEvent>,
+}
+
+#[cfg(feature = "test-util")]
+pub fn start_mock_transport(
+    config: ObserverConfig,
+) -> (
+    impl Stream<Item = ObserverEvent>,
+    ObserverTransportHandle,
+    MockObserverTransport,
+) {
+    let (disconnects_tx, disconnects_rx) = new_queue(config.disconnects_queue_size, "disconnects");
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_queue(config.boot_node_updates_queue_size, "boot_node_updates");
+    let (routing_table_requests_tx, routing_table_requests_rx) =
+        new_queue(config.routing_table_requests_queue_size, "routing_table_requests");
+    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let handle = ObserverTransportHandle {
+        routing_table_requests_tx,
+        stats: NetworkStatsCollector::new(config.stats_window),
+        base: BaseTransportHandle::mock(disconnects_tx, boot_node_updates_tx),
+        _task_manager: Arc::new(TaskManager::new(config.shutdown_timeout)),
+    };
+    let mock = MockObserverTransport {
+        disconnects_rx,
+        boot_node_updates_rx,
+        routing_table_requests_rx,
+        events_tx,
+    };
+    (events_rx, handle, mock)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::actors::testing::new_test_swarm;
+
+    use super::*;
+
+    fn new_observer_swarm(config: ObserverConfig) -> Swarm<Wrapped<ObserverBehaviour>> {
+        new_test_swarm(|_, base| ObserverBehaviour::new(base, config))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stops_when_event_stream_dropped() {
+        let config = ObserverConfig::new(PeerId::random());
+        let (events, handle) = start_transport(new_observer_swarm(config), config, None);
+        let peer_id = PeerId::random();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(handle.disconnect_peer(peer_id).is_ok());
+
+        // Once the transport has stopped, nothing receives from the handle's queues
+        drop(events);
+        let stopped = async {
+            while handle.disconnect_peer(peer_id).is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(5), stopped).await.is_ok());
+    }
+
+    #[test]
+    fn test_network_stats() {
+        let start = Instant::now();
+        let stats = NetworkStatsCollector::new(Duration::from_secs(10));
+        let [worker1, worker2, gateway] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for peer_id in [worker1, gateway] {
+            stats.on_connection(peer_id, true);
+        }
+        stats.on_ping(worker2, start);
+        stats.on_message(PING_TOPIC, start);
+        for i in 0..20 {
+            let now = start + Duration::from_millis(500 * i);
+            stats.on_ping(worker1, now);
+            stats.on_message(PING_TOPIC, now);
+        }
+        stats.on_message(WORKER_LOGS_TOPIC, start + Duration::from_secs(5));
+
+        let snapshot = stats.snapshot(start + Duration::from_secs(15));
+        assert_eq!(snapshot.connected_peers, 2);
+        assert_eq!(snapshot.connected_workers, 1);
+        assert_eq!(snapshot.other_connected_peers, 1);
+        assert_eq!(snapshot.active_workers, 1);
+        assert_eq!(snapshot.ping_coverage, 0.5);
+        assert_eq!(snapshot.messages_per_sec[PING_TOPIC], 1.0);
+        assert_eq!(snapshot.messages_per_sec[WORKER_LOGS_TOPIC], 0.1);
+    }
+}
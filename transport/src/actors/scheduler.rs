@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{ops::Deref, sync::Arc, time::Duration};
 
 use futures::StreamExt;
 
@@ -14,15 +14,18 @@ use tokio_util::sync::CancellationToken;
 use subsquid_messages::{Ping, Pong};
 
 use crate::{
+    actors::base::{disconnect_peer, update_boot_nodes, BaseTransportHandle},
     behaviour::{
-        base::{BaseBehaviour, BaseBehaviourEvent},
+        base::{BaseBehaviour, BaseBehaviourEvent, BootNodeUpdate, PauseMode, PauseReceiver},
+        handshake::NodeRole,
         request_client::{ClientBehaviour, ClientConfig, ClientEvent},
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
     codec::{ProtoCodec, ACK_SIZE},
     protocol::{MAX_PONG_SIZE, PONG_PROTOCOL},
     record_event,
-    util::{new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    swarm_events::{tap_event, SwarmEventKind},
+    util::{new_actor_queue, new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
     QueueFull,
 };
 
@@ -32,6 +35,16 @@ pub enum SchedulerEvent {
     Ping { peer_id: PeerId, ping: Ping },
     /// Peer was probed for reachability
     PeerProbed { peer_id: PeerId, reachable: bool },
+    /// Remote peer subscribed to or unsubscribed from a pub-sub topic
+    PeerSubscriptionChanged {
+        peer_id: PeerId,
+        topic: String,
+        subscribed: bool,
+    },
+    /// The transport has been paused, see [`BaseTransportHandle::pause`]
+    Paused { mode: PauseMode },
+    /// The transport has been resumed after a pause
+    Resumed,
 }
 
 type PongBehaviour = Wrapped<ClientBehaviour<ProtoCodec<Pong, u32>>>;
@@ -48,8 +61,14 @@ pub struct SchedulerConfig {
     pub max_pong_size: u64,
     pub pongs_queue_size: usize,
     pub probes_queue_size: usize,
+    pub disconnects_queue_size: usize,
+    pub boot_node_updates_queue_size: usize,
     pub events_queue_size: usize,
     pub shutdown_timeout: Duration,
+    /// Label added to the metrics of this actor's queues, to tell apart actors running in one
+    /// process (no label if `None`). Not part of the serialized config.
+    #[serde(skip)]
+    pub metrics_namespace: Option<&'static str>,
 }
 
 impl Default for SchedulerConfig {
@@ -59,8 +78,11 @@ impl Default for SchedulerConfig {
             max_pong_size: MAX_PONG_SIZE,
             pongs_queue_size: 1000,
             probes_queue_size: 1000,
+            disconnects_queue_size: 100,
+            boot_node_updates_queue_size: 100,
             events_queue_size: 1000,
             shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics_namespace: None,
         }
     }
 }
@@ -72,6 +94,7 @@ pub struct SchedulerBehaviour {
 impl SchedulerBehaviour {
     pub fn new(mut base: BaseBehaviour, config: SchedulerConfig) -> Wrapped<Self> {
         base.subscribe_pings();
+        base.set_role(NodeRole::Scheduler);
         Self {
             inner: InnerBehaviour {
                 base: base.into(),
@@ -89,8 +112,20 @@ impl SchedulerBehaviour {
     #[rustfmt::skip]
     fn on_base_event(&mut self, ev: BaseBehaviourEvent) -> Option<SchedulerEvent> {
         match ev {
-            BaseBehaviourEvent::Ping { peer_id, ping } => self.on_ping(peer_id, ping),
+            BaseBehaviourEvent::Ping { peer_id, ping, msg } => {
+                let ev = self.on_ping(peer_id, ping)?;
+                self.inner.base.route_to_topic_stream(&msg, ev)
+            }
             BaseBehaviourEvent::PeerProbed { peer_id, reachable } => self.on_peer_probed(peer_id, reachable),
+            BaseBehaviourEvent::PeerSubscriptionChanged {
+                peer_id,
+                topic,
+                subscribed,
+            } => Some(SchedulerEvent::PeerSubscriptionChanged {
+                peer_id,
+                topic: topic.to_string(),
+                subscribed,
+            }),
             _ => None
         }
     }
@@ -116,7 +151,13 @@ impl SchedulerBehaviour {
         match ev {
             ClientEvent::Response { .. } => {} // response is just ACK, no useful information
             ClientEvent::PeerUnknown { peer_id } => self.inner.base.find_and_dial(peer_id),
+            ClientEvent::PendingRequestsSent { peer_id, count, .. } => {
+                log::debug!("Sent {count} buffered pongs to {peer_id}")
+            }
             ClientEvent::Timeout { peer_id, .. } => log::warn!("Sending pong to {peer_id} failed"),
+            ClientEvent::UnsupportedProtocol { peer_id, .. } => {
+                log::warn!("Peer {peer_id} doesn't support pong protocol")
+            }
         }
         None
     }
@@ -157,7 +198,11 @@ struct SchedulerTransport {
     swarm: Swarm<Wrapped<SchedulerBehaviour>>,
     pongs_rx: Receiver<(PeerId, Pong)>,
     probes_rx: Receiver<PeerId>,
+    disconnects_rx: Receiver<PeerId>,
+    boot_node_updates_rx: Receiver<BootNodeUpdate>,
     events_tx: Sender<SchedulerEvent>,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    pause_rx: PauseReceiver,
 }
 
 impl SchedulerTransport {
@@ -166,9 +211,31 @@ impl SchedulerTransport {
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => break,
+                _ = self.events_tx.closed() => {
+                    log::info!("Event stream dropped by the consumer");
+                    break;
+                }
                 ev = self.swarm.select_next_some() => self.on_swarm_event(ev),
-                Some((peer_id, pong)) = self.pongs_rx.recv() => self.swarm.behaviour_mut().send_pong(peer_id, pong),
-                Some(peer_id) = self.probes_rx.recv() => self.probe_peer(peer_id),
+                Some((peer_id, pong)) = self.pongs_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("pong") {
+                        self.swarm.behaviour_mut().send_pong(peer_id, pong)
+                    }
+                }
+                Some(peer_id) = self.probes_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("probe") {
+                        self.probe_peer(peer_id)
+                    }
+                }
+                Some(peer_id) = self.disconnects_rx.recv() => {
+                    disconnect_peer(&mut self.swarm, peer_id)
+                }
+                Some(update) = self.boot_node_updates_rx.recv() => {
+                    update_boot_nodes(&mut self.swarm, update, |b| &mut b.inner.base)
+                }
+                ev = self.pause_rx.next_event(
+                    |mode| SchedulerEvent::Paused { mode },
+                    SchedulerEvent::Resumed,
+                ) => self.events_tx.send_lossy(ev),
             }
         }
         log::info!("Shutting down scheduler P2P transport");
@@ -177,6 +244,7 @@ impl SchedulerTransport {
     fn on_swarm_event(&mut self, ev: SwarmEvent<SchedulerEvent>) {
         log::trace!("Swarm event: {ev:?}");
         record_event(&ev);
+        tap_event(&self.raw_events_tx, &ev);
         if let SwarmEvent::Behaviour(ev) = ev {
             self.events_tx.send_lossy(ev)
         }
@@ -199,6 +267,7 @@ impl SchedulerTransport {
 pub struct SchedulerTransportHandle {
     pongs_tx: Sender<(PeerId, Pong)>,
     probes_tx: Sender<PeerId>,
+    base: BaseTransportHandle,
     _task_manager: Arc<TaskManager>,
 }
 
@@ -206,14 +275,22 @@ impl SchedulerTransportHandle {
     fn new(
         pongs_tx: Sender<(PeerId, Pong)>,
         probes_tx: Sender<PeerId>,
+        disconnects_tx: Sender<PeerId>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
         transport: SchedulerTransport,
         shutdown_timeout: Duration,
     ) -> Self {
+        let base = BaseTransportHandle::new(
+            &transport.swarm.behaviour().inner.base,
+            disconnects_tx,
+            boot_node_updates_tx,
+        );
         let mut task_manager = TaskManager::new(shutdown_timeout);
         task_manager.spawn(|c| transport.run(c));
         Self {
             pongs_tx,
             probes_tx,
+            base,
             _task_manager: Arc::new(task_manager),
         }
     }
@@ -222,26 +299,103 @@ impl SchedulerTransportHandle {
         self.pongs_tx.try_send((peer_id, pong))
     }
 
+    /// Check whether each of the peers can be connected to, looking it up in the DHT and dialing
+    /// it if needed. At most `concurrency` peers are checked at a time, each one for at most
+    /// `timeout`. Results are yielded as the checks complete.
+    pub fn check_peers(
+        &self,
+        peers: impl IntoIterator<Item = PeerId>,
+        concurrency: usize,
+        timeout: Duration,
+    ) -> impl Stream<Item = (PeerId, bool)> {
+        self.base.peer_waiters.check_all(peers, concurrency, timeout)
+    }
+
     pub fn probe_peer(&self, peer_id: PeerId) -> Result<(), QueueFull> {
         log::debug!("Queueing probe of peer {peer_id}");
         self.probes_tx.try_send(peer_id)
     }
 }
 
+impl Deref for SchedulerTransportHandle {
+    type Target = BaseTransportHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
 pub fn start_transport(
     swarm: Swarm<Wrapped<SchedulerBehaviour>>,
     config: SchedulerConfig,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
 ) -> (impl Stream<Item = SchedulerEvent>, SchedulerTransportHandle) {
-    let (pongs_tx, pongs_rx) = new_queue(config.pongs_queue_size, "pongs");
-    let (probes_tx, probes_rx) = new_queue(config.probes_queue_size, "probes");
-    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let namespace = config.metrics_namespace;
+    let (pongs_tx, pongs_rx) = new_actor_queue(config.pongs_queue_size, "pongs", namespace);
+    let (probes_tx, probes_rx) = new_actor_queue(config.probes_queue_size, "probes", namespace);
+    let (disconnects_tx, disconnects_rx) =
+        new_actor_queue(config.disconnects_queue_size, "disconnects", namespace);
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_actor_queue(config.boot_node_updates_queue_size, "boot_node_updates", namespace);
+    let (events_tx, events_rx) = new_actor_queue(config.events_queue_size, "events", namespace);
+    let pause_rx = swarm.behaviour().inner.base.pause_state().subscribe();
     let transport = SchedulerTransport {
         swarm,
         pongs_rx,
         probes_rx,
+        disconnects_rx,
+        boot_node_updates_rx,
         events_tx,
+        raw_events_tx,
+        pause_rx,
     };
-    let handle =
-        SchedulerTransportHandle::new(pongs_tx, probes_tx, transport, config.shutdown_timeout);
+    let handle = SchedulerTransportHandle::new(
+        pongs_tx,
+        probes_tx,
+        disconnects_tx,
+        boot_node_updates_tx,
+        transport,
+        config.shutdown_timeout,
+    );
     (events_rx, handle)
 }
+
+/// Mock end of the scheduler transport, see [`crate::mock`]
+#[cfg(feature = "test-util")]
+pub struct MockSchedulerTransport {
+    pub pongs_rx: Receiver<(PeerId, Pong)>,
+    pub probes_rx: Receiver<PeerId>,
+    pub disconnects_rx: Receiver<PeerId>,
+    pub boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    pub events_tx: Sender<SchedulerEvent>,
+}
+
+#[cfg(feature = "test-util")]
+pub fn start_mock_transport(
+    config: SchedulerConfig,
+) -> (
+    impl Stream<Item = SchedulerEvent>,
+    SchedulerTransportHandle,
+    MockSchedulerTransport,
+) {
+    let (pongs_tx, pongs_rx) = new_queue(config.pongs_queue_size, "pongs");
+    let (probes_tx, probes_rx) = new_queue(config.probes_queue_size, "probes");
+    let (disconnects_tx, disconnects_rx) = new_queue(config.disconnects_queue_size, "disconnects");
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_queue(config.boot_node_updates_queue_size, "boot_node_updates");
+    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let handle = SchedulerTransportHandle {
+        pongs_tx,
+        probes_tx,
+        base: BaseTransportHandle::mock(disconnects_tx, boot_node_updates_tx),
+        _task_manager: Arc::new(TaskManager::new(config.shutdown_timeout)),
+    };
+    let mock = MockSchedulerTransport {
+        pongs_rx,
+        probes_rx,
+        disconnects_rx,
+        boot_node_updates_rx,
+        events_tx,
+    };
+    (events_rx, handle, mock)
+}
@@ -0,0 +1,143 @@
+//! Helpers for tests running transports over in-memory connections
+// Not every helper is used with every set of actor features
+#![allow(dead_code)]
+
+use std::time::SystemTime;
+
+use contract_client::{
+    Allocation, Client, ClientError, Epoch, GatewayAllocation, GatewayCluster, GatewayInfo, Worker,
+    U256,
+};
+use libp2p::{
+    core::{transport::MemoryTransport, upgrade::Version},
+    identity::Keypair,
+    noise,
+    swarm::NetworkBehaviour,
+    yamux, Multiaddr, StreamProtocol, Swarm, Transport,
+};
+
+use crate::{behaviour::base::BaseBehaviour, PeerId};
+
+/// Contract client without any chain data
+pub struct NoChainClient;
+
+#[async_trait::async_trait]
+impl Client for NoChainClient {
+    fn clone_client(&self) -> Box<dyn Client> {
+        Box::new(NoChainClient)
+    }
+
+    async fn current_epoch(&self) -> Result<u32, ClientError> {
+        Err(ClientError::BlockNotFound)
+    }
+
+    async fn current_epoch_start(&self) -> Result<SystemTime, ClientError> {
+        Err(ClientError::BlockNotFound)
+    }
+
+    async fn current_epoch_info(&self) -> Result<Epoch, ClientError> {
+        Err(ClientError::BlockNotFound)
+    }
+
+    async fn gateway_allocation(
+        &self,
+        _gateway_id: PeerId,
+        _epoch: u32,
+    ) -> Result<GatewayAllocation, ClientError> {
+        Err(ClientError::BlockNotFound)
+    }
+
+    async fn worker_id(&self, _peer_id: PeerId) -> Result<U256, ClientError> {
+        Err(ClientError::BlockNotFound)
+    }
+
+    async fn active_workers(&self) -> Result<Vec<Worker>, ClientError> {
+        Ok(vec![])
+    }
+
+    async fn is_gateway_registered(&self, _peer_id: PeerId) -> Result<bool, ClientError> {
+        Ok(false)
+    }
+
+    async fn worker_registration_time(
+        &self,
+        _peer_id: PeerId,
+    ) -> Result<Option<SystemTime>, ClientError> {
+        Ok(None)
+    }
+
+    async fn gateway_info(&self, peer_id: PeerId) -> Result<GatewayInfo, ClientError> {
+        Err(ClientError::GatewayNotRegistered(peer_id))
+    }
+
+    async fn active_gateways(&self) -> Result<Vec<PeerId>, ClientError> {
+        Ok(vec![])
+    }
+
+    async fn current_allocations(
+        &self,
+        _client_id: PeerId,
+        _worker_ids: Option<Vec<Worker>>,
+    ) -> Result<Vec<Allocation>, ClientError> {
+        Ok(vec![])
+    }
+
+    async fn gateway_clusters(&self, _worker_id: U256) -> Result<Vec<GatewayCluster>, ClientError> {
+        Ok(vec![])
+    }
+}
+
+/// Swarm with an actor behaviour built on top of a default [`BaseBehaviour`]
+pub fn new_test_swarm<B: NetworkBehaviour>(
+    behaviour: impl FnOnce(&Keypair, BaseBehaviour) -> B,
+) -> Swarm<B> {
+    libp2p::SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_other_transport(|keypair| {
+            Ok::<_, noise::Error>(
+                MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(keypair)?)
+                    .multiplex(yamux::Config::default()),
+            )
+        })
+        .unwrap()
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .unwrap()
+        .with_behaviour(|keypair, relay| {
+            let base = BaseBehaviour::new(
+                keypair,
+                Box::new(NoChainClient),
+                Default::default(),
+                vec![],
+                relay,
+                StreamProtocol::new("/test/kad/1.0.0"),
+                "test".to_string(),
+            );
+            behaviour(keypair, base)
+        })
+        .unwrap()
+        .build()
+}
+
+/// Swarm with a bare behaviour, e.g. standing in for a remote peer
+pub fn new_memory_swarm<B: NetworkBehaviour>(keypair: Keypair, behaviour: B) -> Swarm<B> {
+    libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|keypair| {
+            Ok::<_, noise::Error>(
+                MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(keypair)?)
+                    .multiplex(yamux::Config::default()),
+            )
+        })
+        .unwrap()
+        .with_behaviour(|_| behaviour)
+        .unwrap()
+        .build()
+}
+
+pub fn random_memory_addr() -> Multiaddr {
+    format!("/memory/{}", rand::random::<u64>()).parse().unwrap()
+}
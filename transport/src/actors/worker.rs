@@ -1,33 +1,53 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    ops::Deref,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use futures::StreamExt;
+use contract_client::ClientError;
+use futures::{FutureExt, StreamExt};
+use futures_bounded::FuturesMap;
 use futures_core::Stream;
 use libp2p::{
-    request_response::ResponseChannel,
-    swarm::{NetworkBehaviour, SwarmEvent, ToSwarm},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent, ToSwarm},
     PeerId, Swarm,
 };
 use libp2p_swarm_derive::NetworkBehaviour;
+use lru::LruCache;
+use prost::Message;
 
 use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
 use tokio_util::sync::CancellationToken;
 
 use subsquid_messages::{
-    signatures::SignedMessage, LogsCollected, Ping, Pong, Query, QueryExecuted, QueryResult,
+    query_result, signatures::SignedMessage, DatasetRanges, LogsBackfillRequest,
+    LogsBackfillResponse, LogsCollected, Ping, Pong, Query, QueryExecuted, QueryResult,
 };
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{QUERY_CACHE_HITS, QUERY_CACHE_MISSES, UNREGISTERED_GATEWAY_QUERIES};
 use crate::{
+    actors::base::{disconnect_peer, update_boot_nodes, BaseTransportHandle},
     behaviour::{
-        base::{BaseBehaviour, BaseBehaviourEvent},
-        request_server::{Request, ServerBehaviour},
+        base::{BaseBehaviour, BaseBehaviourEvent, BootNodeUpdate, PauseMode, PauseReceiver},
+        handshake::NodeRole,
+        request_client::{ClientBehaviour, ClientConfig, ClientEvent},
+        request_server::{Request, ResponseChannel, ServerBehaviour, ServerConfig},
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
-    codec::{ProtoCodec, ACK_SIZE},
+    codec::{PayloadTooLarge, ProtoCodec, ServerCodec, ACK_SIZE},
     protocol::{
-        MAX_PONG_SIZE, MAX_QUERY_RESULT_SIZE, MAX_QUERY_SIZE, PONG_PROTOCOL, QUERY_PROTOCOL,
+        LOGS_BACKFILL_PROTOCOL, MAX_LOGS_BACKFILL_SIZE, MAX_PONG_SIZE, MAX_QUERY_RESULT_SIZE,
+        MAX_QUERY_SIZE, PONG_PROTOCOL, QUERY_PROTOCOL, QUERY_PROTOCOL_COMPRESSED,
+        QUERY_PROTOCOL_JSON, QUERY_RESULT_PROTOCOL,
     },
     record_event,
-    util::{new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
+    swarm_events::{tap_event, SwarmEventKind},
+    util::{new_actor_queue, new_queue, Receiver, Sender, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT},
     QueueFull,
 };
 
@@ -37,48 +57,589 @@ pub enum WorkerEvent {
     Pong(Pong),
     /// Query received from a gateway
     Query { peer_id: PeerId, query: Query },
+    /// Query identical to a recently executed one, whose result has been cached
+    /// (see [`WorkerConfig::query_cache`]). It should be checked against the allocation and
+    /// logged like any other query, and `result` sent instead of executing it again.
+    CachedQuery {
+        peer_id: PeerId,
+        query: Query,
+        result: QueryResult,
+    },
     /// Logs up to `last_seq_no` have been saved by logs collector
     LogsCollected { last_seq_no: Option<u64> },
+    /// The transport has been paused, see [`BaseTransportHandle::pause`]
+    Paused { mode: PauseMode },
+    /// The transport has been resumed after a pause
+    Resumed,
+}
+
+/// Progress of preparing the worker for a restart with a new key
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RotationState {
+    #[default]
+    Running,
+    /// New queries are rejected and pings aren't published anymore,
+    /// the queries in progress are being finished
+    Draining,
+    /// All accepted queries have been answered, it's safe to stop the node
+    Drained,
 }
 
 type PongBehaviour = Wrapped<ServerBehaviour<ProtoCodec<Pong, u32>>>;
-type QueryBehaviour = Wrapped<ServerBehaviour<ProtoCodec<Query, QueryResult>>>;
+type QueryBehaviour = Wrapped<ServerBehaviour<ServerCodec<Query, QueryResult>>>;
+type QueryResultBehaviour = Wrapped<ClientBehaviour<ProtoCodec<QueryResult, u32>>>;
+type LogsBackfillBehaviour =
+    Wrapped<ServerBehaviour<ProtoCodec<LogsBackfillRequest, LogsBackfillResponse>>>;
 
 #[derive(NetworkBehaviour)]
 pub struct InnerBehaviour {
     base: Wrapped<BaseBehaviour>,
     pong: PongBehaviour,
     query: QueryBehaviour,
+    query_result: Toggle<QueryResultBehaviour>,
+    logs_backfill: LogsBackfillBehaviour,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkerConfig {
     pub scheduler_id: PeerId,
     pub logs_collector_id: PeerId,
+    /// Collectors whose acknowledgements are accepted if the primary one fails to collect logs
+    #[serde(default)]
+    pub backup_logs_collector_ids: Vec<PeerId>,
     pub max_pong_size: u64,
     pub max_query_size: u64,
     pub max_query_result_size: u64,
+    /// Results larger than this (in bytes) are compressed if the gateway supports it
+    /// (see [`QUERY_PROTOCOL_COMPRESSED`]). Compressing small results isn't worth the CPU.
+    #[serde(default = "default_compress_results_over")]
+    pub compress_results_over: usize,
+    /// Also accept queries with JSON-encoded messages, for gateways without protobuf support
+    /// (see [`crate::protocol::JSON_PROTOCOL_SUFFIX`])
+    #[serde(default)]
+    pub accept_json_queries: bool,
+    /// Queries not responded to within `response_timeout` get a `Timeout` result. The actual
+    /// result may still be delivered later if `query_result_redelivery` is enabled.
+    #[serde(default)]
+    pub query_server: ServerConfig,
+    /// Ignored: only the latest ping is kept, see [`WorkerTransportHandle::send_ping`]
+    #[deprecated(note = "pings are no longer queued, only the latest one is published")]
+    #[serde(default)]
     pub pings_queue_size: usize,
     pub query_results_queue_size: usize,
     pub logs_queue_size: usize,
+    /// Number of the most recent logs kept to re-send them to logs collectors on request
+    #[serde(default = "default_retained_logs")]
+    pub retained_logs: usize,
+    #[serde(default = "default_queue_size")]
+    pub disconnects_queue_size: usize,
+    #[serde(default = "default_queue_size")]
+    pub dataset_ads_queue_size: usize,
+    #[serde(default = "default_queue_size")]
+    pub boot_node_updates_queue_size: usize,
     pub events_queue_size: usize,
+    /// Max number of ready swarm events inspected at once in order to pass queries
+    /// ahead of other events. 0 keeps the arrival order.
+    #[serde(default)]
+    pub query_priority_batch: usize,
+    /// Queries with a timestamp older than this are rejected. Queries without a timestamp
+    /// are always accepted.
+    #[serde(default = "default_max_query_age")]
+    pub max_query_age: Duration,
+    /// Pass results of recently executed identical queries along with the queries, so that they
+    /// don't need to be executed again (see [`WorkerEvent::CachedQuery`], disabled if `None`)
+    #[serde(default)]
+    pub query_cache: Option<QueryCacheConfig>,
+    /// What to do with queries whose ID is the same as of a query in progress
+    #[serde(default)]
+    pub duplicate_queries: DuplicateQueryPolicy,
+    /// Check on chain that queries come from registered gateways (disabled if `None`).
+    /// Needs a contract client passed to [`WorkerBehaviour::new`].
+    #[serde(default)]
+    pub gateway_verification: Option<GatewayVerificationConfig>,
+    /// If the connection a query arrived on is closed before the result is ready, re-connect
+    /// to the gateway and send the result in a new request (see [`QUERY_RESULT_PROTOCOL`]).
+    /// Such results are dropped if `None`.
+    #[serde(default)]
+    pub query_result_redelivery: Option<ClientConfig>,
+    /// Publish the latest ping set through the handle on this interval, instead of as soon as
+    /// it's set (if `None`). Pings with changed datasets or overload status are published
+    /// right away anyway, see `min_ping_interval`.
+    #[serde(default)]
+    pub ping_interval: Option<Duration>,
+    /// Pings published early because of a state change are at least this far apart,
+    /// so that bursts of changes result in a single ping
+    #[serde(default = "default_min_ping_interval")]
+    pub min_ping_interval: Duration,
     pub shutdown_timeout: Duration,
+    /// Label added to the metrics of this actor's queues, to tell apart actors running in one
+    /// process (no label if `None`). Not part of the serialized config.
+    #[serde(skip)]
+    pub metrics_namespace: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryCacheConfig {
+    pub capacity: NonZeroUsize,
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayVerificationConfig {
+    /// How long the registration status of a gateway is remembered
+    pub ttl: Duration,
+    /// Timeout of a single registration lookup
+    pub lookup_timeout: Duration,
+    /// Max number of queries waiting for their gateways to be verified.
+    /// Queries above the limit are rejected as overloaded.
+    pub max_deferred_queries: usize,
 }
 
 impl WorkerConfig {
+    #[allow(deprecated)]
     pub fn new(scheduler_id: PeerId, logs_collector_id: PeerId) -> Self {
         Self {
             scheduler_id,
             logs_collector_id,
+            backup_logs_collector_ids: vec![],
             max_pong_size: MAX_PONG_SIZE,
             max_query_size: MAX_QUERY_SIZE,
             max_query_result_size: MAX_QUERY_RESULT_SIZE,
+            compress_results_over: default_compress_results_over(),
+            accept_json_queries: false,
+            query_server: Default::default(),
             pings_queue_size: 100,
             query_results_queue_size: 100,
             logs_queue_size: 100,
+            retained_logs: default_retained_logs(),
+            disconnects_queue_size: default_queue_size(),
+            dataset_ads_queue_size: default_queue_size(),
+            boot_node_updates_queue_size: default_queue_size(),
             events_queue_size: 100,
+            query_priority_batch: 0,
+            max_query_age: default_max_query_age(),
+            query_cache: None,
+            duplicate_queries: Default::default(),
+            gateway_verification: None,
+            query_result_redelivery: None,
+            ping_interval: None,
+            min_ping_interval: default_min_ping_interval(),
             shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics_namespace: None,
+        }
+    }
+}
+
+// Defaults of the fields missing in configs serialized by older versions
+
+fn default_compress_results_over() -> usize {
+    64 * 1024
+}
+
+fn default_retained_logs() -> usize {
+    10_000
+}
+
+fn default_queue_size() -> usize {
+    100
+}
+
+fn default_max_query_age() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_min_ping_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Handling of a query arriving while a query with the same ID is in progress,
+/// e.g. when a gateway retries it after a timeout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateQueryPolicy {
+    /// Respond to the new query with an error
+    #[default]
+    Reject,
+    /// Execute the query again if the one in progress has been received longer than this ago,
+    /// otherwise reject it. The first result is sent to both of them, the result of the other
+    /// execution is discarded.
+    ReplaceIfStale(Duration),
+    /// Don't execute the query again, send it the result of the one in progress
+    Dedup,
+}
+
+/// Outcome of receiving a query, see [`InFlightQueries::insert`]
+#[derive(Debug, PartialEq, Eq)]
+enum Admission<C> {
+    /// The query should be executed
+    Execute,
+    /// The result of the query in progress will be sent to this one too
+    Attached,
+    /// Duplicate query rejected, the channel is returned to respond with an error
+    Rejected(C),
+}
+
+struct InFlightQuery<C> {
+    received: Instant,
+    channels: Vec<(PeerId, C)>,
+    // Number of executions not finished yet, more than one if the query has been retried
+    executions: usize,
+}
+
+/// Queries being executed, with the channels to send their results to
+struct InFlightQueries<C> {
+    queries: HashMap<String, InFlightQuery<C>>,
+    policy: DuplicateQueryPolicy,
+}
+
+impl<C> InFlightQueries<C> {
+    pub fn new(policy: DuplicateQueryPolicy) -> Self {
+        Self {
+            queries: Default::default(),
+            policy,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        query_id: String,
+        peer_id: PeerId,
+        chan: C,
+        now: Instant,
+    ) -> Admission<C> {
+        let Some(query) = self.queries.get_mut(&query_id) else {
+            let channels = vec![(peer_id, chan)];
+            self.queries.insert(
+                query_id,
+                InFlightQuery {
+                    received: now,
+                    channels,
+                    executions: 1,
+                },
+            );
+            return Admission::Execute;
+        };
+        match self.policy {
+            DuplicateQueryPolicy::Reject => Admission::Rejected(chan),
+            DuplicateQueryPolicy::ReplaceIfStale(threshold)
+                if now.duration_since(query.received) >= threshold =>
+            {
+                query.received = now;
+                query.channels.push((peer_id, chan));
+                query.executions += 1;
+                Admission::Execute
+            }
+            DuplicateQueryPolicy::ReplaceIfStale(_) => Admission::Rejected(chan),
+            DuplicateQueryPolicy::Dedup => {
+                query.channels.push((peer_id, chan));
+                Admission::Attached
+            }
+        }
+    }
+
+    /// When the query was received (the last time, if it was replaced)
+    pub fn received(&self, query_id: &str) -> Option<Instant> {
+        self.queries.get(query_id).map(|query| query.received)
+    }
+
+    /// Channels waiting for the result of the query, `None` if the query is unknown.
+    /// The first result of a retried query goes to all the channels, the query is only
+    /// forgotten once the results of all its executions have come in.
+    pub fn on_result(&mut self, query_id: &str) -> Option<Vec<(PeerId, C)>> {
+        let query = self.queries.get_mut(query_id)?;
+        let channels = std::mem::take(&mut query.channels);
+        query.executions -= 1;
+        if query.executions == 0 {
+            self.queries.remove(query_id);
+        }
+        Some(channels)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+}
+
+/// Query content, excluding ID, signature and timestamp
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QueryCacheKey {
+    dataset: Option<String>,
+    query: Option<String>,
+    profiling: Option<bool>,
+    client_state_json: Option<String>,
+}
+
+/// Successful results of recently executed queries, keyed by the query content
+struct QueryCache {
+    results: LruCache<QueryCacheKey, (Instant, QueryResult)>,
+    ttl: Duration,
+}
+
+impl QueryCache {
+    pub fn new(config: QueryCacheConfig) -> Self {
+        Self {
+            results: LruCache::new(config.capacity),
+            ttl: config.ttl,
+        }
+    }
+
+    pub fn key(query: &Query) -> QueryCacheKey {
+        QueryCacheKey {
+            dataset: query.dataset.clone(),
+            query: query.query.clone(),
+            profiling: query.profiling,
+            client_state_json: query.client_state_json.clone(),
+        }
+    }
+
+    pub fn get(&mut self, key: &QueryCacheKey, now: Instant) -> Option<QueryResult> {
+        match self.results.get(key) {
+            Some((inserted, result)) if now.duration_since(*inserted) < self.ttl => {
+                Some(result.clone())
+            }
+            Some(_) => {
+                self.results.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: QueryCacheKey, result: QueryResult, now: Instant) {
+        if let Some(query_result::Result::Ok(_)) = result.result {
+            self.results.put(key, (now, result));
+        }
+    }
+}
+
+/// How far in the future a query timestamp may be, to tolerate clock differences
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+const MAX_CACHED_GATEWAYS: usize = 1024;
+
+/// Query waiting for the gateway registration to be confirmed
+type DeferredQuery = (String, Query, Option<ResponseChannel<QueryResult>>);
+
+/// Checks on chain whether gateways sending queries are registered. Lookups run in the
+/// background, queries from gateways which haven't been checked recently wait for them.
+struct GatewayVerifier {
+    client: Arc<dyn contract_client::Client>,
+    // Registration status of recently checked gateways, with the time of the lookup
+    registered: LruCache<PeerId, (Instant, bool)>,
+    ttl: Duration,
+    lookups: FuturesMap<PeerId, Result<bool, ClientError>>,
+    deferred: HashMap<PeerId, Vec<DeferredQuery>>,
+    num_deferred: usize,
+    max_deferred: usize,
+}
+
+impl GatewayVerifier {
+    pub fn new(
+        client: Box<dyn contract_client::Client>,
+        config: GatewayVerificationConfig,
+    ) -> Self {
+        let max_deferred = config.max_deferred_queries.max(1);
+        Self {
+            client: client.into(),
+            registered: LruCache::new(NonZeroUsize::new(MAX_CACHED_GATEWAYS).unwrap()),
+            ttl: config.ttl,
+            lookups: FuturesMap::new(config.lookup_timeout, max_deferred),
+            deferred: Default::default(),
+            num_deferred: 0,
+            max_deferred,
+        }
+    }
+
+    /// Registration status of the gateway, if it has been checked within the TTL
+    pub fn is_registered(&mut self, peer_id: &PeerId, now: Instant) -> Option<bool> {
+        match self.registered.get(peer_id) {
+            Some((checked, registered)) if now.duration_since(*checked) < self.ttl => {
+                Some(*registered)
+            }
+            Some(_) => {
+                self.registered.pop(peer_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Hold the query until the gateway is checked. Returns it back if there are
+    /// too many queries waiting already.
+    pub fn defer(&mut self, peer_id: PeerId, query: DeferredQuery) -> Result<(), DeferredQuery> {
+        if self.num_deferred >= self.max_deferred {
+            return Err(query);
+        }
+        if !self.lookups.contains(peer_id) {
+            let client = self.client.clone();
+            let lookup = async move { client.is_gateway_registered(peer_id).await };
+            if self.lookups.try_push(peer_id, lookup).is_err() {
+                return Err(query);
+            }
+            log::debug!("Checking registration of gateway {peer_id}");
+        }
+        self.deferred.entry(peer_id).or_default().push(query);
+        self.num_deferred += 1;
+        Ok(())
+    }
+
+    pub fn num_deferred(&self) -> usize {
+        self.num_deferred
+    }
+
+    /// Returns the result of a finished lookup (`None` if it failed)
+    /// with the queries which were waiting for it
+    pub fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<(PeerId, Option<bool>, Vec<DeferredQuery>)> {
+        let (peer_id, result) = futures::ready!(self.lookups.poll_unpin(cx));
+        let registered = match result {
+            Ok(Ok(registered)) => {
+                self.registered.put(peer_id, (Instant::now(), registered));
+                Some(registered)
+            }
+            Ok(Err(e)) => {
+                log::warn!("Cannot check registration of gateway {peer_id}: {e:?}");
+                None
+            }
+            Err(_) => {
+                log::warn!("Checking registration of gateway {peer_id} timed out");
+                None
+            }
+        };
+        let queries = self.deferred.remove(&peer_id).unwrap_or_default();
+        self.num_deferred -= queries.len();
+        Poll::Ready((peer_id, registered, queries))
+    }
+}
+
+/// Check the query timestamp (in milliseconds since the UNIX epoch) against the current time
+fn check_query_age(
+    timestamp_ms: Option<u64>,
+    max_age: Duration,
+    now: SystemTime,
+) -> Result<(), String> {
+    let Some(timestamp_ms) = timestamp_ms else {
+        return Ok(());
+    };
+    let timestamp = UNIX_EPOCH + Duration::from_millis(timestamp_ms);
+    match now.duration_since(timestamp) {
+        Ok(age) if age > max_age => Err(format!(
+            "Query expired: {}ms old, max age is {}ms",
+            age.as_millis(),
+            max_age.as_millis()
+        )),
+        Ok(_) => Ok(()),
+        Err(e) if e.duration() > MAX_CLOCK_SKEW => {
+            Err(format!("Query timestamp is {}ms in the future", e.duration().as_millis()))
         }
+        Err(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogsCollectorStats {
+    /// Number of `LogsCollected` messages received from the collector
+    pub acks: u64,
+    /// Last sequence number of the local worker's logs saved by the collector
+    pub last_seq_no: Option<u64>,
+}
+
+/// Logs collectors the worker accepts acknowledgements from. Acknowledgements from
+/// the primary collector are always reported. Backup collectors are only reported when
+/// they get ahead of everything reported so far, i.e. when the primary one is lagging or down.
+struct LogsCollectors {
+    primary: PeerId,
+    stats: HashMap<PeerId, LogsCollectorStats>,
+    reported_seq_no: Option<u64>,
+}
+
+impl LogsCollectors {
+    pub fn new(primary: PeerId, backups: impl IntoIterator<Item = PeerId>) -> Self {
+        let stats = std::iter::once(primary)
+            .chain(backups)
+            .map(|peer_id| (peer_id, Default::default()))
+            .collect();
+        Self {
+            primary,
+            stats,
+            reported_seq_no: None,
+        }
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.stats.keys().copied()
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.stats.contains_key(peer_id)
+    }
+
+    /// Record acknowledgement from the collector and decide whether it should be reported
+    pub fn on_ack(&mut self, peer_id: PeerId, last_seq_no: Option<u64>) -> bool {
+        let Some(stats) = self.stats.get_mut(&peer_id) else {
+            return false;
+        };
+        stats.acks += 1;
+        stats.last_seq_no = last_seq_no;
+        let report = peer_id == self.primary || last_seq_no > self.reported_seq_no;
+        if report {
+            self.reported_seq_no = self.reported_seq_no.max(last_seq_no);
+        }
+        report
+    }
+}
+
+/// The most recent logs sent by the worker, to re-send them to logs collectors which
+/// have missed them. Logs are assumed to come in the order of their sequence numbers.
+struct RetainedLogs {
+    logs: VecDeque<QueryExecuted>,
+    capacity: usize,
+    // The highest sequence number of the logs evicted so far
+    evicted_seq_no: Option<u64>,
+}
+
+impl RetainedLogs {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            logs: Default::default(),
+            capacity,
+            evicted_seq_no: None,
+        }
+    }
+
+    pub fn extend(&mut self, logs: impl IntoIterator<Item = QueryExecuted>) {
+        for log in logs {
+            self.logs.push_back(log);
+            while self.logs.len() > self.capacity {
+                let evicted = self.logs.pop_front().expect("logs are not empty");
+                self.evicted_seq_no = self.evicted_seq_no.max(evicted.seq_no);
+            }
+        }
+    }
+
+    /// Signed logs starting from `from_seq_no`, as many as fit into `max_size` bytes
+    pub fn backfill(
+        &self,
+        from_seq_no: u64,
+        max_size: usize,
+        sign: impl Fn(&mut QueryExecuted),
+    ) -> LogsBackfillResponse {
+        let gap = self.evicted_seq_no.is_some_and(|seq_no| seq_no >= from_seq_no);
+        let mut response = LogsBackfillResponse {
+            queries_executed: vec![],
+            gap,
+        };
+        let logs = self.logs.iter().filter(|log| log.seq_no.is_some_and(|n| n >= from_seq_no));
+        for log in logs {
+            let mut log = log.clone();
+            sign(&mut log);
+            response.queries_executed.push(log);
+            if response.encoded_len() > max_size {
+                response.queries_executed.pop();
+                break;
+            }
+        }
+        response
     }
 }
 
@@ -86,21 +647,49 @@ pub struct WorkerBehaviour {
     inner: InnerBehaviour,
     local_peer_id: String,
     scheduler_id: PeerId,
-    logs_collector_id: PeerId,
-    query_response_channels: HashMap<String, ResponseChannel<QueryResult>>,
+    logs_collectors: LogsCollectors,
+    retained_logs: RetainedLogs,
+    max_query_age: Duration,
+    query_cache: Option<QueryCache>,
+    // Cache keys of the queries being executed (query_id -> key)
+    pending_cache_keys: HashMap<String, QueryCacheKey>,
+    // Trace IDs of the queries being executed which have one (query_id -> trace_id)
+    query_trace_ids: HashMap<String, String>,
+    // Channels to respond to the queries being executed
+    in_flight_queries: InFlightQueries<ResponseChannel<QueryResult>>,
+    gateway_verifier: Option<GatewayVerifier>,
+    // The last pong received from the scheduler before it completed the handshake
+    unauthenticated_pong: Option<Request<Pong, u32>>,
+    rotation: Arc<watch::Sender<RotationState>>,
 }
 
 impl WorkerBehaviour {
+    /// `contract_client` is only used to verify gateways, and is required if
+    /// [`WorkerConfig::gateway_verification`] is set. Panics if it's missing then.
     pub fn new(
         mut base: BaseBehaviour,
         local_peer_id: PeerId,
+        contract_client: Option<Box<dyn contract_client::Client>>,
         config: WorkerConfig,
     ) -> Wrapped<Self> {
         base.subscribe_pings();
         base.subscribe_worker_logs();
         base.subscribe_logs_collected();
-        base.allow_peer(config.logs_collector_id);
+        let logs_collectors =
+            LogsCollectors::new(config.logs_collector_id, config.backup_logs_collector_ids);
+        for collector_id in logs_collectors.ids() {
+            base.allow_peer(collector_id);
+            base.protect_peer(collector_id);
+        }
         base.allow_peer(config.scheduler_id);
+        base.protect_peer(config.scheduler_id);
+        base.set_role(NodeRole::Worker);
+        base.trust_role(config.scheduler_id, NodeRole::Scheduler);
+        let mut query_protocols = vec![QUERY_PROTOCOL, QUERY_PROTOCOL_COMPRESSED];
+        if config.accept_json_queries {
+            query_protocols.push(QUERY_PROTOCOL_JSON);
+        }
+        let substream_rates = base.substream_rates();
         Self {
             inner: InnerBehaviour {
                 base: base.into(),
@@ -108,17 +697,55 @@ impl WorkerBehaviour {
                     ProtoCodec::new(config.max_pong_size, ACK_SIZE),
                     PONG_PROTOCOL,
                 )
+                .with_substream_rates(substream_rates.clone())
+                .into(),
+                query: ServerBehaviour::with_config(
+                    ServerCodec::new(config.max_query_size, config.max_query_result_size)
+                        .with_compression_threshold(config.compress_results_over),
+                    query_protocols,
+                    config.query_server,
+                )
+                .with_timeout_response(|query| {
+                    // The query ID is unknown for queries which haven't been read
+                    let query_id =
+                        query.as_ref().ok().and_then(|q| q.query_id.clone()).unwrap_or_default();
+                    QueryResult::new(query_id, query_result::Result::Timeout(()))
+                })
+                .with_substream_rates(substream_rates.clone())
                 .into(),
-                query: ServerBehaviour::new(
-                    ProtoCodec::new(config.max_query_size, config.max_query_result_size),
-                    QUERY_PROTOCOL,
+                query_result: config
+                    .query_result_redelivery
+                    .map(|client_config| {
+                        Wrapped::from(ClientBehaviour::new(
+                            ProtoCodec::new(config.max_query_result_size, ACK_SIZE),
+                            QUERY_RESULT_PROTOCOL,
+                            client_config,
+                        ))
+                    })
+                    .into(),
+                logs_backfill: ServerBehaviour::new(
+                    ProtoCodec::new(MAX_LOGS_BACKFILL_SIZE, MAX_LOGS_BACKFILL_SIZE),
+                    LOGS_BACKFILL_PROTOCOL,
                 )
+                .with_substream_rates(substream_rates)
                 .into(),
             },
             local_peer_id: local_peer_id.to_base58(),
             scheduler_id: config.scheduler_id,
-            logs_collector_id: config.logs_collector_id,
-            query_response_channels: Default::default(),
+            logs_collectors,
+            retained_logs: RetainedLogs::new(config.retained_logs),
+            max_query_age: config.max_query_age,
+            query_cache: config.query_cache.map(QueryCache::new),
+            pending_cache_keys: Default::default(),
+            query_trace_ids: Default::default(),
+            in_flight_queries: InFlightQueries::new(config.duplicate_queries),
+            gateway_verifier: config.gateway_verification.map(|config| {
+                let client =
+                    contract_client.expect("gateway verification requires a contract client");
+                GatewayVerifier::new(client, config)
+            }),
+            unauthenticated_pong: None,
+            rotation: Arc::new(watch::channel(RotationState::Running).0),
         }
         .into()
     }
@@ -128,7 +755,17 @@ impl WorkerBehaviour {
             BaseBehaviourEvent::LogsCollected {
                 peer_id,
                 logs_collected,
-            } => self.on_logs_collected(peer_id, logs_collected),
+                msg,
+            } => {
+                let ev = self.on_logs_collected(peer_id, logs_collected)?;
+                self.inner.base.route_to_topic_stream(&msg, ev)
+            }
+            BaseBehaviourEvent::PeerAuthenticated {
+                peer_id,
+                role: NodeRole::Scheduler,
+            } if peer_id == self.scheduler_id => {
+                self.unauthenticated_pong.take().and_then(|pong| self.on_pong_event(pong))
+            }
             _ => None,
         }
     }
@@ -138,14 +775,16 @@ impl WorkerBehaviour {
         peer_id: PeerId,
         mut logs_collected: LogsCollected,
     ) -> Option<WorkerEvent> {
-        if peer_id != self.logs_collector_id {
+        if !self.logs_collectors.contains(&peer_id) {
             log::warn!("Peer {peer_id} impersonating logs collector");
             return None;
         }
-        log::debug!("Received logs collected message");
+        log::debug!("Received logs collected message from {peer_id}");
         // Extract last_seq_no for the local worker
         let last_seq_no = logs_collected.sequence_numbers.remove(&self.local_peer_id);
-        Some(WorkerEvent::LogsCollected { last_seq_no })
+        self.logs_collectors
+            .on_ack(peer_id, last_seq_no)
+            .then_some(WorkerEvent::LogsCollected { last_seq_no })
     }
 
     fn on_query(
@@ -167,48 +806,365 @@ impl WorkerBehaviour {
                 return None;
             }
         };
-        log::debug!("Query {query_id} verified");
+        match &query.trace_id {
+            Some(trace_id) => log::debug!("Query {query_id} verified, trace ID {trace_id}"),
+            None => log::debug!("Query {query_id} verified"),
+        }
+        if let Some(timestamp_ms) = query.timestamp_ms {
+            self.inner.base.record_peer_timestamp(peer_id, timestamp_ms);
+        }
+        if self.is_draining() {
+            log::debug!("Rejecting query {query_id} from {peer_id}: preparing for key rotation");
+            if let Some(resp_chan) = resp_chan {
+                let result =
+                    QueryResult::new(query_id, query_result::Result::Overloaded(())).traced(&query);
+                _ = self.inner.query.try_send_response(resp_chan, result);
+            }
+            return None;
+        }
+        if let Err(e) = check_query_age(query.timestamp_ms, self.max_query_age, SystemTime::now()) {
+            log::warn!("Rejecting query {query_id} from {peer_id}: {e}");
+            if let Some(resp_chan) = resp_chan {
+                let result =
+                    QueryResult::new(query_id, query_result::Result::BadRequest(e)).traced(&query);
+                _ = self.inner.query.try_send_response(resp_chan, result);
+            }
+            return None;
+        }
+        if let Some(verifier) = &mut self.gateway_verifier {
+            match verifier.is_registered(&peer_id, Instant::now()) {
+                Some(true) => {}
+                Some(false) => {
+                    self.reject_unregistered(peer_id, query_id, resp_chan);
+                    return None;
+                }
+                None => {
+                    if let Err((query_id, _, resp_chan)) =
+                        verifier.defer(peer_id, (query_id, query, resp_chan))
+                    {
+                        log::warn!(
+                            "Rejecting query {query_id} from {peer_id}: verification queue full"
+                        );
+                        self.respond(resp_chan, query_id, query_result::Result::Overloaded(()));
+                    }
+                    return None;
+                }
+            }
+        }
+        self.accept_query(peer_id, query_id, query, resp_chan)
+    }
+
+    fn accept_query(
+        &mut self,
+        peer_id: PeerId,
+        query_id: String,
+        query: Query,
+        resp_chan: Option<ResponseChannel<QueryResult>>,
+    ) -> Option<WorkerEvent> {
+        let mut cache_key = None;
+        let mut cached = None;
+        if let Some(cache) = &mut self.query_cache {
+            let key = QueryCache::key(&query);
+            match cache.get(&key, Instant::now()) {
+                Some(mut result) => {
+                    log::debug!("Query {query_id} found in cache");
+                    #[cfg(feature = "metrics")]
+                    QUERY_CACHE_HITS.inc();
+                    result.query_id = query_id.clone();
+                    // Nothing has been executed for this query
+                    result.stats = None;
+                    cached = Some(result.traced(&query));
+                }
+                None => {
+                    #[cfg(feature = "metrics")]
+                    QUERY_CACHE_MISSES.inc();
+                    cache_key = Some(key);
+                }
+            }
+        }
         if let Some(resp_chan) = resp_chan {
-            self.query_response_channels.insert(query_id, resp_chan);
+            let now = Instant::now();
+            match self.in_flight_queries.insert(query_id.clone(), peer_id, resp_chan, now) {
+                Admission::Execute => {}
+                Admission::Attached => {
+                    log::debug!("Query {query_id} from {peer_id} already in progress");
+                    return None;
+                }
+                Admission::Rejected(resp_chan) => {
+                    log::warn!("Rejecting query {query_id} from {peer_id}: duplicate query ID");
+                    let error = "Query with the same ID is in progress".to_string();
+                    self.respond(
+                        Some(resp_chan),
+                        query_id,
+                        query_result::Result::BadRequest(error),
+                    );
+                    return None;
+                }
+            }
+        }
+        if let Some(trace_id) = &query.trace_id {
+            self.query_trace_ids.insert(query_id.clone(), trace_id.clone());
+        }
+        // The application still checks and logs cache hits, and sends the cached result
+        if let Some(result) = cached {
+            return Some(WorkerEvent::CachedQuery {
+                peer_id,
+                query,
+                result,
+            });
+        }
+        if let Some(key) = cache_key {
+            self.pending_cache_keys.insert(query_id, key);
         }
         Some(WorkerEvent::Query { peer_id, query })
     }
 
-    fn on_pong_event(
+    fn on_gateway_verified(
         &mut self,
-        Request {
-            peer_id,
-            request,
-            response_channel,
-        }: Request<Pong, u32>,
+        peer_id: PeerId,
+        registered: Option<bool>,
+        (query_id, query, resp_chan): DeferredQuery,
     ) -> Option<WorkerEvent> {
+        match registered {
+            _ if self.is_draining() => {
+                log::debug!(
+                    "Rejecting query {query_id} from {peer_id}: preparing for key rotation"
+                );
+                self.respond(resp_chan, query_id, query_result::Result::Overloaded(()));
+                None
+            }
+            Some(true) => self.accept_query(peer_id, query_id, query, resp_chan),
+            Some(false) => {
+                self.reject_unregistered(peer_id, query_id, resp_chan);
+                None
+            }
+            None => {
+                let error = "Cannot verify gateway registration".to_owned();
+                self.respond(resp_chan, query_id, query_result::Result::ServerError(error));
+                None
+            }
+        }
+    }
+
+    fn reject_unregistered(
+        &mut self,
+        peer_id: PeerId,
+        query_id: String,
+        resp_chan: Option<ResponseChannel<QueryResult>>,
+    ) {
+        log::warn!("Rejecting query {query_id} from {peer_id}: gateway not registered");
+        #[cfg(feature = "metrics")]
+        UNREGISTERED_GATEWAY_QUERIES.inc();
+        let error = "Gateway not registered".to_owned();
+        self.respond(resp_chan, query_id, query_result::Result::BadRequest(error));
+    }
+
+    fn respond(
+        &mut self,
+        resp_chan: Option<ResponseChannel<QueryResult>>,
+        query_id: String,
+        result: query_result::Result,
+    ) {
+        if let Some(resp_chan) = resp_chan {
+            _ = self
+                .inner
+                .query
+                .try_send_response(resp_chan, QueryResult::new(query_id, result));
+        }
+    }
+
+    fn on_query_too_large(
+        &mut self,
+        peer_id: PeerId,
+        error: PayloadTooLarge,
+        resp_chan: ResponseChannel<QueryResult>,
+    ) -> Option<WorkerEvent> {
+        log::warn!("Rejecting query from {peer_id}: {error}");
+        // The query hasn't been read, so its ID is unknown. The gateway matches the result
+        // to the query by the request.
+        let result =
+            QueryResult::new(String::new(), query_result::Result::BadRequest(error.to_string()));
+        _ = self.inner.query.try_send_response(resp_chan, result);
+        None
+    }
+
+    fn on_pong_event(&mut self, pong: Request<Pong, u32>) -> Option<WorkerEvent> {
+        let peer_id = pong.peer_id;
         if peer_id != self.scheduler_id {
             log::warn!("Peer {peer_id} impersonating scheduler");
             return None;
         }
+        let base = &self.inner.base;
+        if base.handshake_enabled() && base.peer_role(&peer_id) != Some(NodeRole::Scheduler) {
+            // Processed once the scheduler proves its role
+            log::debug!("Received pong from scheduler before the handshake completed");
+            self.unauthenticated_pong = Some(pong);
+            return None;
+        }
+        let Request {
+            request,
+            response_channel,
+            ..
+        } = pong;
         log::debug!("Received pong from scheduler: {request:?}");
         // Send minimal response to avoid getting errors
         _ = self.inner.pong.try_send_response(response_channel, 1);
         Some(WorkerEvent::Pong(request))
     }
 
+    fn on_logs_backfill_request(
+        &mut self,
+        Request {
+            peer_id,
+            request,
+            response_channel,
+        }: Request<LogsBackfillRequest, LogsBackfillResponse>,
+    ) -> Option<WorkerEvent> {
+        if !self.logs_collectors.contains(&peer_id) {
+            log::warn!("Peer {peer_id} impersonating logs collector");
+            return None;
+        }
+        let base = &self.inner.base;
+        let response = self.retained_logs.backfill(
+            request.from_seq_no,
+            usize::try_from(MAX_LOGS_BACKFILL_SIZE).unwrap_or(usize::MAX),
+            |log| base.sign(log),
+        );
+        log::debug!(
+            "Re-sending {} logs from seq no {} to {peer_id} (gap: {})",
+            response.queries_executed.len(),
+            request.from_seq_no,
+            response.gap
+        );
+        _ = self.inner.logs_backfill.try_send_response(response_channel, response);
+        None
+    }
+
     pub fn send_ping(&mut self, ping: Ping) {
+        if self.is_draining() {
+            return log::debug!("Not publishing ping while preparing for key rotation");
+        }
         self.inner.base.publish_ping(ping);
     }
 
-    pub fn send_query_result(&mut self, result: QueryResult) {
+    pub fn send_query_result(&mut self, mut result: QueryResult) {
+        // Results carry the trace ID of their query, unless the application has set one
+        if let Some(trace_id) = self.query_trace_ids.remove(&result.query_id) {
+            result.trace_id.get_or_insert(trace_id);
+        }
+        // Stats are reported by the application. If they lack the execution time,
+        // it's measured from when the query was received.
+        if let Some(stats) = &mut result.stats {
+            if let Some(received) = self.in_flight_queries.received(&result.query_id) {
+                let elapsed = received.elapsed().as_millis();
+                stats.exec_time_ms.get_or_insert(elapsed.try_into().unwrap_or(u32::MAX));
+            }
+        }
         log::debug!("Sending query result {result:?}");
-        let Some(resp_chan) = self.query_response_channels.remove(&result.query_id) else {
+        if let Some(key) = self.pending_cache_keys.remove(&result.query_id) {
+            if let Some(cache) = &mut self.query_cache {
+                cache.insert(key, result.clone(), Instant::now());
+            }
+        }
+        let Some(mut channels) = self.in_flight_queries.on_result(&result.query_id) else {
             return log::error!("No response channel for query: {}", result.query_id);
         };
-        self.inner
-            .query
-            .try_send_response(resp_chan, result)
-            .unwrap_or_else(|e| log::error!("Cannot send result for query {}", e.query_id));
+        // Duplicates of the query get a copy of the result
+        let Some((peer_id, resp_chan)) = channels.pop() else {
+            return log::debug!("Query {} already answered by another execution", result.query_id);
+        };
+        for (peer_id, resp_chan) in channels {
+            self.send_result_to(peer_id, resp_chan, result.clone());
+        }
+        self.send_result_to(peer_id, resp_chan, result);
+    }
+
+    fn send_result_to(
+        &mut self,
+        peer_id: PeerId,
+        resp_chan: ResponseChannel<QueryResult>,
+        result: QueryResult,
+    ) {
+        // The channel is closed if the connection the query arrived on has been closed
+        let result = if resp_chan.is_open() {
+            match self.inner.query.try_send_response(resp_chan, result) {
+                Ok(()) => return,
+                Err(result) => result,
+            }
+        } else {
+            result
+        };
+        self.redeliver_query_result(peer_id, result);
+    }
+
+    fn redeliver_query_result(&mut self, peer_id: PeerId, result: QueryResult) {
+        let query_id = result.query_id.clone();
+        let Some(client) = self.inner.query_result.as_mut() else {
+            return log::error!("Cannot send result for query {query_id}: connection closed");
+        };
+        log::debug!("Connection closed, re-sending result for query {query_id} to {peer_id}");
+        if client.try_send_request(peer_id, result).is_err() {
+            log::error!("Cannot re-send result for query {query_id}: outbound queue full");
+        }
+    }
+
+    fn on_query_result_event(&mut self, ev: ClientEvent<u32>) -> Option<WorkerEvent> {
+        match ev {
+            ClientEvent::PeerUnknown { peer_id } => self.inner.base.find_and_dial(peer_id),
+            ClientEvent::Timeout { peer_id, .. } => {
+                log::warn!("Re-sending query result to {peer_id} timed out")
+            }
+            ClientEvent::UnsupportedProtocol { peer_id, .. } => {
+                log::warn!("Gateway {peer_id} doesn't accept re-sent query results")
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Number of query results queued for sending but not sent out yet
+    pub fn pending_query_results(&self) -> usize {
+        self.inner.query.pending_responses()
+    }
+
+    /// Shared state of the key rotation, see [`WorkerTransportHandle::prepare_rotation`]
+    pub fn rotation(&self) -> Arc<watch::Sender<RotationState>> {
+        self.rotation.clone()
+    }
+
+    fn is_draining(&self) -> bool {
+        *self.rotation.borrow() != RotationState::Running
+    }
+
+    /// Stop advertising the node, so that it can be replaced by one with a new key
+    pub fn start_draining(&mut self) {
+        log::info!("Preparing for key rotation, new queries will be rejected");
+        self.inner.base.stop_advertising();
+        self.update_drained();
+    }
+
+    /// Mark the rotation as drained once all the accepted queries have been answered
+    pub fn update_drained(&mut self) {
+        let deferred = self.gateway_verifier.as_ref().map_or(0, GatewayVerifier::num_deferred);
+        let in_flight = self.in_flight_queries.len() + self.pending_query_results() + deferred;
+        self.rotation.send_if_modified(|state| {
+            let drained = *state == RotationState::Draining && in_flight == 0;
+            if drained {
+                log::info!("All queries answered, ready for key rotation");
+                *state = RotationState::Drained;
+            }
+            drained
+        });
+    }
+
+    /// Delivery stats of each configured logs collector
+    pub fn logs_collector_stats(&self) -> impl Iterator<Item = (PeerId, LogsCollectorStats)> + '_ {
+        self.logs_collectors.stats.iter().map(|(peer_id, stats)| (*peer_id, *stats))
     }
 
     pub fn send_logs(&mut self, logs: Vec<QueryExecuted>) {
         log::debug!("Sending query logs");
+        self.retained_logs.extend(logs.iter().cloned());
         self.inner.base.publish_worker_logs(logs);
     }
 }
@@ -228,22 +1184,121 @@ impl BehaviourWrapper for WorkerBehaviour {
         let ev = match ev {
             InnerBehaviourEvent::Base(ev) => self.on_base_event(ev),
             InnerBehaviourEvent::Pong(ev) => self.on_pong_event(ev),
+            InnerBehaviourEvent::LogsBackfill(ev) => self.on_logs_backfill_request(ev),
             InnerBehaviourEvent::Query(Request {
                 peer_id,
-                request,
+                request: Ok(query),
                 response_channel,
-            }) => self.on_query(peer_id, request, Some(response_channel)),
+            }) => self.on_query(peer_id, query, Some(response_channel)),
+            InnerBehaviourEvent::Query(Request {
+                peer_id,
+                request: Err(e),
+                response_channel,
+            }) => self.on_query_too_large(peer_id, e, response_channel),
+            InnerBehaviourEvent::QueryResult(ev) => self.on_query_result_event(ev),
         };
         ev.map(ToSwarm::GenerateEvent)
     }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
+        let Some(verifier) = &mut self.gateway_verifier else {
+            return Poll::Pending;
+        };
+        let (peer_id, registered, queries) = futures::ready!(verifier.poll(cx));
+        let events: Vec<_> = queries
+            .into_iter()
+            .filter_map(|query| self.on_gateway_verified(peer_id, registered, query))
+            .map(ToSwarm::GenerateEvent)
+            .collect();
+        Poll::Ready(events)
+    }
+}
+
+/// When to publish the next ping if the ping interval is configured
+struct PingSchedule {
+    interval: Duration,
+    min_interval: Duration,
+    // Published ping and whether the transport marked it as overloaded
+    last_ping: Option<(Instant, Ping, bool)>,
+    next: Instant,
+}
+
+impl PingSchedule {
+    pub fn new(interval: Duration, min_interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            min_interval,
+            last_ping: None,
+            next: now + interval,
+        }
+    }
+
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+
+    /// A new ping has been set, publish it early if the datasets or the overload status
+    /// differ from the last published ping
+    pub fn on_ping_set(&mut self, ping: &Ping, now: Instant) {
+        let changed = match &self.last_ping {
+            None => true,
+            Some((_, last, _)) => {
+                last.stored_ranges != ping.stored_ranges || last.overloaded != ping.overloaded
+            }
+        };
+        if changed {
+            self.publish_early(now);
+        }
+    }
+
+    /// Publish early if the transport started or stopped rejecting queries
+    pub fn on_overload(&mut self, overloaded: bool, now: Instant) {
+        if self.last_ping.as_ref().is_some_and(|(_, _, last)| *last != overloaded) {
+            self.publish_early(now);
+        }
+    }
+
+    pub fn on_published(&mut self, ping: Ping, overloaded: bool, now: Instant) {
+        self.last_ping = Some((now, ping, overloaded));
+        self.next = now + self.interval;
+    }
+
+    pub fn on_skipped(&mut self, now: Instant) {
+        self.next = now + self.interval;
+    }
+
+    fn publish_early(&mut self, now: Instant) {
+        let earliest = match &self.last_ping {
+            Some((published, ..)) => now.max(*published + self.min_interval),
+            None => now,
+        };
+        self.next = self.next.min(earliest);
+    }
+}
+
+async fn next_scheduled_ping(schedule: &Option<PingSchedule>) {
+    match schedule {
+        Some(schedule) => tokio::time::sleep_until(schedule.next()).await,
+        None => futures::future::pending().await,
+    }
 }
 
 struct WorkerTransport {
     swarm: Swarm<Wrapped<WorkerBehaviour>>,
-    pings_rx: Receiver<Ping>,
+    pings_rx: watch::Receiver<Option<Ping>>,
+    // Pings are published as soon as they're set if `None`
+    ping_schedule: Option<PingSchedule>,
     query_results_rx: Receiver<QueryResult>,
     logs_rx: Receiver<Vec<QueryExecuted>>,
+    disconnects_rx: Receiver<PeerId>,
+    dataset_ads_rx: Receiver<DatasetRanges>,
+    boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    rotation_rx: watch::Receiver<RotationState>,
     events_tx: Sender<WorkerEvent>,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    pause_rx: PauseReceiver,
+    query_priority_batch: usize,
+    shutdown_timeout: Duration,
 }
 
 impl WorkerTransport {
@@ -252,53 +1307,205 @@ impl WorkerTransport {
         loop {
             tokio::select! {
                  _ = cancel_token.cancelled() => break,
-                ev = self.swarm.select_next_some() => self.on_swarm_event(ev),
-                Some(ping) = self.pings_rx.recv() => self.swarm.behaviour_mut().send_ping(ping),
+                _ = self.events_tx.closed() => {
+                    log::info!("Event stream dropped by the consumer");
+                    break;
+                }
+                ev = self.swarm.select_next_some() => self.on_swarm_events(ev),
+                Ok(()) = self.pings_rx.changed(), if !self.pause_rx.buffering() => {
+                    self.on_ping_set()
+                }
+                _ = next_scheduled_ping(&self.ping_schedule), if !self.pause_rx.buffering() => {
+                    self.send_latest_ping()
+                }
                 Some(res) = self.query_results_rx.recv() => self.swarm.behaviour_mut().send_query_result(res),
-                Some(logs) = self.logs_rx.recv() => self.swarm.behaviour_mut().send_logs(logs),
+                Some(logs) = self.logs_rx.recv(), if !self.pause_rx.buffering() => {
+                    if !self.pause_rx.rejects("logs") {
+                        self.swarm.behaviour_mut().send_logs(logs)
+                    }
+                }
+                Some(peer_id) = self.disconnects_rx.recv() => {
+                    disconnect_peer(&mut self.swarm, peer_id)
+                }
+                Some(ranges) = self.dataset_ads_rx.recv() => {
+                    self.swarm.behaviour_mut().inner.base.advertise_dataset(ranges)
+                }
+                Some(update) = self.boot_node_updates_rx.recv() => {
+                    update_boot_nodes(&mut self.swarm, update, |b| &mut b.inner.base)
+                }
+                ev = self.pause_rx.next_event(
+                    |mode| WorkerEvent::Paused { mode },
+                    WorkerEvent::Resumed,
+                ) => self.events_tx.send_lossy(ev),
+                Ok(()) = self.rotation_rx.changed() => self.on_rotation_update(),
+            }
+            self.swarm.behaviour_mut().update_drained();
+            if let Some(schedule) = &mut self.ping_schedule {
+                schedule.on_overload(self.events_tx.is_full(), Instant::now());
             }
         }
         log::info!("Shutting down worker P2P transport");
+        self.drain().await;
+    }
+
+    /// Send out query results and logs that have been queued before the shutdown,
+    /// so that already computed results reach the gateways.
+    async fn drain(&mut self) {
+        self.query_results_rx.close();
+        self.logs_rx.close();
+        while let Some(res) = self.query_results_rx.try_recv() {
+            self.swarm.behaviour_mut().send_query_result(res);
+        }
+        while let Some(logs) = self.logs_rx.try_recv() {
+            self.swarm.behaviour_mut().send_logs(logs);
+        }
+        let flush = async {
+            while self.swarm.behaviour().pending_query_results() > 0 {
+                let ev = self.swarm.select_next_some().await;
+                self.on_swarm_events(ev);
+            }
+        };
+        // The task manager aborts the task after the shutdown timeout. Stop flushing earlier,
+        // so that the undelivered results are logged.
+        let budget = self.shutdown_timeout * 3 / 4;
+        if tokio::time::timeout(budget, flush).await.is_err() {
+            log::warn!(
+                "{} query results not sent before shutdown",
+                self.swarm.behaviour().pending_query_results()
+            );
+        }
+    }
+
+    fn on_ping_set(&mut self) {
+        match &mut self.ping_schedule {
+            None => self.send_latest_ping(),
+            Some(schedule) => {
+                if let Some(ping) = self.pings_rx.borrow_and_update().as_ref() {
+                    schedule.on_ping_set(ping, Instant::now());
+                }
+            }
+        }
+    }
+
+    fn send_latest_ping(&mut self) {
+        let now = Instant::now();
+        // Pings set while the swarm was busy are coalesced, only the latest one is published
+        let ping = self.pings_rx.borrow_and_update().clone();
+        let Some(mut ping) = ping.filter(|_| !self.pause_rx.rejects("ping")) else {
+            if let Some(schedule) = &mut self.ping_schedule {
+                schedule.on_skipped(now);
+            }
+            return;
+        };
+        // Queries are being rejected if the worker doesn't keep up with the events
+        let overloaded = self.events_tx.is_full();
+        if let Some(schedule) = &mut self.ping_schedule {
+            schedule.on_published(ping.clone(), overloaded, now);
+        }
+        if overloaded {
+            ping.overloaded = Some(true);
+        }
+        self.swarm.behaviour_mut().send_ping(ping);
+    }
+
+    fn on_rotation_update(&mut self) {
+        if *self.rotation_rx.borrow_and_update() == RotationState::Draining {
+            self.swarm.behaviour_mut().start_draining();
+        }
+    }
+
+    fn on_swarm_events(&mut self, first: SwarmEvent<WorkerEvent>) {
+        if self.query_priority_batch == 0 {
+            return self.on_swarm_event(first);
+        }
+        // Drain events which are already available and handle queries before the rest
+        let mut events = vec![first];
+        while events.len() < self.query_priority_batch {
+            match self.swarm.next().now_or_never() {
+                Some(Some(ev)) => events.push(ev),
+                _ => break,
+            }
+        }
+        let (queries, others): (Vec<_>, Vec<_>) = events
+            .into_iter()
+            .partition(|ev| matches!(ev, SwarmEvent::Behaviour(WorkerEvent::Query { .. })));
+        for ev in queries.into_iter().chain(others) {
+            self.on_swarm_event(ev);
+        }
     }
 
     fn on_swarm_event(&mut self, ev: SwarmEvent<WorkerEvent>) {
         log::trace!("Swarm event: {ev:?}");
         record_event(&ev);
-        if let SwarmEvent::Behaviour(ev) = ev {
-            self.events_tx.send_lossy(ev)
+        tap_event(&self.raw_events_tx, &ev);
+        match ev {
+            SwarmEvent::Behaviour(WorkerEvent::Query { peer_id, query }) => {
+                self.forward_query(peer_id, query)
+            }
+            SwarmEvent::Behaviour(ev) => self.events_tx.send_lossy(ev),
+            _ => {}
+        }
+    }
+
+    /// Pass the query to the worker, or tell the gateway right away that the worker
+    /// is overloaded if the events queue is full, so it can retry with another worker.
+    fn forward_query(&mut self, peer_id: PeerId, query: Query) {
+        let query_id = query.query_id.clone().unwrap_or_default();
+        if self.events_tx.try_send(WorkerEvent::Query { peer_id, query }).is_err() {
+            log::warn!("Events queue full. Rejecting query {query_id} from {peer_id}");
+            let result = QueryResult::new(query_id, query_result::Result::Overloaded(()));
+            self.swarm.behaviour_mut().send_query_result(result);
         }
     }
 }
 
 #[derive(Clone)]
 pub struct WorkerTransportHandle {
-    pings_tx: Sender<Ping>,
+    pings_tx: Arc<watch::Sender<Option<Ping>>>,
     query_results_tx: Sender<QueryResult>,
     logs_tx: Sender<Vec<QueryExecuted>>,
+    dataset_ads_tx: Sender<DatasetRanges>,
+    rotation: Arc<watch::Sender<RotationState>>,
+    base: BaseTransportHandle,
     _task_manager: Arc<TaskManager>, // This ensures that transport is stopped when the last handle is dropped
 }
 
 impl WorkerTransportHandle {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        pings_tx: Sender<Ping>,
+        pings_tx: Arc<watch::Sender<Option<Ping>>>,
         query_results_tx: Sender<QueryResult>,
         logs_tx: Sender<Vec<QueryExecuted>>,
+        disconnects_tx: Sender<PeerId>,
+        dataset_ads_tx: Sender<DatasetRanges>,
+        boot_node_updates_tx: Sender<BootNodeUpdate>,
         transport: WorkerTransport,
         shutdown_timeout: Duration,
     ) -> Self {
+        let rotation = transport.swarm.behaviour().rotation();
+        let base = BaseTransportHandle::new(
+            &transport.swarm.behaviour().inner.base,
+            disconnects_tx,
+            boot_node_updates_tx,
+        );
         let mut task_manager = TaskManager::new(shutdown_timeout);
         task_manager.spawn(|c| transport.run(c));
         Self {
             pings_tx,
             query_results_tx,
             logs_tx,
+            dataset_ads_tx,
+            rotation,
+            base,
             _task_manager: Arc::new(task_manager),
         }
     }
 
-    pub fn send_ping(&self, ping: Ping) -> Result<(), QueueFull> {
+    /// Set the ping to be published next. Pings are snapshots of the worker state, so only
+    /// the latest one is kept: if a previous ping hasn't been published yet, it's dropped.
+    pub fn send_ping(&self, ping: Ping) {
         log::debug!("Queueing ping {ping:?}");
-        self.pings_tx.try_send(ping)
+        self.pings_tx.send_replace(Some(ping));
     }
 
     pub fn send_query_result(&self, result: QueryResult) -> Result<(), QueueFull> {
@@ -310,30 +1517,594 @@ impl WorkerTransportHandle {
         log::debug!("Queueing {} query logs", logs.len());
         self.logs_tx.try_send(logs)
     }
+
+    /// Put a signed record with the ranges of the dataset into the DHT, so that gateways can
+    /// find this worker without waiting for its pings. The record is re-published periodically,
+    /// advertising the dataset again replaces the ranges.
+    pub fn advertise_dataset(&self, ranges: DatasetRanges) -> Result<(), QueueFull> {
+        log::debug!("Queueing advertisement of dataset {}", ranges.url);
+        self.dataset_ads_tx.try_send(ranges)
+    }
+
+    /// Prepare for restarting the worker with a new key. New queries are rejected as overloaded
+    /// (so that gateways retry them with other workers), pings aren't published anymore and
+    /// the node stops serving the DHT. Connections are kept open and the results of accepted
+    /// queries still have to be sent through [`Self::send_query_result`]. Once this returns
+    /// `true`, all of them have been sent out and the node can be stopped, e.g. after the
+    /// new one becomes reachable (see [`BaseTransportHandle::wait_for_peer`]).
+    /// Returns `false` if the queries haven't been answered within `timeout`.
+    pub async fn prepare_rotation(&self, timeout: Duration) -> bool {
+        log::debug!("Requesting key rotation");
+        self.rotation.send_if_modified(|state| {
+            let start = *state == RotationState::Running;
+            if start {
+                *state = RotationState::Draining;
+            }
+            start
+        });
+        let mut rotation = self.rotation.subscribe();
+        let drained = rotation.wait_for(|state| *state == RotationState::Drained);
+        matches!(tokio::time::timeout(timeout, drained).await, Ok(Ok(_)))
+    }
+}
+
+impl Deref for WorkerTransportHandle {
+    type Target = BaseTransportHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
 }
 
 pub fn start_transport(
     swarm: Swarm<Wrapped<WorkerBehaviour>>,
     config: WorkerConfig,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
 ) -> (impl Stream<Item = WorkerEvent>, WorkerTransportHandle) {
-    let (pings_tx, pings_rx) = new_queue(config.pings_queue_size, "pings");
+    let namespace = config.metrics_namespace;
+    let (pings_tx, pings_rx) = watch::channel(None);
     let (query_results_tx, query_results_rx) =
-        new_queue(config.query_results_queue_size, "query_results");
-    let (logs_tx, logs_rx) = new_queue(config.logs_queue_size, "logs");
-    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+        new_actor_queue(config.query_results_queue_size, "query_results", namespace);
+    let (logs_tx, logs_rx) = new_actor_queue(config.logs_queue_size, "logs", namespace);
+    let (disconnects_tx, disconnects_rx) =
+        new_actor_queue(config.disconnects_queue_size, "disconnects", namespace);
+    let (dataset_ads_tx, dataset_ads_rx) =
+        new_actor_queue(config.dataset_ads_queue_size, "dataset_ads", namespace);
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_actor_queue(config.boot_node_updates_queue_size, "boot_node_updates", namespace);
+    let (events_tx, events_rx) = new_actor_queue(config.events_queue_size, "events", namespace);
+    let rotation_rx = swarm.behaviour().rotation().subscribe();
+    let pause_rx = swarm.behaviour().inner.base.pause_state().subscribe();
     let transport = WorkerTransport {
         swarm,
         pings_rx,
+        ping_schedule: config
+            .ping_interval
+            .map(|interval| PingSchedule::new(interval, config.min_ping_interval, Instant::now())),
         query_results_rx,
         logs_rx,
+        disconnects_rx,
+        dataset_ads_rx,
+        boot_node_updates_rx,
+        rotation_rx,
         events_tx,
+        raw_events_tx,
+        pause_rx,
+        query_priority_batch: config.query_priority_batch,
+        shutdown_timeout: config.shutdown_timeout,
     };
     let handle = WorkerTransportHandle::new(
-        pings_tx,
+        Arc::new(pings_tx),
         query_results_tx,
         logs_tx,
+        disconnects_tx,
+        dataset_ads_tx,
+        boot_node_updates_tx,
         transport,
         config.shutdown_timeout,
     );
     (events_rx, handle)
 }
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        identity::Keypair,
+        request_response::{self, ProtocolSupport},
+    };
+
+    use crate::{
+        actors::testing::{new_memory_swarm, new_test_swarm, random_memory_addr},
+        protocol::PING_TOPIC,
+        PublishRetry,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_duplicate_query_policy() {
+        let peer_id = PeerId::random();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let query_id = || "query".to_string();
+
+        let mut queries = InFlightQueries::new(DuplicateQueryPolicy::Reject);
+        assert_eq!(queries.insert(query_id(), peer_id, 1, start), Admission::Execute);
+        assert_eq!(queries.insert(query_id(), peer_id, 2, secs(100)), Admission::Rejected(2));
+        assert_eq!(queries.on_result("query"), Some(vec![(peer_id, 1)]));
+        assert_eq!(queries.insert(query_id(), peer_id, 3, secs(100)), Admission::Execute);
+
+        let policy = DuplicateQueryPolicy::ReplaceIfStale(Duration::from_secs(10));
+        let mut queries = InFlightQueries::new(policy);
+        assert_eq!(queries.insert(query_id(), peer_id, 1, start), Admission::Execute);
+        assert_eq!(queries.insert(query_id(), peer_id, 2, secs(5)), Admission::Rejected(2));
+        assert_eq!(queries.insert(query_id(), peer_id, 3, secs(10)), Admission::Execute);
+        // The staleness is counted from the retry
+        assert_eq!(queries.insert(query_id(), peer_id, 4, secs(15)), Admission::Rejected(4));
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries.on_result("query"), Some(vec![(peer_id, 1), (peer_id, 3)]));
+        // The query is kept until the retry finishes, its result has no one to go to
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries.on_result("query"), Some(vec![]));
+        assert_eq!(queries.len(), 0);
+        assert_eq!(queries.on_result("query"), None);
+
+        let mut queries = InFlightQueries::new(DuplicateQueryPolicy::Dedup);
+        let other_peer = PeerId::random();
+        assert_eq!(queries.insert(query_id(), peer_id, 1, start), Admission::Execute);
+        assert_eq!(queries.insert(query_id(), other_peer, 2, secs(100)), Admission::Attached);
+        assert_eq!(queries.on_result("query"), Some(vec![(peer_id, 1), (other_peer, 2)]));
+        assert_eq!(queries.on_result("query"), None);
+    }
+
+    #[test]
+    fn test_deserialize_old_config() {
+        // Fields of the config before the optional ones were added
+        const OLD_FIELDS: [&str; 10] = [
+            "scheduler_id",
+            "logs_collector_id",
+            "max_pong_size",
+            "max_query_size",
+            "max_query_result_size",
+            "pings_queue_size",
+            "query_results_queue_size",
+            "logs_queue_size",
+            "events_queue_size",
+            "shutdown_timeout",
+        ];
+        let config = WorkerConfig::new(PeerId::random(), PeerId::random());
+        let mut json = serde_json::to_value(&config).unwrap();
+        json.as_object_mut()
+            .unwrap()
+            .retain(|key, _| OLD_FIELDS.contains(&key.as_str()));
+
+        let old: WorkerConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(old.scheduler_id, config.scheduler_id);
+        assert_eq!(old.compress_results_over, config.compress_results_over);
+        assert_eq!(old.retained_logs, config.retained_logs);
+        assert_eq!(old.max_query_age, config.max_query_age);
+        assert_eq!(old.min_ping_interval, config.min_ping_interval);
+        assert_eq!(old.duplicate_queries, config.duplicate_queries);
+        assert!(old.query_result_redelivery.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_results_flushed_on_shutdown() {
+        let gateway_keypair = Keypair::generate_ed25519();
+        let gateway_id = gateway_keypair.public().to_peer_id();
+        let mut config = WorkerConfig::new(PeerId::random(), PeerId::random());
+        config.shutdown_timeout = Duration::from_secs(2);
+        let mut worker = new_test_swarm(|keypair, base| {
+            WorkerBehaviour::new(base, keypair.public().to_peer_id(), None, config.clone())
+        });
+        worker.behaviour_mut().inner.base.allow_peer(gateway_id);
+        let worker_id = *worker.local_peer_id();
+        let worker_addr = random_memory_addr();
+        worker.listen_on(worker_addr.clone()).unwrap();
+        let (events, handle) = start_transport(worker, config, None);
+        let mut events = Box::pin(events);
+
+        // The gateway runs in its own task, so that it keeps reading while the worker stops
+        let mut gateway = new_memory_swarm(
+            gateway_keypair.clone(),
+            request_response::Behaviour::with_codec(
+                ProtoCodec::<Query, QueryResult>::new(MAX_QUERY_SIZE, MAX_QUERY_RESULT_SIZE),
+                [(QUERY_PROTOCOL, ProtocolSupport::Outbound)],
+                Default::default(),
+            ),
+        );
+        gateway.dial(worker_addr).unwrap();
+        let gateway = tokio::spawn(async move {
+            loop {
+                match gateway.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { .. } => {
+                        let mut query = Query {
+                            query_id: Some("q1".to_string()),
+                            ..Default::default()
+                        };
+                        query.sign(&gateway_keypair);
+                        gateway.behaviour_mut().send_request(&worker_id, query);
+                    }
+                    SwarmEvent::Behaviour(request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    }) => break response,
+                    _ => {}
+                }
+            }
+        });
+
+        let received = async {
+            loop {
+                match events.next().await {
+                    Some(WorkerEvent::Query { query, .. }) => break query,
+                    Some(_) => {}
+                    None => panic!("the transport stopped"),
+                }
+            }
+        };
+        let query = tokio::time::timeout(Duration::from_secs(5), received)
+            .await
+            .expect("the worker should receive the query");
+
+        // The transport is stopped right after the result has been queued
+        let result =
+            QueryResult::new(query.query_id.unwrap(), query_result::Result::Ok(Default::default()));
+        handle.send_query_result(result).unwrap();
+        drop(handle);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), gateway)
+            .await
+            .expect("the result should be sent before the transport stops")
+            .unwrap();
+        assert_eq!(result.query_id, "q1");
+    }
+
+    #[test]
+    fn test_logs_backfill() {
+        let keypair = Keypair::generate_ed25519();
+        let worker_id = keypair.public().to_peer_id();
+        let log = |seq_no| QueryExecuted {
+            worker_id: worker_id.to_base58(),
+            seq_no: Some(seq_no),
+            ..Default::default()
+        };
+        let sign = |log: &mut QueryExecuted| log.sign(&keypair);
+        let seq_nos = |response: &LogsBackfillResponse| {
+            response
+                .queries_executed
+                .iter()
+                .map(|log| log.seq_no.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut retained = RetainedLogs::new(5);
+        retained.extend((0..3).map(log));
+
+        let response = retained.backfill(1, usize::MAX, sign);
+        assert_eq!(seq_nos(&response), vec![1, 2]);
+        assert!(!response.gap);
+
+        // Round trip through the wire format, as received by the collector
+        let mut decoded =
+            LogsBackfillResponse::decode(response.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, response);
+        assert!(decoded.queries_executed.iter_mut().all(|log| log.verify_signature(&worker_id)));
+
+        // Logs 0-4 get evicted
+        retained.extend((3..10).map(log));
+        let response = retained.backfill(2, usize::MAX, sign);
+        assert_eq!(seq_nos(&response), vec![5, 6, 7, 8, 9]);
+        assert!(response.gap);
+        let response = retained.backfill(5, usize::MAX, sign);
+        assert_eq!(seq_nos(&response), vec![5, 6, 7, 8, 9]);
+        assert!(!response.gap);
+        let response = retained.backfill(10, usize::MAX, sign);
+        assert!(response.queries_executed.is_empty());
+        assert!(!response.gap);
+
+        // Response size is limited, the rest is requested again
+        let max_size = retained.backfill(5, usize::MAX, sign).encoded_len() / 2;
+        let response = retained.backfill(5, max_size, sign);
+        assert!(response.encoded_len() <= max_size);
+        assert_eq!(seq_nos(&response), vec![5, 6]);
+        let response = retained.backfill(7, max_size, sign);
+        assert_eq!(seq_nos(&response), vec![7, 8]);
+    }
+
+    #[test]
+    fn test_logs_collector_failover() {
+        let primary = PeerId::random();
+        let backup = PeerId::random();
+        let mut collectors = LogsCollectors::new(primary, [backup]);
+        assert!(!collectors.contains(&PeerId::random()));
+
+        assert!(collectors.on_ack(primary, Some(1)));
+        // Backup is behind the primary
+        assert!(!collectors.on_ack(backup, Some(1)));
+        // Primary is down, backup takes over
+        assert!(collectors.on_ack(backup, Some(2)));
+        assert!(collectors.on_ack(backup, Some(3)));
+        // Primary is back
+        assert!(collectors.on_ack(primary, Some(3)));
+        assert!(!collectors.on_ack(backup, Some(3)));
+
+        assert_eq!(
+            collectors.stats[&primary],
+            LogsCollectorStats {
+                acks: 2,
+                last_seq_no: Some(3)
+            }
+        );
+        assert_eq!(
+            collectors.stats[&backup],
+            LogsCollectorStats {
+                acks: 4,
+                last_seq_no: Some(3)
+            }
+        );
+    }
+
+    #[test]
+    fn test_ping_schedule() {
+        let start = Instant::now();
+        let millis = |ms| start + Duration::from_millis(ms);
+        let ping = |datasets: &[&str]| Ping {
+            stored_ranges: datasets
+                .iter()
+                .map(|url| DatasetRanges {
+                    url: url.to_string(),
+                    ranges: vec![],
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut schedule =
+            PingSchedule::new(Duration::from_secs(10), Duration::from_secs(1), start);
+
+        // The first ping is published right away
+        schedule.on_ping_set(&ping(&["a"]), millis(100));
+        assert_eq!(schedule.next(), millis(100));
+        schedule.on_published(ping(&["a"]), false, millis(100));
+        assert_eq!(schedule.next(), millis(10_100));
+
+        // Pings without significant changes wait for the interval
+        schedule.on_ping_set(&ping(&["a"]), millis(3_000));
+        schedule.on_overload(false, millis(3_000));
+        assert_eq!(schedule.next(), millis(10_100));
+
+        // A dataset added triggers a ping right away
+        schedule.on_ping_set(&ping(&["a", "b"]), millis(4_000));
+        assert_eq!(schedule.next(), millis(4_000));
+        schedule.on_published(ping(&["a", "b"]), false, millis(4_000));
+
+        // A burst of changes is coalesced into one ping after the min interval
+        schedule.on_ping_set(&ping(&["a", "b", "c"]), millis(4_200));
+        schedule.on_overload(true, millis(4_300));
+        schedule.on_ping_set(&ping(&["a", "b", "c", "d"]), millis(4_400));
+        assert_eq!(schedule.next(), millis(5_000));
+        schedule.on_published(ping(&["a", "b", "c", "d"]), true, millis(5_000));
+
+        // So is recovering from the overload
+        schedule.on_overload(true, millis(5_500));
+        assert_eq!(schedule.next(), millis(15_000));
+        schedule.on_overload(false, millis(5_500));
+        assert_eq!(schedule.next(), millis(6_000));
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let mut cache = QueryCache::new(QueryCacheConfig {
+            capacity: NonZeroUsize::new(10).unwrap(),
+            ttl: Duration::from_secs(60),
+        });
+        let query = |id: &str| Query {
+            query_id: Some(id.to_string()),
+            dataset: Some("dataset".to_string()),
+            query: Some("{}".to_string()),
+            signature: id.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let result =
+            QueryResult::new("1".to_string(), query_result::Result::Ok(Default::default()));
+        let now = Instant::now();
+
+        let key = QueryCache::key(&query("1"));
+        assert_eq!(cache.get(&key, now), None);
+        cache.insert(key.clone(), result.clone(), now);
+
+        // Identical query with a different ID and signature hits the cache
+        assert_eq!(QueryCache::key(&query("2")), key);
+        assert_eq!(cache.get(&key, now), Some(result.clone()));
+
+        let other = Query {
+            dataset: Some("other".to_string()),
+            ..query("3")
+        };
+        assert_ne!(QueryCache::key(&other), key);
+        assert_eq!(cache.get(&QueryCache::key(&other), now), None);
+
+        // Expired result
+        assert_eq!(cache.get(&key, now + Duration::from_secs(61)), None);
+
+        // Errors are not cached
+        let error =
+            QueryResult::new("4".to_string(), query_result::Result::ServerError("error".into()));
+        cache.insert(key.clone(), error, now);
+        assert_eq!(cache.get(&key, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_draining() {
+        let gateway_keypair = Keypair::generate_ed25519();
+        let gateway_id = gateway_keypair.public().to_peer_id();
+        let config = WorkerConfig::new(PeerId::random(), PeerId::random());
+        let mut worker = new_test_swarm(|keypair, mut base| {
+            // No peers are subscribed to pings, so the published ones stay in the retry buffer
+            let retry = PublishRetry {
+                timeout: Duration::from_secs(60),
+                max_buffered: 10,
+            };
+            base.set_publish_retry(PING_TOPIC, Some(retry));
+            WorkerBehaviour::new(base, keypair.public().to_peer_id(), None, config)
+        });
+        worker.behaviour_mut().inner.base.allow_peer(gateway_id);
+        let worker_id = *worker.local_peer_id();
+        let worker_addr = random_memory_addr();
+        worker.listen_on(worker_addr.clone()).unwrap();
+        let rotation = worker.behaviour().rotation();
+        let num_pings = |worker: &Swarm<Wrapped<WorkerBehaviour>>| {
+            worker.behaviour().inner.base.num_retry_msgs(PING_TOPIC)
+        };
+
+        let mut gateway = new_memory_swarm(
+            gateway_keypair.clone(),
+            request_response::Behaviour::with_codec(
+                ProtoCodec::<Query, QueryResult>::new(MAX_QUERY_SIZE, MAX_QUERY_RESULT_SIZE),
+                [(QUERY_PROTOCOL, ProtocolSupport::Outbound)],
+                Default::default(),
+            ),
+        );
+        gateway.dial(worker_addr).unwrap();
+        let query = |query_id: &str| {
+            let mut query = Query {
+                query_id: Some(query_id.to_string()),
+                ..Default::default()
+            };
+            query.sign(&gateway_keypair);
+            query
+        };
+
+        let rotate = async {
+            loop {
+                tokio::select! {
+                    ev = worker.select_next_some() => {
+                        let SwarmEvent::Behaviour(WorkerEvent::Query { query, .. }) = ev else {
+                            continue;
+                        };
+                        assert_eq!(query.query_id.as_deref(), Some("q1"));
+                        worker.behaviour_mut().send_ping(Ping::default());
+                        assert_eq!(num_pings(&worker), 1);
+
+                        // Rotation requested while the query is in progress
+                        rotation.send_replace(RotationState::Draining);
+                        worker.behaviour_mut().start_draining();
+                        assert_eq!(*rotation.borrow(), RotationState::Draining);
+                        worker.behaviour_mut().send_ping(Ping::default());
+                        assert_eq!(num_pings(&worker), 1);
+                        gateway.behaviour_mut().send_request(&worker_id, query("q2"));
+                    }
+                    ev = gateway.select_next_some() => match ev {
+                        SwarmEvent::ConnectionEstablished { .. } => {
+                            gateway.behaviour_mut().send_request(&worker_id, query("q1"));
+                        }
+                        SwarmEvent::Behaviour(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. },
+                            ..
+                        }) if response.query_id == "q2" => {
+                            // New queries are rejected, so that the gateway retries elsewhere
+                            assert_eq!(response.result, Some(query_result::Result::Overloaded(())));
+                            let result = QueryResult::new(
+                                "q1".to_string(),
+                                query_result::Result::Ok(Default::default()),
+                            );
+                            worker.behaviour_mut().send_query_result(result);
+                        }
+                        SwarmEvent::Behaviour(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. },
+                            ..
+                        }) => break response,
+                        _ => {}
+                    },
+                }
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), rotate)
+            .await
+            .expect("the accepted query should be answered");
+        assert_eq!(result.query_id, "q1");
+        assert_eq!(*rotation.borrow(), RotationState::Draining);
+
+        // Drained once the result has been sent out
+        let drained = async {
+            while worker.behaviour().pending_query_results() > 0 {
+                worker.select_next_some().await;
+            }
+            worker.behaviour_mut().update_drained();
+        };
+        tokio::time::timeout(Duration::from_secs(5), drained)
+            .await
+            .expect("the result should be sent");
+        assert_eq!(*rotation.borrow(), RotationState::Drained);
+    }
+
+    #[test]
+    fn test_query_age() {
+        let max_age = Duration::from_secs(60);
+        let now = SystemTime::now();
+        let ts = |t: SystemTime| {
+            Some(u64::try_from(t.duration_since(UNIX_EPOCH).unwrap().as_millis()).unwrap())
+        };
+
+        assert!(check_query_age(None, max_age, now).is_ok());
+        assert!(check_query_age(ts(now), max_age, now).is_ok());
+        assert!(check_query_age(ts(now - Duration::from_secs(30)), max_age, now).is_ok());
+        // Expired query
+        let err = check_query_age(ts(now - Duration::from_secs(61)), max_age, now).unwrap_err();
+        assert!(err.starts_with("Query expired"), "{err}");
+        // Small clock skew is tolerated
+        assert!(check_query_age(ts(now + Duration::from_secs(2)), max_age, now).is_ok());
+        assert!(check_query_age(ts(now + Duration::from_secs(10)), max_age, now).is_err());
+    }
+}
+
+/// Mock end of the worker transport, see [`crate::mock`]
+#[cfg(feature = "test-util")]
+pub struct MockWorkerTransport {
+    /// The latest ping set through the handle
+    pub pings_rx: watch::Receiver<Option<Ping>>,
+    pub query_results_rx: Receiver<QueryResult>,
+    pub logs_rx: Receiver<Vec<QueryExecuted>>,
+    pub disconnects_rx: Receiver<PeerId>,
+    pub dataset_ads_rx: Receiver<DatasetRanges>,
+    pub boot_node_updates_rx: Receiver<BootNodeUpdate>,
+    /// Key rotation state requested through the handle. Set it to `Drained`
+    /// to let `prepare_rotation` complete.
+    pub rotation: Arc<watch::Sender<RotationState>>,
+    pub events_tx: Sender<WorkerEvent>,
+}
+
+#[cfg(feature = "test-util")]
+pub fn start_mock_transport(
+    config: WorkerConfig,
+) -> (impl Stream<Item = WorkerEvent>, WorkerTransportHandle, MockWorkerTransport) {
+    let (pings_tx, pings_rx) = watch::channel(None);
+    let (query_results_tx, query_results_rx) =
+        new_queue(config.query_results_queue_size, "query_results");
+    let (logs_tx, logs_rx) = new_queue(config.logs_queue_size, "logs");
+    let (disconnects_tx, disconnects_rx) = new_queue(config.disconnects_queue_size, "disconnects");
+    let (dataset_ads_tx, dataset_ads_rx) = new_queue(config.dataset_ads_queue_size, "dataset_ads");
+    let (boot_node_updates_tx, boot_node_updates_rx) =
+        new_queue(config.boot_node_updates_queue_size, "boot_node_updates");
+    let (events_tx, events_rx) = new_queue(config.events_queue_size, "events");
+    let rotation = Arc::new(watch::channel(RotationState::Running).0);
+    let handle = WorkerTransportHandle {
+        pings_tx: Arc::new(pings_tx),
+        query_results_tx,
+        logs_tx,
+        dataset_ads_tx,
+        rotation: rotation.clone(),
+        base: BaseTransportHandle::mock(disconnects_tx, boot_node_updates_tx),
+        _task_manager: Arc::new(TaskManager::new(config.shutdown_timeout)),
+    };
+    let mock = MockWorkerTransport {
+        pings_rx,
+        query_results_rx,
+        logs_rx,
+        disconnects_rx,
+        dataset_ads_rx,
+        boot_node_updates_rx,
+        rotation,
+        events_tx,
+    };
+    (events_rx, handle, mock)
+}
@@ -0,0 +1,306 @@
+use std::{
+    future::Future,
+    io,
+    num::{NonZeroU64, NonZeroUsize},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerEvent},
+    PeerId,
+};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tokio::time::Sleep;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{BANDWIDTH_RECEIVED, BANDWIDTH_SENT, THROTTLED_READS};
+
+const MAX_TRACKED_PEERS: usize = 4096;
+
+/// Bytes exchanged with a peer over direct (non-relayed) connections
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The number of times reading from the peer was paused because of the rate limit
+    pub times_throttled: u64,
+}
+
+/// Inbound byte budget (token bucket), negative if the peer exceeded the limit
+struct Allowance {
+    bytes: f64,
+    last_refill: Instant,
+}
+
+impl Allowance {
+    fn new(max_bytes_per_sec: Option<NonZeroU64>) -> Self {
+        Self {
+            bytes: max_bytes_per_sec.map_or(0.0, |rate| rate.get() as f64),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// How long reading from the peer should be paused, if at all
+    fn read_delay(&mut self, max_bytes_per_sec: NonZeroU64, now: Instant) -> Option<Duration> {
+        let rate = max_bytes_per_sec.get() as f64;
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.bytes = (self.bytes + elapsed * rate).min(rate);
+        self.last_refill = now;
+        (self.bytes < 0.0).then(|| Duration::from_secs_f64(-self.bytes / rate))
+    }
+}
+
+/// Counters of a single peer, updated by its streams without locking the whole tracker
+struct PeerBandwidth {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    times_throttled: AtomicU64,
+    max_bytes_per_sec: Option<NonZeroU64>,
+    allowance: Mutex<Allowance>,
+}
+
+impl PeerBandwidth {
+    fn new(max_bytes_per_sec: Option<NonZeroU64>) -> Self {
+        Self {
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            times_throttled: Default::default(),
+            max_bytes_per_sec,
+            allowance: Mutex::new(Allowance::new(max_bytes_per_sec)),
+        }
+    }
+
+    fn stats(&self) -> BandwidthStats {
+        BandwidthStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            times_throttled: self.times_throttled.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_sent(&self, bytes: usize) {
+        #[cfg(feature = "metrics")]
+        BANDWIDTH_SENT.inc_by(bytes as u64);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn record_received(&self, bytes: usize) {
+        #[cfg(feature = "metrics")]
+        BANDWIDTH_RECEIVED.inc_by(bytes as u64);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        if self.max_bytes_per_sec.is_some() {
+            self.allowance.lock().unwrap().bytes -= bytes as f64;
+        }
+    }
+
+    fn read_delay(&self) -> Option<Duration> {
+        let max_bytes_per_sec = self.max_bytes_per_sec?;
+        let delay = self.allowance.lock().unwrap().read_delay(max_bytes_per_sec, Instant::now());
+        if delay.is_some() {
+            self.times_throttled.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            THROTTLED_READS.inc();
+        }
+        delay
+    }
+}
+
+/// Per-peer bandwidth accounting shared between the transport and the transport handles.
+/// Optionally limits the rate of reading from every single peer.
+#[derive(Clone)]
+pub struct BandwidthTracker {
+    // Only locked when a connection is established and when the stats are read
+    peers: Arc<Mutex<LruCache<PeerId, Arc<PeerBandwidth>>>>,
+    max_bytes_per_sec: Option<NonZeroU64>,
+}
+
+impl Default for BandwidthTracker {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl BandwidthTracker {
+    pub fn new(max_bytes_per_sec: Option<NonZeroU64>) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self {
+            peers: Arc::new(Mutex::new(LruCache::new(capacity))),
+            max_bytes_per_sec,
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<BandwidthStats> {
+        self.peers.lock().unwrap().peek(peer_id).map(|peer| peer.stats())
+    }
+
+    /// Wrap the connection muxer, so that all its substreams are accounted to the peer
+    pub fn meter<M>(&self, peer_id: PeerId, muxer: M) -> MeteredMuxer<M> {
+        MeteredMuxer {
+            inner: muxer,
+            peer: self.peer(peer_id),
+        }
+    }
+
+    fn peer(&self, peer_id: PeerId) -> Arc<PeerBandwidth> {
+        let mut peers = self.peers.lock().unwrap();
+        peers
+            .get_or_insert(peer_id, || Arc::new(PeerBandwidth::new(self.max_bytes_per_sec)))
+            .clone()
+    }
+}
+
+pub struct MeteredMuxer<M> {
+    inner: M,
+    peer: Arc<PeerBandwidth>,
+}
+
+impl<M> MeteredMuxer<M> {
+    fn wrap<S>(&self, stream: S) -> MeteredStream<S> {
+        MeteredStream {
+            inner: stream,
+            peer: self.peer.clone(),
+            throttle: None,
+        }
+    }
+}
+
+impl<M> StreamMuxer for MeteredMuxer<M>
+where
+    M: StreamMuxer + Unpin,
+    M::Substream: Unpin,
+{
+    type Substream = MeteredStream<M::Substream>;
+    type Error = M::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = futures::ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        Poll::Ready(Ok(self.wrap(stream)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = futures::ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        Poll::Ready(Ok(self.wrap(stream)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+pub struct MeteredStream<S> {
+    inner: S,
+    peer: Arc<PeerBandwidth>,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(throttle) = self.throttle.as_mut() {
+                futures::ready!(throttle.as_mut().poll(cx));
+                self.throttle = None;
+            }
+            match self.peer.read_delay() {
+                Some(delay) => self.throttle = Some(Box::pin(tokio::time::sleep(delay))),
+                None => break,
+            }
+        }
+        let bytes = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.peer.record_received(bytes);
+        Poll::Ready(Ok(bytes))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let bytes = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.peer.record_sent(bytes);
+        Poll::Ready(Ok(bytes))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{io::Cursor, AsyncReadExt};
+
+    use super::*;
+
+    #[test]
+    fn test_read_delay() {
+        let rate = NonZeroU64::new(1000).unwrap();
+        let mut allowance = Allowance::new(Some(rate));
+        let start = allowance.last_refill;
+        assert_eq!(allowance.read_delay(rate, start), None);
+
+        allowance.bytes -= 1500.0;
+        assert_eq!(allowance.read_delay(rate, start), Some(Duration::from_millis(500)));
+        assert_eq!(
+            allowance.read_delay(rate, start + Duration::from_millis(250)),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(allowance.read_delay(rate, start + Duration::from_millis(500)), None);
+
+        // The budget doesn't accumulate beyond one second worth of bytes
+        allowance.read_delay(rate, start + Duration::from_secs(10));
+        assert_eq!(allowance.bytes, 1000.0);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        let tracker = BandwidthTracker::new(NonZeroU64::new(100_000));
+        let peer_id = PeerId::random();
+        let mut stream = MeteredStream {
+            inner: Cursor::new(vec![0u8; 150_000]),
+            peer: tracker.peer(peer_id),
+            throttle: None,
+        };
+
+        // The first second worth of bytes is read right away, the rest has to wait for the budget
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 150_000);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let stats = tracker.get(&peer_id).unwrap();
+        assert_eq!(stats.bytes_received, 150_000);
+        assert!(stats.times_throttled > 0);
+    }
+}
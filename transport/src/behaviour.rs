@@ -1,8 +1,16 @@
 mod addr_cache;
 pub mod base;
+mod dial_queue;
+mod filtered_identify;
+pub mod handshake;
+pub mod peer_blocks;
 pub mod pubsub;
+pub mod reconnect_backoff;
+mod relay_ping;
+pub mod relay_selection;
 #[cfg(feature = "request-client")]
 pub mod request_client;
 #[cfg(feature = "request-server")]
 pub mod request_server;
+pub mod substream_rates;
 pub mod wrapped;
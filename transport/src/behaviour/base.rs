@@ -1,14 +1,16 @@
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::{
     collections::{HashMap, HashSet},
-    task::{Context, Poll},
-    time::Duration,
+    future::Future,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     vec,
 };
 
 use bimap::BiHashMap;
 use contract_client::NodeStream;
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use futures_bounded::FuturesMap;
 use libp2p::{
     allow_block_list,
@@ -19,57 +21,178 @@ use libp2p::{
     dcutr, identify,
     identity::Keypair,
     kad,
-    kad::{store::MemoryStore, GetClosestPeersError, GetClosestPeersOk, QueryId, QueryResult},
-    ping, relay,
+    kad::{
+        store::{MemoryStore, MemoryStoreConfig, RecordStore},
+        GetClosestPeersError, GetClosestPeersOk, GetProvidersOk, GetRecordOk, InboundRequest,
+        PeerRecord, QueryId, QueryResult, RecordKey, StoreInserts,
+    },
+    relay,
     swarm::{
-        behaviour::ConnectionEstablished,
+        behaviour::{toggle::Toggle, ConnectionEstablished},
         dial_opts::{DialOpts, PeerCondition},
-        ConnectionClosed, DialFailure, FromSwarm, NetworkBehaviour, ToSwarm,
+        CloseConnection, ConnectionClosed, ConnectionDenied, ConnectionId, DialFailure, FromSwarm,
+        ListenFailure, ListenerClosed, NetworkBehaviour, ToSwarm,
     },
-    StreamProtocol,
+    Multiaddr, StreamProtocol,
 };
 use libp2p_swarm_derive::NetworkBehaviour;
+use lru::LruCache;
 use prost::Message;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{oneshot, watch},
+    time::Interval,
+};
 
 use subsquid_messages::{
-    signatures::SignedMessage, worker_logs_msg, LogsCollected, Ping, QueryExecuted, QueryLogs,
-    WorkerLogsMsg,
+    signatures::SignedMessage, worker_logs_msg, DatasetRanges, DatasetRecord, LogsCollected, Ping,
+    QueryExecuted, QueryLogs, WorkerLogsMsg,
 };
 
 use crate::behaviour::addr_cache::AddressCache;
 use crate::{
+    bandwidth::BandwidthTracker,
     behaviour::{
-        pubsub::{PubsubBehaviour, PubsubMsg},
+        dial_queue::DialQueue,
+        filtered_identify::FilteredIdentify,
+        handshake::{HandshakeBehaviour, HandshakeConfig, NodeRole, PeerAuthenticated, PeerRoles},
+        peer_blocks::{PeerBlockConfig, PeerBlocked, PeerBlocks},
+        pubsub::{
+            GossipsubTuning, InboundSizeLimit, PublishRateLimit, PublishRetry, PubsubBehaviour,
+            PubsubEvent, PubsubMsg, TopicValidator,
+        },
+        reconnect_backoff::{DialBackoff, ReconnectBackoffConfig, ReconnectBackoffs},
+        relay_ping::RelayAwarePing,
+        relay_selection::RelaySelector,
+        substream_rates::{SubstreamChurnLimit, SubstreamRates},
         wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
     },
     cli::BootNode,
+    clock_skew::ClockSkewTracker,
     protocol::{
-        ID_PROTOCOL, KEEP_LAST_WORKER_LOGS, LOGS_COLLECTED_TOPIC, MAX_PUBSUB_MSG_SIZE, PING_TOPIC,
-        WORKER_LOGS_TOPIC,
+        is_compatible_protocol, ID_PROTOCOL, KEEP_LAST_WORKER_LOGS, LOGS_COLLECTED_TOPIC,
+        MAX_PUBSUB_MSG_SIZE, PING_TOPIC, WORKER_LOGS_TOPIC,
     },
     record_event,
-    util::addr_is_reachable,
-    PeerId, QueueFull,
+    swarm_events::DialErrorKind,
+    util::{addr_is_reachable, new_queue, AdvertisedAddrs, Receiver, Sender},
+    Error, PeerId, QueueFull, Timeout,
 };
 
 #[cfg(feature = "metrics")]
-use crate::metrics::{ACTIVE_CONNECTIONS, ONGOING_PROBES, ONGOING_QUERIES};
+use crate::metrics::{
+    ACTIVE_CONNECTIONS, CONNECTIONS_BY_TYPE, CONNECTIONS_REJECTED, CONNECTION_FAILURES,
+    LEGACY_MESSAGES, ONGOING_PROBES, ONGOING_QUERIES, PROTOCOL_MISMATCHES, RELAY_CIRCUITS,
+    RELAY_RESERVATIONS,
+};
+
+const TOPIC_STREAM_QUEUE_SIZE: usize = 100;
+const CONNECTION_EVENTS_QUEUE_SIZE: usize = 100;
+const PEER_BLOCKS_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const DATASET_KEY_PREFIX: &str = "/sqd/datasets/";
+// Record timestamps have millisecond precision, and records are re-published every half TTL
+const MIN_DATASET_RECORD_TTL: Duration = Duration::from_secs(1);
 
 #[derive(NetworkBehaviour)]
 pub struct InnerBehaviour {
-    identify: identify::Behaviour,
+    identify: FilteredIdentify,
     kademlia: kad::Behaviour<MemoryStore>,
-    relay: relay::client::Behaviour,
-    dcutr: dcutr::Behaviour,
-    ping: ping::Behaviour,
-    autonat: autonat::Behaviour,
+    relay: Toggle<relay::client::Behaviour>,
+    relay_server: Toggle<relay::Behaviour>,
+    dcutr: Toggle<dcutr::Behaviour>,
+    ping: RelayAwarePing,
+    autonat: Toggle<autonat::Behaviour>,
     allow: allow_block_list::Behaviour<AllowedPeers>,
     pubsub: Wrapped<PubsubBehaviour>,
     address_cache: AddressCache,
+    handshake: Toggle<Wrapped<HandshakeBehaviour>>,
+}
+
+/// Limits of the relay server, serving reservations and circuits for other peers
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RelayServerConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration: Duration,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration: Duration,
+    /// Max number of bytes relayed over a single circuit
+    pub max_circuit_bytes: u64,
 }
 
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        let default = relay::Config::default();
+        Self {
+            max_reservations: default.max_reservations,
+            max_reservations_per_peer: default.max_reservations_per_peer,
+            reservation_duration: default.reservation_duration,
+            max_circuits: default.max_circuits,
+            max_circuits_per_peer: default.max_circuits_per_peer,
+            max_circuit_duration: default.max_circuit_duration,
+            max_circuit_bytes: default.max_circuit_bytes,
+        }
+    }
+}
+
+/// Limits of the Kademlia record store.
+///
+/// The transport uses Kademlia mostly for peer routing: looking up peers' addresses
+/// (`get_closest_peers`) and maintaining the routing table. The only records it puts and gets
+/// are the dataset records of workers, so by default the ones sent by other peers aren't stored
+/// at all. When the store is enabled, dataset records are only stored if they're correctly
+/// signed by the worker and not expired.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct KadStoreConfig {
+    pub max_records: usize,
+    /// Max size of a single record value
+    pub max_value_bytes: usize,
+    pub max_providers_per_key: usize,
+    pub max_provided_keys: usize,
+}
+
+impl Default for KadStoreConfig {
+    fn default() -> Self {
+        let default = MemoryStoreConfig::default();
+        Self {
+            max_records: default.max_records,
+            max_value_bytes: default.max_value_bytes,
+            max_providers_per_key: default.max_providers_per_key,
+            max_provided_keys: default.max_provided_keys,
+        }
+    }
+}
+
+impl From<KadStoreConfig> for MemoryStoreConfig {
+    fn from(config: KadStoreConfig) -> Self {
+        Self {
+            max_records: config.max_records,
+            max_value_bytes: config.max_value_bytes,
+            max_providers_per_key: config.max_providers_per_key,
+            max_provided_keys: config.max_provided_keys,
+        }
+    }
+}
+
+impl From<RelayServerConfig> for relay::Config {
+    fn from(config: RelayServerConfig) -> Self {
+        Self {
+            max_reservations: config.max_reservations,
+            max_reservations_per_peer: config.max_reservations_per_peer,
+            reservation_duration: config.reservation_duration,
+            max_circuits: config.max_circuits,
+            max_circuits_per_peer: config.max_circuits_per_peer,
+            max_circuit_duration: config.max_circuit_duration,
+            max_circuit_bytes: config.max_circuit_bytes,
+            ..Default::default()
+        }
+    }
+}
+
+/// Fields missing in the serialized config take their default values
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BaseConfig {
     pub nodes_update_interval: Duration,
     pub autonat_timeout: Duration,
@@ -79,6 +202,56 @@ pub struct BaseConfig {
     pub max_concurrent_probes: usize,
     pub max_pubsub_msg_size: usize,
     pub addr_cache_size: NonZeroUsize,
+    pub relay_client: bool,
+    /// Ping interval for relayed connections
+    pub relay_keepalive_interval: Duration,
+    /// Act as a relay server for other peers (disabled if `None`)
+    pub relay_server: Option<RelayServerConfig>,
+    pub dcutr: bool,
+    pub autonat: bool,
+    /// Interval between AutoNAT probes, both while the reachability is being determined
+    /// and when refreshing it (AutoNAT defaults if `None`)
+    pub autonat_probe_interval: Option<Duration>,
+    /// Number of consistent probe results after which the reachability is confirmed
+    /// (AutoNAT default if `None`)
+    pub autonat_confidence: Option<usize>,
+    /// Subscribe to topics automatically when broadcasting to them
+    pub ensure_subscribed_before_broadcast: bool,
+    /// Disconnect peers whose identify protocol version is incompatible with the local one
+    pub strict_protocol_match: bool,
+    /// Neither send nor accept messages in legacy formats, kept for older peers
+    pub reject_legacy: bool,
+    pub gossipsub_tuning: GossipsubTuning,
+    /// Pause reading from peers sending more than this many bytes per second (unlimited if `None`)
+    pub max_bytes_per_sec_per_peer: Option<NonZeroU64>,
+    /// Store Kademlia records from other peers, within the limits (rejected if `None`).
+    /// Verified dataset records and dataset providers are stored either way, with the
+    /// default limits if `None`.
+    pub kad_store: Option<KadStoreConfig>,
+    /// Max number of dials to peers found by lookups in progress at once, others wait in a queue
+    pub max_concurrent_dials: usize,
+    /// Peer lookups which haven't found the peer in this time are abandoned
+    pub lookup_timeout: Duration,
+    /// Max number of peer lookups in progress at once, new ones are not started above it
+    pub max_concurrent_lookups: usize,
+    /// Don't re-dial peers which disconnected recently, backing off longer from peers
+    /// which keep reconnecting (disabled if `None`)
+    pub reconnect_backoff: Option<ReconnectBackoffConfig>,
+    /// How long dataset records put into the DHT stay valid. Advertised datasets are
+    /// re-published twice within this time.
+    pub dataset_record_ttl: Duration,
+    /// Duration of peer blocks, growing for peers which get blocked repeatedly
+    pub peer_blocks: PeerBlockConfig,
+    /// Drop requests from peers opening substreams faster than this, or block them
+    /// (unlimited if `None`; the rate is tracked either way)
+    pub substream_churn_limit: Option<SubstreamChurnLimit>,
+    /// Challenge connected peers to prove their roles (disabled if `None`)
+    pub handshake: Option<HandshakeConfig>,
+    /// Which of the listen addresses are advertised to other peers
+    pub advertised_addrs: AdvertisedAddrs,
+    /// Reject inbound connections once this many connections are established (unlimited
+    /// if `None`). Boot nodes and protected peers are always accepted.
+    pub max_established_connections: Option<usize>,
 }
 
 impl Default for BaseConfig {
@@ -92,19 +265,844 @@ impl Default for BaseConfig {
             max_concurrent_probes: 1000,
             max_pubsub_msg_size: MAX_PUBSUB_MSG_SIZE,
             addr_cache_size: NonZeroUsize::new(1024).unwrap(),
+            relay_client: true,
+            relay_keepalive_interval: Duration::from_secs(5),
+            relay_server: None,
+            dcutr: true,
+            autonat: true,
+            autonat_probe_interval: None,
+            autonat_confidence: None,
+            ensure_subscribed_before_broadcast: false,
+            strict_protocol_match: false,
+            reject_legacy: false,
+            gossipsub_tuning: Default::default(),
+            max_bytes_per_sec_per_peer: None,
+            kad_store: None,
+            max_concurrent_dials: 100,
+            lookup_timeout: Duration::from_secs(60),
+            max_concurrent_lookups: 1000,
+            reconnect_backoff: None,
+            dataset_record_ttl: Duration::from_secs(3600),
+            peer_blocks: Default::default(),
+            substream_churn_limit: None,
+            handshake: None,
+            advertised_addrs: Default::default(),
+            max_established_connections: None,
+        }
+    }
+}
+
+/// Information advertised by a peer in its last identify message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub agent_version: String,
+    pub protocol_version: String,
+}
+
+/// Peer info cache shared between the behaviour and the transport handles
+#[derive(Clone)]
+pub struct PeerInfoCache(Arc<Mutex<LruCache<PeerId, PeerInfo>>>);
+
+impl PeerInfoCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.0.lock().unwrap().peek(peer_id).cloned()
+    }
+
+    fn put(&self, peer_id: PeerId, info: PeerInfo) {
+        self.0.lock().unwrap().put(peer_id, info);
+    }
+}
+
+impl Default for PeerInfoCache {
+    fn default() -> Self {
+        Self::new(BaseConfig::default().addr_cache_size)
+    }
+}
+
+#[derive(Default)]
+struct PeerWaitersState {
+    connected: HashSet<PeerId>,
+    waiting: HashMap<PeerId, Vec<oneshot::Sender<()>>>,
+    // Peers which should be looked up and dialed by the behaviour
+    to_dial: Vec<PeerId>,
+    waker: Option<Waker>,
+}
+
+/// Connected peers and the tasks waiting for a connection to a specific peer,
+/// shared between the behaviour and the transport handles
+#[derive(Clone, Default)]
+pub struct PeerWaiters(Arc<Mutex<PeerWaitersState>>);
+
+impl PeerWaiters {
+    /// Wait until the peer is connected. If it's not, the behaviour looks it up in the DHT
+    /// and dials it.
+    pub async fn wait_for(&self, peer_id: PeerId, timeout: Duration) -> Result<(), Timeout> {
+        let rx = self.register(peer_id);
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(Timeout),
+        }
+    }
+
+    /// Wait for many peers, at most `concurrency` at a time. Yields whether each peer has been
+    /// connected within `timeout`, in the order of completion.
+    pub fn check_all(
+        &self,
+        peers: impl IntoIterator<Item = PeerId>,
+        concurrency: usize,
+        timeout: Duration,
+    ) -> impl Stream<Item = (PeerId, bool)> {
+        let waiters = self.clone();
+        futures::stream::iter(peers)
+            .map(move |peer_id| {
+                let waiters = waiters.clone();
+                async move { (peer_id, waiters.wait_for(peer_id, timeout).await.is_ok()) }
+            })
+            .buffer_unordered(concurrency.max(1))
+    }
+
+    fn register(&self, peer_id: PeerId) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.0.lock().unwrap();
+        if state.connected.contains(&peer_id) {
+            _ = tx.send(());
+            return rx;
+        }
+        // Forget the waiters which have already timed out
+        state.waiting.retain(|_, waiters| {
+            waiters.retain(|tx| !tx.is_closed());
+            !waiters.is_empty()
+        });
+        let waiters = state.waiting.entry(peer_id).or_default();
+        if waiters.is_empty() {
+            state.to_dial.push(peer_id);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+        waiters.push(tx);
+        rx
+    }
+
+    fn on_connected(&self, peer_id: PeerId) {
+        let mut state = self.0.lock().unwrap();
+        state.connected.insert(peer_id);
+        for tx in state.waiting.remove(&peer_id).unwrap_or_default() {
+            _ = tx.send(());
+        }
+    }
+
+    fn on_disconnected(&self, peer_id: PeerId) {
+        self.0.lock().unwrap().connected.remove(&peer_id);
+    }
+
+    /// The peer couldn't be found, the waiters give up right away
+    fn on_not_found(&self, peer_id: PeerId) {
+        self.0.lock().unwrap().waiting.remove(&peer_id);
+    }
+
+    fn poll_to_dial(&self, cx: &mut Context<'_>) -> Vec<PeerId> {
+        let mut state = self.0.lock().unwrap();
+        state.waker = Some(cx.waker().clone());
+        std::mem::take(&mut state.to_dial)
+    }
+}
+
+/// Worker which has advertised the ranges of a dataset in the DHT
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetProvider {
+    pub peer_id: PeerId,
+    pub ranges: DatasetRanges,
+    /// Time the record was signed by the worker
+    pub timestamp_ms: u64,
+}
+
+struct DatasetLookup {
+    dataset: String,
+    result_tx: oneshot::Sender<Vec<DatasetProvider>>,
+    providers: HashSet<PeerId>,
+    found: Vec<DatasetProvider>,
+    pending_records: usize,
+    providers_done: bool,
+}
+
+fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we're after 1970")
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+/// Key of the provider records of all the workers having the dataset
+fn dataset_providers_key(dataset: &str) -> RecordKey {
+    RecordKey::from(format!("{DATASET_KEY_PREFIX}{dataset}").into_bytes())
+}
+
+/// Key of the record with the dataset ranges of a single worker
+fn dataset_record_key(dataset: &str, peer_id: &PeerId) -> RecordKey {
+    RecordKey::from(format!("{DATASET_KEY_PREFIX}{dataset}/{peer_id}").into_bytes())
+}
+
+fn is_dataset_key(key: &RecordKey) -> bool {
+    key.as_ref().starts_with(DATASET_KEY_PREFIX.as_bytes())
+}
+
+/// Check the TTL of dataset records, advertised datasets are re-published every half of it
+pub fn validate_dataset_record_ttl(ttl: Duration) -> Result<(), Error> {
+    if ttl < MIN_DATASET_RECORD_TTL {
+        return Err(Error::InvalidConfig(format!(
+            "Dataset record TTL ({ttl:?}) should be at least {MIN_DATASET_RECORD_TTL:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// Decode a dataset record, checking that it's signed by the worker from its key, matches
+/// the dataset from its key, and is not older than `ttl`
+fn verify_dataset_record(record: &kad::Record, ttl: Duration) -> Option<DatasetProvider> {
+    let key = std::str::from_utf8(record.key.as_ref()).ok()?;
+    let (dataset, peer_id) = key.strip_prefix(DATASET_KEY_PREFIX)?.rsplit_once('/')?;
+    let peer_id: PeerId = peer_id.parse().ok()?;
+    let mut msg = DatasetRecord::decode(record.value.as_slice()).ok()?;
+    if !msg.verify_signature(&peer_id) {
+        return None;
+    }
+    let age = timestamp_now().saturating_sub(msg.timestamp_ms);
+    let ranges = msg.ranges.filter(|ranges| ranges.url == dataset)?;
+    (age <= u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)).then_some(DatasetProvider {
+        peer_id,
+        ranges,
+        timestamp_ms: msg.timestamp_ms,
+    })
+}
+
+/// Whether the node can be reached from the outside, as determined by AutoNAT
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reachability {
+    #[default]
+    Unknown,
+    Public(Multiaddr),
+    Private,
+}
+
+impl From<NatStatus> for Reachability {
+    fn from(status: NatStatus) -> Self {
+        match status {
+            NatStatus::Public(addr) => Self::Public(addr),
+            NatStatus::Private => Self::Private,
+            NatStatus::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// Reachability of the node along with the confidence in it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatState {
+    pub reachability: Reachability,
+    /// Number of consecutive probes which confirmed the reachability
+    pub confidence: usize,
+    /// Confidence has reached the configured threshold
+    pub confirmed: bool,
+}
+
+impl NatState {
+    fn new(status: NatStatus, confidence: usize, confidence_threshold: usize) -> Self {
+        let reachability = Reachability::from(status);
+        let confirmed = reachability != Reachability::Unknown && confidence >= confidence_threshold;
+        Self {
+            reachability,
+            confidence,
+            confirmed,
+        }
+    }
+}
+
+/// Peer in a routing table snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingTableEntry {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    /// Index of the k-bucket, i.e. the log2 of the XOR distance from the local peer
+    pub bucket: u32,
+}
+
+/// Snapshot of a node's Kademlia routing table. Snapshots of different nodes can be compared
+/// to find out why they can't find each other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingTableSnapshot {
+    pub local_peer_id: PeerId,
+    pub peers: Vec<RoutingTableEntry>,
+}
+
+/// Comparison of two routing table snapshots
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingTableDiff {
+    /// Peers known to both nodes
+    pub common: Vec<PeerId>,
+    /// Peers known only to the first node
+    pub only_left: Vec<PeerId>,
+    /// Peers known only to the second node
+    pub only_right: Vec<PeerId>,
+    /// Whether each node has the other one in its routing table
+    pub left_knows_right: bool,
+    pub right_knows_left: bool,
+}
+
+impl RoutingTableSnapshot {
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.iter().any(|entry| entry.peer_id == *peer_id)
+    }
+
+    pub fn diff(&self, other: &Self) -> RoutingTableDiff {
+        let left: HashSet<PeerId> = self.peers.iter().map(|entry| entry.peer_id).collect();
+        let right: HashSet<PeerId> = other.peers.iter().map(|entry| entry.peer_id).collect();
+        let sorted = |peers: Vec<&PeerId>| {
+            let mut peers: Vec<PeerId> = peers.into_iter().copied().collect();
+            peers.sort();
+            peers
+        };
+        RoutingTableDiff {
+            common: sorted(left.intersection(&right).collect()),
+            only_left: sorted(left.difference(&right).collect()),
+            only_right: sorted(right.difference(&left).collect()),
+            left_knows_right: left.contains(&other.local_peer_id),
+            right_knows_left: right.contains(&self.local_peer_id),
+        }
+    }
+}
+
+/// Whether dialing under `condition` could do anything, given if the peer is connected.
+/// Pending dials are checked by the swarm.
+fn dial_needed(condition: PeerCondition, connected: bool) -> bool {
+    match condition {
+        PeerCondition::Disconnected | PeerCondition::DisconnectedAndNotDialing => !connected,
+        PeerCondition::NotDialing | PeerCondition::Always => true,
+    }
+}
+
+/// DHT lookups of peers in progress. Each lookup has a deadline, after which it's
+/// abandoned, even if Kademlia hasn't reported its end.
+struct PeerLookups<Q> {
+    queries: BiHashMap<PeerId, Q>,
+    deadlines: FuturesMap<PeerId, ()>,
+    capacity: usize,
+}
+
+impl<Q: Eq + std::hash::Hash + Copy> PeerLookups<Q> {
+    fn new(timeout: Duration, capacity: usize) -> Self {
+        Self {
+            queries: Default::default(),
+            deadlines: FuturesMap::new(timeout, capacity),
+            capacity,
+        }
+    }
+
+    fn contains(&self, peer_id: &PeerId) -> bool {
+        self.queries.contains_left(peer_id)
+    }
+
+    fn is_full(&self) -> bool {
+        self.queries.len() >= self.capacity
+    }
+
+    fn insert(&mut self, peer_id: PeerId, query_id: Q) {
+        if self.deadlines.try_push(peer_id, futures::future::pending()).is_ok() {
+            self.queries.insert(peer_id, query_id);
+        }
+    }
+
+    fn peer(&self, query_id: &Q) -> Option<PeerId> {
+        self.queries.get_by_right(query_id).copied()
+    }
+
+    fn remove(&mut self, query_id: &Q) -> Option<PeerId> {
+        let (peer_id, _) = self.queries.remove_by_right(query_id)?;
+        self.deadlines.remove(peer_id);
+        Some(peer_id)
+    }
+
+    /// Lookup which has passed its deadline, already removed
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Option<(PeerId, Q)> {
+        loop {
+            match self.deadlines.poll_unpin(cx) {
+                Poll::Ready((peer_id, Err(_))) => {
+                    if let Some((peer_id, query_id)) = self.queries.remove_by_left(&peer_id) {
+                        return Some((peer_id, query_id));
+                    }
+                }
+                Poll::Pending => return None,
+                _ => unreachable!(), // future::pending() should never complete
+            }
+        }
+    }
+}
+
+/// How a connection has been established
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ConnectionType {
+    Direct,
+    /// Through a relay circuit
+    Relayed,
+    /// Direct connection upgraded from a relayed one by hole punching (DCUtR)
+    HolePunched,
+}
+
+impl ConnectionType {
+    fn from_endpoint(endpoint: &ConnectedPoint) -> Self {
+        if endpoint.is_relayed() {
+            Self::Relayed
+        } else {
+            Self::Direct
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Direct => "direct",
+            Self::Relayed => "relayed",
+            Self::HolePunched => "hole_punched",
+        }
+    }
+}
+
+/// Types of the open connections to each peer, shared between the behaviour
+/// and the transport handles
+#[derive(Clone, Default)]
+pub struct ConnectionTypes(Arc<Mutex<HashMap<PeerId, HashMap<ConnectionId, ConnectionType>>>>);
+
+impl ConnectionTypes {
+    /// Total number of established connections
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().values().map(HashMap::len).sum()
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Vec<ConnectionType> {
+        let peers = self.0.lock().unwrap();
+        peers
+            .get(peer_id)
+            .map(|conns| conns.values().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn insert(&self, peer_id: PeerId, conn_id: ConnectionId, conn_type: ConnectionType) {
+        let old_type =
+            self.0.lock().unwrap().entry(peer_id).or_default().insert(conn_id, conn_type);
+        record_connection_type_change(old_type, Some(conn_type));
+    }
+
+    fn remove(&self, peer_id: PeerId, conn_id: ConnectionId) {
+        let mut peers = self.0.lock().unwrap();
+        let Some(conns) = peers.get_mut(&peer_id) else {
+            return;
+        };
+        let conn_type = conns.remove(&conn_id);
+        if conns.is_empty() {
+            peers.remove(&peer_id);
+        }
+        record_connection_type_change(conn_type, None);
+    }
+
+    /// Mark the connection as hole-punched, unless it's been closed already
+    fn set_hole_punched(&self, peer_id: PeerId, conn_id: ConnectionId) {
+        let is_open =
+            self.0.lock().unwrap().get(&peer_id).is_some_and(|c| c.contains_key(&conn_id));
+        if is_open {
+            self.insert(peer_id, conn_id, ConnectionType::HolePunched);
+        }
+    }
+}
+
+/// Inbound connection rejected because the node has too many connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Too many established connections (max {max})")]
+pub struct ConnectionCapReached {
+    pub max: usize,
+}
+
+fn check_connection_cap(
+    max: Option<usize>,
+    established: usize,
+    protected: bool,
+) -> Result<(), ConnectionCapReached> {
+    match max {
+        Some(max) if established >= max && !protected => Err(ConnectionCapReached { max }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn record_connection_type_change(old: Option<ConnectionType>, new: Option<ConnectionType>) {
+    if let Some(old) = old {
+        CONNECTIONS_BY_TYPE.get_or_create(&vec![("type", old.as_str())]).dec();
+    }
+    if let Some(new) = new {
+        CONNECTIONS_BY_TYPE.get_or_create(&vec![("type", new.as_str())]).inc();
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+fn record_connection_type_change(_old: Option<ConnectionType>, _new: Option<ConnectionType>) {}
+
+#[cfg(feature = "metrics")]
+fn record_connection_failure(kind: DialErrorKind) {
+    CONNECTION_FAILURES.get_or_create(&vec![("kind", kind.as_str())]).inc();
+}
+
+#[cfg(not(feature = "metrics"))]
+fn record_connection_failure(_kind: DialErrorKind) {}
+
+#[derive(Default)]
+struct TopicStreamsState {
+    streams: HashMap<&'static str, Vec<Sender<PubsubMsg>>>,
+    // Messages dropped because a stream of the topic was full
+    dropped: HashMap<&'static str, u64>,
+}
+
+/// Per-topic streams of pub-sub messages, shared between the behaviour and the transport handles.
+/// Every stream has its own queue, so a flood on one topic doesn't cause drops on the others.
+#[derive(Clone, Default)]
+pub struct TopicStreams(Arc<Mutex<TopicStreamsState>>);
+
+impl TopicStreams {
+    /// The stream can be registered before the behaviour subscribes to the topic.
+    /// Messages start to arrive once it does.
+    pub fn register(&self, topic: &'static str) -> Receiver<PubsubMsg> {
+        self.register_with_capacity(topic, TOPIC_STREAM_QUEUE_SIZE)
+    }
+
+    /// Register a stream buffering up to `capacity` (at least one) messages
+    pub fn register_with_capacity(
+        &self,
+        topic: &'static str,
+        capacity: usize,
+    ) -> Receiver<PubsubMsg> {
+        // The queue is named after the topic, so the queue metrics are per topic
+        let (tx, rx) = new_queue(capacity.max(1), topic);
+        self.0.lock().unwrap().streams.entry(topic).or_default().push(tx);
+        rx
+    }
+
+    /// The number of the topic's messages dropped because a stream was full
+    pub fn dropped(&self, topic: &str) -> u64 {
+        self.0.lock().unwrap().dropped.get(topic).copied().unwrap_or_default()
+    }
+
+    /// Deliver the message to the topic's streams. Returns `false` if there are none.
+    fn route(&self, msg: &PubsubMsg) -> bool {
+        let mut state = self.0.lock().unwrap();
+        let TopicStreamsState { streams, dropped } = &mut *state;
+        let Some(senders) = streams.get_mut(msg.topic) else {
+            return false;
+        };
+        senders.retain(|tx| !tx.is_closed());
+        if senders.is_empty() {
+            streams.remove(msg.topic);
+            return false;
+        }
+        for tx in senders.iter() {
+            if !tx.send_or_drop(msg.clone()) {
+                *dropped.entry(msg.topic).or_default() += 1;
+            }
+        }
+        true
+    }
+}
+
+/// Which side opened the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
+}
+
+impl ConnectionDirection {
+    fn from_endpoint(endpoint: &ConnectedPoint) -> Self {
+        match endpoint {
+            ConnectedPoint::Dialer { .. } => Self::Outbound,
+            ConnectedPoint::Listener { .. } => Self::Inbound,
+        }
+    }
+}
+
+/// Lifecycle of a single connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionEvent {
+    Established {
+        peer_id: PeerId,
+        direction: ConnectionDirection,
+    },
+    Closed {
+        peer_id: PeerId,
+        direction: ConnectionDirection,
+        /// Number of connections to the peer which are still open
+        remaining: usize,
+    },
+    /// Connection couldn't be established. The peer is not known for some failed
+    /// inbound connections and dials without a peer ID.
+    Failed {
+        peer_id: Option<PeerId>,
+        direction: ConnectionDirection,
+        error: String,
+    },
+    /// The last peer has been removed from the Kademlia routing table, the node can't
+    /// reach the network until it's bootstrapped again
+    RoutingTableEmpty {
+        /// Number of peers in the routing table before it became empty
+        last_peer_count: usize,
+    },
+    /// A peer has been added to the empty Kademlia routing table
+    RoutingTablePopulated { peer_count: usize },
+    /// AutoNAT has determined the reachability with the configured confidence
+    ReachabilityConfirmed { reachability: Reachability },
+}
+
+impl ConnectionEvent {
+    /// Event for the routing table size change, if it became empty or stopped being empty
+    fn routing_table_change(old_size: usize, new_size: usize) -> Option<Self> {
+        match (old_size, new_size) {
+            (0, 0) => None,
+            (last_peer_count, 0) => Some(Self::RoutingTableEmpty { last_peer_count }),
+            (0, peer_count) => Some(Self::RoutingTablePopulated { peer_count }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ConnectionEventsState {
+    streams: Vec<Sender<ConnectionEvent>>,
+    dropped: u64,
+}
+
+/// Streams of connection lifecycle events, shared between the behaviour and the transport
+/// handles. Each stream has its own queue, events are dropped if it's full.
+#[derive(Clone, Default)]
+pub struct ConnectionEvents(Arc<Mutex<ConnectionEventsState>>);
+
+impl ConnectionEvents {
+    pub fn subscribe(&self) -> Receiver<ConnectionEvent> {
+        let (tx, rx) = new_queue(CONNECTION_EVENTS_QUEUE_SIZE, "connection_events");
+        self.0.lock().unwrap().streams.push(tx);
+        rx
+    }
+
+    /// The number of events dropped because a stream was full
+    pub fn dropped(&self) -> u64 {
+        self.0.lock().unwrap().dropped
+    }
+
+    fn emit(&self, ev: ConnectionEvent) {
+        let mut state = self.0.lock().unwrap();
+        state.streams.retain(|tx| !tx.is_closed());
+        let dropped = state.streams.iter().filter(|tx| !tx.send_or_drop(ev.clone())).count();
+        state.dropped += dropped as u64;
+    }
+}
+
+/// What happens to outbound messages while the transport is paused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PauseMode {
+    /// Messages wait in the queues and are sent after resuming. Once a queue is full,
+    /// further messages are rejected with `QueueFull`.
+    Buffer,
+    /// Messages are dropped
+    Reject,
+}
+
+/// Dial cancelled because the transport is paused
+#[derive(Debug, thiserror::Error)]
+#[error("Transport paused")]
+pub struct TransportPaused;
+
+/// Pause state shared between the behaviour and the transport handles. While paused,
+/// no peers are dialed and no new outbound messages are sent, but the existing
+/// connections are kept alive.
+#[derive(Clone)]
+pub struct TransportPause {
+    state: Arc<watch::Sender<Option<PauseMode>>>,
+    // Waker of the behaviour waiting for the dials to be resumed
+    dial_waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Default for TransportPause {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(None)),
+            dial_waker: Default::default(),
         }
     }
 }
 
+impl TransportPause {
+    pub fn pause(&self, mode: PauseMode) {
+        self.state.send_if_modified(|state| state.replace(mode) != Some(mode));
+    }
+
+    pub fn resume(&self) {
+        if self.state.send_if_modified(|state| state.take().is_some()) {
+            if let Some(waker) = self.dial_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn mode(&self) -> Option<PauseMode> {
+        *self.state.borrow()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode().is_some()
+    }
+
+    pub fn subscribe(&self) -> PauseReceiver {
+        PauseReceiver(self.state.subscribe())
+    }
+
+    fn check_dial(&self) -> Result<(), ConnectionDenied> {
+        if self.is_paused() {
+            return Err(ConnectionDenied::new(TransportPaused));
+        }
+        Ok(())
+    }
+
+    /// Next dial to start, unless the transport is paused. While paused, the behaviour
+    /// is woken up once the transport is resumed.
+    fn poll_dial(&self, dial_queue: &mut DialQueue, cx: &mut Context<'_>) -> Option<DialOpts> {
+        // Checked under the lock, so that a concurrent resume can't miss the waker
+        let mut dial_waker = self.dial_waker.lock().unwrap();
+        if self.is_paused() {
+            *dial_waker = Some(cx.waker().clone());
+            return None;
+        }
+        drop(dial_waker);
+        dial_queue.poll(cx)
+    }
+}
+
+/// Transport-side view of the pause state
+pub struct PauseReceiver(watch::Receiver<Option<PauseMode>>);
+
+impl PauseReceiver {
+    /// Wait for the transport to be paused or resumed, returns the new state
+    pub async fn changed(&mut self) -> Option<PauseMode> {
+        if self.0.changed().await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+        *self.0.borrow_and_update()
+    }
+
+    /// Wait for the transport to be paused or resumed, and log the change.
+    /// Returns the event reporting the new state to the application.
+    pub async fn next_event<E>(&mut self, paused: fn(PauseMode) -> E, resumed: E) -> E {
+        match self.changed().await {
+            Some(mode) => {
+                log::info!("Transport paused, outbound messages: {mode:?}");
+                paused(mode)
+            }
+            None => {
+                log::info!("Transport resumed");
+                resumed
+            }
+        }
+    }
+
+    /// Outbound messages should be left in the queues
+    pub fn buffering(&self) -> bool {
+        *self.0.borrow() == Some(PauseMode::Buffer)
+    }
+
+    /// Returns `true` if the outbound message should be dropped
+    pub fn rejects(&self, msg_type: &str) -> bool {
+        let reject = *self.0.borrow() == Some(PauseMode::Reject);
+        if reject {
+            log::debug!("Transport paused, dropping outbound {msg_type}");
+        }
+        reject
+    }
+}
+
+/// Runtime change of the boot node set
+#[derive(Debug)]
+pub enum BootNodeUpdate {
+    Add(BootNode),
+    Remove(PeerId),
+    /// Bootstrap the routing table now instead of waiting for the periodic bootstrap,
+    /// e.g. after adding boot nodes. The sender gets the result once it has finished.
+    Bootstrap(oneshot::Sender<Result<(), BootstrapError>>),
+}
+
+impl BootNodeUpdate {
+    /// Bootstrap request, together with the future resolving to its result
+    pub fn bootstrap() -> (Self, impl Future<Output = Result<(), BootstrapError>>) {
+        let (result_tx, result_rx) = oneshot::channel();
+        let result = async move { result_rx.await.unwrap_or(Err(BootstrapError::Stopped)) };
+        (Self::Bootstrap(result_tx), result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BootstrapError {
+    #[error("No peers in the routing table to bootstrap from")]
+    NoKnownPeers,
+    #[error("Transport stopped before the bootstrap finished")]
+    Stopped,
+}
+
 pub struct BaseBehaviour {
     inner: InnerBehaviour,
     keypair: Keypair,
-    ongoing_queries: BiHashMap<PeerId, QueryId>,
+    ongoing_queries: PeerLookups<QueryId>,
+    // Callers waiting for requested bootstraps to finish
+    ongoing_bootstraps: HashMap<QueryId, oneshot::Sender<Result<(), BootstrapError>>>,
     outbound_conns: HashMap<PeerId, u32>,
     probe_timeouts: FuturesMap<PeerId, ()>,
     registered_nodes: HashSet<PeerId>,
     active_nodes_stream: NodeStream,
     max_pubsub_msg_size: usize,
+    peer_protocols: LruCache<PeerId, Vec<StreamProtocol>>,
+    peer_info: PeerInfoCache,
+    strict_protocol_match: bool,
+    reject_legacy: bool,
+    max_established_connections: Option<usize>,
+    // Peers accepted over the connection cap
+    protected_peers: HashSet<PeerId>,
+    relay_reservations: HashSet<PeerId>,
+    relay_circuits: u32,
+    routing_table_size: watch::Sender<usize>,
+    nat_state: watch::Sender<NatState>,
+    autonat_confidence: usize,
+    boot_nodes: watch::Sender<Vec<BootNode>>,
+    pinned_peers: HashMap<PeerId, Vec<Multiaddr>>,
+    bandwidth: BandwidthTracker,
+    relay_selector: Option<RelaySelector>,
+    peer_waiters: PeerWaiters,
+    topic_streams: TopicStreams,
+    connection_events: ConnectionEvents,
+    connection_types: ConnectionTypes,
+    clock_skew: ClockSkewTracker,
+    dial_queue: DialQueue,
+    reconnect_backoffs: Option<ReconnectBackoffs>,
+    peer_blocks: PeerBlocks,
+    peer_blocks_sweep: Interval,
+    substream_rates: SubstreamRates,
+    store_records: bool,
+    dataset_record_ttl: Duration,
+    // Datasets advertised by this node, re-published periodically
+    advertised_datasets: HashMap<String, DatasetRanges>,
+    dataset_republish: Interval,
+    // Dataset provider lookups by the ID of the providers query
+    dataset_lookups: HashMap<QueryId, DatasetLookup>,
+    // Record queries of the dataset lookups (record query ID -> providers query ID)
+    dataset_record_queries: HashMap<QueryId, QueryId>,
+    pause: TransportPause,
+    peer_roles: PeerRoles,
 }
 
 #[allow(dead_code)]
@@ -116,51 +1114,134 @@ impl BaseBehaviour {
         boot_nodes: Vec<BootNode>,
         relay: relay::client::Behaviour,
         dht_protocol: StreamProtocol,
+        agent_version: String,
     ) -> Self {
         let local_peer_id = keypair.public().to_peer_id();
         let mut kad_config = kad::Config::new(dht_protocol);
         kad_config.set_replication_factor(20.try_into().unwrap());
-        let mut inner = InnerBehaviour {
-            identify: identify::Behaviour::new(
+        // Filtered records are reported in events instead of being stored. Dataset records
+        // are stored after validation, other records only if the store is enabled.
+        kad_config.set_record_filtering(StoreInserts::FilterBoth);
+        let store_records = config.kad_store.is_some();
+        let kad_store_config = config.kad_store.unwrap_or_default().into();
+        let peer_roles = PeerRoles::default();
+        let peer_blocks = PeerBlocks::new(config.peer_blocks);
+        let autonat_confidence =
+            config.autonat_confidence.unwrap_or(autonat::Config::default().confidence_max);
+        let inner = InnerBehaviour {
+            identify: FilteredIdentify::new(
                 identify::Config::new(ID_PROTOCOL.to_string(), keypair.public())
                     .with_interval(config.identify_interval)
+                    .with_agent_version(agent_version)
                     .with_push_listen_addr_updates(true),
+                config.advertised_addrs,
             ),
             kademlia: kad::Behaviour::with_config(
                 local_peer_id,
-                MemoryStore::new(local_peer_id),
+                MemoryStore::with_config(local_peer_id, kad_store_config),
                 kad_config,
             ),
-            relay,
-            dcutr: dcutr::Behaviour::new(local_peer_id),
-            ping: ping::Behaviour::new(ping::Config::default()),
-            autonat: autonat::Behaviour::new(
-                local_peer_id,
-                autonat::Config {
-                    timeout: config.autonat_timeout,
-                    ..Default::default()
-                },
-            ),
+            relay: config.relay_client.then_some(relay).into(),
+            relay_server: config
+                .relay_server
+                .map(|c| relay::Behaviour::new(local_peer_id, c.into()))
+                .into(),
+            dcutr: config.dcutr.then(|| dcutr::Behaviour::new(local_peer_id)).into(),
+            ping: RelayAwarePing::new(config.relay_keepalive_interval),
+            autonat: config
+                .autonat
+                .then(|| {
+                    let defaults = autonat::Config::default();
+                    autonat::Behaviour::new(
+                        local_peer_id,
+                        autonat::Config {
+                            timeout: config.autonat_timeout,
+                            retry_interval: config
+                                .autonat_probe_interval
+                                .unwrap_or(defaults.retry_interval),
+                            refresh_interval: config
+                                .autonat_probe_interval
+                                .unwrap_or(defaults.refresh_interval),
+                            confidence_max: autonat_confidence,
+                            ..defaults
+                        },
+                    )
+                })
+                .into(),
             allow: Default::default(),
-            pubsub: PubsubBehaviour::new(keypair.clone(), config.max_pubsub_msg_size).into(),
+            pubsub: PubsubBehaviour::new(
+                keypair.clone(),
+                config.max_pubsub_msg_size,
+                config.ensure_subscribed_before_broadcast,
+                config.gossipsub_tuning,
+            )
+            .with_peer_blocks(peer_blocks.clone())
+            .into(),
             address_cache: AddressCache::new(config.addr_cache_size),
+            handshake: config
+                .handshake
+                .map(|handshake_config| {
+                    Wrapped::from(HandshakeBehaviour::new(
+                        keypair.clone(),
+                        contract_client.clone_client(),
+                        handshake_config,
+                        peer_roles.clone(),
+                    ))
+                })
+                .into(),
         };
 
-        for boot_node in boot_nodes {
-            inner.allow.allow_peer(boot_node.peer_id);
-            inner.autonat.add_server(boot_node.peer_id, Some(boot_node.address));
-        }
-
-        Self {
+        let mut behaviour = Self {
             inner,
             keypair: keypair.clone(),
-            ongoing_queries: Default::default(),
+            ongoing_queries: PeerLookups::new(config.lookup_timeout, config.max_concurrent_lookups),
+            ongoing_bootstraps: Default::default(),
             outbound_conns: Default::default(),
             probe_timeouts: FuturesMap::new(config.probe_timeout, config.max_concurrent_probes),
             registered_nodes: Default::default(),
             active_nodes_stream: contract_client.network_nodes_stream(config.nodes_update_interval),
             max_pubsub_msg_size: config.max_pubsub_msg_size,
+            peer_protocols: LruCache::new(config.addr_cache_size),
+            peer_info: PeerInfoCache::new(config.addr_cache_size),
+            strict_protocol_match: config.strict_protocol_match,
+            reject_legacy: config.reject_legacy,
+            max_established_connections: config.max_established_connections,
+            protected_peers: Default::default(),
+            relay_reservations: Default::default(),
+            relay_circuits: 0,
+            routing_table_size: watch::Sender::new(0),
+            nat_state: watch::Sender::new(Default::default()),
+            autonat_confidence,
+            boot_nodes: watch::Sender::new(vec![]),
+            pinned_peers: Default::default(),
+            bandwidth: Default::default(),
+            relay_selector: None,
+            peer_waiters: Default::default(),
+            topic_streams: Default::default(),
+            connection_events: Default::default(),
+            connection_types: Default::default(),
+            clock_skew: Default::default(),
+            dial_queue: DialQueue::new(config.max_concurrent_dials),
+            reconnect_backoffs: config.reconnect_backoff.map(ReconnectBackoffs::new),
+            peer_blocks: peer_blocks.clone(),
+            peer_blocks_sweep: tokio::time::interval(PEER_BLOCKS_SWEEP_INTERVAL),
+            substream_rates: SubstreamRates::new(config.substream_churn_limit, peer_blocks),
+            store_records,
+            dataset_record_ttl: config.dataset_record_ttl,
+            advertised_datasets: Default::default(),
+            dataset_republish: tokio::time::interval_at(
+                (Instant::now() + config.dataset_record_ttl / 2).into(),
+                config.dataset_record_ttl / 2,
+            ),
+            dataset_lookups: Default::default(),
+            dataset_record_queries: Default::default(),
+            pause: Default::default(),
+            peer_roles,
+        };
+        for boot_node in boot_nodes {
+            behaviour.add_boot_node(boot_node);
         }
+        behaviour
     }
 
     pub fn subscribe_pings(&mut self) {
@@ -177,6 +1258,26 @@ impl BaseBehaviour {
         self.inner.pubsub.subscribe(LOGS_COLLECTED_TOPIC, 1);
     }
 
+    pub fn set_allowed_topics(&mut self, topics: &[&str]) {
+        self.inner.pubsub.set_allowed_topics(topics.iter().copied());
+    }
+
+    pub fn set_publish_rate_limit(&mut self, topic: &str, limit: Option<PublishRateLimit>) {
+        self.inner.pubsub.set_publish_rate_limit(topic, limit);
+    }
+
+    pub fn set_publish_retry(&mut self, topic: &str, retry: Option<PublishRetry>) {
+        self.inner.pubsub.set_publish_retry(topic, retry);
+    }
+
+    pub fn set_inbound_size_limit(&mut self, topic: &str, limit: Option<InboundSizeLimit>) {
+        self.inner.pubsub.set_inbound_size_limit(topic, limit);
+    }
+
+    pub fn set_topic_validator(&mut self, topic: &str, validator: Option<TopicValidator>) {
+        self.inner.pubsub.set_topic_validator(topic, validator);
+    }
+
     pub fn sign<T: SignedMessage>(&self, msg: &mut T) {
         msg.sign(&self.keypair)
     }
@@ -186,6 +1287,11 @@ impl BaseBehaviour {
         self.inner.pubsub.publish(PING_TOPIC, ping.encode_to_vec());
     }
 
+    #[cfg(test)]
+    pub fn num_retry_msgs(&self, topic_name: &str) -> usize {
+        self.inner.pubsub.num_retry_msgs(topic_name)
+    }
+
     pub fn publish_worker_logs(&mut self, mut logs: Vec<QueryExecuted>) {
         for log in &mut logs {
             self.sign(log);
@@ -198,13 +1304,20 @@ impl BaseBehaviour {
 
     pub fn publish_logs_collected(&mut self, logs_collected: LogsCollected) {
         self.inner.pubsub.publish(LOGS_COLLECTED_TOPIC, logs_collected.encode_to_vec());
+        if self.reject_legacy {
+            return;
+        }
+        #[cfg(feature = "metrics")]
+        record_legacy_message("outbound", "logs_collected");
         let msg: WorkerLogsMsg = logs_collected.into();
         self.inner.pubsub.publish(WORKER_LOGS_TOPIC, msg.encode_to_vec()); // TODO: remove after dropping support for v1.0.0-rc2
     }
 
     pub fn find_and_dial(&mut self, peer_id: PeerId) {
-        if self.ongoing_queries.contains_left(&peer_id) {
+        if self.ongoing_queries.contains(&peer_id) {
             log::debug!("Query for peer {peer_id} already ongoing");
+        } else if self.ongoing_queries.is_full() {
+            log::warn!("Too many peer lookups in progress, not looking up {peer_id}");
         } else {
             log::debug!("Starting query for peer {peer_id}");
             let query_id = self.inner.kademlia.get_closest_peers(peer_id);
@@ -214,6 +1327,28 @@ impl BaseBehaviour {
         }
     }
 
+    /// Establish a connection to the peer ahead of sending anything to it. Does nothing if
+    /// the peer is already connected.
+    pub fn dial_peer(&mut self, peer_id: PeerId) {
+        self.dial_peer_with(peer_id, PeerCondition::Disconnected)
+    }
+
+    /// Dial the peer if `condition` allows it. Peers with known addresses go straight to
+    /// the dial queue, others are looked up first. `PeerCondition::Always` can open extra
+    /// connections to a connected peer, up to the per-peer connection limit.
+    pub fn dial_peer_with(&mut self, peer_id: PeerId, condition: PeerCondition) {
+        let connected = !self.connection_types.get(&peer_id).is_empty();
+        if !dial_needed(condition, connected) {
+            return log::debug!("Peer {peer_id} already connected");
+        }
+        if self.inner.address_cache.contains(&peer_id) {
+            log::debug!("Dialing {peer_id} ({condition:?})");
+            self.dial_queue.push(DialOpts::peer_id(peer_id).condition(condition).build());
+        } else {
+            self.find_and_dial(peer_id);
+        }
+    }
+
     /// Try to probe if peer is reachable. Returns:
     ///   * Ok(true) if there is an established outbound connection to peer,
     ///   * Ok(false) if a probe has been scheduled,
@@ -237,6 +1372,270 @@ impl BaseBehaviour {
         Ok(false)
     }
 
+    /// Protocols advertised by the peer in the last identify message (empty if not known)
+    pub fn peer_protocols(&self, peer_id: &PeerId) -> Vec<StreamProtocol> {
+        self.peer_protocols.peek(peer_id).cloned().unwrap_or_default()
+    }
+
+    /// Handle to the cache of agent/protocol versions received from peers
+    pub fn peer_info_cache(&self) -> PeerInfoCache {
+        self.peer_info.clone()
+    }
+
+    /// Handle to the estimates of the peers' clock skew
+    pub fn clock_skew_tracker(&self) -> ClockSkewTracker {
+        self.clock_skew.clone()
+    }
+
+    /// Record the timestamp (in milliseconds since the UNIX epoch) of a message just received
+    /// from the peer, to estimate its clock skew
+    pub fn record_peer_timestamp(&self, peer_id: PeerId, timestamp_ms: u64) {
+        self.clock_skew.record_timestamp(peer_id, timestamp_ms);
+    }
+
+    /// Handle for waiting until specific peers are connected
+    pub fn peer_waiters(&self) -> PeerWaiters {
+        self.peer_waiters.clone()
+    }
+
+    /// Handle for pausing and resuming the transport
+    pub fn pause_state(&self) -> TransportPause {
+        self.pause.clone()
+    }
+
+    /// Handle for creating per-topic streams of pub-sub messages
+    pub fn topic_streams(&self) -> TopicStreams {
+        self.topic_streams.clone()
+    }
+
+    /// Handle for creating streams of connection lifecycle events
+    pub fn connection_events(&self) -> ConnectionEvents {
+        self.connection_events.clone()
+    }
+
+    /// Handle to the types of connections to peers
+    pub fn connection_types(&self) -> ConnectionTypes {
+        self.connection_types.clone()
+    }
+
+    /// Handle to the roles peers have proven in the handshake
+    pub fn peer_roles(&self) -> PeerRoles {
+        self.peer_roles.clone()
+    }
+
+    /// Role the peer has proven in the handshake, if it's connected
+    pub fn peer_role(&self, peer_id: &PeerId) -> Option<NodeRole> {
+        self.peer_roles.get(peer_id)
+    }
+
+    pub fn handshake_enabled(&self) -> bool {
+        self.inner.handshake.is_enabled()
+    }
+
+    /// Role proven to other peers in the handshake
+    pub fn set_role(&mut self, role: NodeRole) {
+        if let Some(handshake) = self.inner.handshake.as_mut() {
+            handshake.set_local_role(role);
+        }
+    }
+
+    /// Accept the role from the peer without checking its on-chain registration,
+    /// e.g. for the scheduler
+    pub fn trust_role(&mut self, peer_id: PeerId, role: NodeRole) {
+        if let Some(handshake) = self.inner.handshake.as_mut() {
+            handshake.trust_role(peer_id, role);
+        }
+    }
+
+    /// Use the tracker which accounts the bytes exchanged over the swarm's transport
+    pub fn set_bandwidth_tracker(&mut self, tracker: BandwidthTracker) {
+        self.bandwidth = tracker;
+    }
+
+    /// Listen through a single relay chosen by the selector instead of fixed relay addresses
+    pub fn set_relay_selector(&mut self, selector: RelaySelector) {
+        self.relay_selector = Some(selector);
+    }
+
+    /// Handle to the per-peer bandwidth stats
+    pub fn bandwidth_tracker(&self) -> BandwidthTracker {
+        self.bandwidth.clone()
+    }
+
+    /// Handle to the per-peer reconnection backoff (empty if the backoff is disabled)
+    pub fn reconnect_backoffs(&self) -> ReconnectBackoffs {
+        self.reconnect_backoffs.clone().unwrap_or_default()
+    }
+
+    /// Handle to the peer blocks. Blocked peers are neither dialed nor accepted.
+    pub fn peer_blocks(&self) -> PeerBlocks {
+        self.peer_blocks.clone()
+    }
+
+    /// Handle to the per-peer rates of inbound substreams, fed by the request servers
+    pub fn substream_rates(&self) -> SubstreamRates {
+        self.substream_rates.clone()
+    }
+
+    /// Sign a record with the ranges of the dataset and put it into the DHT along with
+    /// a provider record, so that gateways can find this node. The records are re-published
+    /// until the node stops, replacing the previously advertised ranges of the dataset.
+    pub fn advertise_dataset(&mut self, ranges: DatasetRanges) {
+        self.put_dataset_record(&ranges);
+        self.advertised_datasets.insert(ranges.url.clone(), ranges);
+    }
+
+    /// Find the workers which have advertised the dataset in the DHT. Records which aren't
+    /// correctly signed by the worker, or have expired, are skipped.
+    pub fn find_dataset_providers(
+        &mut self,
+        dataset: String,
+        result_tx: oneshot::Sender<Vec<DatasetProvider>>,
+    ) {
+        log::debug!("Looking up providers of dataset {dataset}");
+        let query_id = self.inner.kademlia.get_providers(dataset_providers_key(&dataset));
+        self.dataset_lookups.insert(
+            query_id,
+            DatasetLookup {
+                dataset,
+                result_tx,
+                providers: Default::default(),
+                found: Default::default(),
+                pending_records: 0,
+                providers_done: false,
+            },
+        );
+    }
+
+    /// Receiver of the current number of peers in the Kademlia routing table
+    pub fn routing_table_size(&self) -> watch::Receiver<usize> {
+        self.routing_table_size.subscribe()
+    }
+
+    /// Reachability determined by AutoNAT
+    pub fn nat_state(&self) -> watch::Receiver<NatState> {
+        self.nat_state.subscribe()
+    }
+
+    /// Stop serving DHT requests, so that other peers stop learning about the node from it.
+    /// Existing connections are kept open.
+    pub fn stop_advertising(&mut self) {
+        log::info!("Switching Kademlia to client mode");
+        self.inner.kademlia.set_mode(Some(kad::Mode::Client));
+    }
+
+    /// Add peer to the Kademlia routing table and make sure it stays there. If the peer gets
+    /// evicted, it will be re-added. Calling this again for the same peer replaces its addresses.
+    pub fn pin_peer(&mut self, peer_id: PeerId, addrs: impl IntoIterator<Item = Multiaddr>) {
+        let addrs: Vec<Multiaddr> = addrs.into_iter().collect();
+        log::info!("Pinning peer {peer_id} with addresses {addrs:?}");
+        if let Some(old_addrs) = self.pinned_peers.remove(&peer_id) {
+            for addr in old_addrs.iter().filter(|a| !addrs.contains(a)) {
+                self.inner.kademlia.remove_address(&peer_id, addr);
+            }
+        }
+        for addr in &addrs {
+            self.inner.kademlia.add_address(&peer_id, addr.clone());
+        }
+        self.pinned_peers.insert(peer_id, addrs);
+    }
+
+    /// Stop keeping the peer in the routing table. It may still be evicted later.
+    pub fn unpin_peer(&mut self, peer_id: &PeerId) {
+        log::info!("Unpinning peer {peer_id}");
+        self.pinned_peers.remove(peer_id);
+    }
+
+    pub fn pinned_peers(&self) -> Vec<PeerId> {
+        self.pinned_peers.keys().copied().collect()
+    }
+
+    /// Add a boot node: allow it, use it as an autonat server and pin it in the routing table.
+    /// Returns `false` if the node is already known with the same address.
+    pub fn add_boot_node(&mut self, node: BootNode) -> bool {
+        let BootNode { peer_id, address } = node.clone();
+        if self
+            .boot_nodes
+            .borrow()
+            .iter()
+            .any(|n| n.peer_id == peer_id && n.address == address)
+        {
+            log::debug!("Boot node {peer_id} already added");
+            return false;
+        }
+        log::info!("Adding boot node {peer_id} at {address}");
+        self.allow_peer(peer_id);
+        if let Some(autonat) = self.inner.autonat.as_mut() {
+            autonat.add_server(peer_id, Some(address.clone()));
+        }
+        self.pin_peer(peer_id, [address]);
+        self.boot_nodes.send_modify(|nodes| {
+            nodes.retain(|n| n.peer_id != peer_id);
+            nodes.push(node);
+        });
+        true
+    }
+
+    /// Stop using the peer as a boot node. The peer stays allowed, because it may
+    /// have been allowed for another reason.
+    pub fn remove_boot_node(&mut self, peer_id: &PeerId) {
+        let removed = self.boot_nodes.send_if_modified(|nodes| {
+            let len = nodes.len();
+            nodes.retain(|n| n.peer_id != *peer_id);
+            nodes.len() != len
+        });
+        if !removed {
+            return log::warn!("Peer {peer_id} is not a boot node");
+        }
+        log::info!("Removing boot node {peer_id}");
+        self.unpin_peer(peer_id);
+        if let Some(autonat) = self.inner.autonat.as_mut() {
+            autonat.remove_server(peer_id);
+        }
+        if self.boot_nodes.borrow().is_empty() {
+            log::warn!("No boot nodes left");
+        }
+    }
+
+    /// Apply the update. Returns the boot node to connect to, if one has been added.
+    pub fn update_boot_nodes(&mut self, update: BootNodeUpdate) -> Option<BootNode> {
+        match update {
+            BootNodeUpdate::Add(node) => self.add_boot_node(node.clone()).then_some(node),
+            BootNodeUpdate::Remove(peer_id) => {
+                self.remove_boot_node(&peer_id);
+                None
+            }
+            BootNodeUpdate::Bootstrap(result_tx) => {
+                self.bootstrap(result_tx);
+                None
+            }
+        }
+    }
+
+    fn bootstrap(&mut self, result_tx: oneshot::Sender<Result<(), BootstrapError>>) {
+        match self.inner.kademlia.bootstrap() {
+            Ok(query_id) => {
+                log::info!("Bootstrapping the routing table");
+                self.ongoing_bootstraps.insert(query_id, result_tx);
+            }
+            Err(kad::NoKnownPeers()) => {
+                log::warn!("Cannot bootstrap: no known peers");
+                _ = result_tx.send(Err(BootstrapError::NoKnownPeers));
+            }
+        }
+    }
+
+    /// Receiver of the current boot node set
+    pub fn boot_nodes(&self) -> watch::Receiver<Vec<BootNode>> {
+        self.boot_nodes.subscribe()
+    }
+
+    /// Always accept connections from the peer, even over the connection cap.
+    /// Boot nodes are protected without this.
+    pub fn protect_peer(&mut self, peer_id: PeerId) {
+        self.protected_peers.insert(peer_id);
+    }
+
     pub fn allow_peer(&mut self, peer_id: PeerId) {
         log::info!("Allowing peer {peer_id}");
         self.inner.allow.allow_peer(peer_id);
@@ -272,17 +1671,22 @@ impl BaseBehaviour {
 
 #[derive(Debug, Clone)]
 pub enum BaseBehaviourEvent {
+    // Pub-sub events carry the raw message, so that actors route it to the topic streams
+    // (see `BaseBehaviour::route_to_topic_stream`) once they've validated it
     Ping {
         peer_id: PeerId,
         ping: Ping,
+        msg: PubsubMsg,
     },
     WorkerQueryLogs {
         peer_id: PeerId,
         query_logs: QueryLogs,
+        msg: PubsubMsg,
     },
     LogsCollected {
         peer_id: PeerId,
         logs_collected: LogsCollected,
+        msg: PubsubMsg,
     },
     PeerProbed {
         peer_id: PeerId,
@@ -292,6 +1696,15 @@ pub enum BaseBehaviourEvent {
         peer_id: PeerId,
         protocols: Vec<StreamProtocol>,
     },
+    PeerSubscriptionChanged {
+        peer_id: PeerId,
+        topic: &'static str,
+        subscribed: bool,
+    },
+    PeerAuthenticated {
+        peer_id: PeerId,
+        role: NodeRole,
+    },
 }
 
 impl BehaviourWrapper for BaseBehaviour {
@@ -306,11 +1719,43 @@ impl BehaviourWrapper for BaseBehaviour {
         match ev {
             FromSwarm::ConnectionEstablished(conn) => self.on_connection_established(conn),
             FromSwarm::ConnectionClosed(conn) => self.on_connection_closed(conn),
-            FromSwarm::DialFailure(DialFailure { peer_id, error, .. }) => {
+            FromSwarm::DialFailure(DialFailure {
+                peer_id,
+                error,
+                connection_id,
+            }) => {
+                self.dial_queue.on_dial_finished(connection_id);
+                // Kademlia removes peers without working addresses without emitting an event
+                if let Some(peer_id) = peer_id {
+                    self.restore_pinned_peer(peer_id);
+                }
+                self.update_routing_table_size();
+                let kind = DialErrorKind::from(error);
+                record_connection_failure(kind);
                 log::debug!(
-                    "Failed to dial {}: {error:?}",
-                    peer_id.map(PeerId::to_base58).unwrap_or_default()
+                    "Failed to dial {} ({}): {error:?}",
+                    peer_id.map(PeerId::to_base58).unwrap_or_default(),
+                    kind.as_str()
                 );
+                self.connection_events.emit(ConnectionEvent::Failed {
+                    peer_id,
+                    direction: ConnectionDirection::Outbound,
+                    error: error.to_string(),
+                });
+                None
+            }
+            FromSwarm::ListenFailure(ListenFailure { error, .. }) => {
+                self.connection_events.emit(ConnectionEvent::Failed {
+                    peer_id: None,
+                    direction: ConnectionDirection::Inbound,
+                    error: error.to_string(),
+                });
+                None
+            }
+            FromSwarm::ListenerClosed(ListenerClosed { listener_id, .. }) => {
+                if let Some(selector) = &mut self.relay_selector {
+                    selector.on_listener_closed(listener_id);
+                }
                 None
             }
             _ => None,
@@ -326,27 +1771,75 @@ impl BehaviourWrapper for BaseBehaviour {
             InnerBehaviourEvent::Kademlia(ev) => self.on_kademlia_event(ev),
             InnerBehaviourEvent::Autonat(ev) => self.on_autonat_event(ev),
             InnerBehaviourEvent::Pubsub(ev) => self.on_pubsub_event(ev),
+            InnerBehaviourEvent::RelayServer(ev) => self.on_relay_server_event(ev),
             InnerBehaviourEvent::Ping(ev) => {
                 record_event(&ev);
+                if let Ok(rtt) = &ev.result {
+                    self.clock_skew.record_rtt(ev.peer, *rtt);
+                    if let Some(selector) = &mut self.relay_selector {
+                        selector.on_ping(ev.peer, *rtt);
+                    }
+                }
                 None
             }
             InnerBehaviourEvent::Dcutr(ev) => {
                 record_event(&ev);
+                self.on_dcutr_event(ev);
                 None
             }
+            InnerBehaviourEvent::Handshake(PeerAuthenticated { peer_id, role }) => {
+                Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerAuthenticated {
+                    peer_id,
+                    role,
+                }))
+            }
             _ => None,
         }
     }
 
     fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
         loop {
+            if let Some((old_listener, opts)) =
+                self.relay_selector.as_mut().and_then(|s| s.poll(cx))
+            {
+                let remove = old_listener.map(|id| ToSwarm::RemoveListener { id });
+                return Poll::Ready(
+                    remove.into_iter().chain([ToSwarm::ListenOn { opts }]).collect::<Vec<_>>(),
+                );
+            }
+
             match self.active_nodes_stream.poll_next_unpin(cx) {
                 Poll::Ready(Some(res)) => {
                     self.on_nodes_update(res);
                     continue;
                 }
-                Poll::Pending => {}
-                _ => unreachable!(), // infinite stream
+                Poll::Pending => {}
+                _ => unreachable!(), // infinite stream
+            }
+
+            if self.peer_blocks_sweep.poll_tick(cx).is_ready() {
+                for peer_id in self.peer_blocks.sweep() {
+                    log::info!("Block of peer {peer_id} expired");
+                }
+            }
+
+            if self.dataset_republish.poll_tick(cx).is_ready() {
+                self.republish_datasets();
+            }
+
+            for peer_id in self.peer_waiters.poll_to_dial(cx) {
+                self.find_and_dial(peer_id);
+            }
+
+            if let Some(opts) = self.pause.poll_dial(&mut self.dial_queue, cx) {
+                return Poll::Ready(vec![ToSwarm::Dial { opts }]);
+            }
+
+            if let Some((peer_id, query_id)) = self.ongoing_queries.poll_expired(cx) {
+                if let Some(ev) = self.on_lookup_expired(peer_id, query_id) {
+                    return Poll::Ready(vec![ev]);
+                }
+                continue;
             }
 
             match self.probe_timeouts.poll_unpin(cx) {
@@ -354,12 +1847,12 @@ impl BehaviourWrapper for BaseBehaviour {
                     #[cfg(feature = "metrics")]
                     ONGOING_PROBES.dec();
                     log::debug!("Probe for peer {peer_id} timed out");
-                    return Poll::Ready(Some(ToSwarm::GenerateEvent(
+                    return Poll::Ready(vec![ToSwarm::GenerateEvent(
                         BaseBehaviourEvent::PeerProbed {
                             peer_id,
                             reachable: false,
                         },
-                    )));
+                    )]);
                 }
                 Poll::Pending => {}
                 _ => unreachable!(), // future::pending() should never complete
@@ -368,12 +1861,72 @@ impl BehaviourWrapper for BaseBehaviour {
             return Poll::Pending;
         }
     }
+
+    fn on_pending_outbound_connection(
+        &mut self,
+        maybe_peer: Option<PeerId>,
+    ) -> Result<(), ConnectionDenied> {
+        self.pause.check_dial()?;
+        if let Some(peer_id) = maybe_peer {
+            self.check_blocked(peer_id)?;
+        }
+        let (Some(peer_id), Some(backoffs)) = (maybe_peer, &self.reconnect_backoffs) else {
+            return Ok(());
+        };
+        match backoffs.check_dial(&peer_id) {
+            Some(remaining) => {
+                log::debug!("Not dialing {peer_id}: reconnection backoff for {remaining:?}");
+                Err(ConnectionDenied::new(DialBackoff { peer_id, remaining }))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn on_established_inbound_connection(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<(), ConnectionDenied> {
+        self.check_blocked(peer_id)?;
+        let protected =
+            self.protected_peers.contains(&peer_id) || self.pinned_peers.contains_key(&peer_id);
+        let established = self.connection_types.count();
+        check_connection_cap(self.max_established_connections, established, protected).map_err(
+            |e| {
+                log::debug!("Rejecting connection from {peer_id}: {e}");
+                #[cfg(feature = "metrics")]
+                CONNECTIONS_REJECTED.inc();
+                ConnectionDenied::new(e)
+            },
+        )
+    }
 }
 
 impl BaseBehaviour {
+    fn check_blocked(&self, peer_id: PeerId) -> Result<(), ConnectionDenied> {
+        match self.peer_blocks.remaining(&peer_id) {
+            Some(remaining) => {
+                log::debug!("Denying connection with {peer_id}: blocked for {remaining:?}");
+                Err(ConnectionDenied::new(PeerBlocked { peer_id, remaining }))
+            }
+            None => Ok(()),
+        }
+    }
+
     fn on_connection_established(&mut self, conn: ConnectionEstablished) -> Option<TToSwarm<Self>> {
         #[cfg(feature = "metrics")]
         ACTIVE_CONNECTIONS.inc();
+        self.dial_queue.on_dial_finished(conn.connection_id);
+        self.peer_waiters.on_connected(conn.peer_id);
+        if let (0, Some(backoffs)) = (conn.other_established, &self.reconnect_backoffs) {
+            backoffs.on_connected(conn.peer_id);
+        }
+        let conn_type = ConnectionType::from_endpoint(conn.endpoint);
+        log::debug!("Established {conn_type:?} connection to {}", conn.peer_id);
+        self.connection_types.insert(conn.peer_id, conn.connection_id, conn_type);
+        self.connection_events.emit(ConnectionEvent::Established {
+            peer_id: conn.peer_id,
+            direction: ConnectionDirection::from_endpoint(conn.endpoint),
+        });
         let peer_id = match conn.endpoint {
             ConnectedPoint::Dialer { .. } => conn.peer_id,
             _ => return None,
@@ -396,6 +1949,23 @@ impl BaseBehaviour {
     fn on_connection_closed(&mut self, conn: ConnectionClosed) -> Option<TToSwarm<Self>> {
         #[cfg(feature = "metrics")]
         ACTIVE_CONNECTIONS.dec();
+        self.connection_types.remove(conn.peer_id, conn.connection_id);
+        self.connection_events.emit(ConnectionEvent::Closed {
+            peer_id: conn.peer_id,
+            direction: ConnectionDirection::from_endpoint(conn.endpoint),
+            remaining: conn.remaining_established,
+        });
+        // Reservations are dropped together with the connection
+        if conn.remaining_established == 0 {
+            self.update_routing_table_size();
+            self.peer_waiters.on_disconnected(conn.peer_id);
+            if self.relay_reservations.remove(&conn.peer_id) {
+                self.update_relay_metrics();
+            }
+            if let Some(backoffs) = &self.reconnect_backoffs {
+                backoffs.on_disconnected(conn.peer_id);
+            }
+        }
         let peer_id = match conn.endpoint {
             ConnectedPoint::Dialer { .. } => conn.peer_id,
             _ => return None,
@@ -408,20 +1978,118 @@ impl BaseBehaviour {
         None
     }
 
-    fn on_identify_event(&mut self, ev: identify::Event) -> Option<TToSwarm<Self>> {
-        log::debug!("Identify event received: {ev:?}");
+    /// Snapshot of the Kademlia routing table, at most `limit` peers with their addresses
+    pub fn routing_table(&mut self, limit: usize) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.routing_table_snapshot(limit)
+            .peers
+            .into_iter()
+            .map(|entry| (entry.peer_id, entry.addrs))
+            .collect()
+    }
+
+    pub fn routing_table_snapshot(&mut self, limit: usize) -> RoutingTableSnapshot {
+        let peers = self
+            .inner
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                let index = bucket.range().0.ilog2().unwrap_or_default();
+                bucket
+                    .iter()
+                    .map(|entry| RoutingTableEntry {
+                        peer_id: *entry.node.key.preimage(),
+                        addrs: entry.node.value.iter().cloned().collect(),
+                        bucket: index,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .take(limit)
+            .collect();
+        RoutingTableSnapshot {
+            local_peer_id: self.keypair.public().to_peer_id(),
+            peers,
+        }
+    }
+
+    fn update_routing_table_size(&mut self) {
+        let size = self.inner.kademlia.kbuckets().map(|b| b.num_entries()).sum();
+        let old_size = self.routing_table_size.send_replace(size);
+        if let Some(ev) = ConnectionEvent::routing_table_change(old_size, size) {
+            match ev {
+                ConnectionEvent::RoutingTableEmpty { .. } => {
+                    log::warn!("Routing table is empty, lost all {old_size} peers")
+                }
+                _ => log::info!("Routing table is no longer empty"),
+            }
+            self.connection_events.emit(ev);
+        }
+    }
+
+    fn on_relay_server_event(&mut self, ev: relay::Event) -> Option<TToSwarm<Self>> {
+        log::debug!("Relay server event received: {ev:?}");
         record_event(&ev);
-        let (peer_id, listen_addrs, protocols) = match ev {
-            identify::Event::Received { peer_id, info, .. } => {
-                (peer_id, info.listen_addrs, info.protocols)
+        match ev {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                self.relay_reservations.insert(src_peer_id);
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                self.relay_reservations.remove(&src_peer_id);
+            }
+            relay::Event::CircuitReqAccepted { .. } => self.relay_circuits += 1,
+            relay::Event::CircuitClosed { .. } => {
+                self.relay_circuits = self.relay_circuits.saturating_sub(1)
             }
             _ => return None,
+        }
+        self.update_relay_metrics();
+        None
+    }
+
+    fn update_relay_metrics(&self) {
+        #[cfg(feature = "metrics")]
+        {
+            RELAY_RESERVATIONS.set(self.relay_reservations.len().try_into().unwrap_or(u32::MAX));
+            RELAY_CIRCUITS.set(self.relay_circuits);
+        }
+    }
+
+    fn on_identify_event(&mut self, ev: identify::Event) -> Option<TToSwarm<Self>> {
+        log::debug!("Identify event received: {ev:?}");
+        record_event(&ev);
+        let identify::Event::Received { peer_id, info, .. } = ev else {
+            return None;
         };
+        let identify::Info {
+            listen_addrs,
+            protocols,
+            agent_version,
+            protocol_version,
+            ..
+        } = info;
+        if self.strict_protocol_match && !is_compatible_protocol(ID_PROTOCOL, &protocol_version) {
+            log::warn!("Disconnecting {peer_id} with incompatible protocol {protocol_version}");
+            #[cfg(feature = "metrics")]
+            PROTOCOL_MISMATCHES.inc();
+            self.inner.kademlia.remove_peer(&peer_id);
+            self.update_routing_table_size();
+            return Some(ToSwarm::CloseConnection {
+                peer_id,
+                connection: CloseConnection::All,
+            });
+        }
+        self.peer_info.put(
+            peer_id,
+            PeerInfo {
+                agent_version,
+                protocol_version,
+            },
+        );
         let listen_addrs = listen_addrs.into_iter().filter(addr_is_reachable);
         self.inner.address_cache.put(peer_id, listen_addrs.clone());
         listen_addrs.for_each(|addr| {
             self.inner.kademlia.add_address(&peer_id, addr);
         });
+        self.peer_protocols.put(peer_id, protocols.clone());
         let ev = BaseBehaviourEvent::PeerProtocols { peer_id, protocols };
         Some(ToSwarm::GenerateEvent(ev))
     }
@@ -429,6 +2097,61 @@ impl BaseBehaviour {
     fn on_kademlia_event(&mut self, ev: kad::Event) -> Option<TToSwarm<Self>> {
         log::debug!("Kademlia event received: {ev:?}");
         record_event(&ev);
+        if let kad::Event::RoutingUpdated { .. } = ev {
+            self.update_routing_table_size();
+        }
+        if let kad::Event::RoutingUpdated {
+            old_peer: Some(evicted_peer),
+            ..
+        } = ev
+        {
+            self.restore_pinned_peer(evicted_peer);
+            return None;
+        }
+        if let kad::Event::UnroutablePeer { peer } = ev {
+            self.restore_pinned_peer(peer);
+            return None;
+        }
+        if let kad::Event::OutboundQueryProgressed {
+            id,
+            result: QueryResult::Bootstrap(_),
+            step,
+            ..
+        } = ev
+        {
+            // Each step reports the bootstrap of a single bucket
+            if step.last {
+                if let Some(result_tx) = self.ongoing_bootstraps.remove(&id) {
+                    log::info!("Bootstrap finished");
+                    _ = result_tx.send(Ok(()));
+                }
+            }
+            return None;
+        }
+        if let kad::Event::InboundRequest { request } = ev {
+            self.on_inbound_kad_request(request);
+            return None;
+        }
+        if let kad::Event::OutboundQueryProgressed {
+            id,
+            result: QueryResult::GetProviders(result),
+            step,
+            ..
+        } = ev
+        {
+            self.on_dataset_providers_found(id, result, step.last);
+            return None;
+        }
+        if let kad::Event::OutboundQueryProgressed {
+            id,
+            result: QueryResult::GetRecord(result),
+            step,
+            ..
+        } = ev
+        {
+            self.on_dataset_record_found(id, result, step.last);
+            return None;
+        }
         let kad::Event::OutboundQueryProgressed {
             id: query_id,
             result: QueryResult::GetClosestPeers(result),
@@ -438,10 +2161,7 @@ impl BaseBehaviour {
             return None;
         };
 
-        let peer_id = match self.ongoing_queries.get_by_right(&query_id) {
-            None => return None,
-            Some(peer_id) => peer_id.to_owned(),
-        };
+        let peer_id = self.ongoing_queries.peer(&query_id)?;
         let Some(peer_info) = (match result {
             Ok(GetClosestPeersOk { peers, .. })
             | Err(GetClosestPeersError::Timeout { peers, .. }) => {
@@ -455,80 +2175,313 @@ impl BaseBehaviour {
         self.inner.address_cache.put(peer_id, peer_info.addrs);
 
         log::debug!("Query for peer {peer_id} finished.");
-        self.ongoing_queries.remove_by_right(&query_id);
+        self.ongoing_queries.remove(&query_id);
         #[cfg(feature = "metrics")]
         ONGOING_QUERIES.dec();
-        return Some(ToSwarm::Dial {
-            // Not using the default condition (`DisconnectedAndNotDialing`), because we may want
-            // to establish an outbound connection to the peer despite existing inbound connection.
-            opts: DialOpts::peer_id(peer_id).condition(PeerCondition::NotDialing).build(),
-        });
+        // Not using the default condition (`DisconnectedAndNotDialing`), because we may want
+        // to establish an outbound connection to the peer despite existing inbound connection.
+        let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::NotDialing).build();
+        self.dial_queue.push(opts);
+        None
     }
 
-    fn on_autonat_event(&mut self, ev: autonat::Event) -> Option<TToSwarm<Self>> {
-        log::debug!("AutoNAT event received: {ev:?}");
-        let autonat::Event::StatusChanged { new: status, .. } = ev else {
-            return None;
+    /// Inbound records are filtered, so that dataset records can be verified before they're
+    /// stored. They're stored even if the store is disabled, otherwise the datasets couldn't
+    /// be found. Other records are stored as they are, if the store is enabled.
+    fn on_inbound_kad_request(&mut self, request: InboundRequest) {
+        let result = match request {
+            InboundRequest::PutRecord {
+                source,
+                record: Some(record),
+                ..
+            } => {
+                let is_dataset = is_dataset_key(&record.key);
+                if !is_dataset && !self.store_records {
+                    return;
+                }
+                if is_dataset && verify_dataset_record(&record, self.dataset_record_ttl).is_none() {
+                    log::debug!("Rejecting invalid dataset record from {source}");
+                    return;
+                }
+                self.inner.kademlia.store_mut().put(record)
+            }
+            InboundRequest::AddProvider {
+                record: Some(record),
+            } if self.store_records || is_dataset_key(&record.key) => {
+                self.inner.kademlia.store_mut().add_provider(record)
+            }
+            _ => return,
         };
-        match status {
-            NatStatus::Public(addr) => log::info!("Public address confirmed: {addr}"),
-            NatStatus::Private => log::warn!("Public address check failed."),
-            NatStatus::Unknown => {}
+        if let Err(e) = result {
+            log::debug!("Couldn't store Kademlia record: {e}");
+        }
+    }
+
+    fn put_dataset_record(&mut self, ranges: &DatasetRanges) {
+        let local_peer_id = self.keypair.public().to_peer_id();
+        let mut msg = DatasetRecord {
+            ranges: Some(ranges.clone()),
+            timestamp_ms: timestamp_now(),
+            signature: Default::default(),
+        };
+        msg.sign(&self.keypair);
+        let mut record =
+            kad::Record::new(dataset_record_key(&ranges.url, &local_peer_id), msg.encode_to_vec());
+        record.publisher = Some(local_peer_id);
+        record.expires = Some(Instant::now() + self.dataset_record_ttl);
+        if let Err(e) = self.inner.kademlia.put_record(record, kad::Quorum::One) {
+            log::warn!("Couldn't put the record of dataset {}: {e}", ranges.url);
+            return;
+        }
+        if let Err(e) = self.inner.kademlia.start_providing(dataset_providers_key(&ranges.url)) {
+            log::warn!("Couldn't advertise dataset {}: {e}", ranges.url);
         }
-        None
     }
 
-    fn on_pubsub_event(
+    fn republish_datasets(&mut self) {
+        let datasets: Vec<DatasetRanges> = self.advertised_datasets.values().cloned().collect();
+        if !datasets.is_empty() {
+            log::debug!("Re-publishing {} dataset records", datasets.len());
+        }
+        for ranges in datasets {
+            self.put_dataset_record(&ranges);
+        }
+    }
+
+    /// Start fetching the records of newly found providers
+    fn on_dataset_providers_found(
+        &mut self,
+        query_id: QueryId,
+        result: kad::GetProvidersResult,
+        last: bool,
+    ) {
+        let Some(lookup) = self.dataset_lookups.get_mut(&query_id) else {
+            return;
+        };
+        let providers = match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => providers,
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => Default::default(),
+            Err(e) => {
+                log::debug!("Providers lookup of dataset {} failed: {e:?}", lookup.dataset);
+                Default::default()
+            }
+        };
+        for peer_id in providers {
+            if lookup.providers.insert(peer_id) {
+                let key = dataset_record_key(&lookup.dataset, &peer_id);
+                let record_query_id = self.inner.kademlia.get_record(key);
+                self.dataset_record_queries.insert(record_query_id, query_id);
+                lookup.pending_records += 1;
+            }
+        }
+        lookup.providers_done |= last;
+        self.finish_dataset_lookup(query_id);
+    }
+
+    fn on_dataset_record_found(
         &mut self,
-        PubsubMsg {
+        query_id: QueryId,
+        result: kad::GetRecordResult,
+        last: bool,
+    ) {
+        let Some(&lookup_id) = self.dataset_record_queries.get(&query_id) else {
+            return;
+        };
+        let Some(lookup) = self.dataset_lookups.get_mut(&lookup_id) else {
+            return;
+        };
+        if let Ok(GetRecordOk::FoundRecord(PeerRecord { record, .. })) = result {
+            match verify_dataset_record(&record, self.dataset_record_ttl) {
+                Some(provider) => lookup.found.push(provider),
+                None => log::debug!("Skipping invalid record of dataset {}", lookup.dataset),
+            }
+        }
+        if last {
+            self.dataset_record_queries.remove(&query_id);
+            lookup.pending_records -= 1;
+            self.finish_dataset_lookup(lookup_id);
+        }
+    }
+
+    fn finish_dataset_lookup(&mut self, query_id: QueryId) {
+        let finished = self
+            .dataset_lookups
+            .get(&query_id)
+            .is_some_and(|lookup| lookup.providers_done && lookup.pending_records == 0);
+        if !finished {
+            return;
+        }
+        let Some(lookup) = self.dataset_lookups.remove(&query_id) else {
+            return;
+        };
+        log::debug!("Found {} providers of dataset {}", lookup.found.len(), lookup.dataset);
+        _ = lookup.result_tx.send(lookup.found);
+    }
+
+    /// The peer hasn't been found in time. Waiters and the probe for the peer are resolved
+    /// as not found.
+    fn on_lookup_expired(&mut self, peer_id: PeerId, query_id: QueryId) -> Option<TToSwarm<Self>> {
+        log::debug!("Query for peer {peer_id} timed out");
+        #[cfg(feature = "metrics")]
+        ONGOING_QUERIES.dec();
+        if let Some(mut query) = self.inner.kademlia.query_mut(&query_id) {
+            query.finish();
+        }
+        self.peer_waiters.on_not_found(peer_id);
+        self.probe_timeouts.remove(peer_id)?;
+        #[cfg(feature = "metrics")]
+        ONGOING_PROBES.dec();
+        log::debug!("Probe for peer {peer_id} failed: peer not found");
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed {
             peer_id,
-            topic,
-            data,
-        }: PubsubMsg,
-    ) -> Option<TToSwarm<Self>> {
+            reachable: false,
+        }))
+    }
+
+    /// Put the pinned peer back into the routing table after it's been evicted,
+    /// found unroutable, or dropped by Kademlia because its addresses failed
+    fn restore_pinned_peer(&mut self, peer_id: PeerId) {
+        let Some(addrs) = self.pinned_peers.get(&peer_id) else {
+            return;
+        };
+        log::debug!("Re-adding pinned peer {peer_id} to the routing table");
+        for addr in addrs {
+            if let kad::RoutingUpdate::Failed =
+                self.inner.kademlia.add_address(&peer_id, addr.clone())
+            {
+                log::warn!("Cannot re-add pinned peer {peer_id} with address {addr}");
+            }
+        }
+        self.update_routing_table_size();
+    }
+
+    fn on_dcutr_event(&mut self, ev: dcutr::Event) {
+        let conn_id = match ev.result {
+            Ok(conn_id) => conn_id,
+            Err(e) => return log::debug!("Hole punching to {} failed: {e:?}", ev.remote_peer_id),
+        };
+        log::debug!("Hole punched to {}", ev.remote_peer_id);
+        self.connection_types.set_hole_punched(ev.remote_peer_id, conn_id);
+    }
+
+    fn on_autonat_event(&mut self, ev: autonat::Event) -> Option<TToSwarm<Self>> {
+        log::debug!("AutoNAT event received: {ev:?}");
+        if let autonat::Event::StatusChanged { new: status, .. } = &ev {
+            match status {
+                NatStatus::Public(addr) => log::info!("Public address confirmed: {addr}"),
+                NatStatus::Private => log::warn!("Public address check failed."),
+                NatStatus::Unknown => {}
+            }
+        }
+        // Confidence changes with each probe, not only with the status
+        let autonat = self.inner.autonat.as_ref()?;
+        let state =
+            NatState::new(autonat.nat_status(), autonat.confidence(), self.autonat_confidence);
+        let old_state = self.nat_state.send_replace(state.clone());
+        if state.confirmed && !(old_state.confirmed && old_state.reachability == state.reachability)
+        {
+            log::info!("Reachability confirmed: {:?}", state.reachability);
+            self.connection_events.emit(ConnectionEvent::ReachabilityConfirmed {
+                reachability: state.reachability,
+            });
+        }
+        None
+    }
+
+    fn on_pubsub_event(&mut self, ev: PubsubEvent) -> Option<TToSwarm<Self>> {
+        match ev {
+            PubsubEvent::Message(msg) => self.on_pubsub_msg(msg),
+            PubsubEvent::SubscriptionChanged {
+                peer_id,
+                topic,
+                subscribed,
+            } => Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerSubscriptionChanged {
+                peer_id,
+                topic,
+                subscribed,
+            })),
+        }
+    }
+
+    fn on_pubsub_msg(&mut self, msg: PubsubMsg) -> Option<TToSwarm<Self>> {
+        let (peer_id, topic) = (msg.peer_id, msg.topic);
         log::debug!("Pub-sub message received: peer_id={peer_id} topic={topic}");
-        let data = data.as_ref();
         let ev = match topic {
-            PING_TOPIC => decode_ping(peer_id, data)?,
-            WORKER_LOGS_TOPIC => decode_worker_logs_msg(peer_id, data)?,
-            LOGS_COLLECTED_TOPIC => decode_logs_collected(peer_id, data)?,
+            PING_TOPIC => decode_ping(msg)?,
+            WORKER_LOGS_TOPIC => decode_worker_logs_msg(msg, self.reject_legacy)?,
+            LOGS_COLLECTED_TOPIC => decode_logs_collected(msg)?,
             _ => return None,
         };
+        if let BaseBehaviourEvent::WorkerQueryLogs { query_logs, .. } = &ev {
+            let latest = query_logs.queries_executed.iter().filter_map(|q| q.timestamp_ms).max();
+            if let Some(timestamp_ms) = latest {
+                self.clock_skew.record_timestamp(peer_id, timestamp_ms);
+            }
+        }
         Some(ToSwarm::GenerateEvent(ev))
     }
+
+    /// Deliver a message accepted by the actor to the topic streams, if there are any,
+    /// instead of passing the actor's event on to the event stream. Returns the event
+    /// if the message hasn't been routed.
+    pub fn route_to_topic_stream<E>(&self, msg: &PubsubMsg, ev: E) -> Option<E> {
+        (!self.topic_streams.route(msg)).then_some(ev)
+    }
 }
 
-fn decode_ping(peer_id: PeerId, data: &[u8]) -> Option<BaseBehaviourEvent> {
-    let mut ping = Ping::decode(data).map_err(|e| log::warn!("Error decoding ping: {e:?}")).ok()?;
+fn decode_ping(msg: PubsubMsg) -> Option<BaseBehaviourEvent> {
+    let peer_id = msg.peer_id;
+    let mut ping = Ping::decode(msg.data.as_ref())
+        .map_err(|e| log::warn!("Error decoding ping: {e:?}"))
+        .ok()?;
     if !ping.verify_signature(&peer_id) {
         log::warn!("Invalid ping signature from {peer_id}");
         return None;
     }
-    Some(BaseBehaviourEvent::Ping { peer_id, ping })
+    Some(BaseBehaviourEvent::Ping { peer_id, ping, msg })
 }
 
-fn decode_worker_logs_msg(peer_id: PeerId, data: &[u8]) -> Option<BaseBehaviourEvent> {
-    let msg = WorkerLogsMsg::decode(data)
+fn decode_worker_logs_msg(msg: PubsubMsg, reject_legacy: bool) -> Option<BaseBehaviourEvent> {
+    let peer_id = msg.peer_id;
+    let logs_msg = WorkerLogsMsg::decode(msg.data.as_ref())
         .map_err(|e| log::warn!("Error decoding worker logs: {e:?}"))
         .ok()?;
-    match msg.msg {
+    match logs_msg.msg {
         Some(worker_logs_msg::Msg::QueryLogs(query_logs)) => {
             Some(BaseBehaviourEvent::WorkerQueryLogs {
                 peer_id,
                 query_logs,
+                msg,
             })
         }
-        _ => None,
+        // Legacy copy of a message which is also published on its own topic
+        Some(worker_logs_msg::Msg::LogsCollected(_)) => {
+            #[cfg(feature = "metrics")]
+            record_legacy_message("inbound", "logs_collected");
+            if reject_legacy {
+                log::debug!("Dropping legacy logs collected message from {peer_id}");
+            }
+            None
+        }
+        None => None,
     }
 }
 
-fn decode_logs_collected(peer_id: PeerId, data: &[u8]) -> Option<BaseBehaviourEvent> {
-    let logs_collected = LogsCollected::decode(data)
+#[cfg(feature = "metrics")]
+fn record_legacy_message(direction: &'static str, msg_type: &'static str) {
+    LEGACY_MESSAGES
+        .get_or_create(&vec![("direction", direction), ("msg_type", msg_type)])
+        .inc();
+}
+
+fn decode_logs_collected(msg: PubsubMsg) -> Option<BaseBehaviourEvent> {
+    let logs_collected = LogsCollected::decode(msg.data.as_ref())
         .map_err(|e| log::warn!("Error decoding logs collected msg: {e:?}"))
         .ok()?;
     Some(BaseBehaviourEvent::LogsCollected {
-        peer_id,
+        peer_id: msg.peer_id,
         logs_collected,
+        msg,
     })
 }
 
@@ -564,8 +2517,415 @@ fn bundle_messages<T: prost::Message>(
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
     use super::*;
 
+    #[tokio::test]
+    async fn test_wait_for_peer() {
+        let waiters = PeerWaiters::default();
+        let peer_id = PeerId::random();
+        let timeout = Duration::from_millis(10);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(waiters.wait_for(peer_id, timeout).await.is_err());
+        assert_eq!(waiters.poll_to_dial(&mut cx), vec![peer_id]);
+
+        // The peer is dialed once, even if several tasks are waiting for it
+        let rx1 = waiters.register(peer_id);
+        let rx2 = waiters.register(peer_id);
+        assert_eq!(waiters.poll_to_dial(&mut cx), vec![peer_id]);
+
+        waiters.on_connected(peer_id);
+        assert!(rx1.await.is_ok());
+        assert!(rx2.await.is_ok());
+        assert!(waiters.wait_for(peer_id, timeout).await.is_ok());
+        assert!(waiters.poll_to_dial(&mut cx).is_empty());
+
+        waiters.on_disconnected(peer_id);
+        assert!(waiters.wait_for(peer_id, timeout).await.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_old_config() {
+        let json = serde_json::json!({
+            "nodes_update_interval": { "secs": 10, "nanos": 0 },
+            "autonat_timeout": { "secs": 60, "nanos": 0 },
+            "identify_interval": { "secs": 60, "nanos": 0 },
+            "request_timeout": { "secs": 60, "nanos": 0 },
+            "probe_timeout": { "secs": 60, "nanos": 0 },
+            "max_concurrent_probes": 1000,
+            "max_pubsub_msg_size": 65536,
+            "addr_cache_size": 1024,
+        });
+        let config: BaseConfig = serde_json::from_value(json).unwrap();
+        let default = BaseConfig::default();
+        assert_eq!(config.nodes_update_interval, Duration::from_secs(10));
+        assert_eq!(config.relay_client, default.relay_client);
+        assert_eq!(config.dataset_record_ttl, default.dataset_record_ttl);
+        assert_eq!(config.max_concurrent_dials, default.max_concurrent_dials);
+        // Opt-in features stay off for nodes which haven't configured them
+        assert!(config.reconnect_backoff.is_none());
+    }
+
+    #[test]
+    fn test_connection_types() {
+        let types = ConnectionTypes::default();
+        let peer_id = PeerId::random();
+        let [conn1, conn2] = [ConnectionId::new_unchecked(1), ConnectionId::new_unchecked(2)];
+
+        types.insert(peer_id, conn1, ConnectionType::Relayed);
+        types.insert(peer_id, conn2, ConnectionType::Direct);
+        types.set_hole_punched(peer_id, conn2);
+        let mut conns = types.get(&peer_id);
+        conns.sort_by_key(|t| *t as u8);
+        assert_eq!(conns, [ConnectionType::Relayed, ConnectionType::HolePunched]);
+
+        types.remove(peer_id, conn1);
+        types.remove(peer_id, conn2);
+        // Closed connections aren't brought back
+        types.set_hole_punched(peer_id, conn2);
+        assert!(types.get(&peer_id).is_empty());
+        assert!(types.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_all() {
+        let waiters = PeerWaiters::default();
+        let [peer1, peer2, peer3] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        waiters.on_connected(peer2);
+
+        let results: Vec<_> = waiters
+            .check_all([peer1, peer2, peer3], 2, Duration::from_millis(10))
+            .collect()
+            .await;
+        assert_eq!(results, [(peer2, true), (peer1, false), (peer3, false)]);
+        let waker = futures::task::noop_waker();
+        assert_eq!(waiters.poll_to_dial(&mut Context::from_waker(&waker)), vec![peer1, peer3]);
+    }
+
+    #[tokio::test]
+    async fn test_topic_streams() {
+        let streams = TopicStreams::default();
+        let msg = PubsubMsg {
+            peer_id: PeerId::random(),
+            topic: PING_TOPIC,
+            data: Box::new([1, 2, 3]),
+        };
+        assert!(!streams.route(&msg));
+
+        let mut rx = streams.register(PING_TOPIC);
+        let other_rx = streams.register(WORKER_LOGS_TOPIC);
+        assert!(streams.route(&msg));
+        assert_eq!(rx.try_recv().map(|msg| msg.data), Some(msg.data.clone()));
+
+        drop(rx);
+        drop(other_rx);
+        assert!(!streams.route(&msg));
+        assert!(streams.0.lock().unwrap().streams.get(PING_TOPIC).is_none());
+    }
+
+    #[test]
+    fn test_topic_stream_capacity() {
+        let streams = TopicStreams::default();
+        let msg = |topic| PubsubMsg {
+            peer_id: PeerId::random(),
+            topic,
+            data: Box::new([]),
+        };
+        let _pings_rx = streams.register_with_capacity(PING_TOPIC, 2);
+        let mut logs_rx = streams.register_with_capacity(WORKER_LOGS_TOPIC, 1);
+        for _ in 0..5 {
+            streams.route(&msg(PING_TOPIC));
+        }
+        // A flood of pings doesn't affect the other topic
+        assert!(streams.route(&msg(WORKER_LOGS_TOPIC)));
+        assert!(logs_rx.try_recv().is_some());
+        assert_eq!(streams.dropped(PING_TOPIC), 3);
+        assert_eq!(streams.dropped(WORKER_LOGS_TOPIC), 0);
+    }
+
+    #[test]
+    fn test_connection_events() {
+        let events = ConnectionEvents::default();
+        let peer_id = PeerId::random();
+        let established = ConnectionEvent::Established {
+            peer_id,
+            direction: ConnectionDirection::Inbound,
+        };
+        events.emit(established.clone());
+
+        let mut rx = events.subscribe();
+        let slow_rx = events.subscribe();
+        events.emit(established.clone());
+        assert_eq!(rx.try_recv(), Some(established.clone()));
+        for _ in 0..CONNECTION_EVENTS_QUEUE_SIZE {
+            events.emit(established.clone());
+        }
+        // Only the stream which isn't consumed drops events
+        assert_eq!(events.dropped(), 1);
+
+        drop(slow_rx);
+        drop(rx);
+        events.emit(established);
+        assert!(events.0.lock().unwrap().streams.is_empty());
+    }
+
+    #[test]
+    fn test_connection_cap() {
+        let types = ConnectionTypes::default();
+        let peer_id = PeerId::random();
+        for i in 0..3 {
+            types.insert(peer_id, ConnectionId::new_unchecked(i), ConnectionType::Direct);
+        }
+        types.insert(PeerId::random(), ConnectionId::new_unchecked(3), ConnectionType::Relayed);
+        assert_eq!(types.count(), 4);
+
+        assert_eq!(check_connection_cap(None, types.count(), false), Ok(()));
+        assert_eq!(check_connection_cap(Some(5), types.count(), false), Ok(()));
+        assert_eq!(
+            check_connection_cap(Some(4), types.count(), false),
+            Err(ConnectionCapReached { max: 4 })
+        );
+        // Protected peers are accepted over the cap
+        assert_eq!(check_connection_cap(Some(4), types.count(), true), Ok(()));
+
+        types.remove(peer_id, ConnectionId::new_unchecked(0));
+        assert_eq!(check_connection_cap(Some(4), types.count(), false), Ok(()));
+    }
+
+    #[test]
+    fn test_routing_table_change() {
+        assert_eq!(ConnectionEvent::routing_table_change(0, 0), None);
+        assert_eq!(ConnectionEvent::routing_table_change(3, 5), None);
+        assert_eq!(
+            ConnectionEvent::routing_table_change(0, 2),
+            Some(ConnectionEvent::RoutingTablePopulated { peer_count: 2 })
+        );
+        assert_eq!(
+            ConnectionEvent::routing_table_change(4, 0),
+            Some(ConnectionEvent::RoutingTableEmpty { last_peer_count: 4 })
+        );
+    }
+
+    #[cfg(feature = "actors")]
+    #[tokio::test]
+    async fn test_pinned_peer_restored() {
+        use crate::actors::testing::{new_test_swarm, random_memory_addr};
+
+        let mut swarm = new_test_swarm(|_, base| Wrapped::from(base));
+        let base: &mut BaseBehaviour = swarm.behaviour_mut();
+        let peer_id = PeerId::random();
+        let addr = random_memory_addr();
+        let routed = |base: &mut BaseBehaviour| {
+            base.routing_table(usize::MAX).into_iter().find(|(peer, _)| *peer == peer_id)
+        };
+        base.pin_peer(peer_id, [addr.clone()]);
+        assert_eq!(routed(base), Some((peer_id, vec![addr.clone()])));
+
+        base.inner.kademlia.remove_peer(&peer_id);
+        base.on_kademlia_event(kad::Event::UnroutablePeer { peer: peer_id });
+        assert_eq!(routed(base), Some((peer_id, vec![addr.clone()])));
+        assert_eq!(*base.routing_table_size().borrow(), 1);
+
+        // Peers which aren't pinned anymore are left out
+        base.unpin_peer(&peer_id);
+        base.inner.kademlia.remove_peer(&peer_id);
+        base.on_kademlia_event(kad::Event::UnroutablePeer { peer: peer_id });
+        assert_eq!(routed(base), None);
+    }
+
+    #[test]
+    fn test_validate_dataset_record_ttl() {
+        assert!(validate_dataset_record_ttl(Duration::ZERO).is_err());
+        assert!(validate_dataset_record_ttl(Duration::from_nanos(1)).is_err());
+        assert!(validate_dataset_record_ttl(MIN_DATASET_RECORD_TTL).is_ok());
+        assert!(validate_dataset_record_ttl(BaseConfig::default().dataset_record_ttl).is_ok());
+    }
+
+    #[test]
+    fn test_dataset_record_verification() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let ttl = Duration::from_secs(3600);
+        let ranges = DatasetRanges {
+            url: "s3://moonbeam-evm-1".to_string(),
+            ranges: vec![],
+        };
+        let record = |key: RecordKey, timestamp_ms: u64| {
+            let mut msg = DatasetRecord {
+                ranges: Some(ranges.clone()),
+                timestamp_ms,
+                signature: Default::default(),
+            };
+            msg.sign(&keypair);
+            kad::Record::new(key, msg.encode_to_vec())
+        };
+        let key = dataset_record_key(&ranges.url, &peer_id);
+        assert!(is_dataset_key(&key));
+        assert!(!is_dataset_key(&RecordKey::from(b"other".to_vec())));
+
+        let now = timestamp_now();
+        let provider = verify_dataset_record(&record(key.clone(), now), ttl).unwrap();
+        assert_eq!(provider.peer_id, peer_id);
+        assert_eq!(provider.ranges, ranges);
+
+        // Expired
+        let expired = now - u64::try_from(ttl.as_millis()).unwrap() - 1;
+        assert_eq!(verify_dataset_record(&record(key.clone(), expired), ttl), None);
+        // Put under the key of another worker or dataset
+        let other_worker = dataset_record_key(&ranges.url, &PeerId::random());
+        assert_eq!(verify_dataset_record(&record(other_worker, now), ttl), None);
+        let other_dataset = dataset_record_key("s3://ethereum-mainnet", &peer_id);
+        assert_eq!(verify_dataset_record(&record(other_dataset, now), ttl), None);
+        // Tampered with
+        let mut tampered = record(key, now);
+        tampered.value[0] ^= 1;
+        assert_eq!(verify_dataset_record(&tampered, ttl), None);
+    }
+
+    #[test]
+    fn test_nat_state() {
+        let state = NatState::new(NatStatus::Unknown, 5, 3);
+        assert!(!state.confirmed);
+        let state = NatState::new(NatStatus::Private, 2, 3);
+        assert_eq!(state.reachability, Reachability::Private);
+        assert!(!state.confirmed);
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/12345".parse().unwrap();
+        let state = NatState::new(NatStatus::Public(addr.clone()), 3, 3);
+        assert_eq!(state.reachability, Reachability::Public(addr));
+        assert!(state.confirmed);
+    }
+
+    #[test]
+    fn test_routing_table_diff() {
+        let [a, b, c, d] = [(); 4].map(|_| PeerId::random());
+        let snapshot = |local_peer_id, peers: &[PeerId]| RoutingTableSnapshot {
+            local_peer_id,
+            peers: peers
+                .iter()
+                .map(|peer_id| RoutingTableEntry {
+                    peer_id: *peer_id,
+                    addrs: vec![],
+                    bucket: 255,
+                })
+                .collect(),
+        };
+        let left = snapshot(a, &[b, c]);
+        let right = snapshot(b, &[c, d]);
+
+        let diff = left.diff(&right);
+        assert_eq!(diff.common, vec![c]);
+        assert_eq!(diff.only_left, vec![b]);
+        assert_eq!(diff.only_right, vec![d]);
+        assert!(diff.left_knows_right);
+        assert!(!diff.right_knows_left);
+
+        // Snapshots are meant to be passed around between tools
+        let json = serde_json::to_string(&left).unwrap();
+        assert_eq!(serde_json::from_str::<RoutingTableSnapshot>(&json).unwrap(), left);
+    }
+
+    #[test]
+    fn test_dial_conditions() {
+        // Connected peers are only dialed again if the condition allows extra connections
+        assert!(!dial_needed(PeerCondition::Disconnected, true));
+        assert!(!dial_needed(PeerCondition::DisconnectedAndNotDialing, true));
+        assert!(dial_needed(PeerCondition::NotDialing, true));
+        assert!(dial_needed(PeerCondition::Always, true));
+        for condition in [
+            PeerCondition::Disconnected,
+            PeerCondition::DisconnectedAndNotDialing,
+            PeerCondition::NotDialing,
+            PeerCondition::Always,
+        ] {
+            assert!(dial_needed(condition, false), "{condition:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_deadline() {
+        let mut lookups = PeerLookups::new(Duration::from_millis(50), 2);
+        let (peer1, peer2) = (PeerId::random(), PeerId::random());
+        lookups.insert(peer1, 1);
+        lookups.insert(peer2, 2);
+        assert!(lookups.is_full());
+        assert_eq!(lookups.remove(&2), Some(peer2));
+        assert!(!lookups.contains(&peer2));
+
+        // The lookup for peer1 never finishes, so it's removed after the deadline
+        let expired = futures::future::poll_fn(|cx| match lookups.poll_expired(cx) {
+            Some(expired) => Poll::Ready(expired),
+            None => Poll::Pending,
+        });
+        let expired = tokio::time::timeout(Duration::from_secs(1), expired).await.unwrap();
+        assert_eq!(expired, (peer1, 1));
+        assert!(!lookups.contains(&peer1));
+        assert_eq!(lookups.peer(&1), None);
+        assert!(!lookups.is_full());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_result() {
+        let (update, result) = BootNodeUpdate::bootstrap();
+        let BootNodeUpdate::Bootstrap(result_tx) = update else {
+            panic!("unexpected update: {update:?}");
+        };
+        result_tx.send(Err(BootstrapError::NoKnownPeers)).unwrap();
+        assert_eq!(result.await, Err(BootstrapError::NoKnownPeers));
+
+        // The transport dropped the request
+        let (update, result) = BootNodeUpdate::bootstrap();
+        drop(update);
+        assert_eq!(result.await, Err(BootstrapError::Stopped));
+    }
+
+    #[derive(Default)]
+    struct WakeFlag(AtomicBool);
+
+    impl futures::task::ArcWake for WakeFlag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_dials_while_paused() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let pause = TransportPause::default();
+        let mut dial_queue = DialQueue::new(10);
+        let mut state_rx = pause.subscribe();
+
+        pause.pause(PauseMode::Buffer);
+        assert!(state_rx.buffering());
+        assert_eq!(state_rx.changed().await, Some(PauseMode::Buffer));
+        for _ in 0..3 {
+            dial_queue.push(DialOpts::peer_id(PeerId::random()).build());
+        }
+        assert!(pause.poll_dial(&mut dial_queue, &mut cx).is_none());
+        // Dials started elsewhere (e.g. by request-response) are denied too
+        assert!(pause.check_dial().is_err());
+
+        // Pausing again doesn't notify
+        pause.pause(PauseMode::Buffer);
+        assert!(!state_rx.0.has_changed().unwrap());
+
+        // The behaviour is woken up on resume to start the queued dials
+        let woken = Arc::new(WakeFlag::default());
+        let flag_waker = futures::task::waker(woken.clone());
+        assert!(pause
+            .poll_dial(&mut dial_queue, &mut Context::from_waker(&flag_waker))
+            .is_none());
+        pause.resume();
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert_eq!(state_rx.changed().await, None);
+        assert!(pause.check_dial().is_ok());
+        let dials = std::iter::from_fn(|| pause.poll_dial(&mut dial_queue, &mut cx)).count();
+        assert_eq!(dials, 3);
+    }
+
     #[test]
     fn test_bundle_messages() {
         let messages = vec![vec![0u8; 40], vec![0u8; 40], vec![0u8; 200], vec![0u8; 90]];
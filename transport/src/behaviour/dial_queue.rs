@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    task::{Context, Waker},
+};
+
+use libp2p::swarm::{dial_opts::DialOpts, ConnectionId};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{ONGOING_DIALS, QUEUED_DIALS};
+
+/// Limits the number of dials in progress, so that a burst of peer lookups doesn't exhaust
+/// file descriptors. Dials above the limit wait in the queue until some ongoing dial finishes.
+pub struct DialQueue {
+    max_concurrent: usize,
+    ongoing: HashSet<ConnectionId>,
+    queued: VecDeque<DialOpts>,
+    waker: Option<Waker>,
+}
+
+impl DialQueue {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            ongoing: Default::default(),
+            queued: Default::default(),
+            waker: None,
+        }
+    }
+
+    pub fn push(&mut self, opts: DialOpts) {
+        self.queued.push_back(opts);
+        self.update_metrics();
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The connection has been established or the dial has failed
+    pub fn on_dial_finished(&mut self, connection_id: ConnectionId) {
+        if !self.ongoing.remove(&connection_id) {
+            return;
+        }
+        self.update_metrics();
+        if let Some(waker) = self.waker.take().filter(|_| !self.queued.is_empty()) {
+            waker.wake();
+        }
+    }
+
+    /// Returns the next dial to start, if there is a free slot
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Option<DialOpts> {
+        if self.ongoing.len() >= self.max_concurrent || self.queued.is_empty() {
+            self.waker = Some(cx.waker().clone());
+            return None;
+        }
+        let opts = self.queued.pop_front()?;
+        self.ongoing.insert(opts.connection_id());
+        self.update_metrics();
+        Some(opts)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn update_metrics(&self) {
+        ONGOING_DIALS.set(self.ongoing.len().try_into().unwrap_or(u32::MAX));
+        QUEUED_DIALS.set(self.queued.len().try_into().unwrap_or(u32::MAX));
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn update_metrics(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limit() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut queue = DialQueue::new(10);
+        for _ in 0..100 {
+            queue.push(DialOpts::peer_id(PeerId::random()).build());
+        }
+
+        let mut started: Vec<ConnectionId> = std::iter::from_fn(|| queue.poll(&mut cx))
+            .map(|opts| opts.connection_id())
+            .collect();
+        assert_eq!(started.len(), 10);
+        assert_eq!(queue.queued.len(), 90);
+
+        // Unknown connections don't free slots
+        queue.on_dial_finished(ConnectionId::new_unchecked(usize::MAX));
+        assert!(queue.poll(&mut cx).is_none());
+
+        for _ in 0..3 {
+            queue.on_dial_finished(started.pop().unwrap());
+        }
+        started.extend(std::iter::from_fn(|| queue.poll(&mut cx)).map(|opts| opts.connection_id()));
+        assert_eq!(started.len(), 10);
+        assert_eq!(queue.ongoing.len(), 10);
+
+        while let Some(connection_id) = started.pop() {
+            queue.on_dial_finished(connection_id);
+            started.extend(queue.poll(&mut cx).map(|opts| opts.connection_id()));
+            assert!(queue.ongoing.len() <= 10);
+        }
+        assert!(queue.queued.is_empty());
+        assert!(queue.ongoing.is_empty());
+    }
+}
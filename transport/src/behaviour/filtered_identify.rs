@@ -0,0 +1,114 @@
+use std::task::{Context, Poll};
+
+use libp2p::{
+    core::Endpoint,
+    identify,
+    swarm::{
+        ConnectionDenied, ConnectionId, ExpiredListenAddr, FromSwarm, NetworkBehaviour,
+        NewListenAddr, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+
+use crate::util::AdvertisedAddrs;
+
+/// Identify behaviour which advertises only the listen addresses allowed by the policy.
+/// Filtered addresses are never passed to identify, and neither are they reported as
+/// external address candidates when other peers observe them.
+pub struct FilteredIdentify {
+    inner: identify::Behaviour,
+    policy: AdvertisedAddrs,
+}
+
+impl FilteredIdentify {
+    pub fn new(config: identify::Config, policy: AdvertisedAddrs) -> Self {
+        Self {
+            inner: identify::Behaviour::new(config),
+            policy,
+        }
+    }
+
+    fn allows(&self, addr: &Multiaddr) -> bool {
+        let allowed = self.policy.allows(addr);
+        if !allowed {
+            log::debug!("Not advertising address {addr}");
+        }
+        allowed
+    }
+}
+
+impl NetworkBehaviour for FilteredIdentify {
+    type ConnectionHandler = THandler<identify::Behaviour>;
+    type ToSwarm = identify::Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::NewListenAddr(NewListenAddr { addr, .. })
+            | FromSwarm::ExpiredListenAddr(ExpiredListenAddr { addr, .. })
+                if !self.allows(addr) => {}
+            _ => self.inner.on_swarm_event(event),
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner.on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        loop {
+            match self.inner.poll(cx) {
+                Poll::Ready(ToSwarm::NewExternalAddrCandidate(addr)) if !self.allows(&addr) => {}
+                ev => return ev,
+            }
+        }
+    }
+}
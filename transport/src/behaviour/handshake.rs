@@ -0,0 +1,464 @@
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use contract_client::ClientError;
+use futures_bounded::FuturesMap;
+use libp2p::{
+    identity::Keypair,
+    request_response,
+    request_response::{OutboundFailure, OutboundRequestId, ProtocolSupport},
+    swarm::{behaviour::ConnectionEstablished, ConnectionClosed, FromSwarm, ToSwarm},
+    PeerId,
+};
+use lru::LruCache;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use subsquid_messages::{signatures::SignedMessage, HandshakeChallenge, HandshakeResponse};
+
+use crate::{
+    behaviour::wrapped::{BehaviourWrapper, TToSwarm},
+    codec::ProtoCodec,
+    protocol::{HANDSHAKE_PROTOCOL, MAX_HANDSHAKE_SIZE},
+};
+
+const NONCE_SIZE: usize = 32;
+const MAX_CACHED_REGISTRATIONS: usize = 4096;
+const MAX_CONCURRENT_LOOKUPS: usize = 1000;
+
+/// Role of a node in the network, proven by the peer in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NodeRole {
+    Worker,
+    Gateway,
+    Scheduler,
+}
+
+impl NodeRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeRole::Worker => "worker",
+            NodeRole::Gateway => "gateway",
+            NodeRole::Scheduler => "scheduler",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "worker" => Some(NodeRole::Worker),
+            "gateway" => Some(NodeRole::Gateway),
+            "scheduler" => Some(NodeRole::Scheduler),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeConfig {
+    /// Timeout of the challenge and of the on-chain registration lookup, each
+    pub timeout: Duration,
+    /// How long the on-chain registration of a peer is remembered. Peers reconnecting
+    /// within this time still sign a new challenge, but aren't looked up again.
+    pub registration_ttl: Duration,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            registration_ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Roles of the connected peers which completed the handshake. Shared between
+/// the transport and the transport handles.
+#[derive(Debug, Clone, Default)]
+pub struct PeerRoles(Arc<Mutex<HashMap<PeerId, NodeRole>>>);
+
+impl PeerRoles {
+    pub fn get(&self, peer_id: &PeerId) -> Option<NodeRole> {
+        self.0.lock().unwrap().get(peer_id).copied()
+    }
+
+    fn insert(&self, peer_id: PeerId, role: NodeRole) {
+        self.0.lock().unwrap().insert(peer_id, role);
+    }
+
+    fn remove(&self, peer_id: &PeerId) {
+        self.0.lock().unwrap().remove(peer_id);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAuthenticated {
+    pub peer_id: PeerId,
+    pub role: NodeRole,
+}
+
+type Lookup = (NodeRole, Result<bool, ClientError>);
+
+/// Challenges every newly connected peer to sign a random nonce together with the role it
+/// claims. Claimed worker and gateway roles are checked against the on-chain registration.
+/// Scheduler has no on-chain registration, so it's only accepted from trusted peers.
+/// Trusted peers which don't support the handshake, e.g. running an older version,
+/// are accepted in their trusted roles without it.
+/// Roles are forgotten on disconnect and proven again on reconnect.
+pub struct HandshakeBehaviour {
+    inner: request_response::Behaviour<ProtoCodec<HandshakeChallenge, HandshakeResponse>>,
+    keypair: Keypair,
+    local_role: Option<NodeRole>,
+    trusted: HashMap<PeerId, NodeRole>,
+    client: Arc<dyn contract_client::Client>,
+    registrations: LruCache<(PeerId, NodeRole), (Instant, bool)>,
+    registration_ttl: Duration,
+    challenges: HashMap<OutboundRequestId, Vec<u8>>,
+    lookups: FuturesMap<PeerId, Lookup>,
+    connected: HashSet<PeerId>,
+    roles: PeerRoles,
+}
+
+impl HandshakeBehaviour {
+    pub fn new(
+        keypair: Keypair,
+        client: Box<dyn contract_client::Client>,
+        config: HandshakeConfig,
+        roles: PeerRoles,
+    ) -> Self {
+        let inner = request_response::Behaviour::with_codec(
+            ProtoCodec::new(MAX_HANDSHAKE_SIZE, MAX_HANDSHAKE_SIZE),
+            vec![(HANDSHAKE_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default().with_request_timeout(config.timeout),
+        );
+        Self {
+            inner,
+            keypair,
+            local_role: None,
+            trusted: Default::default(),
+            client: client.into(),
+            registrations: LruCache::new(NonZeroUsize::new(MAX_CACHED_REGISTRATIONS).unwrap()),
+            registration_ttl: config.registration_ttl,
+            challenges: Default::default(),
+            lookups: FuturesMap::new(config.timeout, MAX_CONCURRENT_LOOKUPS),
+            connected: Default::default(),
+            roles,
+        }
+    }
+
+    /// Role proven to the peers challenging this node
+    pub fn set_local_role(&mut self, role: NodeRole) {
+        self.local_role = Some(role);
+    }
+
+    /// Accept the role from the peer without an on-chain lookup
+    pub fn trust_role(&mut self, peer_id: PeerId, role: NodeRole) {
+        self.trusted.insert(peer_id, role);
+    }
+
+    fn on_connection_established(&mut self, peer_id: PeerId) {
+        self.connected.insert(peer_id);
+        let mut nonce = vec![0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let challenge = HandshakeChallenge {
+            nonce: nonce.clone(),
+        };
+        let req_id = self.inner.send_request(&peer_id, challenge);
+        self.challenges.insert(req_id, nonce);
+    }
+
+    fn on_connection_closed(&mut self, peer_id: PeerId) {
+        self.connected.remove(&peer_id);
+        self.roles.remove(&peer_id);
+    }
+
+    fn on_challenge(
+        &mut self,
+        peer_id: PeerId,
+        challenge: HandshakeChallenge,
+        channel: request_response::ResponseChannel<HandshakeResponse>,
+    ) {
+        // The signed payload is domain-separated and the nonce has a fixed size, so that
+        // the signature can't be reused as the signature of any other message
+        if challenge.nonce.len() != NONCE_SIZE {
+            return log::warn!("Invalid handshake challenge from {peer_id}");
+        }
+        let mut response = HandshakeResponse {
+            nonce: challenge.nonce,
+            role: self.local_role.map(|role| role.as_str().to_string()).unwrap_or_default(),
+            signature: vec![],
+        };
+        response.sign(&self.keypair);
+        if self.inner.send_response(channel, response).is_err() {
+            log::debug!("Cannot respond to handshake from {peer_id}: connection closed");
+        }
+    }
+
+    fn on_response(
+        &mut self,
+        peer_id: PeerId,
+        req_id: OutboundRequestId,
+        mut response: HandshakeResponse,
+    ) -> Option<PeerAuthenticated> {
+        let nonce = self.challenges.remove(&req_id)?;
+        if response.nonce != nonce || !response.verify_signature(&peer_id) {
+            log::warn!("Peer {peer_id} failed the handshake: invalid signature");
+            return None;
+        }
+        if response.role.is_empty() {
+            log::debug!("Peer {peer_id} doesn't claim any role");
+            return None;
+        }
+        let Some(role) = NodeRole::parse(&response.role) else {
+            log::warn!("Peer {peer_id} claims unknown role {}", response.role);
+            return None;
+        };
+        if let Some(&trusted_role) = self.trusted.get(&peer_id) {
+            return self.on_verified(peer_id, role, trusted_role == role);
+        }
+        match self.registrations.get(&(peer_id, role)).copied() {
+            Some((checked, registered)) if checked.elapsed() < self.registration_ttl => {
+                return self.on_verified(peer_id, role, registered);
+            }
+            Some(_) => {
+                self.registrations.pop(&(peer_id, role));
+            }
+            None => {}
+        }
+        if self.lookups.contains(peer_id) {
+            log::warn!(
+                "Registration of {peer_id} is already being checked. Claim of role {} ignored",
+                role.as_str()
+            );
+            return None;
+        }
+        let client = self.client.clone();
+        let lookup = async move {
+            let registered = match role {
+                NodeRole::Worker => client.worker_id(peer_id).await.map(|id| !id.is_zero()),
+                NodeRole::Gateway => client.is_gateway_registered(peer_id).await,
+                NodeRole::Scheduler => Ok(false),
+            };
+            (role, registered)
+        };
+        if self.lookups.try_push(peer_id, lookup).is_err() {
+            log::warn!("Cannot check registration of {peer_id}: too many lookups in progress");
+        }
+        None
+    }
+
+    fn on_unsupported(&mut self, peer_id: PeerId) -> Option<PeerAuthenticated> {
+        let Some(&role) = self.trusted.get(&peer_id) else {
+            log::debug!("Peer {peer_id} doesn't support the handshake");
+            return None;
+        };
+        log::info!("Trusted peer {peer_id} doesn't support the handshake, accepting its role");
+        self.on_verified(peer_id, role, true)
+    }
+
+    fn on_lookup_finished(
+        &mut self,
+        peer_id: PeerId,
+        result: Result<Lookup, futures_bounded::Timeout>,
+    ) -> Option<PeerAuthenticated> {
+        let (role, registered) = match result {
+            Ok((role, Ok(registered))) => (role, registered),
+            Ok((role, Err(e))) => {
+                log::warn!("Cannot check registration of {} {peer_id}: {e:?}", role.as_str());
+                return None;
+            }
+            Err(_) => {
+                log::warn!("Checking registration of {peer_id} timed out");
+                return None;
+            }
+        };
+        self.registrations.put((peer_id, role), (Instant::now(), registered));
+        if !self.connected.contains(&peer_id) {
+            return None;
+        }
+        self.on_verified(peer_id, role, registered)
+    }
+
+    fn on_verified(
+        &mut self,
+        peer_id: PeerId,
+        role: NodeRole,
+        verified: bool,
+    ) -> Option<PeerAuthenticated> {
+        if !verified {
+            log::warn!("Peer {peer_id} claims role {} which it doesn't have", role.as_str());
+            return None;
+        }
+        log::debug!("Peer {peer_id} authenticated as {}", role.as_str());
+        self.roles.insert(peer_id, role);
+        Some(PeerAuthenticated { peer_id, role })
+    }
+}
+
+impl BehaviourWrapper for HandshakeBehaviour {
+    type Inner = request_response::Behaviour<ProtoCodec<HandshakeChallenge, HandshakeResponse>>;
+    type Event = PeerAuthenticated;
+
+    fn inner(&mut self) -> &mut Self::Inner {
+        &mut self.inner
+    }
+
+    fn on_swarm_event(&mut self, ev: FromSwarm) -> impl IntoIterator<Item = TToSwarm<Self>> {
+        match ev {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                other_established: 0,
+                ..
+            }) => self.on_connection_established(peer_id),
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => self.on_connection_closed(peer_id),
+            _ => {}
+        }
+        None
+    }
+
+    fn on_inner_event(
+        &mut self,
+        ev: request_response::Event<HandshakeChallenge, HandshakeResponse>,
+    ) -> impl IntoIterator<Item = TToSwarm<Self>> {
+        let authenticated = match ev {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            } => {
+                self.on_challenge(peer, request, channel);
+                None
+            }
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => self.on_response(peer, request_id, response),
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error: OutboundFailure::UnsupportedProtocols,
+            } => {
+                self.challenges.remove(&request_id);
+                self.on_unsupported(peer)
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.challenges.remove(&request_id);
+                log::debug!("Handshake with {peer} failed: {error:?}");
+                None
+            }
+            _ => None,
+        };
+        authenticated.map(ToSwarm::GenerateEvent)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
+        loop {
+            let (peer_id, result) = futures::ready!(self.lookups.poll_unpin(cx));
+            if let Some(authenticated) = self.on_lookup_finished(peer_id, result) {
+                return Poll::Ready(Some(ToSwarm::GenerateEvent(authenticated)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::swarm::{dummy, SwarmEvent};
+    use subsquid_messages::ProstMsg;
+
+    use crate::{
+        actors::testing::{new_memory_swarm, random_memory_addr, NoChainClient},
+        behaviour::wrapped::Wrapped,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_signed_role() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let mut response = HandshakeResponse {
+            nonce: vec![1; NONCE_SIZE],
+            role: NodeRole::Scheduler.as_str().to_string(),
+            signature: vec![],
+        };
+        response.sign(&keypair);
+        assert!(response.verify_signature(&peer_id));
+        assert!(!response.verify_signature(&PeerId::random()));
+        assert_eq!(NodeRole::parse(&response.role), Some(NodeRole::Scheduler));
+
+        // The claimed role can't be changed without the key
+        response.role = NodeRole::Worker.as_str().to_string();
+        assert!(!response.verify_signature(&peer_id));
+
+        // The signature doesn't cover the plain protobuf encoding of the response
+        response.role = NodeRole::Scheduler.as_str().to_string();
+        let mut unsigned = response.clone();
+        unsigned.signature = vec![];
+        let encoded = unsigned.encode_to_vec();
+        assert!(!keypair.public().verify(&encoded, &response.signature));
+        assert!(keypair.public().verify(&response.signed_bytes(), &response.signature));
+
+        for role in [NodeRole::Worker, NodeRole::Gateway, NodeRole::Scheduler] {
+            assert_eq!(NodeRole::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(NodeRole::parse(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_peer_without_handshake() {
+        let keypair = Keypair::generate_ed25519();
+        let roles = PeerRoles::default();
+        let mut handshake = HandshakeBehaviour::new(
+            keypair.clone(),
+            Box::new(NoChainClient),
+            Default::default(),
+            roles.clone(),
+        );
+        let legacy_keypair = Keypair::generate_ed25519();
+        let legacy_id = legacy_keypair.public().to_peer_id();
+        handshake.trust_role(legacy_id, NodeRole::Scheduler);
+        let mut node = new_memory_swarm(keypair, Wrapped::from(handshake));
+        // Peer supporting none of the protocols
+        let mut legacy = new_memory_swarm(legacy_keypair, dummy::Behaviour);
+        let addr = random_memory_addr();
+        legacy.listen_on(addr.clone()).unwrap();
+        node.dial(addr).unwrap();
+
+        let run = async {
+            loop {
+                tokio::select! {
+                    ev = node.select_next_some() => if let SwarmEvent::Behaviour(ev) = ev {
+                        break ev;
+                    },
+                    _ = legacy.select_next_some() => {}
+                }
+            }
+        };
+        let authenticated = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the trusted peer should be accepted without the handshake");
+        assert_eq!(authenticated.peer_id, legacy_id);
+        assert_eq!(authenticated.role, NodeRole::Scheduler);
+        assert_eq!(roles.get(&legacy_id), Some(NodeRole::Scheduler));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+const MAX_TRACKED_PEERS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBlockConfig {
+    /// How long a peer is blocked for the first time
+    pub ttl: Duration,
+    /// Limit of the block duration, which doubles every time the peer is blocked again
+    pub max_ttl: Duration,
+    /// Peers not blocked again for this long after the block has expired are forgotten,
+    /// so the next block starts from `ttl` again
+    pub forget_after: Duration,
+}
+
+impl Default for PeerBlockConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(600),
+            max_ttl: Duration::from_secs(86400),
+            forget_after: Duration::from_secs(86400),
+        }
+    }
+}
+
+/// Connection denied because the peer is blocked
+#[derive(Debug, thiserror::Error)]
+#[error("Peer {peer_id} blocked for another {remaining:?}")]
+pub struct PeerBlocked {
+    pub peer_id: PeerId,
+    pub remaining: Duration,
+}
+
+/// Block of a single peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBlockState {
+    /// Duration of the latest block
+    pub ttl: Duration,
+    /// Time until the peer is unblocked (zero if the block has expired)
+    pub remaining: Duration,
+    /// Number of times the peer has been blocked
+    pub offenses: u32,
+}
+
+struct PeerBlock {
+    ttl: Duration,
+    offenses: u32,
+    blocked_until: Instant,
+    expired: bool,
+}
+
+/// Temporary per-peer blocks, getting longer for repeat offenders.
+/// Shared between the transport and the transport handles.
+#[derive(Clone)]
+pub struct PeerBlocks {
+    peers: Arc<Mutex<LruCache<PeerId, PeerBlock>>>,
+    config: PeerBlockConfig,
+}
+
+impl Default for PeerBlocks {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl PeerBlocks {
+    pub fn new(config: PeerBlockConfig) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self {
+            peers: Arc::new(Mutex::new(LruCache::new(capacity))),
+            config,
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<PeerBlockState> {
+        self.get_at(peer_id, Instant::now())
+    }
+
+    fn get_at(&self, peer_id: &PeerId, now: Instant) -> Option<PeerBlockState> {
+        self.peers.lock().unwrap().peek(peer_id).map(|peer| PeerBlockState {
+            ttl: peer.ttl,
+            remaining: peer.blocked_until.saturating_duration_since(now),
+            offenses: peer.offenses,
+        })
+    }
+
+    /// Time until the peer is unblocked, `None` if it isn't blocked
+    pub fn remaining(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.remaining_at(peer_id, Instant::now())
+    }
+
+    fn remaining_at(&self, peer_id: &PeerId, now: Instant) -> Option<Duration> {
+        Some(self.get_at(peer_id, now)?.remaining).filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Block the peer. Returns the duration of the block.
+    pub fn block(&self, peer_id: PeerId) -> Duration {
+        self.block_at(peer_id, Instant::now())
+    }
+
+    fn block_at(&self, peer_id: PeerId, now: Instant) -> Duration {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.get_or_insert_mut(peer_id, || PeerBlock {
+            ttl: Duration::ZERO,
+            offenses: 0,
+            blocked_until: now,
+            expired: false,
+        });
+        peer.ttl = match peer.offenses {
+            0 => self.config.ttl,
+            _ => (peer.ttl * 2).min(self.config.max_ttl),
+        };
+        peer.offenses += 1;
+        peer.blocked_until = now + peer.ttl;
+        peer.expired = false;
+        log::info!("Blocking peer {peer_id} for {:?} (offense #{})", peer.ttl, peer.offenses);
+        peer.ttl
+    }
+
+    /// Lift the block and forget the past offenses of the peer.
+    /// Returns `true` if the peer was blocked.
+    pub fn unblock(&self, peer_id: &PeerId) -> bool {
+        self.peers.lock().unwrap().pop(peer_id).is_some_and(|peer| !peer.expired)
+    }
+
+    /// Remove peers not blocked again long after their block has expired.
+    /// Returns the peers whose blocks have expired since the last sweep.
+    pub fn sweep(&self) -> Vec<PeerId> {
+        self.sweep_at(Instant::now())
+    }
+
+    fn sweep_at(&self, now: Instant) -> Vec<PeerId> {
+        let mut peers = self.peers.lock().unwrap();
+        let mut unblocked = Vec::new();
+        let mut forgotten = Vec::new();
+        for (peer_id, peer) in peers.iter_mut() {
+            if now < peer.blocked_until {
+                continue;
+            }
+            if !peer.expired {
+                peer.expired = true;
+                unblocked.push(*peer_id);
+            }
+            if now.duration_since(peer.blocked_until) >= self.config.forget_after {
+                forgotten.push(*peer_id);
+            }
+        }
+        for peer_id in forgotten {
+            peers.pop(&peer_id);
+        }
+        unblocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_expiry() {
+        let config = PeerBlockConfig {
+            ttl: Duration::from_secs(10),
+            max_ttl: Duration::from_secs(30),
+            forget_after: Duration::from_secs(100),
+        };
+        let blocks = PeerBlocks::new(config);
+        let peer_id = PeerId::random();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        assert_eq!(blocks.remaining_at(&peer_id, start), None);
+
+        assert_eq!(blocks.block_at(peer_id, start), Duration::from_secs(10));
+        assert_eq!(blocks.remaining_at(&peer_id, secs(4)), Some(Duration::from_secs(6)));
+        assert!(blocks.sweep_at(secs(9)).is_empty());
+
+        // The block expires by itself
+        assert_eq!(blocks.remaining_at(&peer_id, secs(10)), None);
+        assert_eq!(blocks.sweep_at(secs(10)), vec![peer_id]);
+        assert!(blocks.sweep_at(secs(11)).is_empty());
+
+        // Repeat offenses double the block, up to the limit
+        for (i, expected) in [20, 30, 30].into_iter().enumerate() {
+            let t = 50 * i as u64 + 20;
+            assert_eq!(blocks.block_at(peer_id, secs(t)), Duration::from_secs(expected));
+            assert_eq!(blocks.remaining_at(&peer_id, secs(t)), Some(Duration::from_secs(expected)));
+        }
+        let state = blocks.get_at(&peer_id, secs(130)).unwrap();
+        assert_eq!(state.offenses, 4);
+        assert_eq!(state.remaining, Duration::from_secs(20));
+
+        // Offenses are forgotten long after the block has expired
+        assert_eq!(blocks.sweep_at(secs(150)), vec![peer_id]);
+        assert!(blocks.sweep_at(secs(250)).is_empty());
+        assert_eq!(blocks.get_at(&peer_id, secs(250)), None);
+        assert_eq!(blocks.block_at(peer_id, secs(250)), Duration::from_secs(10));
+
+        assert!(blocks.unblock(&peer_id));
+        assert_eq!(blocks.remaining_at(&peer_id, secs(251)), None);
+        assert!(!blocks.unblock(&peer_id));
+    }
+}
@@ -1,24 +1,49 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    task::{Context, Poll},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use derivative::Derivative;
+use futures_bounded::FuturesMap;
 use libp2p::{
     gossipsub::{
         self, MessageAcceptance, MessageAuthenticity, PublishError, Sha256Topic, TopicHash,
     },
     identity::Keypair,
-    swarm::{NetworkBehaviour, ToSwarm},
+    swarm::{CloseConnection, NetworkBehaviour, ToSwarm},
 };
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{GOSSIPSUB_CACHE_BYTES, RATE_LIMITED_BROADCASTS};
 use crate::{
-    behaviour::wrapped::{BehaviourWrapper, TToSwarm},
-    record_event, PeerId,
+    behaviour::{
+        peer_blocks::PeerBlocks,
+        wrapped::{BehaviourWrapper, TToSwarm},
+    },
+    record_event, Error, PeerId,
 };
 
+// Gossipsub defaults, the validation window is `history_length * heartbeat_interval`
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const HISTORY_LENGTH: usize = 5;
+const HISTORY_GOSSIP: usize = 3;
 const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(60);
+const MESH_FORMATION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_AUTO_SUBSCRIBED_TOPICS: usize = 64;
+// Messages held per auto-subscribed topic until peers join it, the oldest ones are dropped first
+const MAX_PENDING_MSGS: usize = 100;
+// Number of messages on not allowed topics after which the sender gets blocked
+const MAX_TOPIC_VIOLATIONS: u32 = 10;
+// Number of remote peers whose subscriptions are tracked
+const MAX_TRACKED_PEERS: NonZeroUsize = match NonZeroUsize::new(4096) {
+    Some(n) => n,
+    None => unreachable!(),
+};
 
 struct TopicState {
     name: &'static str,
@@ -40,6 +65,219 @@ impl TopicState {
     }
 }
 
+/// Limit of the size of messages received on a topic, for topics meant for small messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InboundSizeLimit {
+    /// Larger messages are rejected
+    pub max_size: usize,
+    /// Block peers (see [`PeerBlocks`]) after this many oversized messages.
+    /// `None` only rejects the messages.
+    pub blacklist_after: Option<u32>,
+}
+
+/// Maximum number of messages published to a topic within an interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishRateLimit {
+    pub max_msgs: u32,
+    pub interval: Duration,
+}
+
+/// Retrying of messages which couldn't be published because no peers were subscribed
+/// to the topic. Buffered messages are re-published when a peer subscribes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishRetry {
+    /// How long a message waits for peers before it's dropped
+    pub timeout: Duration,
+    /// Maximum number of messages waiting for peers. The oldest ones are dropped first.
+    pub max_buffered: usize,
+}
+
+struct RetryBuffer {
+    config: PublishRetry,
+    // Messages with their deadlines, oldest first
+    msgs: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl RetryBuffer {
+    fn new(config: PublishRetry) -> Self {
+        Self {
+            config,
+            msgs: Default::default(),
+        }
+    }
+
+    /// Returns `false` if the oldest message had to be dropped to make room
+    fn push(&mut self, deadline: Instant, msg: Vec<u8>) -> bool {
+        let full = self.msgs.len() >= self.config.max_buffered;
+        if full {
+            self.msgs.pop_front();
+        }
+        if self.config.max_buffered > 0 {
+            self.msgs.push_back((deadline, msg));
+        }
+        !full
+    }
+
+    /// Drop messages past their deadline. Returns the number of dropped messages.
+    fn prune(&mut self, now: Instant) -> usize {
+        let expired = self.msgs.iter().take_while(|(deadline, _)| *deadline <= now).count();
+        self.msgs.drain(..expired);
+        expired
+    }
+}
+
+/// Gossipsub parameters affecting memory usage, message validation and delivery.
+///
+/// Messages are validated synchronously when received, but they are only forwarded after
+/// being accepted. Gossipsub keeps messages pending validation in its message cache and
+/// drops them once they fall out of it, so the cache window (`history_length` heartbeats)
+/// is extended to cover `validation_timeout` if needed. The cache holds all messages sent
+/// and received within the window, so its memory grows with the window and the traffic.
+///
+/// A message seen again after `duplicate_cache_time` is treated as new. It then goes through
+/// the sequence number validation, which rejects it if it's older than the last `keep_last`
+/// messages of the sender on the topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GossipsubTuning {
+    /// How long IDs of seen messages are remembered to detect duplicates
+    pub duplicate_cache_time: Duration,
+    /// How long a received message can wait for validation before it's dropped
+    pub validation_timeout: Duration,
+    /// Publish own messages to all known peers subscribed to the topic, not only the mesh peers.
+    /// Improves the chance of delivery when the mesh is sparse, at the cost of bandwidth.
+    /// The setting is global: gossipsub doesn't support it per topic, so enabling it floods
+    /// the node's messages on every topic it publishes to, e.g. each worker ping.
+    /// Disabled by default.
+    pub flood_publish: bool,
+    /// Number of heartbeats (1s each) for which messages are kept in the message cache,
+    /// e.g. to answer IWANT requests from peers which learned about them from gossip
+    pub history_length: usize,
+    /// Number of the most recent heartbeats whose messages are advertised in gossip (IHAVE).
+    /// Can't exceed `history_length`.
+    pub history_gossip: usize,
+}
+
+impl Default for GossipsubTuning {
+    fn default() -> Self {
+        Self {
+            duplicate_cache_time: Duration::from_secs(60),
+            validation_timeout: HEARTBEAT_INTERVAL * 5,
+            flood_publish: false,
+            history_length: HISTORY_LENGTH,
+            history_gossip: HISTORY_GOSSIP,
+        }
+    }
+}
+
+impl GossipsubTuning {
+    /// Messages have to stay in the duplicate cache at least as long as they can be pending
+    /// validation, otherwise the same message could be validated twice.
+    /// The validation window can't be shorter than the gossip window either.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.history_gossip > self.history_length {
+            return Err(Error::InvalidConfig(format!(
+                "Gossipsub history gossip ({}) should not exceed history length ({})",
+                self.history_gossip, self.history_length
+            )));
+        }
+        let history_gossip = u32::try_from(self.history_gossip).unwrap_or(u32::MAX);
+        let gossip_window = HEARTBEAT_INTERVAL.saturating_mul(history_gossip);
+        if self.validation_timeout < gossip_window {
+            return Err(Error::InvalidConfig(format!(
+                "Gossipsub validation timeout ({:?}) should be at least {gossip_window:?}",
+                self.validation_timeout
+            )));
+        }
+        if self.validation_timeout > self.duplicate_cache_time {
+            return Err(Error::InvalidConfig(format!(
+                "Gossipsub validation timeout ({:?}) should not exceed duplicate cache time ({:?})",
+                self.validation_timeout, self.duplicate_cache_time
+            )));
+        }
+        Ok(())
+    }
+
+    fn history_length(&self) -> usize {
+        let heartbeats =
+            self.validation_timeout.as_millis().div_ceil(HEARTBEAT_INTERVAL.as_millis());
+        usize::try_from(heartbeats).unwrap_or(usize::MAX).max(self.history_length)
+    }
+}
+
+/// Approximate size of the gossipsub message cache, which gossipsub doesn't expose:
+/// the total size of messages sent and received within the cache window
+struct MessageCacheEstimate {
+    // Bytes of messages per heartbeat, the current one last
+    heartbeats: VecDeque<u64>,
+    heartbeat_start: Instant,
+    history_length: usize,
+}
+
+impl MessageCacheEstimate {
+    fn new(history_length: usize, now: Instant) -> Self {
+        Self {
+            heartbeats: VecDeque::from([0]),
+            heartbeat_start: now,
+            history_length,
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes: usize) {
+        self.advance(now);
+        *self.heartbeats.back_mut().expect("not empty") += bytes as u64;
+    }
+
+    /// Drop the heartbeats which have fallen out of the window
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.heartbeat_start);
+        let passed =
+            u32::try_from(elapsed.as_millis() / HEARTBEAT_INTERVAL.as_millis()).unwrap_or(u32::MAX);
+        if passed == 0 {
+            return;
+        }
+        for _ in 0..usize::try_from(passed).unwrap_or(usize::MAX).min(self.history_length) {
+            self.heartbeats.push_back(0);
+        }
+        while self.heartbeats.len() > self.history_length {
+            self.heartbeats.pop_front();
+        }
+        self.heartbeat_start += HEARTBEAT_INTERVAL * passed;
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.heartbeats.iter().sum()
+    }
+}
+
+struct RateLimiter {
+    limit: PublishRateLimit,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: PublishRateLimit) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Returns `false` if the limit for the current interval has been exhausted
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.window_start) >= self.limit.interval {
+            self.window_start = now;
+            self.count = 0;
+        }
+        if self.count >= self.limit.max_msgs {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct PubsubMsg {
@@ -49,17 +287,106 @@ pub struct PubsubMsg {
     pub data: Box<[u8]>,
 }
 
+/// Outcome of the application-specific validation of a received message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageValidation {
+    /// Deliver the message and forward it to other peers
+    Accept,
+    /// Drop the message and penalize the peer which has sent it
+    Reject,
+    /// Drop the message without penalizing the sender
+    Ignore,
+}
+
+impl From<MessageValidation> for MessageAcceptance {
+    fn from(validation: MessageValidation) -> Self {
+        match validation {
+            MessageValidation::Accept => MessageAcceptance::Accept,
+            MessageValidation::Reject => MessageAcceptance::Reject,
+            MessageValidation::Ignore => MessageAcceptance::Ignore,
+        }
+    }
+}
+
+/// Callback validating messages on a topic, after the transport's own checks have passed.
+/// It runs on the transport's event loop, so it should be quick.
+pub type TopicValidator = Box<dyn Fn(&PubsubMsg) -> MessageValidation + Send>;
+
+#[derive(Debug, Clone)]
+pub enum PubsubEvent {
+    Message(PubsubMsg),
+    /// Remote peer subscribed to or unsubscribed from one of the known (locally subscribed) topics
+    SubscriptionChanged {
+        peer_id: PeerId,
+        topic: &'static str,
+        subscribed: bool,
+    },
+}
+
 pub struct PubsubBehaviour {
     inner: gossipsub::Behaviour,
     topics: HashMap<TopicHash, TopicState>,
+    auto_subscribe: bool,
+    // Messages to auto-subscribed topics, waiting for other peers to join the topic
+    pending_msgs: HashMap<TopicHash, VecDeque<Vec<u8>>>,
+    mesh_timeouts: FuturesMap<TopicHash, ()>,
+    // Outbound rate limits. Topics not present here are unlimited.
+    rate_limits: HashMap<TopicHash, RateLimiter>,
+    // Messages waiting for peers to join the topic. Only topics with retry enabled are present.
+    retries: HashMap<TopicHash, RetryBuffer>,
+    // Topics accepted from other peers, regardless of subscriptions. `None` allows all topics.
+    allowed_topics: Option<HashSet<TopicHash>>,
+    topic_violations: LruCache<PeerId, u32>,
+    // Inbound message size limits. Topics not present here are only limited by `max_msg_size`.
+    size_limits: HashMap<TopicHash, InboundSizeLimit>,
+    size_violations: LruCache<PeerId, u32>,
+    // Repeat offenders are blocked here and disconnected
+    peer_blocks: PeerBlocks,
+    pending_disconnects: Vec<PeerId>,
+    validators: HashMap<TopicHash, TopicValidator>,
+    // Used to de-duplicate subscription change events
+    peer_subscriptions: LruCache<PeerId, HashSet<TopicHash>>,
+    cache_estimate: MessageCacheEstimate,
+}
+
+/// Receive window of a QUIC stream (libp2p's default `max_stream_data`). Larger messages can
+/// still be sent, but they stall the stream until the receiver has read the first part.
+const QUIC_STREAM_WINDOW: usize = 10_000_000;
+
+/// Check the max size of gossipsub messages. Sizes above the QUIC stream window are allowed,
+/// but they hardly make sense for broadcasts which every peer forwards, so a warning is logged.
+/// The QUIC MTU doesn't limit it, messages are split into packets anyway.
+pub fn validate_max_transmit_size(size: usize) -> Result<(), Error> {
+    if size == 0 {
+        return Err(Error::InvalidConfig(
+            "Gossipsub max transmit size should be positive".to_string(),
+        ));
+    }
+    if size > QUIC_STREAM_WINDOW {
+        log::warn!(
+            "Gossipsub max transmit size ({size} bytes) exceeds the QUIC stream window \
+            ({QUIC_STREAM_WINDOW} bytes). Large broadcasts will stall the pubsub streams."
+        );
+    }
+    Ok(())
 }
 
 impl PubsubBehaviour {
-    pub fn new(keypair: Keypair, max_msg_size: usize) -> Self {
+    pub fn new(
+        keypair: Keypair,
+        max_msg_size: usize,
+        auto_subscribe: bool,
+        tuning: GossipsubTuning,
+    ) -> Self {
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .validate_messages()
             .message_id_fn(msg_id)
             .max_transmit_size(max_msg_size)
+            .heartbeat_interval(HEARTBEAT_INTERVAL)
+            .history_gossip(tuning.history_gossip)
+            .history_length(tuning.history_length())
+            .duplicate_cache_time(tuning.duplicate_cache_time)
+            .flood_publish(tuning.flood_publish)
             .build()
             .expect("config should be valid");
         let inner =
@@ -68,9 +395,30 @@ impl PubsubBehaviour {
         Self {
             inner,
             topics: Default::default(),
+            auto_subscribe,
+            pending_msgs: Default::default(),
+            mesh_timeouts: FuturesMap::new(MESH_FORMATION_TIMEOUT, MAX_AUTO_SUBSCRIBED_TOPICS),
+            rate_limits: Default::default(),
+            retries: Default::default(),
+            allowed_topics: None,
+            topic_violations: LruCache::new(MAX_TRACKED_PEERS),
+            size_limits: Default::default(),
+            size_violations: LruCache::new(MAX_TRACKED_PEERS),
+            peer_blocks: Default::default(),
+            pending_disconnects: Default::default(),
+            validators: Default::default(),
+            peer_subscriptions: LruCache::new(MAX_TRACKED_PEERS),
+            cache_estimate: MessageCacheEstimate::new(tuning.history_length(), Instant::now()),
         }
     }
 
+    /// Block peers sending invalid messages through the shared peer blocks, so that they
+    /// can't reconnect until the block expires
+    pub fn with_peer_blocks(mut self, peer_blocks: PeerBlocks) -> Self {
+        self.peer_blocks = peer_blocks;
+        self
+    }
+
     pub fn subscribe(&mut self, topic_name: &'static str, keep_last: u64) {
         log::info!("Subscribing to topic {topic_name}");
         let topic = TopicState::new(topic_name, keep_last);
@@ -87,28 +435,188 @@ impl PubsubBehaviour {
         log::info!("Topic {topic_name} subscribed");
     }
 
+    /// Only accept messages on the given topics, even if other topics get subscribed.
+    /// Messages on other topics are rejected and repeat offenders get blocked.
+    pub fn set_allowed_topics<'a>(&mut self, topic_names: impl IntoIterator<Item = &'a str>) {
+        let topics = topic_names.into_iter().map(|name| Sha256Topic::new(name).hash()).collect();
+        self.allowed_topics = Some(topics);
+    }
+
+    /// Limit the rate of messages published to the topic. Messages exceeding the limit are
+    /// dropped. `None` removes the limit (the default).
+    pub fn set_publish_rate_limit(&mut self, topic_name: &str, limit: Option<PublishRateLimit>) {
+        let topic_hash = Sha256Topic::new(topic_name).hash();
+        match limit {
+            Some(limit) => self.rate_limits.insert(topic_hash, RateLimiter::new(limit)),
+            None => self.rate_limits.remove(&topic_hash),
+        };
+    }
+
+    /// Buffer messages which couldn't be published to the topic for lack of peers, and retry
+    /// when a peer subscribes. `None` disables retrying (the default).
+    pub fn set_publish_retry(&mut self, topic_name: &str, retry: Option<PublishRetry>) {
+        let topic_hash = Sha256Topic::new(topic_name).hash();
+        match retry {
+            Some(retry) => self.retries.insert(topic_hash, RetryBuffer::new(retry)),
+            None => self.retries.remove(&topic_hash),
+        };
+    }
+
+    /// Reject messages received on the topic larger than the limit. `None` removes the limit
+    /// (the default).
+    pub fn set_inbound_size_limit(&mut self, topic_name: &str, limit: Option<InboundSizeLimit>) {
+        let topic_hash = Sha256Topic::new(topic_name).hash();
+        match limit {
+            Some(limit) => self.size_limits.insert(topic_hash, limit),
+            None => self.size_limits.remove(&topic_hash),
+        };
+    }
+
+    /// Validate the messages received on the topic with the callback before they are
+    /// delivered and forwarded. `None` removes the validator (the default).
+    pub fn set_topic_validator(&mut self, topic_name: &str, validator: Option<TopicValidator>) {
+        let topic_hash = Sha256Topic::new(topic_name).hash();
+        match validator {
+            Some(validator) => self.validators.insert(topic_hash, validator),
+            None => self.validators.remove(&topic_hash),
+        };
+    }
+
+    /// Publish message to the topic. If the topic is not subscribed and `auto_subscribe` is
+    /// enabled, the topic gets subscribed and the message is held until some peer joins the topic
+    /// (or `MESH_FORMATION_TIMEOUT` passes). Otherwise, the message is dropped.
     pub fn publish(&mut self, topic_name: &'static str, msg: impl Into<Vec<u8>>) {
         log::debug!("Publishing message to topic {topic_name}");
         let topic_hash = Sha256Topic::new(topic_name).hash();
+        if let Some(limiter) = self.rate_limits.get_mut(&topic_hash) {
+            if !limiter.try_acquire_at(Instant::now()) {
+                #[cfg(feature = "metrics")]
+                RATE_LIMITED_BROADCASTS.get_or_create(&vec![("topic", topic_name)]).inc();
+                return log::warn!(
+                    "Publish rate limit exceeded for {topic_name}. Message dropped."
+                );
+            }
+        }
+        if !self.topics.contains_key(&topic_hash) {
+            if !self.auto_subscribe {
+                return log::error!("Cannot publish to unsubscribed topic: {topic_name}");
+            }
+            self.auto_subscribe(topic_name, topic_hash.clone());
+        }
+        if let Some(pending) = self.pending_msgs.get_mut(&topic_hash) {
+            log::debug!("Waiting for peers to join {topic_name} before publishing");
+            if pending.len() >= MAX_PENDING_MSGS {
+                log::warn!(
+                    "Too many messages waiting for peers on {topic_name}. Oldest message dropped."
+                );
+                pending.pop_front();
+            }
+            pending.push_back(msg.into());
+            return;
+        }
+        self.publish_now(topic_hash, msg.into());
+    }
+
+    /// Number of messages to the topic buffered for retry until peers join it
+    #[cfg(test)]
+    pub fn num_retry_msgs(&self, topic_name: &str) -> usize {
+        let topic_hash = Sha256Topic::new(topic_name).hash();
+        self.retries.get(&topic_hash).map_or(0, |buffer| buffer.msgs.len())
+    }
+
+    fn auto_subscribe(&mut self, topic_name: &'static str, topic_hash: TopicHash) {
+        self.subscribe(topic_name, 1);
+        if !self.topics.contains_key(&topic_hash) {
+            return; // Subscription failed
+        }
+        let has_peers = self.inner.all_peers().any(|(_, topics)| topics.contains(&&topic_hash));
+        if has_peers {
+            return;
+        }
+        if self
+            .mesh_timeouts
+            .try_push(topic_hash.clone(), futures::future::pending())
+            .is_ok()
+        {
+            self.pending_msgs.insert(topic_hash, VecDeque::new());
+        }
+    }
+
+    fn flush_pending(&mut self, topic_hash: TopicHash) {
+        let Some(msgs) = self.pending_msgs.remove(&topic_hash) else {
+            return;
+        };
+        self.mesh_timeouts.remove(topic_hash.clone());
+        for msg in msgs {
+            self.publish_now(topic_hash.clone(), msg);
+        }
+    }
+
+    fn publish_now(&mut self, topic_hash: TopicHash, msg: Vec<u8>) {
+        self.try_publish(topic_hash, msg, None)
+    }
+
+    /// Publish the message, buffering it for retry if there are no peers on the topic
+    /// and retry is enabled. `deadline` is set for messages which have been buffered before.
+    fn try_publish(&mut self, topic_hash: TopicHash, msg: Vec<u8>, deadline: Option<Instant>) {
         let Some(topic) = self.topics.get(&topic_hash) else {
-            return log::error!("Cannot publish to unsubscribed topic: {topic_name}");
+            return log::error!("Cannot publish to unsubscribed topic: {topic_hash}");
         };
+        let topic_name = topic.name;
+        let retry_msg = self.retries.contains_key(&topic_hash).then(|| msg.clone());
+        let msg_size = msg.len();
 
-        match self.inner.publish(topic_hash, msg) {
-            Err(PublishError::InsufficientPeers)
+        match (self.inner.publish(topic_hash.clone(), msg), retry_msg) {
+            (Err(PublishError::InsufficientPeers), Some(msg)) => {
+                let buffer = self.retries.get_mut(&topic_hash).expect("retry enabled");
+                let deadline = deadline.unwrap_or_else(|| Instant::now() + buffer.config.timeout);
+                log::info!("No peers on {topic_name}. Message buffered for retry.");
+                if !buffer.push(deadline, msg) {
+                    log::warn!("Retry buffer for {topic_name} full. Oldest message dropped.");
+                }
+            }
+            (Err(PublishError::InsufficientPeers), None)
                 if topic.subscribed_at.elapsed() <= SUBSCRIPTION_TIMEOUT =>
             {
                 log::info!("Waiting for peers to publish to {topic_name}")
             }
-            Err(e) => log::error!("Error publishing message to {topic_name}: {e:?}"),
-            Ok(_) => log::debug!("Message published to {topic_name}"),
+            (Err(e), _) => log::error!("Error publishing message to {topic_name}: {e:?}"),
+            (Ok(_), _) => {
+                log::debug!("Message published to {topic_name}");
+                self.cache_estimate.record(Instant::now(), msg_size);
+            }
+        }
+    }
+
+    fn retry_buffered(&mut self, topic_hash: &TopicHash) {
+        let Some(buffer) = self.retries.get_mut(topic_hash) else {
+            return;
+        };
+        buffer.prune(Instant::now());
+        let msgs = std::mem::take(&mut buffer.msgs);
+        if !msgs.is_empty() {
+            log::info!("Peer joined {topic_hash}. Retrying {} messages.", msgs.len());
+        }
+        for (deadline, msg) in msgs {
+            self.try_publish(topic_hash.clone(), msg, Some(deadline));
+        }
+    }
+
+    fn prune_retry_buffers(&mut self) {
+        let now = Instant::now();
+        for (topic_hash, buffer) in self.retries.iter_mut() {
+            let expired = buffer.prune(now);
+            if expired > 0 {
+                log::warn!("No peers joined {topic_hash} in time. Dropped {expired} messages.");
+            }
         }
     }
 
     /// Validate gossipsub message
     ///   1) Check if message is not anonymous,
-    ///   2) Check if topic is known (subscribed),
-    ///   3) Enforce message ordering (if configured for topic).
+    ///   2) Check if topic is allowed and known (subscribed),
+    ///   3) Check message size (if limited for topic),
+    ///   4) Enforce message ordering (if configured for topic).
     fn validate_gossipsub_msg(
         &mut self,
         msg: gossipsub::Message,
@@ -116,9 +624,24 @@ impl PubsubBehaviour {
         let Some(peer_id) = msg.source else {
             return Err("anonymous message");
         };
-        let Some(topic_state) = self.topics.get_mut(&msg.topic) else {
+        if self
+            .allowed_topics
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&msg.topic))
+        {
+            self.on_topic_violation(peer_id);
+            return Err("message with not allowed topic");
+        }
+        if !self.topics.contains_key(&msg.topic) {
             return Err("message with unknown topic");
-        };
+        }
+        if let Some(&limit) = self.size_limits.get(&msg.topic) {
+            if msg.data.len() > limit.max_size {
+                self.on_size_violation(peer_id, limit);
+                return Err("message too large");
+            }
+        }
+        let topic_state = self.topics.get_mut(&msg.topic).expect("topic is known");
         let last_seq_no = topic_state.sequence_numbers.entry(peer_id).or_default();
         match msg.sequence_number {
             None => return Err("message without sequence number"),
@@ -137,11 +660,60 @@ impl PubsubBehaviour {
             data: msg.data.into_boxed_slice(),
         })
     }
+
+    fn on_subscription_change(
+        &mut self,
+        peer_id: PeerId,
+        topic: TopicHash,
+        subscribed: bool,
+    ) -> Option<TToSwarm<Self>> {
+        let topic_name = self.topics.get(&topic)?.name;
+        let topics = self.peer_subscriptions.get_or_insert_mut(peer_id, Default::default);
+        let changed = if subscribed {
+            topics.insert(topic)
+        } else {
+            topics.remove(&topic)
+        };
+        if !changed {
+            return None;
+        }
+        log::debug!("Peer {peer_id} subscribed={subscribed} to {topic_name}");
+        Some(ToSwarm::GenerateEvent(PubsubEvent::SubscriptionChanged {
+            peer_id,
+            topic: topic_name,
+            subscribed,
+        }))
+    }
+
+    fn on_size_violation(&mut self, peer_id: PeerId, limit: InboundSizeLimit) {
+        let violations = self.size_violations.get_or_insert_mut(peer_id, Default::default);
+        *violations += 1;
+        if limit.blacklist_after.is_some_and(|max| *violations >= max) {
+            log::warn!("Peer {peer_id} keeps sending oversized messages. Blocking.");
+            self.block_peer(peer_id);
+            self.size_violations.pop(&peer_id);
+        }
+    }
+
+    fn block_peer(&mut self, peer_id: PeerId) {
+        self.peer_blocks.block(peer_id);
+        self.pending_disconnects.push(peer_id);
+    }
+
+    fn on_topic_violation(&mut self, peer_id: PeerId) {
+        let violations = self.topic_violations.get_or_insert_mut(peer_id, Default::default);
+        *violations += 1;
+        if *violations >= MAX_TOPIC_VIOLATIONS {
+            log::warn!("Peer {peer_id} keeps sending messages on not allowed topics. Blocking.");
+            self.block_peer(peer_id);
+            self.topic_violations.pop(&peer_id);
+        }
+    }
 }
 
 impl BehaviourWrapper for PubsubBehaviour {
     type Inner = gossipsub::Behaviour;
-    type Event = PubsubMsg;
+    type Event = PubsubEvent;
 
     fn inner(&mut self) -> &mut Self::Inner {
         &mut self.inner
@@ -153,6 +725,14 @@ impl BehaviourWrapper for PubsubBehaviour {
     ) -> impl IntoIterator<Item = TToSwarm<Self>> {
         log::debug!("Gossipsub event received: {ev:?}");
         record_event(&ev);
+        if let gossipsub::Event::Subscribed { peer_id, topic } = ev {
+            self.flush_pending(topic.clone());
+            self.retry_buffered(&topic);
+            return self.on_subscription_change(peer_id, topic, true);
+        }
+        if let gossipsub::Event::Unsubscribed { peer_id, topic } = ev {
+            return self.on_subscription_change(peer_id, topic, false);
+        }
         let gossipsub::Event::Message {
             message,
             propagation_source,
@@ -162,14 +742,27 @@ impl BehaviourWrapper for PubsubBehaviour {
             return None;
         };
 
+        self.cache_estimate.record(Instant::now(), message.data.len());
+        let topic_hash = message.topic.clone();
         match self.validate_gossipsub_msg(message) {
             Ok(msg) => {
+                let validation = self
+                    .validators
+                    .get(&topic_hash)
+                    .map_or(MessageValidation::Accept, |validator| validator(&msg));
+                if validation != MessageValidation::Accept {
+                    let topic = msg.topic;
+                    log::debug!(
+                        "Validator result for {topic} from {propagation_source}: {validation:?}"
+                    );
+                }
                 let _ = self.inner.report_message_validation_result(
                     &message_id,
                     &propagation_source,
-                    MessageAcceptance::Accept,
+                    validation.into(),
                 );
-                Some(ToSwarm::GenerateEvent(msg))
+                (validation == MessageValidation::Accept)
+                    .then_some(ToSwarm::GenerateEvent(PubsubEvent::Message(msg)))
             }
             Err(e) => {
                 log::debug!("Discarding gossipsub message from {propagation_source}: {e}");
@@ -182,6 +775,28 @@ impl BehaviourWrapper for PubsubBehaviour {
             }
         }
     }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
+        while let Poll::Ready((topic_hash, _)) = self.mesh_timeouts.poll_unpin(cx) {
+            log::debug!("No peers joined topic {topic_hash} in time. Publishing anyway.");
+            self.flush_pending(topic_hash);
+        }
+        self.prune_retry_buffers();
+        self.cache_estimate.advance(Instant::now());
+        #[cfg(feature = "metrics")]
+        GOSSIPSUB_CACHE_BYTES.set(self.cache_estimate.total_bytes() as i64);
+        if self.pending_disconnects.is_empty() {
+            return Poll::Pending;
+        }
+        let disconnects =
+            std::mem::take(&mut self.pending_disconnects).into_iter().map(|peer_id| {
+                ToSwarm::CloseConnection {
+                    peer_id,
+                    connection: CloseConnection::All,
+                }
+            });
+        Poll::Ready(disconnects)
+    }
 }
 
 // Default gossipsub msg ID function, copied from libp2p
@@ -204,3 +819,222 @@ fn timestamp_now() -> u64 {
         .try_into()
         .expect("not that far in the future")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_size_limit() {
+        let keypair = Keypair::generate_ed25519();
+        let mut pubsub = PubsubBehaviour::new(keypair, 1024, false, Default::default());
+        pubsub.subscribe("topic", 1);
+        let limit = InboundSizeLimit {
+            max_size: 10,
+            blacklist_after: Some(2),
+        };
+        pubsub.set_inbound_size_limit("topic", Some(limit));
+        let peer_id = PeerId::random();
+        let seq_no = timestamp_now() - 1000;
+        let message = |size: usize, seq_no: u64| gossipsub::Message {
+            source: Some(peer_id),
+            data: vec![0; size],
+            sequence_number: Some(seq_no),
+            topic: Sha256Topic::new("topic").hash(),
+        };
+
+        assert!(pubsub.validate_gossipsub_msg(message(10, seq_no)).is_ok());
+        assert_eq!(
+            pubsub.validate_gossipsub_msg(message(11, seq_no + 1)).unwrap_err(),
+            "message too large"
+        );
+        assert_eq!(pubsub.size_violations.peek(&peer_id), Some(&1));
+        assert!(pubsub.peer_blocks.remaining(&peer_id).is_none());
+        // The peer is blocked and disconnected on the second violation
+        assert!(pubsub.validate_gossipsub_msg(message(100, seq_no + 2)).is_err());
+        assert_eq!(pubsub.size_violations.peek(&peer_id), None);
+        assert!(pubsub.peer_blocks.remaining(&peer_id).is_some());
+        assert_eq!(pubsub.pending_disconnects, vec![peer_id]);
+    }
+
+    #[test]
+    fn test_topic_violations() {
+        let keypair = Keypair::generate_ed25519();
+        let mut pubsub = PubsubBehaviour::new(keypair, 1024, false, Default::default());
+        pubsub.subscribe("topic", 1);
+        pubsub.set_allowed_topics(["other"]);
+        let peer_id = PeerId::random();
+        let seq_no = timestamp_now() - 1000;
+        let message = |seq_no: u64| gossipsub::Message {
+            source: Some(peer_id),
+            data: vec![0],
+            sequence_number: Some(seq_no),
+            topic: Sha256Topic::new("topic").hash(),
+        };
+
+        for i in 1..MAX_TOPIC_VIOLATIONS {
+            assert_eq!(
+                pubsub.validate_gossipsub_msg(message(seq_no + u64::from(i))).unwrap_err(),
+                "message with not allowed topic"
+            );
+        }
+        assert!(pubsub.peer_blocks.remaining(&peer_id).is_none());
+        assert!(pubsub.validate_gossipsub_msg(message(seq_no)).is_err());
+        assert!(pubsub.peer_blocks.remaining(&peer_id).is_some());
+        assert_eq!(pubsub.pending_disconnects, vec![peer_id]);
+        assert!(pubsub.topic_violations.is_empty());
+    }
+
+    #[test]
+    fn test_topic_validator() {
+        let keypair = Keypair::generate_ed25519();
+        let mut pubsub = PubsubBehaviour::new(keypair, 1024, false, Default::default());
+        pubsub.subscribe("topic", 1);
+        pubsub.set_topic_validator(
+            "topic",
+            Some(Box::new(|msg| match msg.data.as_ref() {
+                b"valid" => MessageValidation::Accept,
+                _ => MessageValidation::Reject,
+            })),
+        );
+        let peer_id = PeerId::random();
+        let seq_no = timestamp_now() - 1000;
+        let receive = |pubsub: &mut PubsubBehaviour, data: &[u8], seq_no: u64| {
+            let message = gossipsub::Message {
+                source: Some(peer_id),
+                data: data.to_vec(),
+                sequence_number: Some(seq_no),
+                topic: Sha256Topic::new("topic").hash(),
+            };
+            let ev = gossipsub::Event::Message {
+                message_id: msg_id(&message),
+                propagation_source: peer_id,
+                message,
+            };
+            pubsub.on_inner_event(ev).into_iter().next()
+        };
+        assert!(matches!(
+            receive(&mut pubsub, b"valid", seq_no),
+            Some(ToSwarm::GenerateEvent(PubsubEvent::Message(_)))
+        ));
+        assert!(receive(&mut pubsub, b"invalid", seq_no + 1).is_none());
+
+        // Topics without a validator are accepted
+        pubsub.set_topic_validator("topic", None);
+        assert!(receive(&mut pubsub, b"invalid", seq_no + 2).is_some());
+    }
+
+    #[test]
+    fn test_max_transmit_size() {
+        assert!(validate_max_transmit_size(0).is_err());
+        assert!(validate_max_transmit_size(65536).is_ok());
+        // Only a warning
+        assert!(validate_max_transmit_size(2 * QUIC_STREAM_WINDOW).is_ok());
+    }
+
+    #[test]
+    fn test_gossipsub_tuning() {
+        let tuning = GossipsubTuning::default();
+        assert!(tuning.validate().is_ok());
+        assert_eq!(tuning.history_length(), 5);
+
+        let tuning = GossipsubTuning {
+            validation_timeout: Duration::from_millis(10_500),
+            ..Default::default()
+        };
+        assert!(tuning.validate().is_ok());
+        assert_eq!(tuning.history_length(), 11);
+
+        let tuning = GossipsubTuning {
+            validation_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert!(tuning.validate().is_err());
+
+        let tuning = GossipsubTuning {
+            duplicate_cache_time: Duration::from_secs(10),
+            validation_timeout: Duration::from_secs(20),
+            ..Default::default()
+        };
+        assert!(tuning.validate().is_err());
+
+        let tuning = GossipsubTuning {
+            history_length: 20,
+            ..Default::default()
+        };
+        assert!(tuning.validate().is_ok());
+        assert_eq!(tuning.history_length(), 20);
+
+        let tuning = GossipsubTuning {
+            history_gossip: 6,
+            validation_timeout: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert!(tuning.validate().is_err());
+    }
+
+    #[test]
+    fn test_message_cache_estimate() {
+        let start = Instant::now();
+        let mut estimate = MessageCacheEstimate::new(3, start);
+        estimate.record(start, 100);
+        estimate.record(start + Duration::from_millis(1500), 10);
+        assert_eq!(estimate.total_bytes(), 110);
+
+        estimate.advance(start + Duration::from_millis(2500));
+        assert_eq!(estimate.total_bytes(), 110);
+        estimate.advance(start + Duration::from_millis(3500));
+        assert_eq!(estimate.total_bytes(), 10);
+        estimate.advance(start + Duration::from_secs(100));
+        assert_eq!(estimate.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_retry_buffer() {
+        let mut buffer = RetryBuffer::new(PublishRetry {
+            timeout: Duration::from_secs(10),
+            max_buffered: 2,
+        });
+        let start = Instant::now();
+        assert!(buffer.push(start, vec![1]));
+        assert!(buffer.push(start + Duration::from_secs(1), vec![2]));
+        assert!(!buffer.push(start + Duration::from_secs(2), vec![3]));
+        assert_eq!(buffer.msgs.iter().map(|(_, msg)| msg[0]).collect::<Vec<_>>(), [2, 3]);
+
+        assert_eq!(buffer.prune(start + Duration::from_millis(500)), 0);
+        assert_eq!(buffer.prune(start + Duration::from_secs(1)), 1);
+        assert_eq!(buffer.prune(start + Duration::from_secs(5)), 1);
+        assert!(buffer.msgs.is_empty());
+    }
+
+    #[test]
+    fn test_pending_msgs_limit() {
+        let keypair = Keypair::generate_ed25519();
+        let mut pubsub = PubsubBehaviour::new(keypair, 1024, true, Default::default());
+        for i in 0..=MAX_PENDING_MSGS {
+            pubsub.publish("topic", i.to_string());
+        }
+        let pending = &pubsub.pending_msgs[&Sha256Topic::new("topic").hash()];
+        assert_eq!(pending.len(), MAX_PENDING_MSGS);
+        // The oldest message has been dropped
+        assert_eq!(pending.front().unwrap(), b"1");
+        assert_eq!(pending.back().unwrap(), MAX_PENDING_MSGS.to_string().as_bytes());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let interval = Duration::from_secs(10);
+        let mut limiter = RateLimiter::new(PublishRateLimit {
+            max_msgs: 2,
+            interval,
+        });
+        let start = limiter.window_start;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start + interval / 2));
+        assert!(!limiter.try_acquire_at(start + interval / 2));
+        // New interval starts, limit is restored
+        assert!(limiter.try_acquire_at(start + interval));
+        assert!(limiter.try_acquire_at(start + interval));
+        assert!(!limiter.try_acquire_at(start + interval * 3 / 2));
+    }
+}
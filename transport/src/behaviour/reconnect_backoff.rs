@@ -0,0 +1,198 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::DIALS_DENIED_BY_BACKOFF;
+
+const MAX_TRACKED_PEERS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReconnectBackoffConfig {
+    /// How long a peer isn't re-dialed after a connection which was stable
+    pub initial: Duration,
+    /// Limit of the backoff, which doubles every time the peer disconnects soon after connecting
+    pub max: Duration,
+    /// Connections kept for this long are stable, the next disconnect resets the backoff
+    pub stable_after: Duration,
+}
+
+impl Default for ReconnectBackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(300),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Dial cancelled because the peer disconnected recently
+#[derive(Debug, thiserror::Error)]
+#[error("Peer {peer_id} in reconnection backoff for another {remaining:?}")]
+pub struct DialBackoff {
+    pub peer_id: PeerId,
+    pub remaining: Duration,
+}
+
+/// Reconnection backoff of a single peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReconnectBackoffState {
+    /// The current backoff duration
+    pub backoff: Duration,
+    /// Time until the peer can be dialed again (zero if it can be dialed now)
+    pub remaining: Duration,
+    /// Number of disconnects in a row which happened before the connection became stable
+    pub flaps: u32,
+}
+
+struct PeerBackoff {
+    backoff: Duration,
+    flaps: u32,
+    connected_at: Option<Instant>,
+    retry_at: Instant,
+}
+
+/// Per-peer reconnection backoff, which prevents dial storms to peers repeatedly connecting
+/// and disconnecting. Shared between the transport and the transport handles.
+#[derive(Clone)]
+pub struct ReconnectBackoffs {
+    peers: Arc<Mutex<LruCache<PeerId, PeerBackoff>>>,
+    config: ReconnectBackoffConfig,
+}
+
+impl Default for ReconnectBackoffs {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl ReconnectBackoffs {
+    pub fn new(config: ReconnectBackoffConfig) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self {
+            peers: Arc::new(Mutex::new(LruCache::new(capacity))),
+            config,
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<ReconnectBackoffState> {
+        self.get_at(peer_id, Instant::now())
+    }
+
+    fn get_at(&self, peer_id: &PeerId, now: Instant) -> Option<ReconnectBackoffState> {
+        self.peers.lock().unwrap().peek(peer_id).map(|peer| ReconnectBackoffState {
+            backoff: peer.backoff,
+            remaining: peer.retry_at.saturating_duration_since(now),
+            flaps: peer.flaps,
+        })
+    }
+
+    /// The first connection to the peer has been established
+    pub fn on_connected(&self, peer_id: PeerId) {
+        self.on_connected_at(peer_id, Instant::now());
+    }
+
+    fn on_connected_at(&self, peer_id: PeerId, now: Instant) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&peer_id) {
+            peer.connected_at = Some(now);
+        }
+    }
+
+    /// The last connection to the peer has been closed
+    pub fn on_disconnected(&self, peer_id: PeerId) {
+        self.on_disconnected_at(peer_id, Instant::now());
+    }
+
+    fn on_disconnected_at(&self, peer_id: PeerId, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get_mut(&peer_id) else {
+            peers.put(
+                peer_id,
+                PeerBackoff {
+                    backoff: self.config.initial,
+                    flaps: 0,
+                    connected_at: None,
+                    retry_at: now + self.config.initial,
+                },
+            );
+            return;
+        };
+        let stable = match peer.connected_at.take() {
+            Some(connected_at) => now.duration_since(connected_at) >= self.config.stable_after,
+            None => true,
+        };
+        if stable {
+            peer.backoff = self.config.initial;
+            peer.flaps = 0;
+        } else {
+            peer.backoff = (peer.backoff * 2).min(self.config.max);
+            peer.flaps += 1;
+            log::debug!(
+                "Peer {peer_id} disconnected {} times in a row, not re-dialing it for {:?}",
+                peer.flaps,
+                peer.backoff
+            );
+        }
+        peer.retry_at = now + peer.backoff;
+    }
+
+    /// Returns the remaining backoff if the peer shouldn't be dialed now
+    pub fn check_dial(&self, peer_id: &PeerId) -> Option<Duration> {
+        let remaining = self.get(peer_id)?.remaining;
+        if remaining.is_zero() {
+            return None;
+        }
+        #[cfg(feature = "metrics")]
+        DIALS_DENIED_BY_BACKOFF.inc();
+        Some(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let config = ReconnectBackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            stable_after: Duration::from_secs(60),
+        };
+        let backoffs = ReconnectBackoffs::new(config);
+        let peer_id = PeerId::random();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        assert_eq!(backoffs.get_at(&peer_id, start), None);
+
+        backoffs.on_disconnected_at(peer_id, start);
+        let state = backoffs.get_at(&peer_id, start).unwrap();
+        assert_eq!(state.remaining, Duration::from_secs(1));
+        assert_eq!(backoffs.get_at(&peer_id, secs(1)).unwrap().remaining, Duration::ZERO);
+
+        // Flapping peer
+        for (i, expected) in [2, 4, 8, 10, 10].into_iter().enumerate() {
+            let t = 10 * i as u64 + 10;
+            backoffs.on_connected_at(peer_id, secs(t));
+            backoffs.on_disconnected_at(peer_id, secs(t + 1));
+            let state = backoffs.get_at(&peer_id, secs(t + 1)).unwrap();
+            assert_eq!(state.backoff, Duration::from_secs(expected));
+            assert_eq!(state.remaining, Duration::from_secs(expected));
+        }
+        assert_eq!(backoffs.get_at(&peer_id, secs(60)).unwrap().flaps, 5);
+
+        // Stable connection resets the backoff
+        backoffs.on_connected_at(peer_id, secs(100));
+        backoffs.on_disconnected_at(peer_id, secs(200));
+        let state = backoffs.get_at(&peer_id, secs(200)).unwrap();
+        assert_eq!(state.backoff, Duration::from_secs(1));
+        assert_eq!(state.flaps, 0);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    collections::HashSet,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    core::Endpoint,
+    multiaddr::Protocol,
+    ping,
+    swarm::{
+        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+
+/// Ping behaviour which pings relayed (circuit) connections with a separate, usually shorter,
+/// interval than direct ones. This keeps relay reservations and circuits warm.
+pub struct RelayAwarePing {
+    direct: ping::Behaviour,
+    relayed: ping::Behaviour,
+    relayed_conns: HashSet<ConnectionId>,
+}
+
+impl RelayAwarePing {
+    pub fn new(relay_keepalive_interval: Duration) -> Self {
+        Self {
+            direct: ping::Behaviour::new(ping::Config::default()),
+            relayed: ping::Behaviour::new(
+                ping::Config::default().with_interval(relay_keepalive_interval),
+            ),
+            relayed_conns: Default::default(),
+        }
+    }
+
+    fn select(&mut self, connection_id: &ConnectionId) -> &mut ping::Behaviour {
+        if self.relayed_conns.contains(connection_id) {
+            &mut self.relayed
+        } else {
+            &mut self.direct
+        }
+    }
+}
+
+fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+impl NetworkBehaviour for RelayAwarePing {
+    type ConnectionHandler = THandler<ping::Behaviour>;
+    type ToSwarm = ping::Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        if is_relayed(local_addr) || is_relayed(remote_addr) {
+            self.relayed_conns.insert(connection_id);
+        }
+        self.select(&connection_id).handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        if is_relayed(addr) {
+            self.relayed_conns.insert(connection_id);
+        }
+        self.select(&connection_id).handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::ConnectionClosed(e) = &event {
+            self.relayed_conns.remove(&e.connection_id);
+        }
+        self.direct.on_swarm_event(event);
+        self.relayed.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.select(&connection_id)
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Poll::Ready(ev) = self.direct.poll(cx) {
+            return Poll::Ready(ev);
+        }
+        self.relayed.poll(cx)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::{HashMap, HashSet},
+    task::Context,
+    time::Duration,
+};
+
+use libp2p::{
+    core::transport::ListenerId, multiaddr::Protocol, swarm::ListenOpts, Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, Interval};
+
+use crate::{cli::BootNode, util::rendezvous_score};
+
+/// How often the relay choice is revisited (and failed relays retried)
+const REEVALUATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Strategy of choosing the relays to listen on, when relaying is enabled
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelaySelection {
+    /// Listen through all boot nodes at once
+    #[default]
+    AllBootNodes,
+    /// Listen through a single boot node, picked pseudo-randomly. The pick is stable
+    /// for the local peer ID, and spreads nodes evenly across the boot nodes.
+    Random,
+    /// Listen through the boot node with the lowest ping RTT. The choice is re-evaluated
+    /// periodically, switching to a relay which is significantly faster than the current one.
+    LowestLatency,
+    /// Listen through the given relay addresses
+    Explicit(Vec<Multiaddr>),
+}
+
+/// Keeps the node listening through a single relay chosen out of the boot nodes
+pub struct RelaySelector {
+    candidates: HashMap<PeerId, Multiaddr>,
+    local_key: String,
+    latency_based: bool,
+    rtts: HashMap<PeerId, Duration>,
+    // Relays whose listeners were closed since the last re-evaluation
+    failed: HashSet<PeerId>,
+    current: Option<(PeerId, ListenerId)>,
+    reevaluation: Interval,
+}
+
+impl RelaySelector {
+    /// Returns `None` if the strategy doesn't pick a single relay or there are no boot nodes
+    pub fn new(
+        selection: &RelaySelection,
+        boot_nodes: &[BootNode],
+        local_peer_id: PeerId,
+    ) -> Option<Self> {
+        let latency_based = match selection {
+            RelaySelection::Random => false,
+            RelaySelection::LowestLatency => true,
+            RelaySelection::AllBootNodes | RelaySelection::Explicit(_) => return None,
+        };
+        if boot_nodes.is_empty() {
+            log::warn!("No boot nodes to select a relay from");
+            return None;
+        }
+        let candidates =
+            boot_nodes.iter().map(|node| (node.peer_id, node.address.clone())).collect();
+        let reevaluation =
+            tokio::time::interval_at(Instant::now() + REEVALUATION_INTERVAL, REEVALUATION_INTERVAL);
+        Some(Self {
+            candidates,
+            local_key: local_peer_id.to_string(),
+            latency_based,
+            rtts: Default::default(),
+            failed: Default::default(),
+            current: None,
+            reevaluation,
+        })
+    }
+
+    pub fn on_ping(&mut self, peer_id: PeerId, rtt: Duration) {
+        if self.candidates.contains_key(&peer_id) {
+            self.rtts.insert(peer_id, rtt);
+        }
+    }
+
+    pub fn on_listener_closed(&mut self, listener_id: ListenerId) {
+        if let Some((peer_id, _)) = self.current.filter(|(_, id)| *id == listener_id) {
+            log::warn!("Listening through relay {peer_id} stopped");
+            self.failed.insert(peer_id);
+            self.current = None;
+        }
+    }
+
+    /// Returns the listener to remove (if any) and the one to open,
+    /// when the node should start listening through another relay
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Option<(Option<ListenerId>, ListenOpts)> {
+        let mut reevaluate = false;
+        while self.reevaluation.poll_tick(cx).is_ready() {
+            reevaluate = true;
+        }
+        if reevaluate {
+            self.failed.clear();
+        }
+        if self.current.is_some() && !(reevaluate && self.latency_based) {
+            return None;
+        }
+        let current = self.current.map(|(peer_id, _)| peer_id);
+        let selected = self.select(current)?;
+        if Some(selected) == current {
+            return None;
+        }
+        log::info!("Selected relay {selected}");
+        let addr = self.candidates[&selected]
+            .clone()
+            .with(Protocol::P2p(selected))
+            .with(Protocol::P2pCircuit);
+        let opts = ListenOpts::new(addr);
+        let old_listener = self.current.replace((selected, opts.listener_id())).map(|(_, id)| id);
+        Some((old_listener, opts))
+    }
+
+    fn select(&self, current: Option<PeerId>) -> Option<PeerId> {
+        let available = self.candidates.keys().filter(|peer_id| !self.failed.contains(peer_id));
+        if self.latency_based {
+            if let Some(peer_id) = select_lowest_latency(current, &self.rtts, available.clone()) {
+                return Some(peer_id);
+            }
+        }
+        available
+            .copied()
+            .max_by_key(|peer_id| rendezvous_score(&self.local_key, peer_id))
+    }
+}
+
+/// Pick the relay with the lowest RTT. The current one is kept unless the other
+/// is at least 20% faster, to avoid switching back and forth on small fluctuations.
+fn select_lowest_latency<'a>(
+    current: Option<PeerId>,
+    rtts: &HashMap<PeerId, Duration>,
+    available: impl Iterator<Item = &'a PeerId>,
+) -> Option<PeerId> {
+    let measured: Vec<(PeerId, Duration)> = available
+        .filter_map(|peer_id| rtts.get(peer_id).map(|rtt| (*peer_id, *rtt)))
+        .collect();
+    let (best, best_rtt) = measured.iter().min_by_key(|(_, rtt)| *rtt).copied()?;
+    match measured.iter().find(|(peer_id, _)| Some(*peer_id) == current) {
+        Some((current, current_rtt)) if best_rtt * 5 > *current_rtt * 4 => Some(*current),
+        _ => Some(best),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_lowest_latency() {
+        let [a, b, c] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        let rtts: HashMap<PeerId, Duration> = [
+            (a, Duration::from_millis(100)),
+            (b, Duration::from_millis(90)),
+            (c, Duration::from_millis(50)),
+        ]
+        .into();
+
+        assert_eq!(select_lowest_latency(None, &rtts, [a, b].iter()), Some(b));
+        // Not significantly faster, keep the current one
+        assert_eq!(select_lowest_latency(Some(a), &rtts, [a, b].iter()), Some(a));
+        assert_eq!(select_lowest_latency(Some(a), &rtts, rtts.keys()), Some(c));
+        // Current relay unavailable
+        assert_eq!(select_lowest_latency(Some(c), &rtts, [a, b].iter()), Some(b));
+        // No measurements
+        assert_eq!(select_lowest_latency(None, &HashMap::new(), [a, b].iter()), None);
+    }
+}
@@ -1,7 +1,9 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
     vec,
 };
 
@@ -10,11 +12,18 @@ use futures_bounded::FuturesMap;
 
 use libp2p::{
     request_response,
-    request_response::{Codec, OutboundRequestId, ProtocolSupport},
-    swarm::{behaviour::ConnectionEstablished, FromSwarm, ToSwarm},
+    request_response::{Codec, OutboundFailure, OutboundRequestId, ProtocolSupport},
+    swarm::{
+        behaviour::{ConnectionClosed, ConnectionEstablished},
+        FromSwarm, ToSwarm,
+    },
 };
+use lru::LruCache;
+use prost::Message;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{record_outbound_failure, PENDING_MESSAGE_BYTES};
 use crate::{
     behaviour::wrapped::{BehaviourWrapper, TToSwarm},
     PeerId, QueueFull,
@@ -32,23 +41,82 @@ pub enum ClientEvent<T> {
     PeerUnknown {
         peer_id: PeerId,
     },
+    /// Requests buffered while the peer was looked up have been sent, once it got connected
+    PendingRequestsSent {
+        peer_id: PeerId,
+        count: usize,
+        /// Time since the lookup has started
+        waited: Duration,
+    },
     Timeout {
         peer_id: PeerId,
         req_id: OutboundRequestId,
     },
+    /// Peer doesn't support the protocol. The request is dropped and won't be retried.
+    UnsupportedProtocol {
+        peer_id: PeerId,
+        req_id: OutboundRequestId,
+    },
+}
+
+/// Max number of peers for which delivery stats are tracked
+const MAX_TRACKED_PEERS: usize = 4096;
+
+/// Outcomes of requests sent to a peer since it was connected
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryStats {
+    pub sent: u64,
+    pub acked: u64,
+    pub failed: u64,
+    pub timed_out: u64,
+}
+
+/// Per-peer delivery stats shared between the behaviour and the transport handle.
+/// Peers are evicted when disconnected or when too many peers are tracked.
+#[derive(Clone)]
+pub struct DeliveryStatsTracker(Arc<Mutex<LruCache<PeerId, DeliveryStats>>>);
+
+impl Default for DeliveryStatsTracker {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+}
+
+impl DeliveryStatsTracker {
+    pub fn get(&self, peer_id: &PeerId) -> Option<DeliveryStats> {
+        self.0.lock().unwrap().peek(peer_id).copied()
+    }
+
+    fn update(&self, peer_id: PeerId, f: impl FnOnce(&mut DeliveryStats)) {
+        f(self.0.lock().unwrap().get_or_insert_mut(peer_id, Default::default))
+    }
+
+    fn remove(&self, peer_id: &PeerId) {
+        self.0.lock().unwrap().pop(peer_id);
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub max_buffered: usize,
+    /// Maximum total size of the requests waiting for delivery. Requests which would
+    /// exceed it are dropped.
+    #[serde(default = "default_max_buffered_bytes")]
+    pub max_buffered_bytes: usize,
     pub send_timeout: Duration,
     pub resp_timeout: Duration,
 }
 
+fn default_max_buffered_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             max_buffered: 1024,
+            max_buffered_bytes: default_max_buffered_bytes(),
             send_timeout: Duration::from_secs(60),
             resp_timeout: Duration::from_secs(60),
         }
@@ -58,50 +126,78 @@ impl Default for ClientConfig {
 pub struct ClientBehaviour<C>
 where
     C: Codec + Clone + Send + 'static,
-    C::Request: Clone,
+    C::Request: Clone + Message,
 {
     inner: request_response::Behaviour<C>,
+    protocol: String,
     // Requests that were submitted for the first time (req_id -> request)
     original_requests: BTreeMap<OutboundRequestId, C::Request>,
     // Requests that failed and wait for peer to be connected
     waiting_for_connection: HashMap<PeerId, HashSet<OutboundRequestId>>,
     // Requests that were submitted for the second time, after the peer had been found (new_id -> old_id)
     resubmitted_requests: BTreeMap<OutboundRequestId, OutboundRequestId>,
+    // Requests cancelled after they had been sent out, their outcomes aren't reported
+    cancelled: HashSet<OutboundRequestId>,
     // Timeouts for peer lookups
     lookup_timeouts: FuturesMap<PeerId, ()>,
+    lookup_started: HashMap<PeerId, Instant>,
     max_buffered: usize,
+    max_buffered_bytes: usize,
+    // Total size of `original_requests`
+    buffered_bytes: usize,
+    delivery_stats: DeliveryStatsTracker,
 }
 
 impl<C> ClientBehaviour<C>
 where
     C: Codec + Clone + Send + 'static,
-    C::Request: Clone,
+    C::Request: Clone + Message,
 {
-    pub fn new(
+    pub fn new(codec: C, protocol: C::Protocol, config: ClientConfig) -> Self {
+        Self::with_protocols(codec, vec![protocol], config)
+    }
+
+    /// Offer several protocols in the order of preference. The first one is used
+    /// as the name in logs and metrics.
+    pub fn with_protocols(
         codec: C,
-        protocol: C::Protocol,
+        protocols: Vec<C::Protocol>,
         ClientConfig {
             max_buffered,
+            max_buffered_bytes,
             send_timeout,
             resp_timeout,
         }: ClientConfig,
     ) -> Self {
+        let protocol_name = protocols.first().map(|p| p.as_ref().to_string()).unwrap_or_default();
         let inner = request_response::Behaviour::with_codec(
             codec,
-            vec![(protocol, ProtocolSupport::Outbound)],
+            protocols.into_iter().map(|p| (p, ProtocolSupport::Outbound)),
             request_response::Config::default().with_request_timeout(resp_timeout),
         );
         Self {
             inner,
+            protocol: protocol_name,
             original_requests: Default::default(),
             waiting_for_connection: Default::default(),
             resubmitted_requests: Default::default(),
+            cancelled: Default::default(),
             lookup_timeouts: FuturesMap::new(send_timeout, max_buffered),
+            lookup_started: Default::default(),
             max_buffered,
+            max_buffered_bytes,
+            buffered_bytes: 0,
+            delivery_stats: Default::default(),
         }
     }
 
-    /// Try to send a request. It will be dropped if the outbound buffer is full
+    /// Handle to the per-peer delivery stats
+    pub fn delivery_stats(&self) -> DeliveryStatsTracker {
+        self.delivery_stats.clone()
+    }
+
+    /// Try to send a request. It will be dropped if the outbound buffer is full,
+    /// either by the number of requests or by their total size
     pub fn try_send_request(
         &mut self,
         peer_id: PeerId,
@@ -111,34 +207,89 @@ where
             log::warn!("Outbound buffer full. Dropped message to {peer_id}");
             return Err(QueueFull);
         }
+        let size = request.encoded_len();
+        if self.buffered_bytes + size > self.max_buffered_bytes {
+            log::warn!("Outbound buffer size limit reached. Dropped message to {peer_id}");
+            return Err(QueueFull);
+        }
 
         let req_id = self.inner.send_request(&peer_id, request.clone());
         log::debug!("Sending request {req_id} to {peer_id}");
 
         // Buffer request for possible future retry
         self.original_requests.insert(req_id, request);
+        self.add_buffered_bytes(size);
+        self.delivery_stats.update(peer_id, |stats| stats.sent += 1);
 
         Ok(req_id)
     }
 
+    /// Give up on the request: it isn't retried, and neither its response nor its failure is
+    /// reported. Requests which are already out can't be withdrawn from the peer, though.
+    pub fn cancel_request(&mut self, req_id: OutboundRequestId) {
+        if self.remove_request(&req_id).is_some() {
+            // Not sent out again once the peer connects
+            if self
+                .waiting_for_connection
+                .values_mut()
+                .any(|buffered| buffered.remove(&req_id))
+            {
+                return;
+            }
+            self.cancelled.insert(req_id);
+        } else if let Some(new_id) = self
+            .resubmitted_requests
+            .iter()
+            .find_map(|(new_id, old_id)| (*old_id == req_id).then_some(*new_id))
+        {
+            self.resubmitted_requests.remove(&new_id);
+            self.cancelled.insert(new_id);
+        }
+    }
+
+    fn remove_request(&mut self, req_id: &OutboundRequestId) -> Option<C::Request> {
+        let request = self.original_requests.remove(req_id)?;
+        self.sub_buffered_bytes(request.encoded_len());
+        Some(request)
+    }
+
+    fn add_buffered_bytes(&mut self, bytes: usize) {
+        self.buffered_bytes += bytes;
+        #[cfg(feature = "metrics")]
+        PENDING_MESSAGE_BYTES.inc_by(i64::try_from(bytes).unwrap_or(i64::MAX));
+    }
+
+    fn sub_buffered_bytes(&mut self, bytes: usize) {
+        self.buffered_bytes -= bytes;
+        #[cfg(feature = "metrics")]
+        PENDING_MESSAGE_BYTES.dec_by(i64::try_from(bytes).unwrap_or(i64::MAX));
+    }
+
     fn on_timeout(&mut self, peer_id: PeerId) -> Vec<TToSwarm<Self>> {
+        self.lookup_started.remove(&peer_id);
         let buffered = self.waiting_for_connection.remove(&peer_id).unwrap_or_default();
         log::debug!("Lookup for peer {peer_id} timed out, dropping {} requests", buffered.len());
         buffered
             .into_iter()
             .map(|req_id| {
-                self.original_requests.remove(&req_id);
+                self.remove_request(&req_id);
+                self.delivery_stats.update(peer_id, |stats| stats.timed_out += 1);
                 ToSwarm::GenerateEvent(ClientEvent::Timeout { peer_id, req_id })
             })
             .collect()
     }
 
-    fn on_connection_established(&mut self, peer_id: PeerId) {
+    fn on_connection_established(&mut self, peer_id: PeerId) -> Option<TToSwarm<Self>> {
         self.lookup_timeouts.remove(peer_id);
+        let waited = self.lookup_started.remove(&peer_id).map(|t| t.elapsed()).unwrap_or_default();
         let buffered = self.waiting_for_connection.remove(&peer_id).unwrap_or_default();
+        if buffered.is_empty() {
+            return None;
+        }
         log::debug!("Peer {peer_id} connected, sending {} requests", buffered.len());
+        let mut count = 0;
         for old_id in buffered {
-            let Some(request) = self.original_requests.remove(&old_id) else {
+            let Some(request) = self.remove_request(&old_id) else {
                 log::error!("Unknown request: {old_id}");
                 continue;
             };
@@ -146,7 +297,13 @@ where
             let new_id = self.inner.send_request(&peer_id, request);
             log::debug!("Resubmitting request {old_id} as {new_id}");
             self.resubmitted_requests.insert(new_id, old_id);
+            count += 1;
         }
+        Some(ToSwarm::GenerateEvent(ClientEvent::PendingRequestsSent {
+            peer_id,
+            count,
+            waited,
+        }))
     }
 
     fn on_success(
@@ -156,7 +313,8 @@ where
         response: C::Response,
     ) -> Option<TToSwarm<Self>> {
         log::debug!("Request {req_id} successful");
-        self.original_requests.remove(&req_id);
+        self.remove_request(&req_id);
+        self.delivery_stats.update(peer_id, |stats| stats.acked += 1);
         let req_id = self.resubmitted_requests.remove(&req_id).unwrap_or(req_id);
         Some(ToSwarm::GenerateEvent(ClientEvent::Response {
             peer_id,
@@ -169,25 +327,54 @@ where
         log::debug!("Request {req_id} failed");
         // If request was already resubmitted, remove it and emit timeout event
         if let Some(req_id) = self.resubmitted_requests.remove(&req_id) {
+            self.delivery_stats.update(peer_id, |stats| stats.timed_out += 1);
             return Some(ToSwarm::GenerateEvent(ClientEvent::Timeout { peer_id, req_id }));
         }
 
         // If request was submitted for the first time, try to find peer and connect
-        self.waiting_for_connection.entry(peer_id).or_default().insert(req_id);
-        if !self.lookup_timeouts.contains(peer_id) {
-            log::debug!("Requesting lookup for peer {peer_id}");
-            _ = self.lookup_timeouts.try_push(peer_id, futures::future::pending());
-            return Some(ToSwarm::GenerateEvent(ClientEvent::PeerUnknown { peer_id }));
+        if self.lookup_timeouts.contains(peer_id) {
+            self.waiting_for_connection.entry(peer_id).or_default().insert(req_id);
+            return None;
         }
+        if self.lookup_timeouts.try_push(peer_id, futures::future::pending()).is_err() {
+            log::warn!("Too many peer lookups in progress, dropping request {req_id}");
+            self.remove_request(&req_id);
+            self.delivery_stats.update(peer_id, |stats| stats.timed_out += 1);
+            return Some(ToSwarm::GenerateEvent(ClientEvent::Timeout { peer_id, req_id }));
+        }
+        log::debug!("Requesting lookup for peer {peer_id}");
+        self.waiting_for_connection.entry(peer_id).or_default().insert(req_id);
+        self.lookup_started.insert(peer_id, Instant::now());
+        Some(ToSwarm::GenerateEvent(ClientEvent::PeerUnknown { peer_id }))
+    }
 
-        None
+    fn on_unsupported_protocol(
+        &mut self,
+        peer_id: PeerId,
+        req_id: OutboundRequestId,
+    ) -> Option<TToSwarm<Self>> {
+        log::warn!("Peer {peer_id} doesn't support {}. Request {req_id} dropped", self.protocol);
+        self.remove_request(&req_id);
+        self.delivery_stats.update(peer_id, |stats| stats.failed += 1);
+        let req_id = self.resubmitted_requests.remove(&req_id).unwrap_or(req_id);
+        Some(ToSwarm::GenerateEvent(ClientEvent::UnsupportedProtocol { peer_id, req_id }))
+    }
+}
+
+impl<C> Drop for ClientBehaviour<C>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Request: Clone + Message,
+{
+    fn drop(&mut self) {
+        self.sub_buffered_bytes(self.buffered_bytes);
     }
 }
 
 impl<C> BehaviourWrapper for ClientBehaviour<C>
 where
     C: Codec + Clone + Send + 'static,
-    C::Request: Clone,
+    C::Request: Clone + Message,
 {
     type Inner = request_response::Behaviour<C>;
     type Event = ClientEvent<C::Response>;
@@ -197,8 +384,16 @@ where
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm) -> impl IntoIterator<Item = TToSwarm<Self>> {
-        if let FromSwarm::ConnectionEstablished(ConnectionEstablished { peer_id, .. }) = event {
-            self.on_connection_established(peer_id)
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished { peer_id, .. }) => {
+                return self.on_connection_established(peer_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => self.delivery_stats.remove(&peer_id),
+            _ => {}
         }
         None
     }
@@ -207,7 +402,21 @@ where
         &mut self,
         ev: request_response::Event<C::Request, C::Response>,
     ) -> impl IntoIterator<Item = TToSwarm<Self>> {
+        #[cfg(feature = "metrics")]
+        if let request_response::Event::OutboundFailure { error, .. } = &ev {
+            record_outbound_failure(&self.protocol, error);
+        }
         match ev {
+            request_response::Event::Message {
+                message: request_response::Message::Response { request_id, .. },
+                ..
+            }
+            | request_response::Event::OutboundFailure { request_id, .. }
+                if self.cancelled.remove(&request_id) =>
+            {
+                log::debug!("Request {request_id} has been cancelled, its outcome is dropped");
+                None
+            }
             request_response::Event::Message {
                 peer,
                 message:
@@ -217,6 +426,12 @@ where
                     },
                 ..
             } => self.on_success(peer, request_id, response),
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error: OutboundFailure::UnsupportedProtocols,
+                ..
+            } => self.on_unsupported_protocol(peer, request_id),
             request_response::Event::OutboundFailure {
                 peer, request_id, ..
             } => self.on_failure(peer, request_id),
@@ -232,3 +447,89 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::ProtoCodec;
+
+    use super::*;
+
+    const PROTOCOL: &str = "/test/1.0.0";
+
+    #[test]
+    fn test_buffered_bytes_limit() {
+        let config = ClientConfig {
+            max_buffered: 100,
+            max_buffered_bytes: 100,
+            ..Default::default()
+        };
+        let codec = ProtoCodec::<Vec<u8>, u32>::new(1024, 4);
+        let mut client = ClientBehaviour::new(codec, PROTOCOL, config);
+        let peer_id = PeerId::random();
+        let request = vec![0u8; 40];
+        let size = request.encoded_len();
+
+        let first = client.try_send_request(peer_id, request.clone()).unwrap();
+        client.try_send_request(peer_id, request.clone()).unwrap();
+        assert_eq!(client.buffered_bytes, 2 * size);
+        // Far below the count limit, but over the size limit
+        assert!(client.try_send_request(peer_id, request.clone()).is_err());
+        client.try_send_request(peer_id, vec![0u8; 100 - 2 * size - 2]).unwrap();
+        assert_eq!(client.buffered_bytes, 100);
+        assert!(client.try_send_request(peer_id, vec![0u8; 1]).is_err());
+
+        // Delivered requests free up space
+        client.on_success(peer_id, first, 0);
+        assert_eq!(client.buffered_bytes, 100 - size);
+        client.try_send_request(peer_id, request).unwrap();
+        assert_eq!(client.buffered_bytes, 100);
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let codec = ProtoCodec::<Vec<u8>, u32>::new(1024, 4);
+        let mut client = ClientBehaviour::new(codec, PROTOCOL, Default::default());
+        let peer_id = PeerId::random();
+        let sent = client.try_send_request(peer_id, vec![1]).unwrap();
+        let waiting = client.try_send_request(peer_id, vec![2]).unwrap();
+        client.on_failure(peer_id, waiting);
+
+        client.cancel_request(sent);
+        client.cancel_request(waiting);
+        assert_eq!(client.buffered_bytes, 0);
+        // Only the request which is out waits for its outcome to be dropped
+        assert_eq!(client.cancelled, HashSet::from([sent]));
+        // The waiting request isn't sent once the peer connects
+        assert!(client.on_connection_established(peer_id).is_none());
+        assert!(client.resubmitted_requests.is_empty());
+    }
+
+    #[test]
+    fn test_lookups_limit() {
+        let config = ClientConfig {
+            max_buffered: 1,
+            ..Default::default()
+        };
+        let codec = ProtoCodec::<Vec<u8>, u32>::new(1024, 4);
+        let mut client = ClientBehaviour::new(codec, PROTOCOL, config);
+        let first_peer = PeerId::random();
+        let req_id = client.try_send_request(first_peer, vec![1]).unwrap();
+        assert!(matches!(
+            client.on_failure(first_peer, req_id),
+            Some(ToSwarm::GenerateEvent(ClientEvent::PeerUnknown { .. }))
+        ));
+        // The lookup keeps running after the request is cancelled
+        client.cancel_request(req_id);
+
+        // No room for another lookup, the request fails right away
+        let second_peer = PeerId::random();
+        let req_id = client.try_send_request(second_peer, vec![2]).unwrap();
+        assert!(matches!(
+            client.on_failure(second_peer, req_id),
+            Some(ToSwarm::GenerateEvent(ClientEvent::Timeout { peer_id, .. })) if peer_id == second_peer
+        ));
+        assert!(!client.lookup_started.contains_key(&second_peer));
+        assert!(!client.waiting_for_connection.contains_key(&second_peer));
+        assert_eq!(client.buffered_bytes, 0);
+    }
+}
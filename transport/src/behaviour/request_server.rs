@@ -1,11 +1,26 @@
-use crate::behaviour::wrapped::{BehaviourWrapper, TToSwarm};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::behaviour::{
+    substream_rates::{ChurnVerdict, SubstreamRates},
+    wrapped::{BehaviourWrapper, TToSwarm},
+};
+#[cfg(feature = "metrics")]
+use crate::metrics::{record_inbound_failure, record_response_timeout};
 use derivative::Derivative;
+use futures::FutureExt;
+use futures_bounded::FuturesMap;
 use libp2p::{
     request_response,
-    request_response::{Codec, ProtocolSupport, ResponseChannel},
-    swarm::ToSwarm,
+    request_response::{Codec, InboundFailure, InboundRequestId, ProtocolSupport},
+    swarm::{CloseConnection, ToSwarm},
     PeerId,
 };
+use serde::{Deserialize, Serialize};
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -17,11 +32,63 @@ pub struct Request<Req, Res> {
     pub response_channel: ResponseChannel<Res>,
 }
 
+/// Time to send out the timeout response after the application's deadline has passed
+const TIMEOUT_RESPONSE_GRACE: Duration = Duration::from_secs(5);
+/// Requests tracked for a timeout response. Those over the limit get no response
+/// when the application doesn't respond in time, only the stream is closed.
+const MAX_AWAITING_RESPONSE: usize = 10_000;
+
+// Shared with the behaviour, which takes the channel out when the deadline passes
+type SharedChannel<Res> = Arc<Mutex<Option<request_response::ResponseChannel<Res>>>>;
+type WeakChannel<Res> = Weak<Mutex<Option<request_response::ResponseChannel<Res>>>>;
+type TimeoutResponse<C> = Box<dyn Fn(&<C as Codec>::Request) -> <C as Codec>::Response + Send>;
+
+/// Channel for responding to a request, see [`ServerBehaviour::try_send_response`]
+#[derive(Debug)]
+pub struct ResponseChannel<Res> {
+    request_id: InboundRequestId,
+    inner: SharedChannel<Res>,
+}
+
+impl<Res> ResponseChannel<Res> {
+    /// Whether the response can still be sent, i.e. the request hasn't failed or timed out
+    pub fn is_open(&self) -> bool {
+        self.inner.lock().unwrap().as_ref().is_some_and(|channel| channel.is_open())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Time the application has to respond to a request. After that the requester gets
+    /// the response set with [`ServerBehaviour::with_timeout_response`], or the stream is
+    /// closed without a response if there is none, and the response channel becomes invalid.
+    pub response_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            response_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
 pub struct ServerBehaviour<C>
 where
     C: Codec + Clone + Send + 'static,
 {
     inner: request_response::Behaviour<C>,
+    protocol: String,
+    // Requests responded to, whose responses haven't been sent out yet
+    responding: HashSet<InboundRequestId>,
+    substream_rates: Option<SubstreamRates>,
+    // Requests dropped on purpose, their failures are expected
+    dropped: HashSet<InboundRequestId>,
+    // Requests the application hasn't responded to yet, with the response to send
+    // if it doesn't respond before the deadline
+    awaiting_response: HashMap<InboundRequestId, (WeakChannel<C::Response>, Option<C::Response>)>,
+    deadlines: FuturesMap<InboundRequestId, ()>,
+    timeout_response: Option<TimeoutResponse<C>>,
 }
 
 impl<C> ServerBehaviour<C>
@@ -29,12 +96,56 @@ where
     C: Codec + Clone + Send + 'static,
 {
     pub fn new(codec: C, protocol: C::Protocol) -> Self {
+        Self::with_protocols(codec, vec![protocol])
+    }
+
+    /// Serve requests on all the given protocols, e.g. variants with different encodings.
+    /// The first one is used to identify the behaviour in logs and metrics.
+    pub fn with_protocols(codec: C, protocols: Vec<C::Protocol>) -> Self {
+        Self::with_config(codec, protocols, Default::default())
+    }
+
+    pub fn with_config(
+        codec: C,
+        protocols: Vec<C::Protocol>,
+        ServerConfig { response_timeout }: ServerConfig,
+    ) -> Self {
+        let protocol_name = protocols.first().map(|p| p.as_ref().to_string()).unwrap_or_default();
         let inner = request_response::Behaviour::with_codec(
             codec,
-            vec![(protocol, ProtocolSupport::Inbound)],
-            request_response::Config::default(),
+            protocols.into_iter().map(|p| (p, ProtocolSupport::Inbound)),
+            // Leave time to send the timeout response before the stream is closed
+            request_response::Config::default()
+                .with_request_timeout(response_timeout + TIMEOUT_RESPONSE_GRACE),
         );
-        Self { inner }
+        Self {
+            inner,
+            protocol: protocol_name,
+            responding: Default::default(),
+            substream_rates: None,
+            dropped: Default::default(),
+            awaiting_response: Default::default(),
+            deadlines: FuturesMap::new(response_timeout, MAX_AWAITING_RESPONSE),
+            timeout_response: None,
+        }
+    }
+
+    /// Respond to requests the application hasn't responded to within `response_timeout`
+    /// with an explicit error, built from the request, so the requester can tell a timeout
+    /// from a network failure.
+    pub fn with_timeout_response(
+        mut self,
+        response: impl Fn(&C::Request) -> C::Response + Send + 'static,
+    ) -> Self {
+        self.timeout_response = Some(Box::new(response));
+        self
+    }
+
+    /// Count each inbound request, which comes in its own substream, towards the rate of
+    /// substreams opened by the peer. Requests over the churn limit are dropped.
+    pub fn with_substream_rates(mut self, rates: SubstreamRates) -> Self {
+        self.substream_rates = Some(rates);
+        self
     }
 
     pub fn try_send_response(
@@ -42,7 +153,51 @@ where
         resp_chan: ResponseChannel<C::Response>,
         response: C::Response,
     ) -> Result<(), C::Response> {
-        self.inner.send_response(resp_chan, response)
+        let request_id = resp_chan.request_id;
+        // Taken by the behaviour if the deadline has passed
+        let Some(channel) = resp_chan.inner.lock().unwrap().take() else {
+            return Err(response);
+        };
+        self.stop_awaiting(request_id);
+        self.inner.send_response(channel, response)?;
+        self.responding.insert(request_id);
+        Ok(())
+    }
+
+    /// Number of responses which have been queued but not yet sent out
+    pub fn pending_responses(&self) -> usize {
+        self.responding.len()
+    }
+
+    fn stop_awaiting(&mut self, request_id: InboundRequestId) {
+        if self.awaiting_response.remove(&request_id).is_some() {
+            self.deadlines.remove(request_id);
+        }
+    }
+
+    fn on_deadline(&mut self, request_id: InboundRequestId) {
+        let Some((channel, response)) = self.awaiting_response.remove(&request_id) else {
+            return;
+        };
+        // The application has dropped the channel, which has closed the stream already
+        let Some(channel) = channel.upgrade().and_then(|channel| channel.lock().unwrap().take())
+        else {
+            return;
+        };
+        #[cfg(feature = "metrics")]
+        record_response_timeout(&self.protocol);
+        log::warn!("{} request {request_id} not responded to in time", self.protocol);
+        match response {
+            Some(response) => {
+                if self.inner.send_response(channel, response).is_ok() {
+                    self.responding.insert(request_id);
+                }
+            }
+            // Dropping the channel closes the stream without a response
+            None => {
+                self.dropped.insert(request_id);
+            }
+        }
     }
 }
 
@@ -66,20 +221,236 @@ where
                 peer,
                 message:
                     request_response::Message::Request {
-                        request, channel, ..
+                        request_id,
+                        request,
+                        channel,
                     },
             } => {
+                let verdict = self
+                    .substream_rates
+                    .as_ref()
+                    .map_or(ChurnVerdict::Allow, |rates| rates.on_substream_opened(peer));
+                match verdict {
+                    ChurnVerdict::Allow => {}
+                    // Dropping the channel closes the stream without a response
+                    ChurnVerdict::Throttle => {
+                        self.dropped.insert(request_id);
+                        return None;
+                    }
+                    ChurnVerdict::Block => {
+                        return Some(ToSwarm::CloseConnection {
+                            peer_id: peer,
+                            connection: CloseConnection::All,
+                        })
+                    }
+                }
+                let channel = Arc::new(Mutex::new(Some(channel)));
+                if self.deadlines.try_push(request_id, futures::future::pending()).is_ok() {
+                    let timeout_response = self.timeout_response.as_ref().map(|f| f(&request));
+                    self.awaiting_response
+                        .insert(request_id, (Arc::downgrade(&channel), timeout_response));
+                }
                 return Some(ToSwarm::GenerateEvent(Request {
                     peer_id: peer,
                     request,
-                    response_channel: channel,
-                }))
+                    response_channel: ResponseChannel {
+                        request_id,
+                        inner: channel,
+                    },
+                }));
+            }
+            // Throttled and timed out requests fail with no response, it's not an error
+            // on our side
+            request_response::Event::InboundFailure { request_id, .. }
+                if self.dropped.remove(&request_id) => {}
+            request_response::Event::ResponseSent { request_id, .. } => {
+                self.responding.remove(&request_id);
             }
-            request_response::Event::InboundFailure { peer, error, .. } => {
-                log::error!("Request from {peer} failed: {error:?}")
+            request_response::Event::InboundFailure {
+                peer,
+                request_id,
+                error: InboundFailure::Timeout,
+                ..
+            } => {
+                // The response might have been queued just before the timeout
+                self.responding.remove(&request_id);
+                self.stop_awaiting(request_id);
+                #[cfg(feature = "metrics")]
+                record_response_timeout(&self.protocol);
+                log::warn!("{} request from {peer} not responded to in time", self.protocol)
+            }
+            request_response::Event::InboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                self.responding.remove(&request_id);
+                self.stop_awaiting(request_id);
+                #[cfg(feature = "metrics")]
+                record_inbound_failure(&self.protocol, &error);
+                log::error!("{} request from {peer} failed: {error:?}", self.protocol)
             }
             _ => {}
         }
         None
     }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
+        match self.deadlines.poll_unpin(cx) {
+            Poll::Ready((request_id, Err(_))) => {
+                self.on_deadline(request_id);
+                Poll::Ready(None::<TToSwarm<Self>>)
+            }
+            Poll::Pending => Poll::Pending,
+            _ => unreachable!(), // future::pending() should never complete
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade::Version},
+        noise,
+        swarm::{NetworkBehaviour, SwarmEvent},
+        yamux, Multiaddr, Swarm, Transport,
+    };
+
+    use crate::{
+        behaviour::{substream_rates::SubstreamChurnLimit, wrapped::Wrapped},
+        codec::ProtoCodec,
+    };
+
+    use super::*;
+
+    const PROTOCOL: &str = "/test/1.0.0";
+
+    fn new_swarm<B: NetworkBehaviour>(behaviour: B) -> Swarm<B> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|keypair| {
+                Ok::<_, noise::Error>(
+                    MemoryTransport::default()
+                        .upgrade(Version::V1)
+                        .authenticate(noise::Config::new(keypair)?)
+                        .multiplex(yamux::Config::default()),
+                )
+            })
+            .unwrap()
+            .with_behaviour(|_| behaviour)
+            .unwrap()
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout() {
+        let config = ServerConfig {
+            response_timeout: Duration::from_millis(100),
+        };
+        let codec = ProtoCodec::<u32, u32>::new(4, 4);
+        let mut server = new_swarm(Wrapped::from(
+            ServerBehaviour::with_config(codec.clone(), vec![PROTOCOL], config)
+                .with_timeout_response(|request| request + 100),
+        ));
+        let mut client = new_swarm(request_response::Behaviour::with_codec(
+            codec,
+            [(PROTOCOL, ProtocolSupport::Outbound)],
+            Default::default(),
+        ));
+        let server_id = *server.local_peer_id();
+        let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+        server.listen_on(addr.clone()).unwrap();
+        client.dial(addr).unwrap();
+
+        // The application receives the request, but never responds to it
+        let mut unanswered = None;
+        let run = async {
+            loop {
+                tokio::select! {
+                    ev = server.select_next_some() => if let SwarmEvent::Behaviour(req) = ev {
+                        unanswered = Some(req.response_channel);
+                    },
+                    ev = client.select_next_some() => match ev {
+                        SwarmEvent::ConnectionEstablished { .. } => {
+                            client.behaviour_mut().send_request(&server_id, 1);
+                        }
+                        SwarmEvent::Behaviour(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. },
+                            ..
+                        }) => break Ok(response),
+                        SwarmEvent::Behaviour(request_response::Event::OutboundFailure {
+                            error,
+                            ..
+                        }) => break Err(error),
+                        _ => {}
+                    },
+                }
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the requester should get a response before its own timeout");
+        assert_eq!(result.expect("the requester should get the timeout response"), 101);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while server.behaviour().pending_responses() > 0 {
+                server.select_next_some().await;
+            }
+        })
+        .await
+        .expect("the timeout response should be sent out");
+        let channel = unanswered.expect("request should reach the application");
+        assert!(!channel.is_open());
+        assert!(server.behaviour_mut().try_send_response(channel, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_throttled_requests() {
+        let limit = SubstreamChurnLimit {
+            max_per_sec: 0.01,
+            block: false,
+        };
+        let codec = ProtoCodec::<u32, u32>::new(4, 4);
+        let mut server = new_swarm(Wrapped::from(
+            ServerBehaviour::new(codec.clone(), PROTOCOL)
+                .with_substream_rates(SubstreamRates::new(Some(limit), Default::default())),
+        ));
+        let mut client = new_swarm(request_response::Behaviour::with_codec(
+            codec,
+            [(PROTOCOL, ProtocolSupport::Outbound)],
+            Default::default(),
+        ));
+        let server_id = *server.local_peer_id();
+        let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+        server.listen_on(addr.clone()).unwrap();
+        client.dial(addr).unwrap();
+
+        // All the requests are over the limit, so they never reach the application
+        let mut failures = 0;
+        let run = async {
+            while failures < 3 || !server.behaviour().dropped.is_empty() {
+                tokio::select! {
+                    ev = server.select_next_some() => if let SwarmEvent::Behaviour(req) = ev {
+                        panic!("Throttled request delivered: {req:?}");
+                    },
+                    ev = client.select_next_some() => match ev {
+                        SwarmEvent::ConnectionEstablished { .. } => {
+                            for i in 0..3 {
+                                client.behaviour_mut().send_request(&server_id, i);
+                            }
+                        }
+                        SwarmEvent::Behaviour(request_response::Event::OutboundFailure {
+                            ..
+                        }) => failures += 1,
+                        _ => {}
+                    },
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("all the requests should fail");
+        assert_eq!(server.behaviour_mut().pending_responses(), 0);
+    }
 }
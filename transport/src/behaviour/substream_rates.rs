@@ -0,0 +1,166 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::peer_blocks::PeerBlocks;
+#[cfg(feature = "metrics")]
+use crate::metrics::THROTTLED_SUBSTREAMS;
+
+const MAX_TRACKED_PEERS: usize = 4096;
+/// Time over which the substream rate is averaged
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SubstreamChurnLimit {
+    /// Average number of inbound substreams per second a peer may open. The rate is averaged
+    /// over 10 seconds, so a burst of up to ten times this number is tolerated.
+    pub max_per_sec: f64,
+    /// Block peers exceeding the limit and close their connections, instead of only dropping
+    /// their excess requests
+    pub block: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChurnVerdict {
+    Allow,
+    Throttle,
+    Block,
+}
+
+struct PeerRate {
+    rate: f64,
+    updated: Instant,
+}
+
+impl PeerRate {
+    fn decayed(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.rate * (-elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp()
+    }
+}
+
+/// Rate of inbound substreams opened by each peer, as an exponential moving average.
+/// Shared between the request servers and the transport handles.
+#[derive(Clone)]
+pub struct SubstreamRates {
+    peers: Arc<Mutex<LruCache<PeerId, PeerRate>>>,
+    limit: Option<SubstreamChurnLimit>,
+    peer_blocks: PeerBlocks,
+}
+
+impl Default for SubstreamRates {
+    fn default() -> Self {
+        Self::new(None, Default::default())
+    }
+}
+
+impl SubstreamRates {
+    pub fn new(limit: Option<SubstreamChurnLimit>, peer_blocks: PeerBlocks) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self {
+            peers: Arc::new(Mutex::new(LruCache::new(capacity))),
+            limit,
+            peer_blocks,
+        }
+    }
+
+    /// Inbound substreams per second recently opened by the peer
+    pub fn rate(&self, peer_id: &PeerId) -> f64 {
+        self.rate_at(peer_id, Instant::now())
+    }
+
+    fn rate_at(&self, peer_id: &PeerId, now: Instant) -> f64 {
+        self.peers.lock().unwrap().peek(peer_id).map_or(0.0, |peer| peer.decayed(now))
+    }
+
+    pub(crate) fn on_substream_opened(&self, peer_id: PeerId) -> ChurnVerdict {
+        self.on_substream_opened_at(peer_id, Instant::now())
+    }
+
+    fn on_substream_opened_at(&self, peer_id: PeerId, now: Instant) -> ChurnVerdict {
+        let rate = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.get_or_insert_mut(peer_id, || PeerRate {
+                rate: 0.0,
+                updated: now,
+            });
+            peer.rate = peer.decayed(now) + 1.0 / RATE_WINDOW.as_secs_f64();
+            peer.updated = now;
+            peer.rate
+        };
+        let Some(limit) = self.limit else {
+            return ChurnVerdict::Allow;
+        };
+        if rate <= limit.max_per_sec {
+            return ChurnVerdict::Allow;
+        }
+        #[cfg(feature = "metrics")]
+        THROTTLED_SUBSTREAMS.inc();
+        if !limit.block {
+            log::debug!("Peer {peer_id} opens {rate:.1} substreams/s, throttling");
+            return ChurnVerdict::Throttle;
+        }
+        log::warn!("Peer {peer_id} opens {rate:.1} substreams/s, blocking");
+        self.peer_blocks.block(peer_id);
+        // Start over once the block expires
+        self.peers.lock().unwrap().pop(&peer_id);
+        ChurnVerdict::Block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substream_churn() {
+        let limit = SubstreamChurnLimit {
+            max_per_sec: 5.0,
+            block: false,
+        };
+        let rates = SubstreamRates::new(Some(limit), Default::default());
+        let peer_id = PeerId::random();
+        let start = Instant::now();
+        let millis = |ms| start + Duration::from_millis(ms);
+
+        // Steady traffic below the limit is never throttled
+        for i in 0..200 {
+            assert_eq!(rates.on_substream_opened_at(peer_id, millis(i * 500)), ChurnVerdict::Allow);
+        }
+        let rate = rates.rate_at(&peer_id, millis(100_000));
+        assert!((1.5..2.5).contains(&rate), "{rate}");
+
+        // Rapid churn exceeds the limit within the averaging window
+        let verdicts: Vec<_> = (0..100)
+            .map(|i| rates.on_substream_opened_at(peer_id, millis(100_000 + i)))
+            .collect();
+        let allowed = verdicts.iter().take_while(|v| **v == ChurnVerdict::Allow).count();
+        assert!((20..50).contains(&allowed), "{allowed}");
+        assert!(verdicts[allowed..].iter().all(|v| *v == ChurnVerdict::Throttle));
+        assert!(rates.rate_at(&peer_id, millis(100_100)) > 5.0);
+
+        // The rate decays once the peer calms down
+        assert!(rates.rate_at(&peer_id, millis(130_000)) < 1.0);
+        assert_eq!(rates.on_substream_opened_at(peer_id, millis(130_000)), ChurnVerdict::Allow);
+
+        // Peers exceeding the limit get blocked if configured so
+        let peer_blocks = PeerBlocks::default();
+        let rates = SubstreamRates::new(
+            Some(SubstreamChurnLimit {
+                block: true,
+                ..limit
+            }),
+            peer_blocks.clone(),
+        );
+        let verdicts: Vec<_> =
+            (0..100).map(|i| rates.on_substream_opened_at(peer_id, millis(i))).collect();
+        assert!(verdicts.contains(&ChurnVerdict::Block));
+        assert!(peer_blocks.remaining(&peer_id).is_some());
+    }
+}
@@ -35,6 +35,23 @@ pub trait BehaviourWrapper {
     fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
         Poll::<Vec<ToSwarm<Self::Event, THandlerInEvent<Self::Inner>>>>::Pending
     }
+
+    /// Called before dialing the peer. Returning an error cancels the dial.
+    fn on_pending_outbound_connection(
+        &mut self,
+        _maybe_peer: Option<PeerId>,
+    ) -> Result<(), ConnectionDenied> {
+        Ok(())
+    }
+
+    /// Called when an inbound connection has been established, before it's used.
+    /// Returning an error closes the connection.
+    fn on_established_inbound_connection(
+        &mut self,
+        _peer_id: PeerId,
+    ) -> Result<(), ConnectionDenied> {
+        Ok(())
+    }
 }
 
 pub struct Wrapped<T: BehaviourWrapper + 'static> {
@@ -90,6 +107,7 @@ where
         local_addr: &Multiaddr,
         remote_addr: &Multiaddr,
     ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.wrapper.on_established_inbound_connection(peer)?;
         self.inner().handle_established_inbound_connection(
             connection_id,
             peer,
@@ -105,6 +123,7 @@ where
         addresses: &[Multiaddr],
         effective_role: Endpoint,
     ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.wrapper.on_pending_outbound_connection(maybe_peer)?;
         self.inner().handle_pending_outbound_connection(
             connection_id,
             maybe_peer,
@@ -20,7 +20,7 @@ use tokio::signal::unix::{signal, SignalKind};
 
 use subsquid_network_transport::{
     protocol::{dht_protocol, ID_PROTOCOL},
-    util::{addr_is_reachable, get_keypair},
+    util::{addr_is_reachable, get_keypair, warn_on_ephemeral_with_bootstrap},
     BootNode, Keypair, QuicConfig, TransportArgs,
 };
 
@@ -59,6 +59,7 @@ async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let cli = Cli::parse();
     let listen_addrs = cli.transport.listen_addrs();
+    warn_on_ephemeral_with_bootstrap(cli.transport.key.is_none(), true);
     let keypair = get_keypair(cli.transport.key).await?;
     let local_peer_id = PeerId::from(keypair.public());
     log::info!("Local peer ID: {local_peer_id}");
@@ -1,17 +1,33 @@
-use std::time::Duration;
+use std::{num::NonZeroU64, time::Duration};
 
 use futures_core::Stream;
 use libp2p::{
     multiaddr::Protocol,
-    noise,
+    noise, quic,
     swarm::{dial_opts::DialOpts, NetworkBehaviour},
-    yamux, StreamProtocol, Swarm, SwarmBuilder,
+    yamux, StreamProtocol, Swarm, SwarmBuilder, Transport,
 };
 
 use crate::{
-    behaviour::base::{BaseBehaviour, BaseConfig},
+    bandwidth::BandwidthTracker,
+    behaviour::{
+        base::{
+            validate_dataset_record_ttl, BaseBehaviour, BaseConfig, KadStoreConfig,
+            RelayServerConfig,
+        },
+        handshake::HandshakeConfig,
+        peer_blocks::PeerBlockConfig,
+        pubsub::{
+            validate_max_transmit_size, GossipsubTuning, InboundSizeLimit, MessageValidation,
+            PublishRateLimit, PublishRetry, PubsubMsg, TopicValidator,
+        },
+        reconnect_backoff::ReconnectBackoffConfig,
+        relay_selection::{RelaySelection, RelaySelector},
+        substream_rates::SubstreamChurnLimit,
+    },
     cli::{BootNode, TransportArgs},
-    util::get_keypair,
+    swarm_events::SwarmEventKind,
+    util::{get_keypair, warn_on_ephemeral_with_bootstrap, AdvertisedAddrs, Sender},
     Error, Keypair, Multiaddr, PeerId, QuicConfig,
 };
 
@@ -36,37 +52,59 @@ use crate::actors::scheduler::{
 use crate::actors::worker::{
     self, WorkerBehaviour, WorkerConfig, WorkerEvent, WorkerTransportHandle,
 };
-use crate::protocol::dht_protocol;
+use crate::protocol::{dht_protocol, Topic};
 
 pub struct P2PTransportBuilder {
     keypair: Keypair,
+    ephemeral_key: bool,
+    warn_on_ephemeral_with_bootstrap: bool,
     listen_addrs: Vec<Multiaddr>,
     public_addrs: Vec<Multiaddr>,
     boot_nodes: Vec<BootNode>,
-    relay_addrs: Vec<Multiaddr>,
+    relay_selection: RelaySelection,
     relay: bool,
     quic_config: QuicConfig,
     base_config: BaseConfig,
     contract_client: Box<dyn contract_client::Client>,
     dht_protocol: StreamProtocol,
+    raw_events_tx: Option<Sender<SwarmEventKind>>,
+    publish_rate_limits: Vec<(&'static str, PublishRateLimit)>,
+    publish_retries: Vec<(&'static str, PublishRetry)>,
+    topic_validators: Vec<(&'static str, TopicValidator)>,
+    inbound_size_limits: Vec<(&'static str, InboundSizeLimit)>,
+    allowed_topics: Option<Vec<&'static str>>,
+    agent_version: String,
 }
 impl P2PTransportBuilder {
     pub async fn from_cli(args: TransportArgs) -> anyhow::Result<Self> {
         let listen_addrs = args.listen_addrs();
+        let ephemeral_key = args.key.is_none();
         let keypair = get_keypair(args.key).await?;
         let contract_client = contract_client::get_client(&args.rpc).await?;
         let dht_protocol = dht_protocol(args.rpc.network);
         Ok(Self {
             keypair,
+            ephemeral_key,
+            warn_on_ephemeral_with_bootstrap: true,
             listen_addrs,
             public_addrs: args.p2p_public_addrs,
             boot_nodes: args.boot_nodes,
-            relay_addrs: vec![],
+            relay_selection: Default::default(),
             relay: false,
             quic_config: QuicConfig::from_env(),
-            base_config: Default::default(),
+            base_config: BaseConfig {
+                advertised_addrs: args.advertised_addrs,
+                ..Default::default()
+            },
             contract_client,
             dht_protocol,
+            raw_events_tx: None,
+            publish_rate_limits: vec![],
+            publish_retries: vec![],
+            topic_validators: vec![],
+            inbound_size_limits: vec![],
+            allowed_topics: None,
+            agent_version: format!("subsquid-network/{}", env!("CARGO_PKG_VERSION")),
         })
     }
 
@@ -91,16 +129,289 @@ impl P2PTransportBuilder {
     }
 
     pub fn with_relay_addrs<I: IntoIterator<Item = Multiaddr>>(mut self, addrs: I) -> Self {
-        self.relay_addrs.extend(addrs);
+        match &mut self.relay_selection {
+            RelaySelection::Explicit(relay_addrs) => relay_addrs.extend(addrs),
+            selection => *selection = RelaySelection::Explicit(addrs.into_iter().collect()),
+        }
+        self.relay = true;
+        self
+    }
+
+    /// Choose which relays to listen on (see [`RelaySelection`]). Enables relaying.
+    /// By default, all boot nodes are used.
+    pub fn with_relay_selection(mut self, selection: RelaySelection) -> Self {
+        self.relay_selection = selection;
         self.relay = true;
         self
     }
 
+    /// Enable or disable the relay client. When disabled, the node can neither dial
+    /// peers through relays nor listen on relayed addresses.
+    pub fn with_relay_client(mut self, enabled: bool) -> Self {
+        self.base_config.relay_client = enabled;
+        self
+    }
+
+    /// Ping interval for relayed connections. Shorter than the one for direct connections
+    /// by default, to keep relay circuits from being dropped.
+    pub fn with_relay_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.base_config.relay_keepalive_interval = interval;
+        self
+    }
+
+    /// Enable or disable direct connection upgrade (hole punching)
+    pub fn with_dcutr(mut self, enabled: bool) -> Self {
+        self.base_config.dcutr = enabled;
+        self
+    }
+
+    /// Enable or disable AutoNAT public address probing
+    pub fn with_autonat(mut self, enabled: bool) -> Self {
+        self.base_config.autonat = enabled;
+        self
+    }
+
+    /// Probe reachability with AutoNAT every `interval`. Shorter intervals let a node which
+    /// has just come online find out sooner whether it needs relays.
+    pub fn with_autonat_probe_interval(mut self, interval: Duration) -> Self {
+        self.base_config.autonat_probe_interval = Some(interval);
+        self
+    }
+
+    /// Number of consistent AutoNAT probe results needed to confirm the reachability
+    pub fn with_autonat_confidence(mut self, confidence: usize) -> Self {
+        self.base_config.autonat_confidence = Some(confidence);
+        self
+    }
+
     pub fn with_quic_config(mut self, f: impl FnOnce(QuicConfig) -> QuicConfig) -> Self {
         self.quic_config = f(self.quic_config);
         self
     }
 
+    /// Interval of QUIC keep-alive packets (5s by default). Must be shorter than the idle timeout.
+    /// Keep in mind that keep-alives are sent on every open connection, so overly aggressive
+    /// values waste bandwidth.
+    pub fn with_quic_keep_alive(mut self, interval: Duration) -> Self {
+        self.quic_config.keep_alive_interval_ms = duration_to_ms(interval);
+        self
+    }
+
+    /// Time after which an inactive QUIC connection gets closed (60s by default)
+    pub fn with_quic_max_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.quic_config.max_idle_timeout_ms = duration_to_ms(timeout);
+        self
+    }
+
+    /// Subscribe to topics before broadcasting to them, so that the first messages
+    /// don't get dropped for lack of gossipsub peers
+    pub fn with_ensure_subscribed_before_broadcast(mut self, enabled: bool) -> Self {
+        self.base_config.ensure_subscribed_before_broadcast = enabled;
+        self
+    }
+
+    /// Disconnect peers advertising an incompatible protocol version via identify.
+    /// Disabled by default to allow mixed-version operation.
+    pub fn with_strict_protocol_match(mut self, enabled: bool) -> Self {
+        self.base_config.strict_protocol_match = enabled;
+        self
+    }
+
+    /// Stop sending messages in legacy formats, which are only understood by older peers,
+    /// and drop such messages when received. Track the `legacy_messages` metric to find out
+    /// when it's safe to enable.
+    pub fn with_reject_legacy(mut self, enabled: bool) -> Self {
+        self.base_config.reject_legacy = enabled;
+        self
+    }
+
+    /// Act as a relay server, providing reservations and circuits for peers behind NAT.
+    /// Meant for well-connected infrastructure nodes.
+    pub fn with_relay_server(mut self, config: RelayServerConfig) -> Self {
+        self.base_config.relay_server = Some(config);
+        self
+    }
+
+    /// Set gossipsub duplicate cache time, validation timeout, flood publishing and the message
+    /// cache window (see [`GossipsubTuning`])
+    pub fn with_gossipsub_tuning(mut self, tuning: GossipsubTuning) -> Self {
+        self.base_config.gossipsub_tuning = tuning;
+        self
+    }
+
+    /// Max size of gossipsub messages, both broadcast and received. Larger broadcasts fail
+    /// with `MessageTooLarge`. Defaults to the gossipsub default (64 KiB). It also limits
+    /// the size of the bundles of worker logs.
+    pub fn with_gossipsub_max_transmit_size(mut self, size: usize) -> Self {
+        self.base_config.max_pubsub_msg_size = size;
+        self
+    }
+
+    /// Pause reading from a peer when it sends more than `max_bytes_per_sec` bytes per second,
+    /// until its average rate gets back under the limit. Unlimited by default.
+    /// Only direct connections are limited, relayed ones are not.
+    pub fn with_max_bytes_per_sec_per_peer(mut self, max_bytes_per_sec: NonZeroU64) -> Self {
+        self.base_config.max_bytes_per_sec_per_peer = Some(max_bytes_per_sec);
+        self
+    }
+
+    /// Limit the number of dials to peers found by lookups which can be in progress at once
+    /// (100 by default). Further dials wait until some of the ongoing ones finish.
+    pub fn with_max_concurrent_dials(mut self, max_concurrent_dials: usize) -> Self {
+        self.base_config.max_concurrent_dials = max_concurrent_dials;
+        self
+    }
+
+    /// Back off from re-dialing peers which disconnected recently, longer if they keep
+    /// disconnecting soon after connecting. Disabled by default.
+    pub fn with_reconnect_backoff(mut self, config: Option<ReconnectBackoffConfig>) -> Self {
+        self.base_config.reconnect_backoff = config;
+        self
+    }
+
+    /// How long peers blocked through the transport handle stay blocked
+    pub fn with_peer_blocks(mut self, config: PeerBlockConfig) -> Self {
+        self.base_config.peer_blocks = config;
+        self
+    }
+
+    /// Drop requests from peers which open substreams faster than the limit, or block them.
+    /// Unlimited by default.
+    pub fn with_substream_churn_limit(mut self, limit: SubstreamChurnLimit) -> Self {
+        self.base_config.substream_churn_limit = Some(limit);
+        self
+    }
+
+    /// Store Kademlia records and provider records sent by other peers, within the limits.
+    /// By default they're rejected, as the transport uses Kademlia for peer routing only.
+    /// Verified dataset records and dataset providers are stored regardless.
+    pub fn with_kad_store(mut self, config: KadStoreConfig) -> Self {
+        self.base_config.kad_store = Some(config);
+        self
+    }
+
+    /// Advertise only the listen addresses allowed by the policy, e.g. to keep private
+    /// and loopback addresses out of other peers' routing tables. All are advertised by default.
+    /// Explicitly configured public addresses are always advertised.
+    pub fn with_advertised_addrs(mut self, policy: AdvertisedAddrs) -> Self {
+        self.base_config.advertised_addrs = policy;
+        self
+    }
+
+    /// Reject inbound connections once the node has this many established connections,
+    /// to bound resource use under a connection flood. Boot nodes and the peers the node
+    /// depends on (e.g. the scheduler for workers) are still accepted. Unlimited by default.
+    pub fn with_max_established_connections(mut self, max: usize) -> Self {
+        self.base_config.max_established_connections = Some(max);
+        self
+    }
+
+    /// Challenge every connected peer to prove its role (worker, gateway or scheduler)
+    /// with a signature, checking the claimed role against the on-chain registration.
+    /// Disabled by default.
+    pub fn with_handshake(mut self, config: HandshakeConfig) -> Self {
+        self.base_config.handshake = Some(config);
+        self
+    }
+
+    /// Emit notable swarm events (dials, incoming connections, listen address changes etc.)
+    /// to the given queue, in parallel with normal processing. Events are dropped if the queue
+    /// is full.
+    pub fn with_raw_event_tap(mut self, tx: Sender<SwarmEventKind>) -> Self {
+        self.raw_events_tx = Some(tx);
+        self
+    }
+
+    /// Limit the rate of messages published to the topic (see [`Topic`] for well-known
+    /// topics). Messages exceeding the limit are dropped. By default, publishing is unlimited.
+    pub fn with_publish_rate_limit(
+        mut self,
+        topic: impl Into<Topic>,
+        limit: PublishRateLimit,
+    ) -> Self {
+        self.publish_rate_limits.push((topic.into().name(), limit));
+        self
+    }
+
+    /// Buffer messages which couldn't be published to the topic because no peers were subscribed
+    /// to it, and re-publish them when a peer subscribes. By default, such messages are dropped.
+    pub fn with_publish_retry(mut self, topic: impl Into<Topic>, retry: PublishRetry) -> Self {
+        self.publish_retries.push((topic.into().name(), retry));
+        self
+    }
+
+    /// Reject messages received on the topic which are larger than the limit, e.g. for topics
+    /// meant for small control messages. By default, only the global message size limit applies.
+    pub fn with_inbound_size_limit(
+        mut self,
+        topic: impl Into<Topic>,
+        limit: InboundSizeLimit,
+    ) -> Self {
+        self.inbound_size_limits.push((topic.into().name(), limit));
+        self
+    }
+
+    /// Validate messages received on the topic with an application-specific callback before
+    /// they are delivered. Rejected and ignored messages are not forwarded to other peers,
+    /// and the senders of rejected ones get penalized.
+    pub fn with_topic_validator(
+        mut self,
+        topic: impl Into<Topic>,
+        validator: impl Fn(&PubsubMsg) -> MessageValidation + Send + 'static,
+    ) -> Self {
+        self.topic_validators.push((topic.into().name(), Box::new(validator)));
+        self
+    }
+
+    /// Only accept pub-sub messages on the given topics, regardless of subscriptions.
+    /// Messages on other topics are rejected. By default, all subscribed topics are accepted.
+    pub fn with_allowed_topics<T: Into<Topic>>(
+        mut self,
+        topics: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_topics = Some(topics.into_iter().map(|topic| topic.into().name()).collect());
+        self
+    }
+
+    /// Agent version advertised to other peers via identify, e.g. software name, version
+    /// and role. Defaults to `subsquid-network/<transport crate version>`.
+    pub fn with_agent_version(mut self, agent_version: impl Into<String>) -> Self {
+        self.agent_version = agent_version.into();
+        self
+    }
+
+    /// Use a stable identity, e.g. loaded with [`get_keypair`], instead of the one from the CLI
+    /// arguments. Nodes which other peers know by their ID (workers and gateways registered
+    /// in the contract, boot nodes, relays) need a stable key.
+    pub fn with_keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = keypair;
+        self.ephemeral_key = false;
+        self
+    }
+
+    /// Use a freshly generated identity, which is lost on restart. This is the default if no key
+    /// path is given. Suitable for short-lived tools, like peer checkers or observers, which
+    /// don't need a contract registration. Every restart adds a new peer to the routing tables
+    /// of the other nodes, while the old one stays there until evicted, so long-running nodes
+    /// should use a stable key.
+    pub fn with_ephemeral_keypair(mut self) -> Self {
+        self.keypair = Keypair::generate_ed25519();
+        self.ephemeral_key = true;
+        self
+    }
+
+    /// Warn on build if the node serves as bootstrap infrastructure (a relay server or a node
+    /// with public addresses) while using an ephemeral key. Enabled by default.
+    pub fn with_warn_on_ephemeral_with_bootstrap(mut self, enabled: bool) -> Self {
+        self.warn_on_ephemeral_with_bootstrap = enabled;
+        self
+    }
+
+    /// Whether the identity is generated on start rather than loaded from a key file
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral_key
+    }
+
     pub fn local_peer_id(&self) -> PeerId {
         self.keypair.public().to_peer_id()
     }
@@ -114,42 +425,86 @@ impl P2PTransportBuilder {
     }
 
     fn build_swarm<T: NetworkBehaviour>(
-        mut self,
+        self,
         behaviour: impl FnOnce(BaseBehaviour) -> T,
     ) -> Result<Swarm<T>, Error> {
+        self.quic_config.validate()?;
+        self.base_config.gossipsub_tuning.validate()?;
+        validate_max_transmit_size(self.base_config.max_pubsub_msg_size)?;
+        validate_dataset_record_ttl(self.base_config.dataset_record_ttl)?;
+        if self.warn_on_ephemeral_with_bootstrap {
+            let bootstrap =
+                self.base_config.relay_server.is_some() || !self.public_addrs.is_empty();
+            warn_on_ephemeral_with_bootstrap(self.ephemeral_key, bootstrap);
+        }
+        let relay_selector = if self.relay && self.base_config.relay_client {
+            let local_peer_id = self.local_peer_id();
+            RelaySelector::new(&self.relay_selection, &self.boot_nodes, local_peer_id)
+        } else {
+            None
+        };
+        let bandwidth = BandwidthTracker::new(self.base_config.max_bytes_per_sec_per_peer);
+        let transport_bandwidth = bandwidth.clone();
         let mut swarm = SwarmBuilder::with_existing_identity(self.keypair)
             .with_tokio()
-            .with_quic_config(|config| {
-                let mut config = config.mtu_upper_bound(self.quic_config.mtu_discovery_max);
+            .with_other_transport(|keypair| {
+                let mut config =
+                    quic::Config::new(keypair).mtu_upper_bound(self.quic_config.mtu_discovery_max);
                 config.keep_alive_interval =
                     Duration::from_millis(self.quic_config.keep_alive_interval_ms as u64);
                 config.max_idle_timeout = self.quic_config.max_idle_timeout_ms;
-                config
+                quic::tokio::Transport::new(config).map(move |(peer_id, conn), _| {
+                    (peer_id, transport_bandwidth.meter(peer_id, conn))
+                })
             })
+            .map_err(|e| Error::Transport(e.to_string()))?
             .with_dns()?
             .with_relay_client(noise::Config::new, yamux::Config::default)?
             .with_behaviour(|keypair, relay| {
-                let base = BaseBehaviour::new(
+                let mut base = BaseBehaviour::new(
                     keypair,
                     self.contract_client,
                     self.base_config,
                     self.boot_nodes.clone(),
                     relay,
                     self.dht_protocol,
+                    self.agent_version,
                 );
+                base.set_bandwidth_tracker(bandwidth);
+                if let Some(selector) = relay_selector {
+                    base.set_relay_selector(selector);
+                }
+                for (topic, limit) in self.publish_rate_limits {
+                    base.set_publish_rate_limit(topic, Some(limit));
+                }
+                for (topic, retry) in self.publish_retries {
+                    base.set_publish_retry(topic, Some(retry));
+                }
+                for (topic, limit) in self.inbound_size_limits {
+                    base.set_inbound_size_limit(topic, Some(limit));
+                }
+                for (topic, validator) in self.topic_validators {
+                    base.set_topic_validator(topic, Some(validator));
+                }
+                if let Some(topics) = self.allowed_topics {
+                    base.set_allowed_topics(&topics);
+                }
                 behaviour(base)
             })
             .expect("infallible")
             .build();
 
-        // If relay node not specified explicitly, use boot nodes
-        if self.relay && self.relay_addrs.is_empty() {
-            self.relay_addrs = self
+        // If relay node not specified explicitly, use boot nodes. Strategies picking
+        // a single relay are run by the behaviour.
+        let relay_addrs = match self.relay_selection {
+            RelaySelection::AllBootNodes => self
                 .boot_nodes
                 .iter()
                 .map(|bn| bn.address.clone().with(Protocol::P2p(bn.peer_id)))
-                .collect();
-        }
+                .collect(),
+            RelaySelection::Explicit(addrs) => addrs,
+            RelaySelection::Random | RelaySelection::LowestLatency => vec![],
+        };
 
         // Listen on provided addresses
         for addr in self.listen_addrs {
@@ -168,8 +523,10 @@ impl P2PTransportBuilder {
         }
 
         // Connect to relay and listen for relayed connections
-        if self.relay {
-            for addr in self.relay_addrs {
+        if self.relay && !self.base_config.relay_client {
+            log::warn!("Relay client disabled. Not listening on relayed addresses.");
+        } else if self.relay {
+            for addr in relay_addrs {
                 log::info!("Connecting to relay {addr}");
                 swarm.listen_on(addr.with(Protocol::P2pCircuit))?;
             }
@@ -183,8 +540,11 @@ impl P2PTransportBuilder {
         self,
         config: GatewayConfig,
     ) -> Result<(impl Stream<Item = GatewayEvent>, GatewayTransportHandle), Error> {
-        let swarm = self.build_swarm(|base| GatewayBehaviour::new(base, config))?;
-        Ok(gateway::start_transport(swarm, config))
+        let raw_events_tx = self.raw_events_tx.clone();
+        let contract_client = self.contract_client();
+        let swarm =
+            self.build_swarm(|base| GatewayBehaviour::new(base, contract_client, config))?;
+        Ok(gateway::start_transport(swarm, config, raw_events_tx))
     }
 
     #[cfg(feature = "logs-collector")]
@@ -192,8 +552,9 @@ impl P2PTransportBuilder {
         self,
         config: LogsCollectorConfig,
     ) -> Result<(impl Stream<Item = LogsCollectorEvent>, LogsCollectorTransportHandle), Error> {
+        let raw_events_tx = self.raw_events_tx.clone();
         let swarm = self.build_swarm(|base| LogsCollectorBehaviour::new(base, config))?;
-        Ok(logs_collector::start_transport(swarm, config))
+        Ok(logs_collector::start_transport(swarm, config, raw_events_tx))
     }
 
     #[cfg(feature = "observer")]
@@ -201,9 +562,9 @@ impl P2PTransportBuilder {
         self,
         config: ObserverConfig,
     ) -> Result<(impl Stream<Item = ObserverEvent>, ObserverTransportHandle), Error> {
-        let swarm =
-            self.build_swarm(|base| ObserverBehaviour::new(base, config.logs_collector_id))?;
-        Ok(observer::start_transport(swarm, config))
+        let raw_events_tx = self.raw_events_tx.clone();
+        let swarm = self.build_swarm(|base| ObserverBehaviour::new(base, config))?;
+        Ok(observer::start_transport(swarm, config, raw_events_tx))
     }
 
     #[cfg(feature = "scheduler")]
@@ -211,8 +572,9 @@ impl P2PTransportBuilder {
         self,
         config: SchedulerConfig,
     ) -> Result<(impl Stream<Item = SchedulerEvent>, SchedulerTransportHandle), Error> {
+        let raw_events_tx = self.raw_events_tx.clone();
         let swarm = self.build_swarm(|base| SchedulerBehaviour::new(base, config))?;
-        Ok(scheduler::start_transport(swarm, config))
+        Ok(scheduler::start_transport(swarm, config, raw_events_tx))
     }
 
     #[cfg(feature = "worker")]
@@ -237,7 +599,15 @@ impl P2PTransportBuilder {
             }
             break;
         }
-        let swarm = self.build_swarm(|base| WorkerBehaviour::new(base, local_peer_id, config))?;
-        Ok(worker::start_transport(swarm, config))
+        let raw_events_tx = self.raw_events_tx.clone();
+        let contract_client = self.contract_client();
+        let swarm = self.build_swarm(|base| {
+            WorkerBehaviour::new(base, local_peer_id, Some(contract_client), config.clone())
+        })?;
+        Ok(worker::start_transport(swarm, config, raw_events_tx))
     }
 }
+
+fn duration_to_ms(duration: Duration) -> u32 {
+    duration.as_millis().try_into().unwrap_or(u32::MAX)
+}
@@ -1,4 +1,4 @@
-use crate::PeerId;
+use crate::{util::AdvertisedAddrs, PeerId};
 use clap::Args;
 use contract_client::RpcArgs;
 use libp2p::Multiaddr;
@@ -6,7 +6,12 @@ use std::{path::PathBuf, str::FromStr};
 
 #[derive(Args)]
 pub struct TransportArgs {
-    #[arg(short, long, env = "KEY_PATH", help = "Path to libp2p key file")]
+    #[arg(
+        short,
+        long,
+        env = "KEY_PATH",
+        help = "Path to libp2p key file. If not set, an ephemeral key is generated on every start"
+    )]
     pub key: Option<PathBuf>,
 
     #[arg(
@@ -28,6 +33,15 @@ pub struct TransportArgs {
     )]
     pub p2p_public_addrs: Vec<Multiaddr>,
 
+    #[arg(
+        long,
+        env,
+        value_enum,
+        help = "Which of the listen addresses are advertised to other peers",
+        default_value = "all"
+    )]
+    pub advertised_addrs: AdvertisedAddrs,
+
     #[arg(
         long,
         env,
@@ -0,0 +1,122 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::PeerId;
+use lru::LruCache;
+
+const MAX_TRACKED_PEERS: usize = 4096;
+/// Number of the latest message timestamps the estimate is based on
+const SAMPLES_PER_PEER: usize = 16;
+/// Skew above which messages from the peer are likely to be rejected as too old or from the future
+const SKEW_WARNING_THRESHOLD_MS: i64 = 5000;
+
+#[derive(Default)]
+struct PeerClock {
+    rtt: Option<Duration>,
+    // Remote timestamp minus local time of receiving (in milliseconds) of the latest messages
+    offsets: VecDeque<i64>,
+    warned: bool,
+}
+
+impl PeerClock {
+    /// Messages are received after they're timestamped, so every offset is the skew minus
+    /// the delivery delay. The largest one is the closest to the skew. Half of the ping
+    /// round trip approximates the minimal delay.
+    fn estimate(&self) -> Option<i64> {
+        let offset = self.offsets.iter().max()?;
+        let half_rtt = self.rtt.map_or(0, |rtt| i64::try_from(rtt.as_millis() / 2).unwrap_or(0));
+        Some(offset + half_rtt)
+    }
+}
+
+/// Estimates of the peers' clock skew, based on the timestamps of the messages received
+/// from them. Shared between the transport and the transport handles.
+#[derive(Clone)]
+pub struct ClockSkewTracker(Arc<Mutex<LruCache<PeerId, PeerClock>>>);
+
+impl Default for ClockSkewTracker {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED_PEERS).unwrap();
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+}
+
+impl ClockSkewTracker {
+    /// Estimated difference between the peer's clock and the local one, in milliseconds.
+    /// Positive if the peer's clock is ahead.
+    pub fn get(&self, peer_id: &PeerId) -> Option<i64> {
+        self.0.lock().unwrap().peek(peer_id).and_then(PeerClock::estimate)
+    }
+
+    pub fn record_rtt(&self, peer_id: PeerId, rtt: Duration) {
+        if let Some(clock) = self.0.lock().unwrap().get_mut(&peer_id) {
+            clock.rtt = Some(rtt);
+        }
+    }
+
+    /// Record the timestamp (in milliseconds since the UNIX epoch) of a message just received
+    pub fn record_timestamp(&self, peer_id: PeerId, timestamp_ms: u64) {
+        self.record_timestamp_at(peer_id, timestamp_ms, SystemTime::now());
+    }
+
+    fn record_timestamp_at(&self, peer_id: PeerId, timestamp_ms: u64, now: SystemTime) {
+        let now_ms = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let offset = timestamp_ms as i64 - u64::try_from(now_ms).unwrap_or(u64::MAX) as i64;
+        let mut peers = self.0.lock().unwrap();
+        let clock = peers.get_or_insert_mut(peer_id, Default::default);
+        if clock.offsets.len() == SAMPLES_PER_PEER {
+            clock.offsets.pop_front();
+        }
+        clock.offsets.push_back(offset);
+        let Some(skew) = clock.estimate() else {
+            return;
+        };
+        let skewed = skew.abs() > SKEW_WARNING_THRESHOLD_MS;
+        if skewed && !clock.warned {
+            log::warn!(
+                "Clock of peer {peer_id} differs from the local one by ~{skew}ms. \
+                Its messages may be rejected as expired or coming from the future. \
+                Check that the clocks are synchronized (e.g. with NTP)."
+            );
+        } else if !skewed && clock.warned {
+            log::info!("Clock of peer {peer_id} is in sync again (skew ~{skew}ms)");
+        }
+        clock.warned = skewed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew_estimate() {
+        let tracker = ClockSkewTracker::default();
+        let peer_id = PeerId::random();
+        let now = SystemTime::now();
+        let ts = |t: SystemTime| {
+            u64::try_from(t.duration_since(UNIX_EPOCH).unwrap().as_millis()).unwrap()
+        };
+        assert_eq!(tracker.get(&peer_id), None);
+
+        // Peer's clock is 10s ahead, messages are delivered with various delays
+        let remote_now = now + Duration::from_secs(10);
+        tracker.record_timestamp_at(peer_id, ts(remote_now - Duration::from_millis(300)), now);
+        tracker.record_timestamp_at(peer_id, ts(remote_now - Duration::from_millis(100)), now);
+        tracker.record_timestamp_at(peer_id, ts(remote_now - Duration::from_secs(2)), now);
+        assert_eq!(tracker.get(&peer_id), Some(9900));
+
+        tracker.record_rtt(peer_id, Duration::from_millis(200));
+        assert_eq!(tracker.get(&peer_id), Some(10000));
+
+        // Old samples are forgotten
+        for _ in 0..SAMPLES_PER_PEER {
+            tracker.record_timestamp_at(peer_id, ts(now - Duration::from_millis(100)), now);
+        }
+        assert_eq!(tracker.get(&peer_id), Some(0));
+    }
+}
@@ -1,17 +1,207 @@
-use std::marker::PhantomData;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use futures::{AsyncReadExt, AsyncWriteExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libp2p::request_response;
 use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::{COMPRESSED_PROTOCOL_SUFFIX, JSON_PROTOCOL_SUFFIX};
 
 pub const ACK_SIZE: u64 = 4;
+/// Default time a stream may make no progress before it's aborted
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Flags preceding the responses on the compressed protocols
+const UNCOMPRESSED: u8 = 0;
+const GZIP: u8 = 1;
+
+/// Inbound request exceeding the size limit. The request body isn't read past the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    pub max_size: u64,
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Payload too large (max {} bytes)", self.max_size)
+    }
+}
+
+/// Stream wrapper which fails reads and writes making no progress for `timeout`, so that
+/// a peer trickling data can't keep the stream open for the whole request timeout
+struct IdleTimeout<'a, T> {
+    io: &'a mut T,
+    timeout: Option<Duration>,
+    timer: Option<Pin<Box<tokio::time::Sleep>>>,
+    // Not timing out before the first progress, e.g. while the peer prepares a response
+    started: bool,
+}
+
+impl<'a, T> IdleTimeout<'a, T> {
+    fn new(io: &'a mut T, timeout: Option<Duration>) -> Self {
+        Self {
+            io,
+            timeout,
+            timer: None,
+            started: true,
+        }
+    }
+
+    /// Only time the gaps after the first progress. The wait for the first byte is limited
+    /// by the timeout of the whole request.
+    fn after_first_byte(io: &'a mut T, timeout: Option<Duration>) -> Self {
+        Self {
+            started: false,
+            ..Self::new(io, timeout)
+        }
+    }
+
+    fn on_progress<R>(&mut self, result: io::Result<R>) -> Poll<io::Result<R>> {
+        self.timer = None;
+        self.started = true;
+        Poll::Ready(result)
+    }
+
+    fn poll_idle<R>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<R>> {
+        let Some(timeout) = self.timeout.filter(|_| self.started) else {
+            return Poll::Pending;
+        };
+        let timer = self.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Stream idle for {timeout:?}"),
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut *self.io).poll_read(cx, buf) {
+            Poll::Ready(result) => self.on_progress(result),
+            Poll::Pending => self.poll_idle(cx),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut *self.io).poll_write(cx, buf) {
+            Poll::Ready(result) => self.on_progress(result),
+            Poll::Pending => self.poll_idle(cx),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut *self.io).poll_flush(cx) {
+            Poll::Ready(result) => self.on_progress(result),
+            Poll::Pending => self.poll_idle(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut *self.io).poll_close(cx) {
+            Poll::Ready(result) => self.on_progress(result),
+            Poll::Pending => self.poll_idle(cx),
+        }
+    }
+}
+
+/// Read at most `max_size` bytes. Returns `None` if there is more data.
+async fn read_limited<T>(io: &mut T, max_size: u64) -> io::Result<Option<Vec<u8>>>
+where
+    T: futures::AsyncRead + Unpin + Send,
+{
+    let mut buf = Vec::new();
+    io.take(max_size.saturating_add(1)).read_to_end(&mut buf).await?;
+    Ok((buf.len() as u64 <= max_size).then_some(buf))
+}
+
+fn too_large(max_size: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, PayloadTooLarge { max_size }.to_string())
+}
 
+fn decode<M>(protocol: &str, buf: &[u8]) -> io::Result<M>
+where
+    M: Message + Default + DeserializeOwned,
+{
+    if protocol.ends_with(JSON_PROTOCOL_SUFFIX) {
+        Ok(serde_json::from_slice(buf)?)
+    } else {
+        Ok(M::decode(buf)?)
+    }
+}
+
+fn encode<M: Message + Serialize>(protocol: &str, msg: &M) -> io::Result<Vec<u8>> {
+    if protocol.ends_with(JSON_PROTOCOL_SUFFIX) {
+        Ok(serde_json::to_vec(msg)?)
+    } else {
+        Ok(msg.encode_to_vec())
+    }
+}
+
+/// Prepend the compression flag to the encoded response, compressing it if it's larger
+/// than `threshold` bytes
+fn compress(buf: Vec<u8>, threshold: usize) -> io::Result<Vec<u8>> {
+    if buf.len() <= threshold {
+        let mut framed = Vec::with_capacity(buf.len() + 1);
+        framed.push(UNCOMPRESSED);
+        framed.extend(buf);
+        return Ok(framed);
+    }
+    let mut encoder = GzEncoder::new(vec![GZIP], Compression::fast());
+    io::Write::write_all(&mut encoder, &buf)?;
+    encoder.finish()
+}
+
+/// Inverse of [`compress`]. The decompressed message is limited to `max_size` bytes too.
+fn decompress(buf: Vec<u8>, max_size: u64) -> io::Result<Vec<u8>> {
+    match buf.split_first() {
+        Some((&UNCOMPRESSED, data)) => Ok(data.to_vec()),
+        Some((&GZIP, data)) => {
+            let mut decompressed = Vec::new();
+            let decoder = GzDecoder::new(data);
+            io::Read::read_to_end(
+                &mut io::Read::take(decoder, max_size.saturating_add(1)),
+                &mut decompressed,
+            )?;
+            if decompressed.len() as u64 > max_size {
+                return Err(too_large(max_size));
+            }
+            Ok(decompressed)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown compression flag")),
+    }
+}
+
+/// Codec of protobuf messages (or JSON, see [`JSON_PROTOCOL_SUFFIX`])
 pub struct ProtoCodec<Req, Res> {
     _req: PhantomData<Req>,
     _res: PhantomData<Res>,
     max_req_size: u64,
     max_res_size: u64,
+    compression_threshold: usize,
+    idle_timeout: Option<Duration>,
 }
 
 impl<Req, Res> ProtoCodec<Req, Res> {
@@ -21,8 +211,25 @@ impl<Req, Res> ProtoCodec<Req, Res> {
             _res: Default::default(),
             max_req_size,
             max_res_size,
+            compression_threshold: usize::MAX,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
         }
     }
+
+    /// Abort reading or writing a message if the stream makes no progress for `timeout`
+    /// (never if `None`). This is on top of the timeout of the whole request. Reading
+    /// a response is only timed after its first byte has arrived.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Compress responses larger than `threshold` bytes on the protocols supporting it
+    /// (see [`COMPRESSED_PROTOCOL_SUFFIX`]). By default, responses are not compressed.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
 }
 
 impl<Req, Res> Clone for ProtoCodec<Req, Res> {
@@ -34,8 +241,10 @@ impl<Req, Res> Clone for ProtoCodec<Req, Res> {
 impl<Req, Res> Copy for ProtoCodec<Req, Res> {}
 
 #[async_trait]
-impl<Req: Message + Default, Res: Message + Default> request_response::Codec
-    for ProtoCodec<Req, Res>
+impl<Req, Res> request_response::Codec for ProtoCodec<Req, Res>
+where
+    Req: Message + Default + Serialize + DeserializeOwned,
+    Res: Message + Default + Serialize + DeserializeOwned,
 {
     type Protocol = &'static str;
     type Request = Req;
@@ -43,53 +252,412 @@ impl<Req: Message + Default, Res: Message + Default> request_response::Codec
 
     async fn read_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> std::io::Result<Self::Request>
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let mut buf = Vec::new();
-        io.take(self.max_req_size).read_to_end(&mut buf).await?;
-        Ok(Req::decode(buf.as_slice())?)
+        let mut io = IdleTimeout::new(io, self.idle_timeout);
+        let buf = read_limited(&mut io, self.max_req_size)
+            .await?
+            .ok_or_else(|| too_large(self.max_req_size))?;
+        decode(protocol, &buf)
     }
 
     async fn read_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> std::io::Result<Self::Response>
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let mut buf = Vec::new();
-        io.take(self.max_res_size).read_to_end(&mut buf).await?;
-        Ok(Res::decode(buf.as_slice())?)
+        // The response is only written once the peer has processed the request,
+        // which may take longer than the idle timeout
+        let mut io = IdleTimeout::after_first_byte(io, self.idle_timeout);
+        let mut buf = read_limited(&mut io, self.max_res_size)
+            .await?
+            .ok_or_else(|| too_large(self.max_res_size))?;
+        if protocol.ends_with(COMPRESSED_PROTOCOL_SUFFIX) {
+            buf = decompress(buf, self.max_res_size)?;
+        }
+        decode(protocol, &buf)
     }
 
     async fn write_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> std::io::Result<()>
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let buf = req.encode_to_vec();
+        let buf = encode(protocol, &req)?;
+        let mut io = IdleTimeout::new(io, self.idle_timeout);
         io.write_all(buf.as_slice()).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> std::io::Result<()>
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let buf = res.encode_to_vec();
+        let mut buf = encode(protocol, &res)?;
+        if protocol.ends_with(COMPRESSED_PROTOCOL_SUFFIX) {
+            buf = compress(buf, self.compression_threshold)?;
+        }
+        let mut io = IdleTimeout::new(io, self.idle_timeout);
         io.write_all(buf.as_slice()).await
     }
 }
+
+/// Server side of [`ProtoCodec`]. Requests exceeding the size limit are passed on
+/// as `Err(PayloadTooLarge)` instead of failing the stream, so that the server can
+/// respond with an error right away.
+pub struct ServerCodec<Req, Res>(ProtoCodec<Req, Res>);
+
+impl<Req, Res> ServerCodec<Req, Res> {
+    pub fn new(max_req_size: u64, max_res_size: u64) -> Self {
+        Self(ProtoCodec::new(max_req_size, max_res_size))
+    }
+
+    /// See [`ProtoCodec::with_compression_threshold`]
+    pub fn with_compression_threshold(self, threshold: usize) -> Self {
+        Self(self.0.with_compression_threshold(threshold))
+    }
+
+    /// See [`ProtoCodec::with_idle_timeout`]
+    pub fn with_idle_timeout(self, timeout: Option<Duration>) -> Self {
+        Self(self.0.with_idle_timeout(timeout))
+    }
+}
+
+impl<Req, Res> Clone for ServerCodec<Req, Res> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Req, Res> Copy for ServerCodec<Req, Res> {}
+
+#[async_trait]
+impl<Req, Res> request_response::Codec for ServerCodec<Req, Res>
+where
+    Req: Message + Default + Serialize + DeserializeOwned,
+    Res: Message + Default + Serialize + DeserializeOwned,
+{
+    type Protocol = &'static str;
+    type Request = Result<Req, PayloadTooLarge>;
+    type Response = Res;
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let max_size = self.0.max_req_size;
+        let mut io = IdleTimeout::new(io, self.0.idle_timeout);
+        match read_limited(&mut io, max_size).await? {
+            Some(buf) => Ok(Ok(decode(protocol, &buf)?)),
+            None => Ok(Err(PayloadTooLarge { max_size })),
+        }
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        self.0.read_response(protocol, io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let req = req.map_err(|e| too_large(e.max_size))?;
+        self.0.write_request(protocol, io, req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        self.0.write_response(protocol, io, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use libp2p::request_response::Codec;
+    use subsquid_messages::{query_result, OkResult, Query, QueryResult, QueryStats};
+
+    use super::*;
+
+    const PROTOCOL: &str = "/test/1.0.0";
+
+    fn query(size: usize) -> Query {
+        Query {
+            query_id: Some("id".to_string()),
+            query: Some("x".repeat(size)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request() {
+        let mut codec = ServerCodec::<Query, QueryResult>::new(100, 100);
+
+        let mut stream = Cursor::new(query(50).encode_to_vec());
+        let req = codec.read_request(&PROTOCOL, &mut stream).await.unwrap();
+        assert_eq!(req, Ok(query(50)));
+
+        let buf = query(1000).encode_to_vec();
+        let mut stream = Cursor::new(&buf);
+        let req = codec.read_request(&PROTOCOL, &mut stream).await.unwrap();
+        assert_eq!(req, Err(PayloadTooLarge { max_size: 100 }));
+        // The body isn't read further than necessary
+        assert_eq!(stream.position(), 101);
+
+        let mut codec = ProtoCodec::<Query, QueryResult>::new(100, 100);
+        let mut stream = Cursor::new(&buf);
+        let err = codec.read_request(&PROTOCOL, &mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_json_round_trip() {
+        const JSON_PROTOCOL: &str = "/test/1.0.0/json";
+        let mut codec = ServerCodec::<Query, QueryResult>::new(1000, 1000);
+
+        let req = r#"{
+            "query_id": "id",
+            "dataset": "s3://ethereum-mainnet",
+            "query": "{}",
+            "profiling": false,
+            "client_state_json": null,
+            "signature": "0123abcd",
+            "timestamp_ms": 1700000000000
+        }"#;
+        let mut stream = Cursor::new(req.as_bytes());
+        let req = codec.read_request(&JSON_PROTOCOL, &mut stream).await.unwrap().unwrap();
+        assert_eq!(
+            req,
+            Query {
+                query_id: Some("id".to_string()),
+                dataset: Some("s3://ethereum-mainnet".to_string()),
+                query: Some("{}".to_string()),
+                profiling: Some(false),
+                client_state_json: None,
+                signature: vec![0x01, 0x23, 0xab, 0xcd],
+                timestamp_ms: Some(1700000000000),
+                trace_id: None,
+            }
+        );
+
+        let res = QueryResult::new(
+            "id".to_string(),
+            query_result::Result::BadRequest("invalid".to_string()),
+        );
+        let mut stream = Cursor::new(Vec::new());
+        codec.write_response(&JSON_PROTOCOL, &mut stream, res.clone()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(stream.get_ref()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"query_id": "id", "result": {"BadRequest": "invalid"}})
+        );
+
+        // The size limit applies to JSON too
+        let mut stream = Cursor::new(serde_json::to_vec(&query(1000)).unwrap());
+        let req = codec.read_request(&JSON_PROTOCOL, &mut stream).await.unwrap();
+        assert_eq!(req, Err(PayloadTooLarge { max_size: 1000 }));
+
+        // Protobuf is used for the protocols without the suffix
+        let mut stream = Cursor::new(Vec::new());
+        codec.write_response(&PROTOCOL, &mut stream, res.clone()).await.unwrap();
+        assert_eq!(stream.get_ref(), &res.encode_to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_compression_threshold() {
+        const COMPRESSED_PROTOCOL: &str = "/test/1.0.0/compressed";
+        let mut worker_codec =
+            ServerCodec::<Query, QueryResult>::new(1000, 10_000).with_compression_threshold(100);
+        let mut gateway_codec = ProtoCodec::<Query, QueryResult>::new(1000, 10_000);
+        let result = |size| {
+            let data = vec![7; size];
+            let result = OkResult {
+                data,
+                ..Default::default()
+            };
+            QueryResult::new("id".to_string(), query_result::Result::Ok(result))
+        };
+
+        for (size, expected_flag) in [(10, UNCOMPRESSED), (5000, GZIP)] {
+            let res = result(size);
+            let mut stream = Cursor::new(Vec::new());
+            worker_codec
+                .write_response(&COMPRESSED_PROTOCOL, &mut stream, res.clone())
+                .await
+                .unwrap();
+            assert_eq!(stream.get_ref()[0], expected_flag);
+            if expected_flag == GZIP {
+                assert!(stream.get_ref().len() < res.encoded_len());
+            }
+            stream.set_position(0);
+            let decoded =
+                gateway_codec.read_response(&COMPRESSED_PROTOCOL, &mut stream).await.unwrap();
+            assert_eq!(decoded, res);
+        }
+
+        // The decompressed size is limited too
+        let mut stream = Cursor::new(Vec::new());
+        worker_codec
+            .write_response(&COMPRESSED_PROTOCOL, &mut stream, result(20_000))
+            .await
+            .unwrap();
+        stream.set_position(0);
+        let err = gateway_codec
+            .read_response(&COMPRESSED_PROTOCOL, &mut stream)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // No flag on the other protocols
+        let mut stream = Cursor::new(Vec::new());
+        worker_codec.write_response(&PROTOCOL, &mut stream, result(5000)).await.unwrap();
+        assert_eq!(stream.get_ref(), &result(5000).encode_to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_query_stats_round_trip() {
+        let mut worker_codec = ServerCodec::<Query, QueryResult>::new(1000, 1000);
+        let mut gateway_codec = ProtoCodec::<Query, QueryResult>::new(1000, 1000);
+        let stats = QueryStats {
+            rows_scanned: Some(1500),
+            bytes_read: Some(0),
+            exec_time_ms: Some(42),
+        };
+        let results = [
+            QueryResult::new("ok".to_string(), query_result::Result::Ok(Default::default())),
+            // Partial scan before the failure
+            QueryResult::new("err".to_string(), query_result::Result::Timeout(())),
+        ];
+        for protocol in [PROTOCOL, "/test/1.0.0/json"] {
+            for res in results.clone() {
+                let res = res.with_stats(stats.clone());
+                let mut stream = Cursor::new(Vec::new());
+                worker_codec.write_response(&protocol, &mut stream, res.clone()).await.unwrap();
+                stream.set_position(0);
+                let decoded = gateway_codec.read_response(&protocol, &mut stream).await.unwrap();
+                assert_eq!(decoded, res);
+                assert_eq!(decoded.stats.unwrap().bytes_read, Some(0));
+            }
+        }
+    }
+
+    /// Sends the first bytes of the data, then stalls without closing the stream
+    struct StalledReader {
+        data: Vec<u8>,
+        sent: bool,
+    }
+
+    impl AsyncRead for StalledReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.sent {
+                return Poll::Pending;
+            }
+            self.sent = true;
+            let n = buf.len().min(self.data.len() / 2);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let mut codec = ServerCodec::<Query, QueryResult>::new(1000, 1000)
+            .with_idle_timeout(Some(Duration::from_millis(50)));
+        let mut stream = StalledReader {
+            data: query(100).encode_to_vec(),
+            sent: false,
+        };
+        let read = codec.read_request(&PROTOCOL, &mut stream);
+        let err = tokio::time::timeout(Duration::from_secs(1), read).await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Streams making progress are not affected
+        let mut stream = Cursor::new(query(100).encode_to_vec());
+        let req = codec.read_request(&PROTOCOL, &mut stream).await.unwrap();
+        assert_eq!(req, Ok(query(100)));
+    }
+
+    /// Sends nothing until the delay has passed, then all the data at once
+    struct DelayedReader {
+        delay: Pin<Box<tokio::time::Sleep>>,
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl AsyncRead for DelayedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            futures::ready!(self.delay.as_mut().poll(cx));
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_response() {
+        let mut codec = ProtoCodec::<Query, QueryResult>::new(1000, 1000)
+            .with_idle_timeout(Some(Duration::from_millis(50)));
+        let res = QueryResult::new("id".to_string(), query_result::Result::Ok(Default::default()));
+
+        // Processing the request takes longer than the idle timeout
+        let mut stream = DelayedReader {
+            delay: Box::pin(tokio::time::sleep(Duration::from_millis(200))),
+            data: Cursor::new(res.encode_to_vec()),
+        };
+        let read = codec.read_response(&PROTOCOL, &mut stream);
+        let decoded = tokio::time::timeout(Duration::from_secs(1), read).await.unwrap().unwrap();
+        assert_eq!(decoded, res);
+
+        // Stalling in the middle of the response still fails it
+        let mut stream = StalledReader {
+            data: res.encode_to_vec(),
+            sent: false,
+        };
+        let read = codec.read_response(&PROTOCOL, &mut stream);
+        let err = tokio::time::timeout(Duration::from_secs(1), read).await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
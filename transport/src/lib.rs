@@ -21,6 +21,7 @@ use tokio::sync::mpsc;
 
 pub use libp2p::{
     identity::{Keypair, ParseError as IdParseError, PublicKey},
+    swarm::dial_opts::PeerCondition,
     Multiaddr, PeerId,
 };
 
@@ -32,20 +33,31 @@ pub use prometheus_client::registry::Registry;
 #[cfg(feature = "actors")]
 mod actors;
 #[cfg(feature = "actors")]
+mod bandwidth;
+#[cfg(feature = "actors")]
 mod behaviour;
 #[cfg(feature = "actors")]
 mod builder;
 mod cli;
+#[cfg(feature = "actors")]
+mod clock_skew;
 #[cfg(feature = "proto")]
 mod codec;
 #[cfg(feature = "metrics")]
 pub mod metrics;
+#[cfg(feature = "test-util")]
+pub mod mock;
 pub mod protocol;
+#[cfg(feature = "actors")]
+mod swarm_events;
 pub mod util;
 
+#[cfg(feature = "actors")]
+pub use crate::actors::base::BaseTransportHandle;
 #[cfg(feature = "gateway")]
 pub use crate::actors::gateway::{
-    GatewayBehaviour, GatewayConfig, GatewayEvent, GatewayTransportHandle,
+    GatewayBehaviour, GatewayConfig, GatewayEvent, GatewayTransportHandle, LookupError, QueryError,
+    SendDisposition, WorkerWeights,
 };
 #[cfg(feature = "logs-collector")]
 pub use crate::actors::logs_collector::{
@@ -53,7 +65,7 @@ pub use crate::actors::logs_collector::{
 };
 #[cfg(feature = "observer")]
 pub use crate::actors::observer::{
-    ObserverBehaviour, ObserverConfig, ObserverEvent, ObserverTransportHandle,
+    NetworkStats, ObserverBehaviour, ObserverConfig, ObserverEvent, ObserverTransportHandle,
 };
 #[cfg(feature = "scheduler")]
 pub use crate::actors::scheduler::{
@@ -61,11 +73,35 @@ pub use crate::actors::scheduler::{
 };
 #[cfg(feature = "worker")]
 pub use crate::actors::worker::{
-    WorkerBehaviour, WorkerConfig, WorkerEvent, WorkerTransportHandle,
+    DuplicateQueryPolicy, GatewayVerificationConfig, LogsCollectorStats, QueryCacheConfig,
+    RotationState, WorkerBehaviour, WorkerConfig, WorkerEvent, WorkerTransportHandle,
+};
+#[cfg(feature = "actors")]
+pub use bandwidth::BandwidthStats;
+#[cfg(feature = "request-client")]
+pub use behaviour::request_client::DeliveryStats;
+#[cfg(feature = "actors")]
+pub use behaviour::{
+    base::{
+        BootNodeUpdate, BootstrapError, ConnectionDirection, ConnectionEvent, ConnectionType,
+        DatasetProvider, KadStoreConfig, NatState, PauseMode, PeerInfo, Reachability,
+        RelayServerConfig, RoutingTableDiff, RoutingTableEntry, RoutingTableSnapshot,
+    },
+    handshake::{HandshakeConfig, NodeRole},
+    peer_blocks::{PeerBlockConfig, PeerBlockState, PeerBlocked},
+    pubsub::{
+        GossipsubTuning, InboundSizeLimit, MessageValidation, PublishRateLimit, PublishRetry,
+        PubsubMsg, TopicValidator,
+    },
+    reconnect_backoff::{ReconnectBackoffConfig, ReconnectBackoffState},
+    relay_selection::RelaySelection,
+    substream_rates::SubstreamChurnLimit,
 };
 #[cfg(feature = "actors")]
 pub use builder::P2PTransportBuilder;
 pub use cli::{BootNode, TransportArgs};
+#[cfg(feature = "actors")]
+pub use swarm_events::{DialErrorKind, SwarmEventKind};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QuicConfig {
@@ -89,6 +125,18 @@ impl QuicConfig {
             max_idle_timeout_ms,
         }
     }
+
+    /// Keep-alives need to be sent more often than the idle timeout,
+    /// otherwise they can't keep connections open
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.keep_alive_interval_ms >= self.max_idle_timeout_ms {
+            return Err(Error::InvalidConfig(format!(
+                "QUIC keep-alive interval ({} ms) should be shorter than idle timeout ({} ms)",
+                self.keep_alive_interval_ms, self.max_idle_timeout_ms
+            )));
+        }
+        Ok(())
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -101,6 +149,10 @@ impl<T> From<mpsc::error::TrySendError<T>> for QueueFull {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("Timed out")]
+pub struct Timeout;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Libp2p transport creation failed: {0}")]
@@ -111,6 +163,10 @@ pub enum Error {
     Dial(#[from] DialError),
     #[error("{0}")]
     Contract(#[from] contract_client::ClientError),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Metrics already registered")]
+    MetricsAlreadyRegistered,
 }
 
 impl From<noise::Error> for Error {
@@ -1,35 +1,94 @@
-use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64},
+    OnceLock,
+};
 
 use lazy_static::lazy_static;
-use libp2p::metrics::Metrics;
+use libp2p::{
+    metrics::Metrics,
+    request_response::{InboundFailure, OutboundFailure},
+};
 use prometheus_client::{
+    encoding::text::encode,
     metrics::{counter::Counter, family::Family, gauge::Gauge},
     registry::Registry,
 };
 use tokio::sync::OnceCell;
 
+use crate::Error;
+
 lazy_static! {
     pub static ref ACTIVE_CONNECTIONS: Gauge<u32, AtomicU32> = Default::default();
+    pub static ref CONNECTIONS_BY_TYPE: Family<Vec<(&'static str, &'static str)>, Gauge<u32, AtomicU32>> =
+        Default::default();
+    pub static ref ONGOING_DIALS: Gauge<u32, AtomicU32> = Default::default();
+    pub static ref QUEUED_DIALS: Gauge<u32, AtomicU32> = Default::default();
     pub static ref ONGOING_PROBES: Gauge<u32, AtomicU32> = Default::default();
     pub static ref ONGOING_QUERIES: Gauge<u32, AtomicU32> = Default::default();
+    pub static ref RELAY_RESERVATIONS: Gauge<u32, AtomicU32> = Default::default();
+    pub static ref RELAY_CIRCUITS: Gauge<u32, AtomicU32> = Default::default();
+    pub static ref QUERY_CACHE_HITS: Counter<u64, AtomicU64> = Default::default();
+    pub static ref QUERY_CACHE_MISSES: Counter<u64, AtomicU64> = Default::default();
+    pub static ref UNREGISTERED_GATEWAY_QUERIES: Counter<u64, AtomicU64> = Default::default();
+    pub static ref DIALS_DENIED_BY_BACKOFF: Counter<u64, AtomicU64> = Default::default();
+    pub static ref CONNECTIONS_REJECTED: Counter<u64, AtomicU64> = Default::default();
+    pub static ref PROTOCOL_MISMATCHES: Counter<u64, AtomicU64> = Default::default();
+    pub static ref BANDWIDTH_SENT: Counter<u64, AtomicU64> = Default::default();
+    pub static ref BANDWIDTH_RECEIVED: Counter<u64, AtomicU64> = Default::default();
+    pub static ref THROTTLED_READS: Counter<u64, AtomicU64> = Default::default();
+    pub static ref THROTTLED_SUBSTREAMS: Counter<u64, AtomicU64> = Default::default();
+    pub static ref GOSSIPSUB_CACHE_BYTES: Gauge = Default::default();
+    pub static ref PENDING_MESSAGE_BYTES: Gauge = Default::default();
     pub static ref QUEUE_SIZE: Family<Vec<(&'static str, &'static str)>, Gauge<u32, AtomicU32>> =
         Default::default();
     pub static ref DROPPED: Family<Vec<(&'static str, &'static str)>, Counter<u64, AtomicU64>> =
         Default::default();
+    pub static ref REQUEST_FAILURES: Family<Vec<(&'static str, String)>, Counter<u64, AtomicU64>> =
+        Default::default();
+    pub static ref RESPONSE_TIMEOUTS: Family<Vec<(&'static str, String)>, Counter<u64, AtomicU64>> =
+        Default::default();
+    pub static ref CONNECTION_FAILURES: Family<Vec<(&'static str, &'static str)>, Counter<u64, AtomicU64>> =
+        Default::default();
+    pub static ref RATE_LIMITED_BROADCASTS: Family<Vec<(&'static str, &'static str)>, Counter<u64, AtomicU64>> =
+        Default::default();
+    pub static ref LEGACY_MESSAGES: Family<Vec<(&'static str, &'static str)>, Counter<u64, AtomicU64>> =
+        Default::default();
 }
 
 pub static LIBP2P_METRICS: OnceCell<Metrics> = OnceCell::const_new();
 
-pub fn register_metrics(registry: &mut Registry) {
-    assert!(
-        LIBP2P_METRICS.set(Metrics::new(registry)).is_ok(),
-        "Metrics already initialized"
-    );
+/// Register the transport metrics. They are global for the process, so all transports share
+/// them and they can be registered only once. Further calls return an error.
+pub fn register_metrics(registry: &mut Registry) -> Result<(), Error> {
+    if LIBP2P_METRICS.initialized() || LIBP2P_METRICS.set(Metrics::new(registry)).is_err() {
+        return Err(Error::MetricsAlreadyRegistered);
+    }
+    register_transport_metrics(registry);
+    Ok(())
+}
+
+/// Register the transport-specific metrics, without the libp2p ones
+fn register_transport_metrics(registry: &mut Registry) {
     registry.register(
         "active_connections",
         "The number of active p2p connections (both incoming and outgoing)",
         ACTIVE_CONNECTIONS.clone(),
     );
+    registry.register(
+        "connections_by_type",
+        "The number of active p2p connections by type (direct, relayed or hole-punched)",
+        CONNECTIONS_BY_TYPE.clone(),
+    );
+    registry.register(
+        "ongoing_dials",
+        "The number of peer dials in progress, started after peer lookups",
+        ONGOING_DIALS.clone(),
+    );
+    registry.register(
+        "queued_dials",
+        "The number of peer dials waiting for the dial concurrency limit",
+        QUEUED_DIALS.clone(),
+    );
     registry.register(
         "ongoing_probes",
         "The number of ongoing peer reachability probes",
@@ -40,10 +99,176 @@ pub fn register_metrics(registry: &mut Registry) {
         "The number of ongoing kademlia DHT queries",
         ONGOING_QUERIES.clone(),
     );
+    registry.register(
+        "relay_reservations",
+        "The number of peers holding a reservation on the local relay server",
+        RELAY_RESERVATIONS.clone(),
+    );
+    registry.register(
+        "relay_circuits",
+        "The number of active circuits served by the local relay server",
+        RELAY_CIRCUITS.clone(),
+    );
+    registry.register(
+        "query_cache_hits",
+        "The number of queries served from the worker query result cache",
+        QUERY_CACHE_HITS.clone(),
+    );
+    registry.register(
+        "query_cache_misses",
+        "The number of queries not found in the worker query result cache",
+        QUERY_CACHE_MISSES.clone(),
+    );
+    registry.register(
+        "unregistered_gateway_queries",
+        "The number of queries rejected because the gateway isn't registered on chain",
+        UNREGISTERED_GATEWAY_QUERIES.clone(),
+    );
+    registry.register(
+        "dials_denied_by_backoff",
+        "The number of dials cancelled because the peer disconnected recently",
+        DIALS_DENIED_BY_BACKOFF.clone(),
+    );
+    registry.register(
+        "connections_rejected",
+        "The number of inbound connections rejected because of the connection cap",
+        CONNECTIONS_REJECTED.clone(),
+    );
+    registry.register(
+        "protocol_mismatches",
+        "The number of peers disconnected because of an incompatible protocol version",
+        PROTOCOL_MISMATCHES.clone(),
+    );
+    registry.register(
+        "bandwidth_sent",
+        "The number of bytes sent to peers over direct connections",
+        BANDWIDTH_SENT.clone(),
+    );
+    registry.register(
+        "bandwidth_received",
+        "The number of bytes received from peers over direct connections",
+        BANDWIDTH_RECEIVED.clone(),
+    );
+    registry.register(
+        "throttled_reads",
+        "The number of times reading from a peer was paused due to the per-peer bandwidth limit",
+        THROTTLED_READS.clone(),
+    );
+    registry.register(
+        "throttled_substreams",
+        "The number of inbound substreams dropped because the peer opened them too fast",
+        THROTTLED_SUBSTREAMS.clone(),
+    );
+    registry.register(
+        "gossipsub_cache_bytes",
+        "Approximate size of the messages in the gossipsub message cache",
+        GOSSIPSUB_CACHE_BYTES.clone(),
+    );
+    registry.register(
+        "pending_message_bytes",
+        "Total size of the outbound requests waiting for delivery",
+        PENDING_MESSAGE_BYTES.clone(),
+    );
     registry.register(
         "queue_size",
         "The number of messages/events waiting to be processed",
         QUEUE_SIZE.clone(),
     );
-    registry.register("dropped", "The number of dropped messages/events", DROPPED.clone())
+    registry.register("dropped", "The number of dropped messages/events", DROPPED.clone());
+    registry.register(
+        "rate_limited_broadcasts",
+        "The number of messages dropped due to the publish rate limit of the topic",
+        RATE_LIMITED_BROADCASTS.clone(),
+    );
+    registry.register(
+        "legacy_messages",
+        "The number of messages sent or received in legacy formats by direction and message type",
+        LEGACY_MESSAGES.clone(),
+    );
+    registry.register(
+        "request_failures",
+        "The number of failed direct requests by protocol, direction and reason",
+        REQUEST_FAILURES.clone(),
+    );
+    registry.register(
+        "response_timeouts",
+        "The number of inbound requests not responded to in time by protocol",
+        RESPONSE_TIMEOUTS.clone(),
+    );
+    registry.register(
+        "connection_failures",
+        "The number of failed outgoing connection attempts by the kind of error",
+        CONNECTION_FAILURES.clone(),
+    );
+}
+
+/// Register the transport metrics with names prefixed by `prefix`, so that they don't collide
+/// with other metrics in a registry shared with the rest of the application
+pub fn register_metrics_with_prefix(registry: &mut Registry, prefix: &str) -> Result<(), Error> {
+    register_metrics(registry.sub_registry_with_prefix(prefix))
+}
+
+/// Render the transport metrics in the Prometheus text format, for applications which don't
+/// keep a registry of their own. The libp2p metrics are not included, they're only available
+/// through [`register_metrics`].
+pub fn metrics_text() -> String {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    let registry = REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        register_transport_metrics(&mut registry);
+        registry
+    });
+    let mut text = String::new();
+    encode(&mut text, registry).expect("writing to a string should not fail");
+    text
+}
+
+pub(crate) fn record_outbound_failure(protocol: &str, error: &OutboundFailure) {
+    let reason = match error {
+        OutboundFailure::DialFailure => "DialFailure",
+        OutboundFailure::Timeout => "Timeout",
+        OutboundFailure::ConnectionClosed => "ConnectionClosed",
+        OutboundFailure::UnsupportedProtocols => "UnsupportedProtocols",
+        OutboundFailure::Io(_) => "Io",
+    };
+    record_request_failure(protocol, "outbound", reason);
+}
+
+pub(crate) fn record_inbound_failure(protocol: &str, error: &InboundFailure) {
+    let reason = match error {
+        InboundFailure::Timeout => "Timeout",
+        InboundFailure::ConnectionClosed => "ConnectionClosed",
+        InboundFailure::UnsupportedProtocols => "UnsupportedProtocols",
+        InboundFailure::ResponseOmission => "ResponseOmission",
+        InboundFailure::Io(_) => "Io",
+    };
+    record_request_failure(protocol, "inbound", reason);
+}
+
+pub(crate) fn record_response_timeout(protocol: &str) {
+    RESPONSE_TIMEOUTS.get_or_create(&vec![("protocol", protocol.to_string())]).inc();
+}
+
+fn record_request_failure(protocol: &str, direction: &'static str, reason: &'static str) {
+    REQUEST_FAILURES
+        .get_or_create(&vec![
+            ("protocol", protocol.to_string()),
+            ("direction", direction.to_string()),
+            ("reason", reason.to_string()),
+        ])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_text() {
+        THROTTLED_READS.inc();
+        let text = metrics_text();
+        assert!(text.contains("# TYPE active_connections gauge"), "{text}");
+        assert!(text.contains("throttled_reads_total"), "{text}");
+        assert!(text.ends_with("# EOF\n"), "{text}");
+    }
 }
@@ -0,0 +1,180 @@
+//! Mock transports for testing code which depends on the transport handles
+//! without running real networking.
+//!
+//! Each `start_mock_*_transport` function returns the event stream and the handle, like the
+//! real `start_transport`, together with the mock end of the transport. Messages queued through
+//! the handle can be received from the mock's queues, and events can be injected into the event
+//! stream through its `events_tx`. The handles still own a task manager, so the mocks require
+//! a multi-threaded tokio runtime, like the real transports.
+
+#[cfg(feature = "gateway")]
+pub use crate::actors::gateway::{
+    start_mock_transport as start_mock_gateway_transport, MockGatewayTransport,
+};
+#[cfg(feature = "logs-collector")]
+pub use crate::actors::logs_collector::{
+    start_mock_transport as start_mock_logs_collector_transport, MockLogsCollectorTransport,
+};
+#[cfg(feature = "observer")]
+pub use crate::actors::observer::{
+    start_mock_transport as start_mock_observer_transport, MockObserverTransport,
+};
+#[cfg(feature = "scheduler")]
+pub use crate::actors::scheduler::{
+    start_mock_transport as start_mock_scheduler_transport, MockSchedulerTransport,
+};
+#[cfg(feature = "worker")]
+pub use crate::actors::worker::{
+    start_mock_transport as start_mock_worker_transport, MockWorkerTransport,
+};
+
+#[cfg(test)]
+mod tests {
+    // Each test drives its mock the way a consumer's test would: through the handle on one
+    // side and the mock end on the other
+    use super::*;
+
+    #[cfg(feature = "gateway")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mock_gateway_transport() {
+        use std::time::Duration;
+
+        use futures::StreamExt;
+        use subsquid_messages::{query_result, Query, QueryResult};
+
+        use crate::{GatewayConfig, GatewayEvent, PeerId};
+
+        let (mut events, handle, mut mock) =
+            start_mock_gateway_transport(GatewayConfig::new(PeerId::random()));
+        let worker_id = PeerId::random();
+        let query = Query {
+            query_id: Some("q1".to_string()),
+            ..Default::default()
+        };
+        let respond = async {
+            let (peer_id, query) = mock.queries_rx.recv().await.unwrap();
+            assert_eq!(peer_id, worker_id);
+            let result =
+                QueryResult::new(query.query_id.unwrap(), query_result::Result::Timeout(()));
+            mock.send_query_result(peer_id, result).unwrap();
+        };
+        let (result, ()) =
+            tokio::join!(handle.query(worker_id, query, Duration::from_secs(5)), respond);
+        assert_eq!(result.unwrap().query_id, "q1");
+
+        // Results of queries nobody waits for go to the event stream
+        let result = QueryResult::new("q2".to_string(), query_result::Result::Timeout(()));
+        mock.send_query_result(worker_id, result.clone()).unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(GatewayEvent::QueryResult {
+                peer_id: worker_id,
+                result
+            })
+        );
+    }
+
+    #[cfg(feature = "logs-collector")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mock_logs_collector_transport() {
+        use futures::StreamExt;
+
+        use crate::{LogsCollectorEvent, PeerId};
+
+        let (mut events, handle, mut mock) =
+            start_mock_logs_collector_transport(Default::default());
+        let worker_id = PeerId::random();
+        handle.request_logs_backfill(worker_id, 10).unwrap();
+        assert_eq!(mock.logs_backfill_rx.recv().await, Some((worker_id, 10)));
+
+        mock.events_tx
+            .try_send(LogsCollectorEvent::WorkerLogs {
+                peer_id: worker_id,
+                logs: vec![],
+            })
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(LogsCollectorEvent::WorkerLogs { peer_id, .. }) if peer_id == worker_id
+        ));
+    }
+
+    #[cfg(feature = "observer")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mock_observer_transport() {
+        use crate::{ObserverConfig, PeerId, RoutingTableEntry, RoutingTableSnapshot};
+
+        let (_events, handle, mut mock) =
+            start_mock_observer_transport(ObserverConfig::new(PeerId::random()));
+        let peer_id = PeerId::random();
+        let respond = async {
+            let resp_tx = mock.routing_table_requests_rx.recv().await.unwrap();
+            let snapshot = RoutingTableSnapshot {
+                local_peer_id: PeerId::random(),
+                peers: vec![RoutingTableEntry {
+                    peer_id,
+                    addrs: vec![],
+                    bucket: 255,
+                }],
+            };
+            resp_tx.send(snapshot).unwrap();
+        };
+        let (routing_table, ()) = tokio::join!(handle.routing_table(), respond);
+        assert_eq!(routing_table.unwrap(), vec![(peer_id, vec![])]);
+    }
+
+    #[cfg(feature = "scheduler")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mock_scheduler_transport() {
+        use futures::StreamExt;
+        use subsquid_messages::Pong;
+
+        use crate::{PeerId, SchedulerEvent};
+
+        let (mut events, handle, mut mock) = start_mock_scheduler_transport(Default::default());
+        let worker_id = PeerId::random();
+        handle.send_pong(worker_id, Pong::default()).unwrap();
+        assert_eq!(mock.pongs_rx.recv().await, Some((worker_id, Pong::default())));
+
+        mock.events_tx
+            .try_send(SchedulerEvent::PeerProbed {
+                peer_id: worker_id,
+                reachable: true,
+            })
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(SchedulerEvent::PeerProbed { peer_id, reachable: true }) if peer_id == worker_id
+        ));
+    }
+
+    #[cfg(feature = "worker")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mock_worker_transport() {
+        use futures::StreamExt;
+        use subsquid_messages::{query_result, Query, QueryResult};
+
+        use crate::{PeerId, WorkerConfig, WorkerEvent};
+
+        let (mut events, handle, mut mock) =
+            start_mock_worker_transport(WorkerConfig::new(PeerId::random(), PeerId::random()));
+        let gateway_id = PeerId::random();
+        let query = Query {
+            query_id: Some("q1".to_string()),
+            ..Default::default()
+        };
+        mock.events_tx
+            .try_send(WorkerEvent::Query {
+                peer_id: gateway_id,
+                query,
+            })
+            .unwrap();
+        let Some(WorkerEvent::Query { query, .. }) = events.next().await else {
+            panic!("the query should be delivered");
+        };
+
+        let result = QueryResult::new(query.query_id.unwrap(), query_result::Result::Timeout(()));
+        handle.send_query_result(result.clone()).unwrap();
+        assert_eq!(mock.query_results_rx.recv().await, Some(result));
+    }
+}
@@ -5,21 +5,118 @@ pub const PING_TOPIC: &str = "/subsquid/worker_pings/1.0.0";
 pub const WORKER_LOGS_TOPIC: &str = "/subsquid/worker_query_logs/1.1.0";
 pub const LOGS_COLLECTED_TOPIC: &str = "/subsquid/logs_collected/1.0.0";
 
+/// Name of a pub-sub topic. Referring to topics through the constants (e.g. [`Topic::PINGS`])
+/// rather than string literals avoids typos, which silently create topics nobody listens on.
+/// Any name can still be used, strings convert into topics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Topic(&'static str);
+
+impl Topic {
+    pub const PINGS: Self = Self(PING_TOPIC);
+    pub const WORKER_LOGS: Self = Self(WORKER_LOGS_TOPIC);
+    pub const LOGS_COLLECTED: Self = Self(LOGS_COLLECTED_TOPIC);
+
+    pub const fn new(name: &'static str) -> Self {
+        Self(name)
+    }
+
+    pub const fn name(self) -> &'static str {
+        self.0
+    }
+}
+
+impl From<&'static str> for Topic {
+    fn from(name: &'static str) -> Self {
+        Self(name)
+    }
+}
+
+impl std::fmt::Display for Topic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Messages sent over protocols whose name ends with this suffix are encoded as JSON
+/// instead of protobuf, for peers without a protobuf implementation at hand. Framing and
+/// size limits are the same: one message per stream, limited in its encoded size.
+///
+/// JSON is considerably less efficient. Byte fields take 2-4x more space (hex strings
+/// or arrays of numbers), so the same size limit fits less data, and encoding is several
+/// times slower. Protobuf remains the default.
+pub const JSON_PROTOCOL_SUFFIX: &str = "/json";
+
+/// Responses on protocols whose name ends with this suffix start with a flag byte telling
+/// whether the rest of the message is gzip-compressed. The responder decides per message,
+/// compressing only the ones worth it. Requests are never compressed.
+pub const COMPRESSED_PROTOCOL_SUFFIX: &str = "/compressed";
+
 pub const ID_PROTOCOL: &str = "/subsquid/1.0.0";
 pub const QUERY_PROTOCOL: &str = "/subsquid/query/1.0.0";
+/// Query protocol with JSON-encoded messages, see [`JSON_PROTOCOL_SUFFIX`]
+pub const QUERY_PROTOCOL_JSON: &str = "/subsquid/query/1.0.0/json";
+/// Query protocol with optionally compressed results, see [`COMPRESSED_PROTOCOL_SUFFIX`]
+pub const QUERY_PROTOCOL_COMPRESSED: &str = "/subsquid/query/1.0.0/compressed";
+/// Results of queries whose original connection has been closed, sent by workers to gateways
+/// in a separate request
+pub const QUERY_RESULT_PROTOCOL: &str = "/subsquid/query-result/1.0.0";
 pub const GATEWAY_LOGS_PROTOCOL: &str = "/subsquid/gateway-logs/1.0.0";
 pub const PONG_PROTOCOL: &str = "/subsquid/pong/1.0.0";
+/// Logs collectors requesting workers to re-send logs they may have missed
+pub const LOGS_BACKFILL_PROTOCOL: &str = "/subsquid/logs-backfill/1.0.0";
+/// Peers proving their roles to each other on connection
+pub const HANDSHAKE_PROTOCOL: &str = "/subsquid/handshake/1.0.0";
 
 pub const MAX_QUERY_SIZE: u64 = 1024 * 1024;
 pub const MAX_QUERY_RESULT_SIZE: u64 = 100 * 1024 * 1024;
 pub const MAX_GATEWAY_LOG_SIZE: u64 = 1024 * 1024;
 pub const MAX_PONG_SIZE: u64 = 1024 * 1024;
+pub const MAX_HANDSHAKE_SIZE: u64 = 1024;
+pub const MAX_LOGS_BACKFILL_SIZE: u64 = 10 * 1024 * 1024;
 pub const MAX_PUBSUB_MSG_SIZE: usize = 65536;
 pub const KEEP_LAST_WORKER_LOGS: u64 = 100;
 
+/// Check whether the remote identify protocol (e.g. `/subsquid/1.2.0`) is compatible
+/// with the local one. Versions are compatible if they don't differ in the major version
+/// (or in the minor version for `0.x` versions).
+pub fn is_compatible_protocol(local: &str, remote: &str) -> bool {
+    fn parse(protocol: &str) -> Option<(&str, u64, u64)> {
+        let (prefix, version) = protocol.rsplit_once('/')?;
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((prefix, major, minor))
+    }
+    match (parse(local), parse(remote)) {
+        (Some((local_prefix, 0, local_minor)), Some((remote_prefix, 0, remote_minor))) => {
+            local_prefix == remote_prefix && local_minor == remote_minor
+        }
+        (Some((local_prefix, local_major, _)), Some((remote_prefix, remote_major, _))) => {
+            local_prefix == remote_prefix && local_major == remote_major
+        }
+        _ => false,
+    }
+}
+
 pub const fn dht_protocol(network: Network) -> StreamProtocol {
     match network {
         Network::Tethys => StreamProtocol::new("/subsquid/dht/tethys/1.0.0"),
         Network::Mainnet => StreamProtocol::new("/subsquid/dht/mainnet/1.0.0"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible_protocol() {
+        assert!(is_compatible_protocol("/subsquid/1.0.0", "/subsquid/1.0.0"));
+        assert!(is_compatible_protocol("/subsquid/1.0.0", "/subsquid/1.2.3"));
+        assert!(!is_compatible_protocol("/subsquid/1.0.0", "/subsquid/2.0.0"));
+        assert!(!is_compatible_protocol("/subsquid/0.0.1", "/subsquid/0.1.0"));
+        assert!(is_compatible_protocol("/subsquid/0.1.0", "/subsquid/0.1.5"));
+        assert!(!is_compatible_protocol("/subsquid/1.0.0", "/ipfs/1.0.0"));
+        assert!(!is_compatible_protocol("/subsquid/1.0.0", "garbage"));
+    }
+}
@@ -0,0 +1,179 @@
+use libp2p::{
+    swarm::{DialError, SwarmEvent},
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::util::Sender;
+
+/// Why an outgoing connection attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DialErrorKind {
+    /// All the addresses of the peer were unreachable or failed the handshake
+    Transport,
+    /// The remote turned out to be a different peer than the dialed one
+    WrongPeerId,
+    /// The dial was denied by a behaviour, e.g. because of the reconnection backoff
+    Denied,
+    /// The dial was aborted before it completed
+    Aborted,
+    /// No addresses of the peer are known
+    NoAddresses,
+    /// The dial condition wasn't satisfied, e.g. the peer was already being dialed
+    PeerCondition,
+    /// The dialed peer is the local one
+    LocalPeerId,
+}
+
+impl DialErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Transport => "Transport",
+            Self::WrongPeerId => "WrongPeerId",
+            Self::Denied => "Denied",
+            Self::Aborted => "Aborted",
+            Self::NoAddresses => "NoAddresses",
+            Self::PeerCondition => "PeerCondition",
+            Self::LocalPeerId => "LocalPeerId",
+        }
+    }
+}
+
+impl From<&DialError> for DialErrorKind {
+    fn from(error: &DialError) -> Self {
+        match error {
+            DialError::Transport(_) => Self::Transport,
+            DialError::WrongPeerId { .. } => Self::WrongPeerId,
+            DialError::Denied { .. } => Self::Denied,
+            DialError::Aborted => Self::Aborted,
+            DialError::NoAddresses => Self::NoAddresses,
+            DialError::DialPeerConditionFalse(_) => Self::PeerCondition,
+            DialError::LocalPeerId { .. } => Self::LocalPeerId,
+        }
+    }
+}
+
+/// Simplified, non-generic view of the notable swarm events,
+/// meant for tracing connection lifecycle in observability tooling.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwarmEventKind {
+    Dialing {
+        peer_id: Option<PeerId>,
+    },
+    IncomingConnection {
+        local_addr: Multiaddr,
+        send_back_addr: Multiaddr,
+    },
+    IncomingConnectionError {
+        send_back_addr: Multiaddr,
+        error: String,
+    },
+    OutgoingConnectionError {
+        peer_id: Option<PeerId>,
+        kind: DialErrorKind,
+        error: String,
+    },
+    ConnectionEstablished {
+        peer_id: PeerId,
+        address: Multiaddr,
+        num_established: u32,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        address: Multiaddr,
+        num_established: u32,
+    },
+    NewListenAddr {
+        address: Multiaddr,
+    },
+    ExpiredListenAddr {
+        address: Multiaddr,
+    },
+    ListenerClosed {
+        addresses: Vec<Multiaddr>,
+    },
+    ExternalAddrConfirmed {
+        address: Multiaddr,
+    },
+    ExternalAddrExpired {
+        address: Multiaddr,
+    },
+}
+
+impl SwarmEventKind {
+    /// Convert the swarm event, skipping behaviour events and the ones not worth tracing
+    pub fn from_swarm_event<T>(ev: &SwarmEvent<T>) -> Option<Self> {
+        let kind = match ev {
+            SwarmEvent::Dialing { peer_id, .. } => Self::Dialing { peer_id: *peer_id },
+            SwarmEvent::IncomingConnection {
+                local_addr,
+                send_back_addr,
+                ..
+            } => Self::IncomingConnection {
+                local_addr: local_addr.clone(),
+                send_back_addr: send_back_addr.clone(),
+            },
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => Self::IncomingConnectionError {
+                send_back_addr: send_back_addr.clone(),
+                error: error.to_string(),
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                Self::OutgoingConnectionError {
+                    peer_id: *peer_id,
+                    kind: error.into(),
+                    error: error.to_string(),
+                }
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => Self::ConnectionEstablished {
+                peer_id: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                num_established: num_established.get(),
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => Self::ConnectionClosed {
+                peer_id: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                num_established: *num_established,
+            },
+            SwarmEvent::NewListenAddr { address, .. } => Self::NewListenAddr {
+                address: address.clone(),
+            },
+            SwarmEvent::ExpiredListenAddr { address, .. } => Self::ExpiredListenAddr {
+                address: address.clone(),
+            },
+            SwarmEvent::ListenerClosed { addresses, .. } => Self::ListenerClosed {
+                addresses: addresses.clone(),
+            },
+            SwarmEvent::ExternalAddrConfirmed { address } => Self::ExternalAddrConfirmed {
+                address: address.clone(),
+            },
+            SwarmEvent::ExternalAddrExpired { address } => Self::ExternalAddrExpired {
+                address: address.clone(),
+            },
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Forward the swarm event to the raw event tap (if there is one). Never blocks.
+pub(crate) fn tap_event<T>(tap: &Option<Sender<SwarmEventKind>>, ev: &SwarmEvent<T>) {
+    if let Some(tx) = tap {
+        if let Some(kind) = SwarmEventKind::from_swarm_event(ev) {
+            tx.send_lossy(kind);
+        }
+    }
+}
@@ -3,13 +3,20 @@ use libp2p::{
     multiaddr::Protocol,
     Multiaddr,
 };
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+mod pings_collector;
 mod queue;
+mod rendezvous;
 mod task_manager;
+mod weighted;
 
-pub use queue::{new_queue, Receiver, Sender};
+pub use pings_collector::{PingsCollector, PingsCollectorConfig};
+pub use queue::{new_actor_queue, new_queue, Receiver, Sender};
+pub use rendezvous::{rendezvous_score, select_peer};
 pub use task_manager::{CancellationToken, TaskManager, DEFAULT_SHUTDOWN_TIMEOUT};
+pub use weighted::select_weighted;
 
 /// Load key from file or generate and save to file.
 pub async fn get_keypair(path: Option<PathBuf>) -> anyhow::Result<Keypair> {
@@ -35,12 +42,32 @@ pub async fn get_keypair(path: Option<PathBuf>) -> anyhow::Result<Keypair> {
     }
 }
 
+/// Warn if a node which other peers bootstrap from (a boot node, relay server or a node with
+/// public addresses) runs with an ephemeral key. Its peer id changes on every restart, which
+/// breaks the `<peer_id> <address>` entries other nodes are configured with and leaves stale
+/// entries in their Kademlia routing tables.
+pub fn warn_on_ephemeral_with_bootstrap(ephemeral: bool, bootstrap: bool) {
+    if ephemeral && bootstrap {
+        log::warn!(
+            "Running a bootstrap node with an ephemeral key, its peer ID will change on restart. \
+             Provide a key path to keep a stable identity."
+        );
+    }
+}
+
 pub fn addr_is_reachable(addr: &Multiaddr) -> bool {
+    is_reachable(addr, private_network_allowed())
+}
+
+// We need to allow private addresses for testing in local environment
+fn private_network_allowed() -> bool {
+    std::env::var("PRIVATE_NETWORK").is_ok()
+}
+
+fn is_reachable(addr: &Multiaddr, allow_private: bool) -> bool {
     match addr.iter().next() {
         Some(Protocol::Ip4(addr)) => {
-            !(addr.is_loopback() || addr.is_link_local())
-            // We need to allow private addresses for testing in local environment
-            &&(!addr.is_private() || std::env::var("PRIVATE_NETWORK").is_ok())
+            !(addr.is_loopback() || addr.is_link_local()) && (!addr.is_private() || allow_private)
         }
         Some(Protocol::Ip6(addr)) => !addr.is_loopback(),
         Some(Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)) => {
@@ -50,6 +77,44 @@ pub fn addr_is_reachable(addr: &Multiaddr) -> bool {
     }
 }
 
+fn is_loopback_or_link_local(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(addr)) => addr.is_loopback() || addr.is_link_local(),
+        Some(Protocol::Ip6(addr)) => addr.is_loopback() || (addr.segments()[0] & 0xffc0) == 0xfe80,
+        _ => false,
+    }
+}
+
+/// Which of its own listen addresses the node advertises to other peers. Binding to
+/// `0.0.0.0` yields an address for every interface, including loopback and
+/// container-internal ones, which are useless to (and pollute routing tables of) other peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum AdvertisedAddrs {
+    /// All listen addresses
+    #[default]
+    All,
+    /// All except loopback and link-local addresses
+    NonLocal,
+    /// Only addresses reachable from other hosts, see [`addr_is_reachable`]
+    Reachable,
+}
+
+impl AdvertisedAddrs {
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        self.allows_with(addr, private_network_allowed())
+    }
+
+    fn allows_with(&self, addr: &Multiaddr, allow_private: bool) -> bool {
+        match self {
+            AdvertisedAddrs::All => true,
+            AdvertisedAddrs::NonLocal => !is_loopback_or_link_local(addr),
+            AdvertisedAddrs::Reachable => {
+                is_reachable(addr, allow_private) && !is_loopback_or_link_local(addr)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -57,21 +122,51 @@ mod test {
 
     #[test]
     fn test_addr_is_reachable() {
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([127, 0, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([169, 254, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([192, 168, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([10, 0, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([172, 16, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip6([0, 0, 0, 0, 0, 0, 0, 1]), Tcp(12345u16))));
-
-        std::env::set_var("PRIVATE_NETWORK", "1");
-
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([127, 0, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip4([169, 254, 0, 1]), Tcp(12345u16))));
-        assert!(!addr_is_reachable(&multiaddr!(Ip6([0, 0, 0, 0, 0, 0, 0, 1]), Tcp(12345u16))));
-
-        assert!(addr_is_reachable(&multiaddr!(Ip4([192, 168, 0, 1]), Tcp(12345u16))));
-        assert!(addr_is_reachable(&multiaddr!(Ip4([10, 0, 0, 1]), Tcp(12345u16))));
-        assert!(addr_is_reachable(&multiaddr!(Ip4([172, 16, 0, 1]), Tcp(12345u16))));
+        for allow_private in [false, true] {
+            let reachable = |addr: Multiaddr| is_reachable(&addr, allow_private);
+            assert!(!reachable(multiaddr!(Ip4([127, 0, 0, 1]), Tcp(12345u16))));
+            assert!(!reachable(multiaddr!(Ip4([169, 254, 0, 1]), Tcp(12345u16))));
+            assert!(!reachable(multiaddr!(Ip6([0, 0, 0, 0, 0, 0, 0, 1]), Tcp(12345u16))));
+
+            assert_eq!(reachable(multiaddr!(Ip4([192, 168, 0, 1]), Tcp(12345u16))), allow_private);
+            assert_eq!(reachable(multiaddr!(Ip4([10, 0, 0, 1]), Tcp(12345u16))), allow_private);
+            assert_eq!(reachable(multiaddr!(Ip4([172, 16, 0, 1]), Tcp(12345u16))), allow_private);
+        }
+    }
+
+    #[test]
+    fn test_advertised_addrs() {
+        let addrs = [
+            multiaddr!(Ip4([127, 0, 0, 1]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip4([169, 254, 0, 1]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip4([172, 17, 0, 2]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip4([1, 2, 3, 4]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip6([0, 0, 0, 0, 0, 0, 0, 1]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip6([0xfe80, 0, 0, 0, 0, 0, 0, 1]), Udp(12345u16), QuicV1),
+            multiaddr!(Ip6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]), Udp(12345u16), QuicV1),
+            multiaddr!(Dns("example.com"), Tcp(12345u16)),
+        ];
+        let advertised = |policy: AdvertisedAddrs, allow_private: bool| {
+            addrs
+                .iter()
+                .filter(|addr| policy.allows_with(addr, allow_private))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(advertised(AdvertisedAddrs::All, false), addrs.to_vec());
+        assert_eq!(
+            advertised(AdvertisedAddrs::NonLocal, false),
+            vec![addrs[2].clone(), addrs[3].clone(), addrs[6].clone(), addrs[7].clone()]
+        );
+        // The docker bridge address is private
+        assert_eq!(
+            advertised(AdvertisedAddrs::Reachable, false),
+            vec![addrs[3].clone(), addrs[6].clone(), addrs[7].clone()]
+        );
+        assert_eq!(
+            advertised(AdvertisedAddrs::Reachable, true),
+            vec![addrs[2].clone(), addrs[3].clone(), addrs[6].clone(), addrs[7].clone()]
+        );
     }
 }
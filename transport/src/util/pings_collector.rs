@@ -0,0 +1,201 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, PeerId};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PingsCollectorConfig {
+    /// How often workers are expected to send pings
+    pub ping_interval: Duration,
+    /// Maximum number of ping timestamps stored per worker
+    pub max_pings_per_worker: usize,
+}
+
+impl PingsCollectorConfig {
+    /// Uptime is counted in ping intervals, so the interval can't be zero
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.ping_interval.is_zero() {
+            return Err(Error::InvalidConfig("Ping interval should be positive".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PingsCollectorConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(20),
+            max_pings_per_worker: 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WorkerPings {
+    // Beginning of the period covered by `timestamps`. Moves forward when old pings are evicted.
+    tracked_since: SystemTime,
+    timestamps: VecDeque<SystemTime>,
+}
+
+/// Keeps a rolling window of ping arrival times for every worker
+/// and computes worker uptime based on it.
+pub struct PingsCollector {
+    config: PingsCollectorConfig,
+    workers: HashMap<PeerId, WorkerPings>,
+}
+
+impl PingsCollector {
+    pub fn new(config: PingsCollectorConfig) -> Result<Self, Error> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            workers: Default::default(),
+        })
+    }
+
+    /// Register a ping from the worker, received just now.
+    pub fn record_ping(&mut self, worker_id: PeerId) {
+        self.record_ping_at(worker_id, SystemTime::now())
+    }
+
+    /// Register a ping from the worker with the given arrival time.
+    /// Timestamps from the future (clock skew) are clamped to the current time
+    /// and pings older than the last recorded one are ignored.
+    pub fn record_ping_at(&mut self, worker_id: PeerId, timestamp: SystemTime) {
+        let timestamp = timestamp.min(SystemTime::now());
+        let pings = self.workers.entry(worker_id).or_insert_with(|| WorkerPings {
+            tracked_since: timestamp,
+            timestamps: Default::default(),
+        });
+        if pings.timestamps.back().is_some_and(|last| *last > timestamp) {
+            log::debug!("Ignoring out-of-order ping from {worker_id}");
+            return;
+        }
+        pings.timestamps.push_back(timestamp);
+        while pings.timestamps.len() > self.config.max_pings_per_worker {
+            pings.timestamps.pop_front();
+            if let Some(oldest) = pings.timestamps.front() {
+                pings.tracked_since = *oldest;
+            }
+        }
+    }
+
+    /// Forget all pings of the given worker.
+    pub fn remove_worker(&mut self, worker_id: &PeerId) {
+        self.workers.remove(worker_id);
+    }
+
+    /// Fraction (0.0 – 1.0) of ping intervals within the last `window` in which
+    /// the worker sent at least one ping. If the worker has been tracked for less than `window`,
+    /// only the tracked period is taken into account. Returns `None` if the worker is unknown
+    /// or has been tracked for less than a single ping interval.
+    pub fn uptime(&self, worker_id: &PeerId, window: Duration) -> Option<f64> {
+        self.uptime_at(worker_id, window, SystemTime::now())
+    }
+
+    fn uptime_at(&self, worker_id: &PeerId, window: Duration, now: SystemTime) -> Option<f64> {
+        let pings = self.workers.get(worker_id)?;
+        let interval = self.config.ping_interval;
+        let window_start = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        let start = window_start.max(pings.tracked_since);
+        let tracked = now.duration_since(start).unwrap_or_default();
+        let num_intervals = tracked.as_nanos() / interval.as_nanos();
+        if num_intervals == 0 {
+            return None;
+        }
+
+        let mut covered = 0u128;
+        let mut last_slot = None;
+        for ts in &pings.timestamps {
+            let Ok(offset) = ts.duration_since(start) else {
+                continue; // ping before the window
+            };
+            let slot = offset.as_nanos() / interval.as_nanos();
+            if slot >= num_intervals {
+                break;
+            }
+            if last_slot != Some(slot) {
+                covered += 1;
+                last_slot = Some(slot);
+            }
+        }
+        Some(covered as f64 / num_intervals as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(10);
+
+    fn collector(max_pings_per_worker: usize) -> PingsCollector {
+        PingsCollector::new(PingsCollectorConfig {
+            ping_interval: INTERVAL,
+            max_pings_per_worker,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_zero_interval() {
+        let config = PingsCollectorConfig {
+            ping_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(matches!(PingsCollector::new(config), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_uptime() {
+        let mut collector = collector(100);
+        let worker_id = PeerId::random();
+        let start = SystemTime::now() - INTERVAL * 10;
+        // Worker sends pings in the first 5 intervals, then goes silent
+        for i in 0..5 {
+            collector.record_ping_at(worker_id, start + INTERVAL * i);
+        }
+        let now = start + INTERVAL * 10;
+        assert_eq!(collector.uptime_at(&worker_id, INTERVAL * 10, now), Some(0.5));
+        assert_eq!(collector.uptime_at(&worker_id, INTERVAL * 5, now), Some(0.0));
+        assert_eq!(collector.uptime_at(&PeerId::random(), INTERVAL * 10, now), None);
+    }
+
+    #[test]
+    fn test_uptime_insufficient_history() {
+        let mut collector = collector(100);
+        let worker_id = PeerId::random();
+        let start = SystemTime::now() - INTERVAL * 2;
+        collector.record_ping_at(worker_id, start);
+        assert_eq!(collector.uptime_at(&worker_id, INTERVAL * 10, start + INTERVAL / 2), None);
+        // Worker joined 2 intervals ago, so only these are taken into account
+        collector.record_ping_at(worker_id, start + INTERVAL);
+        assert_eq!(collector.uptime_at(&worker_id, INTERVAL * 10, start + INTERVAL * 2), Some(1.0));
+    }
+
+    #[test]
+    fn test_memory_bounded() {
+        let mut collector = collector(3);
+        let worker_id = PeerId::random();
+        let start = SystemTime::now() - INTERVAL * 10;
+        for i in 0..10 {
+            collector.record_ping_at(worker_id, start + INTERVAL * i);
+        }
+        assert_eq!(collector.workers[&worker_id].timestamps.len(), 3);
+        let now = start + INTERVAL * 10;
+        assert_eq!(collector.uptime_at(&worker_id, INTERVAL * 10, now), Some(1.0));
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let mut collector = collector(100);
+        let worker_id = PeerId::random();
+        collector.record_ping_at(worker_id, SystemTime::now() + INTERVAL * 100);
+        let ts = collector.workers[&worker_id].timestamps[0];
+        assert!(ts <= SystemTime::now());
+    }
+}
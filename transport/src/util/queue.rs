@@ -14,54 +14,112 @@ use crate::QueueFull;
 
 #[cfg(feature = "metrics")]
 const QUEUE_NAME: &str = "queue_name";
+#[cfg(feature = "metrics")]
+const ACTOR: &str = "actor";
+
+#[cfg(feature = "metrics")]
+fn queue_labels(
+    name: &'static str,
+    namespace: Option<&'static str>,
+) -> Vec<(&'static str, &'static str)> {
+    let mut labels = vec![(QUEUE_NAME, name)];
+    labels.extend(namespace.map(|namespace| (ACTOR, namespace)));
+    labels
+}
 
 #[derive(Clone)]
 pub struct Sender<T> {
     inner: mpsc::Sender<T>,
     name: &'static str,
+    #[allow(dead_code)]
+    namespace: Option<&'static str>,
 }
 
 impl<T: Debug> Sender<T> {
     pub fn new(inner: mpsc::Sender<T>, name: &'static str) -> Self {
-        Self { inner, name }
+        Self {
+            inner,
+            name,
+            namespace: None,
+        }
     }
 
     /// Lossy send. Drops the message if queue is full.
     pub fn send_lossy(&self, msg: T) {
-        self.try_send(msg).unwrap_or_else(|_| {
-            #[cfg(feature = "metrics")]
-            DROPPED.get_or_create(&vec![(QUEUE_NAME, self.name)]).inc();
-            log::warn!("Queue {} full. Message dropped", self.name);
-        });
+        self.send_or_drop(msg);
+    }
+
+    /// Lossy send. Returns `false` if the message has been dropped because the queue is full.
+    pub fn send_or_drop(&self, msg: T) -> bool {
+        if self.try_send(msg).is_ok() {
+            return true;
+        }
+        #[cfg(feature = "metrics")]
+        DROPPED.get_or_create(&queue_labels(self.name, self.namespace)).inc();
+        log::warn!("Queue {} full. Message dropped", self.name);
+        false
     }
 
     /// Try to send message. Returns `QueueFull` if queue is full
     pub fn try_send(&self, msg: T) -> Result<(), QueueFull> {
         self.inner.try_send(msg)?;
         #[cfg(feature = "metrics")]
-        QUEUE_SIZE.get_or_create(&vec![(QUEUE_NAME, self.name)]).inc();
+        QUEUE_SIZE.get_or_create(&queue_labels(self.name, self.namespace)).inc();
         Ok(())
     }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.inner.capacity() == 0
+    }
+
+    /// Wait until the receiving side is dropped or closed
+    pub async fn closed(&self) {
+        self.inner.closed().await
+    }
 }
 
 pub struct Receiver<T> {
     inner: mpsc::Receiver<T>,
     #[allow(dead_code)]
     name: &'static str,
+    #[allow(dead_code)]
+    namespace: Option<&'static str>,
 }
 
 impl<T> Receiver<T> {
     pub fn new(inner: mpsc::Receiver<T>, name: &'static str) -> Self {
-        Self { inner, name }
+        Self {
+            inner,
+            name,
+            namespace: None,
+        }
     }
 
     pub async fn recv(&mut self) -> Option<T> {
         self.inner.recv().await.map(|msg| {
             #[cfg(feature = "metrics")]
-            QUEUE_SIZE.get_or_create(&vec![(QUEUE_NAME, self.name)]).dec();
+            QUEUE_SIZE.get_or_create(&queue_labels(self.name, self.namespace)).dec();
+            msg
+        })
+    }
+
+    /// Receive a message if one is available right away
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.inner.try_recv().ok().map(|msg| {
+            #[cfg(feature = "metrics")]
+            QUEUE_SIZE.get_or_create(&queue_labels(self.name, self.namespace)).dec();
             msg
         })
     }
+
+    /// Stop accepting new messages. Messages already in the queue can still be received.
+    pub fn close(&mut self) {
+        self.inner.close()
+    }
 }
 
 impl<T> Stream for Receiver<T> {
@@ -73,8 +131,59 @@ impl<T> Stream for Receiver<T> {
 }
 
 pub fn new_queue<T: Debug>(size: usize, name: &'static str) -> (Sender<T>, Receiver<T>) {
+    new_actor_queue(size, name, None)
+}
+
+/// Queue of a transport actor. If `namespace` is set, the queue metrics are labeled with it,
+/// so that queues of different actors running in one process can be told apart.
+pub fn new_actor_queue<T: Debug>(
+    size: usize,
+    name: &'static str,
+    namespace: Option<&'static str>,
+) -> (Sender<T>, Receiver<T>) {
     let (tx, rx) = mpsc::channel(size);
-    let tx = Sender::new(tx, name);
-    let rx = Receiver::new(rx, name);
+    let mut tx = Sender::new(tx, name);
+    let mut rx = Receiver::new(rx, name);
+    tx.namespace = namespace;
+    rx.namespace = namespace;
     (tx, rx)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_close_keeps_queued() {
+        let (tx, mut rx) = new_queue(10, "test");
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        rx.close();
+        assert!(tx.try_send(3).is_err());
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_queue_labels() {
+        assert_eq!(queue_labels("events", None), vec![(QUEUE_NAME, "events")]);
+        assert_eq!(
+            queue_labels("events", Some("worker")),
+            vec![(QUEUE_NAME, "events"), (ACTOR, "worker")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closed_when_receiver_dropped() {
+        let (tx, rx) = new_queue::<u32>(10, "test");
+        let closed = tokio::time::timeout(Duration::from_millis(10), tx.closed());
+        assert!(closed.await.is_err());
+        drop(rx);
+        let closed = tokio::time::timeout(Duration::from_millis(10), tx.closed());
+        assert!(closed.await.is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+use libp2p::PeerId;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Rendezvous (highest random weight) score of `peer_id` for the given key.
+/// The hash is stable across processes and platforms, so all nodes agree on the result.
+pub fn rendezvous_score(key: &str, peer_id: &PeerId) -> u64 {
+    // FNV-1a followed by a splitmix64 finalizer for better bit dispersion
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in key.as_bytes().iter().chain([0xff].iter()).chain(&peer_id.to_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Pick the candidate with the highest rendezvous score for the given key.
+/// Removing a candidate only reassigns the keys that were mapped to it.
+pub fn select_peer(key: &str, candidates: impl IntoIterator<Item = PeerId>) -> Option<PeerId> {
+    candidates
+        .into_iter()
+        .max_by_key(|peer_id| (rendezvous_score(key, peer_id), peer_id.to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::random()).collect()
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let candidates = peers(10);
+        let selected = select_peer("query", candidates.clone()).unwrap();
+        let mut reversed = candidates.clone();
+        reversed.reverse();
+        assert_eq!(select_peer("query", reversed), Some(selected));
+        assert_eq!(select_peer("query", []), None);
+    }
+
+    #[test]
+    fn test_minimal_reassignment() {
+        let candidates = peers(10);
+        let keys: Vec<String> = (0..1000).map(|i| format!("query-{i}")).collect();
+        let before: Vec<PeerId> =
+            keys.iter().map(|k| select_peer(k, candidates.clone()).unwrap()).collect();
+
+        let removed = candidates[0];
+        let remaining = &candidates[1..];
+        for (key, prev) in keys.iter().zip(before) {
+            let selected = select_peer(key, remaining.iter().copied()).unwrap();
+            if prev != removed {
+                assert_eq!(selected, prev);
+            }
+        }
+    }
+
+    #[test]
+    fn test_balanced() {
+        let candidates = peers(4);
+        let mut counts = [0usize; 4];
+        for i in 0..4000 {
+            let selected = select_peer(&format!("query-{i}"), candidates.clone()).unwrap();
+            counts[candidates.iter().position(|p| *p == selected).unwrap()] += 1;
+        }
+        assert!(counts.iter().all(|c| (700..1300).contains(c)), "{counts:?}");
+    }
+}
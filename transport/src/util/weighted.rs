@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+use rand::{seq::SliceRandom, Rng};
+
+/// Pick a random candidate with probability proportional to its weight. Candidates without
+/// a weight (or with zero) are only picked if none of the candidates has a positive weight.
+pub fn select_weighted(
+    candidates: impl IntoIterator<Item = PeerId>,
+    weights: &HashMap<PeerId, u64>,
+    rng: &mut impl Rng,
+) -> Option<PeerId> {
+    let candidates: Vec<(PeerId, u128)> = candidates
+        .into_iter()
+        .map(|peer_id| (peer_id, weights.get(&peer_id).copied().unwrap_or(0).into()))
+        .collect();
+    let total: u128 = candidates.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return candidates.choose(rng).map(|(peer_id, _)| *peer_id);
+    }
+    let mut point = rng.gen_range(0..total);
+    for (peer_id, weight) in candidates {
+        if point < weight {
+            return Some(peer_id);
+        }
+        point -= weight;
+    }
+    unreachable!("point is below the total weight")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_select_weighted() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let [a, b, c] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        let weights: HashMap<PeerId, u64> = [(a, 100), (b, 300), (c, 0)].into();
+
+        let mut counts = [0usize; 3];
+        for _ in 0..4000 {
+            let selected = select_weighted([a, b, c], &weights, &mut rng).unwrap();
+            counts[[a, b, c].iter().position(|p| *p == selected).unwrap()] += 1;
+        }
+        assert!((800..1200).contains(&counts[0]), "{counts:?}");
+        assert!((2800..3200).contains(&counts[1]), "{counts:?}");
+        assert_eq!(counts[2], 0);
+
+        // Same seed, same choices
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10).map(|_| select_weighted([a, b], &weights, &mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(picks(1), picks(1));
+
+        // Without any weights the choice is uniform
+        assert_eq!(select_weighted([c], &weights, &mut rng), Some(c));
+        assert!(select_weighted([c, PeerId::random()], &weights, &mut rng).is_some());
+        assert_eq!(select_weighted([], &weights, &mut rng), None);
+    }
+}